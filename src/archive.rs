@@ -0,0 +1,842 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{ Read, Write };
+use std::os::unix::ffi::OsStrExt;
+use std::path::{ Component, Path, PathBuf };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use flate2::{ Compression as Level, write::GzEncoder, read::{ GzDecoder, MultiGzDecoder } };
+use tar::{ Archive, Builder, Entry, EntryType, Header };
+use walkdir::{ DirEntry, WalkDir };
+
+use crate::format::Compression;
+use crate::spill::Buffer;
+use crate::summary::{ Summary, human_bytes };
+
+/// Which file metadata to carry through the archive beyond the basics.
+///
+/// Archives always record each file's mode, owner and modification time,
+/// and restore the permission bits (minus setuid, setgid and sticky).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Preserve {
+    /// Restore the full mode, including setuid, setgid and sticky bits.
+    pub permissions: bool,
+    /// Restore the owning user and group; usually needs root.
+    pub ownership: bool,
+    /// Store and restore extended attributes.
+    pub xattrs: bool,
+}
+
+/// Caps on what unpacking an archive may write, against archives that
+/// decompress to far more than expected. None means no cap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Bytes, all files together.
+    pub size: Option<u64>,
+    /// Entries: files, directories and links.
+    pub entries: Option<u64>,
+    /// Bytes, in any one file.
+    pub file_size: Option<u64>,
+}
+
+impl Limits {
+    /// Read through an archive's headers, without writing anything, and
+    /// fail if unpacking it would go over a limit.
+    fn check(&self, tar: &Buffer, compressed: bool) -> std::io::Result<()> {
+        if *self == Limits::default() {
+            return Ok(())
+        }
+        let dec: Box<dyn Read> = match compressed {
+            true  => Box::new(MultiGzDecoder::new(tar.reader())),
+            false => Box::new(tar.reader()),
+        };
+        let (mut entries, mut total) = (0, 0);
+        for entry in Archive::new(dec).entries()? {
+            let entry = entry?;
+            let size = entry.size();
+            entries += 1;
+            total += size;
+            let over = if self.entries.is_some_and(|x| entries > x) {
+                format!("it holds more than {} entries (max_unpack_entries)", entries - 1)
+            } else if let Some(x) = self.file_size.filter(|x| size > *x) {
+                format!("{} is {}, over max_unpack_file_size ({})",
+                        entry.path()?.display(), human_bytes(size), human_bytes(x))
+            } else if let Some(x) = self.size.filter(|x| total > *x) {
+                format!("it holds more than {} (max_unpack_size)", human_bytes(x))
+            } else {
+                continue
+            };
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                                           format!("Archive not unpacked: {}", over)))
+        }
+        Ok(())
+    }
+}
+
+/// What to do with symbolic links in a sync folder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Store links as links.
+    Preserve,
+    /// Store what links point to, as if it were in the folder.
+    Follow,
+    /// Leave links out.
+    Skip,
+}
+
+impl Symlinks {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "preserve" => Some(Symlinks::Preserve),
+            "follow"   => Some(Symlinks::Follow),
+            "skip"     => Some(Symlinks::Skip),
+            _ => None,
+        }
+    }
+
+    /// Walk a folder's contents, handling links according to the policy
+    /// and leaving out excluded files.
+    pub fn walk<'a>(&self, path: &Path, exclude: &'a Exclude)
+    -> impl Iterator<Item = walkdir::Result<DirEntry>> + 'a {
+        self.walk_following(path, exclude, *self == Symlinks::Follow)
+    }
+
+    fn walk_following<'a>(&self, path: &Path, exclude: &'a Exclude, follow: bool)
+    -> impl Iterator<Item = walkdir::Result<DirEntry>> + 'a {
+        let skip = *self == Symlinks::Skip;
+        WalkDir::new(path)
+            .min_depth(1)
+            .sort_by_file_name()
+            .follow_links(follow)
+            .into_iter()
+            .filter(move |e| match e {
+                Ok(e) => ! ((skip && e.path_is_symlink()) || exclude.excludes(e)),
+                Err(_) => true,
+            })
+    }
+}
+
+/// Files to leave out of the archive and the sync.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Exclude {
+    /// Leave out files bigger than this many bytes.
+    pub max_file_size: Option<u64>,
+    /// Leave out files with these extensions, given in lowercase without
+    /// the dot (e.g. "iso", "tar.gz"); matched ignoring case.
+    pub types: Vec<String>,
+}
+
+impl Exclude {
+    fn too_big(&self, e: &DirEntry) -> bool {
+        match self.max_file_size {
+            Some(max) => e.metadata().map(|m| m.len() > max).unwrap_or(false),
+            None => false,
+        }
+    }
+
+    fn excluded_type(&self, e: &DirEntry) -> bool {
+        let name = e.file_name().to_string_lossy().to_lowercase();
+        self.types.iter().any(|x| name.ends_with(&format!(".{}", x)))
+    }
+
+    /// Whether a walked entry should be left out. Only files are.
+    pub fn excludes(&self, e: &DirEntry) -> bool {
+        e.file_type().is_file() && (self.too_big(e) || self.excluded_type(e))
+    }
+
+    /// Unison `-ignore` arguments matching the excluded files, so unison
+    /// neither copies them nor takes their absence from the archive as a
+    /// deletion. Unison can't match on size, so files that are too big are
+    /// listed by path.
+    pub fn unison_args(&self, dir: &Path, symlinks: Symlinks) -> Vec<String> {
+        let mut args = Vec::new();
+        for x in &self.types {
+            // Unison's globs are case sensitive, so spell out both cases.
+            let glob: String = x.chars()
+                .map(|c| match c.is_alphabetic() {
+                    true  => format!("[{}{}]", c, c.to_uppercase()),
+                    false => c.to_string(),
+                })
+                .collect();
+            args.push("-ignore".to_string());
+            args.push(format!("Name *.{}", glob));
+        }
+        if self.max_file_size.is_none() {
+            return args
+        }
+        for e in symlinks.walk(dir, &Exclude::default()).flatten() {
+            if ! (e.file_type().is_file() && self.too_big(&e)) {
+                continue
+            }
+            let path = e.path().strip_prefix(dir).expect("walkdir stays under dir");
+            // Glob characters are matched literally inside brackets.
+            let escaped: String = path.to_string_lossy()
+                .chars()
+                .map(|c| match "*?[{},".contains(c) {
+                    true  => format!("[{}]", c),
+                    false => c.to_string(),
+                })
+                .collect();
+            args.push("-ignore".to_string());
+            args.push(format!("Path {}", escaped));
+        }
+        args
+    }
+}
+
+/// Encode one PAX extended header record, `<len> <key>=<value>\n`, where
+/// the length counts the whole record including its own digits.
+fn pax_record(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let rest = key.len() + value.len() + 3;
+    let mut len = rest + 1;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+    let mut r = format!("{} ", len).into_bytes();
+    r.extend_from_slice(key);
+    r.push(b'=');
+    r.extend_from_slice(value);
+    r.push(b'\n');
+    r
+}
+
+/// The PAX records holding a file's extended attributes, if it has any.
+///
+/// The tar crate splits PAX headers on newlines when reading them back, so
+/// attributes containing one are left out and noted in the summary.
+fn xattr_records(path: &Path, summary: &mut Summary) -> std::io::Result<Vec<u8>> {
+    let mut records = Vec::new();
+    for name in xattr::list(path)? {
+        let value = match xattr::get(path, &name)? {
+            Some(x) => x,
+            None => continue,
+        };
+        if name.as_bytes().contains(&b'\n') || value.contains(&b'\n') {
+            summary.warn(format!("xattr: {:?} on {} contains a newline and wasn't stored",
+                                 name, path.display()));
+            continue
+        }
+        let mut key = b"SCHILY.xattr.".to_vec();
+        key.extend_from_slice(name.as_bytes());
+        records.extend(pax_record(&key, &value));
+    }
+    Ok(records)
+}
+
+/// The PAX record holding a file's modification time to the nanosecond,
+/// which the tar header can only hold to the second. Not needed for whole
+/// seconds, or times before 1970.
+fn mtime_record(entry: &DirEntry) -> std::io::Result<Option<Vec<u8>>> {
+    let mtime = match entry.metadata()?.modified()?.duration_since(UNIX_EPOCH) {
+        Ok(x) if x.subsec_nanos() != 0 => x,
+        _ => return Ok(None),
+    };
+    let value = format!("{}.{:09}", mtime.as_secs(), mtime.subsec_nanos());
+    Ok(Some(pax_record(b"mtime", value.as_bytes())))
+}
+
+/// Parse a PAX time, `<seconds>[.<fraction>]`.
+fn parse_pax_time(s: &str) -> Option<SystemTime> {
+    let (secs, frac) = s.split_once('.').unwrap_or((s, ""));
+    let secs: u64 = secs.parse().ok()?;
+    let frac = frac.get(..9).unwrap_or(frac);
+    let nanos: u32 = match frac {
+        "" => 0,
+        x => format!("{:0<9}", x).parse().ok()?,
+    };
+    Some(UNIX_EPOCH + Duration::new(secs, nanos))
+}
+
+/// The precise modification time in an entry's PAX header, if it has one.
+fn pax_mtime<R: Read>(entry: &mut Entry<R>) -> std::io::Result<Option<SystemTime>> {
+    if let Some(records) = entry.pax_extensions()? {
+        for r in records {
+            let r = r?;
+            if r.key() == Ok("mtime") {
+                return Ok(r.value().ok().and_then(parse_pax_time))
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Fail unless `path`, from an archive, stays inside the directory it's
+/// unpacked in: no root, and no `..`.
+fn inside(path: &Path) -> std::io::Result<()> {
+    if path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return Ok(())
+    }
+    Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                            format!("archive entry {} points outside the folder; the archive \
+                                     is damaged or was crafted, and wasn't unpacked",
+                                    path.display())))
+}
+
+/// Where an entry unpacks to, relative to the destination, without the
+/// `.` components unpack_in() drops. An entry with an absolute path or
+/// `..`, or a hard link to one, is an error rather than skipped.
+fn entry_path<R: Read>(entry: &Entry<R>) -> std::io::Result<PathBuf> {
+    let path = entry.path()?;
+    inside(&path)?;
+    if entry.header().entry_type().is_hard_link() {
+        if let Some(x) = entry.link_name()? {
+            inside(&x)?;
+        }
+    }
+    Ok(path.components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect())
+}
+
+/// Unpack one entry, then give it its precise modification time, if the
+/// archive recorded one. The tar crate only applies whole seconds.
+fn unpack_entry<R: Read>(mut entry: Entry<R>, mtime: Option<SystemTime>, dest: &Path)
+-> std::io::Result<PathBuf> {
+    let path = entry_path(&entry)?;
+    let link = entry.header().entry_type().is_symlink();
+    if entry.unpack_in(dest)? && ! link {
+        if let Some(t) = mtime {
+            // Best effort: a file without read permission can't be opened.
+            if let Ok(f) = File::open(dest.join(&path)) {
+                let _ = f.set_modified(t);
+            }
+        }
+    }
+    Ok(path)
+}
+
+/// Add the contents of `source` to the archive. Each entry whose extended
+/// attributes are kept (with `preserve.xattrs`), or whose modification
+/// time has a fraction of a second, is preceded by a PAX header recording
+/// them. Long paths and large files use GNU extensions.
+fn append_all<W: Sink>(tar: &mut Builder<W>, source: &Path, preserve: Preserve,
+                        symlinks: Symlinks, exclude: &Exclude, summary: &mut Summary)
+-> std::io::Result<()> {
+    tar.follow_symlinks(symlinks == Symlinks::Follow);
+    for entry in symlinks.walk(source, exclude) {
+        let entry = entry?;
+        let name = entry.path().strip_prefix(source).expect("walkdir stays under source");
+        // Attributes of a stored link would be looked up on its target.
+        let link = entry.path_is_symlink() && symlinks == Symlinks::Preserve;
+        tar.get_mut().next_entry(name)?;
+        let mut records = Vec::new();
+        if preserve.xattrs && ! link {
+            records.extend(xattr_records(entry.path(), summary)?);
+        }
+        if ! link {
+            records.extend(mtime_record(&entry)?.unwrap_or_default());
+        }
+        if ! records.is_empty() {
+            let mut h = Header::new_ustar();
+            h.set_entry_type(EntryType::XHeader);
+            h.set_path("PaxHeader")?;
+            h.set_mode(0o644);
+            h.set_size(records.len() as u64);
+            h.set_cksum();
+            tar.append(&h, records.as_slice())?;
+        }
+        tar.append_path_with_name(entry.path(), name)?;
+    }
+    Ok(())
+}
+
+/// How many bytes unpacking an archive writes, at least. A gzip stream
+/// ends with its uncompressed size modulo 4GiB, which is taken to be the
+/// smallest size that's no less than the compressed one.
+pub fn unpacked_size(tar: &Buffer, compressed: bool) -> std::io::Result<u64> {
+    let len = tar.len();
+    if ! compressed {
+        return Ok(len)
+    }
+    let isize = match tar.tail(4)?.try_into() {
+        Ok(x) => u32::from_le_bytes(x) as u64,
+        Err(_) => return Ok(0),
+    };
+    let wraps = len.saturating_sub(isize).div_ceil(1 << 32);
+    Ok(isize + (wraps << 32))
+}
+
+/// Unpack tar data, gzip-compressed if `compressed` (in one member or
+/// several), and write the folder to disk, leaving out the paths in `skip`.
+/// Returns the paths unpacked, relative to `dest`. An entry that would be
+/// written outside `dest`, by its path or through a link unpacked before
+/// it, stops the unpacking with an error; an archive over `limits` isn't
+/// unpacked at all.
+pub async fn unpack_tar(tar: &Buffer, dest: &Path, preserve: Preserve, limits: Limits,
+                        compressed: bool, skip: &HashSet<PathBuf>)
+-> Result<HashSet<PathBuf>, std::io::Error> {
+    limits.check(tar, compressed)?;
+    let dec: Box<dyn Read> = match compressed {
+        true  => Box::new(MultiGzDecoder::new(tar.reader())),
+        false => Box::new(tar.reader()),
+    };
+    let mut tar = Archive::new(dec);
+    tar.set_preserve_permissions(preserve.permissions);
+    tar.set_preserve_ownerships(preserve.ownership);
+    tar.set_unpack_xattrs(preserve.xattrs);
+    std::fs::create_dir_all(dest)?;
+    let dest = &dest.canonicalize()?;
+    // Directories go last, children before parents, so filling them in
+    // doesn't disturb the modification times they're given.
+    let mut dirs = Vec::new();
+    let mut paths = HashSet::new();
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let mtime = pax_mtime(&mut entry)?;
+        if skip.contains(&entry_path(&entry)?) {
+            continue
+        }
+        if entry.header().entry_type() == EntryType::Directory {
+            dirs.push((entry, mtime));
+        } else {
+            paths.insert(unpack_entry(entry, mtime, dest)?);
+        }
+    }
+    for (entry, mtime) in dirs.into_iter().rev() {
+        paths.insert(unpack_entry(entry, mtime, dest)?);
+    }
+    Ok(paths)
+}
+
+/// Remove what's in `dir` but not in `keep`, the paths an archive held,
+/// relative to it. Excluded files were never archived, so they're left.
+/// Links are never followed, even with `Symlinks::Follow`: what they point
+/// to is outside the folder, so a link is kept if the archive held
+/// anything through it, and otherwise only the link is removed. Returns
+/// what was removed.
+pub fn prune(dir: &Path, keep: &HashSet<PathBuf>, symlinks: Symlinks, exclude: &Exclude)
+-> std::io::Result<Vec<PathBuf>> {
+    // Walked in full first, as removing a directory mid-walk would fail it.
+    let entries = symlinks.walk_following(dir, exclude, false).collect::<Result<Vec<_>, _>>()?;
+    let mut gone: Vec<PathBuf> = Vec::new();
+    for entry in entries {
+        let path = entry.path().strip_prefix(dir).expect("walkdir stays under dir");
+        if keep.contains(path) || gone.iter().any(|x| path.starts_with(x)) {
+            continue
+        }
+        if entry.path_is_symlink() && keep.iter().any(|x| x.starts_with(path)) {
+            continue
+        }
+        match entry.file_type().is_dir() && ! entry.path_is_symlink() {
+            true  => std::fs::remove_dir_all(entry.path())?,
+            false => std::fs::remove_file(entry.path())?,
+        }
+        gone.push(path.to_path_buf());
+    }
+    Ok(gone)
+}
+
+/// Find the regular file `path` in tar data, gzip-compressed if
+/// `compressed`, and return its contents, without unpacking anything else.
+pub fn extract_file(tar: &Buffer, path: &Path, compressed: bool)
+-> Result<Option<Vec<u8>>, std::io::Error> {
+    let dec: Box<dyn Read> = match compressed {
+        true  => Box::new(MultiGzDecoder::new(tar.reader())),
+        false => Box::new(tar.reader()),
+    };
+    let mut tar = Archive::new(dec);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_file() && entry.path()? == path {
+            let mut b = Vec::new();
+            entry.read_to_end(&mut b)?;
+            return Ok(Some(b))
+        }
+    }
+    Ok(None)
+}
+
+/// Create an archive of the sync folder, compressed as asked, kept in
+/// memory up to `spill` bytes and on disk past that.
+pub async fn create_tar(source: &Path, preserve: Preserve, symlinks: Symlinks,
+                        exclude: &Exclude, compression: Compression, spill: Option<u64>,
+                        summary: &mut Summary)
+-> Result<Buffer, std::io::Error> {
+    let out = Buffer::new(spill);
+    let (raw, comp) = match compression {
+        Compression::None => {
+            let mut tar = Builder::new(out);
+            append_all(&mut tar, source, preserve, symlinks, exclude, summary)?;
+            let tar = tar.into_inner()?;
+            summary.archive_bytes += tar.len();
+            return Ok(tar)
+        }
+        Compression::Gzip => {
+            let enc = GzEncoder::new(out, Level::default());
+            let mut tar = Builder::new(Counter { inner: enc, count: 0 });
+            append_all(&mut tar, source, preserve, symlinks, exclude, summary)?;
+            let counter = tar.into_inner()?;
+            (counter.count, counter.inner.finish()?)
+        }
+        Compression::Auto => {
+            let mut tar = Builder::new(Members::new(out));
+            append_all(&mut tar, source, preserve, symlinks, exclude, summary)?;
+            let members = tar.into_inner()?;
+            (members.count, members.finish()?)
+        }
+    };
+    summary.archive_bytes += raw;
+    summary.compressed_in += raw;
+    summary.compressed_bytes += comp.len();
+    Ok(comp)
+}
+
+/// Where an archive is written, told of each entry before it's added.
+trait Sink: Write {
+    fn next_entry(&mut self, _path: &Path) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for Buffer {}
+
+/// File types whose contents are compressed already, so gzip would take
+/// its time over them for next to nothing.
+const PRECOMPRESSED: &[&str] = &[
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "docx", "epub", "flac", "gif", "gpg",
+    "gz", "heic", "heif", "jar", "jpeg", "jpg", "jxl", "lz", "lz4", "lzma", "m4a", "m4v",
+    "mkv", "mov", "mp3", "mp4", "odp", "ods", "odt", "ogg", "opus", "pgp", "png", "pptx",
+    "rar", "tbz2", "tgz", "txz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// Whether a file's extension says it's compressed already.
+pub fn precompressed(path: &Path) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| PRECOMPRESSED.contains(&x.to_lowercase().as_str()))
+}
+
+/// Gzip in several members, a new one starting wherever the level changes,
+/// so files that are compressed already are stored rather than deflated.
+/// The members decompress as one stream.
+struct Members {
+    enc: Option<GzEncoder<Buffer>>,
+    level: Level,
+    /// Bytes written, before compression.
+    count: u64,
+}
+
+impl Members {
+    fn new(out: Buffer) -> Self {
+        let level = Level::default();
+        Members { enc: Some(GzEncoder::new(out, level)), level, count: 0 }
+    }
+
+    fn enc(&mut self) -> &mut GzEncoder<Buffer> {
+        self.enc.as_mut().expect("only taken while switching members")
+    }
+
+    fn finish(mut self) -> std::io::Result<Buffer> {
+        self.enc.take().expect("not taken").finish()
+    }
+}
+
+impl Write for Members {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.enc().write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.enc().flush()
+    }
+}
+
+impl Sink for Members {
+    fn next_entry(&mut self, path: &Path) -> std::io::Result<()> {
+        let level = match precompressed(path) {
+            true  => Level::none(),
+            false => Level::default(),
+        };
+        if level != self.level {
+            let out = self.enc.take().expect("not taken").finish()?;
+            self.enc = Some(GzEncoder::new(out, level));
+            self.level = level;
+        }
+        Ok(())
+    }
+}
+
+/// A writer that counts the bytes written through it.
+struct Counter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Sink for Counter<W> {}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Gzip-compress data.
+pub fn compress(bytes: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    compress_at(bytes, Level::default())
+}
+
+/// Gzip data without compressing it, for data that's compressed already.
+pub fn store(bytes: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    compress_at(bytes, Level::none())
+}
+
+fn compress_at(bytes: &[u8], level: Level) -> Result<Vec<u8>, std::io::Error> {
+    let mut enc = GzEncoder::new(Vec::new(), level);
+    enc.write_all(bytes)?;
+    enc.finish()
+}
+
+/// Decompress gzip data.
+pub fn decompress(bytes: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let mut b = Vec::new();
+    GzDecoder::new(bytes).read_to_end(&mut b)?;
+    Ok(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tar entry with `name` as it is, which the tar crate's builder
+    /// would refuse for `..` or a leading slash.
+    fn raw_entry(tar: &mut Vec<u8>, name: &[u8], kind: EntryType, link: &[u8], data: &[u8]) {
+        let mut h = Header::new_old();
+        h.as_old_mut().name[..name.len()].copy_from_slice(name);
+        h.as_old_mut().linkname[..link.len()].copy_from_slice(link);
+        h.set_entry_type(kind);
+        h.set_mode(0o644);
+        h.set_size(data.len() as u64);
+        h.set_cksum();
+        tar.extend_from_slice(h.as_bytes());
+        tar.extend_from_slice(data);
+        tar.resize(tar.len().div_ceil(512) * 512, 0);
+    }
+
+    fn finish(mut tar: Vec<u8>) -> Buffer {
+        tar.resize(tar.len() + 1024, 0);
+        Buffer::from(tar)
+    }
+
+    async fn unpack(tar: &Buffer, dest: &Path) -> std::io::Result<HashSet<PathBuf>> {
+        unpack_tar(tar, dest, Preserve::default(), Limits::default(), false, &HashSet::new()).await
+    }
+
+    #[tokio::test]
+    async fn refuses_parent_dirs() {
+        let root = tempfile::tempdir().unwrap();
+        let dest = root.path().join("dest");
+        let mut tar = Vec::new();
+        raw_entry(&mut tar, b"fine", EntryType::Regular, b"", b"ok");
+        raw_entry(&mut tar, b"a/../../escaped", EntryType::Regular, b"", b"bad");
+        assert!(unpack(&finish(tar), &dest).await.is_err());
+        assert!(! root.path().join("escaped").exists());
+    }
+
+    #[tokio::test]
+    async fn refuses_absolute_paths() {
+        let root = tempfile::tempdir().unwrap();
+        let target = root.path().join("absolute");
+        let mut tar = Vec::new();
+        raw_entry(&mut tar, target.as_os_str().as_bytes(), EntryType::Regular, b"", b"bad");
+        assert!(unpack(&finish(tar), &root.path().join("dest")).await.is_err());
+        assert!(! target.exists());
+    }
+
+    #[tokio::test]
+    async fn refuses_hard_links_out() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("secret"), "secret").unwrap();
+        let mut tar = Vec::new();
+        raw_entry(&mut tar, b"link", EntryType::Link, b"../secret", b"");
+        let dest = root.path().join("dest");
+        assert!(unpack(&finish(tar), &dest).await.is_err());
+        assert!(! dest.join("link").exists());
+    }
+
+    #[tokio::test]
+    async fn refuses_writing_through_links() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = root.path().join("outside");
+        std::fs::create_dir(&outside).unwrap();
+        let mut tar = Vec::new();
+        raw_entry(&mut tar, b"out", EntryType::Symlink, outside.as_os_str().as_bytes(), b"");
+        raw_entry(&mut tar, b"out/planted", EntryType::Regular, b"", b"bad");
+        assert!(unpack(&finish(tar), &root.path().join("dest")).await.is_err());
+        assert!(! outside.join("planted").exists());
+    }
+
+    #[tokio::test]
+    async fn stops_at_limits() {
+        let root = tempfile::tempdir().unwrap();
+        let dest = root.path().join("dest");
+        let mut tar = Vec::new();
+        raw_entry(&mut tar, b"one", EntryType::Regular, b"", &[0; 600]);
+        raw_entry(&mut tar, b"two", EntryType::Regular, b"", &[0; 600]);
+        let tar = finish(tar);
+        let limits = [
+            Limits { entries: Some(1), ..Limits::default() },
+            Limits { file_size: Some(599), ..Limits::default() },
+            Limits { size: Some(1000), ..Limits::default() },
+        ];
+        for x in limits {
+            let res = unpack_tar(&tar, &dest, Preserve::default(), x, false, &HashSet::new()).await;
+            assert!(res.is_err(), "{:?}", x);
+            // Nothing is written, not even what came before the limit.
+            assert!(! dest.join("one").exists());
+        }
+        let x = Limits { size: Some(1200), entries: Some(2), file_size: Some(600) };
+        let res = unpack_tar(&tar, &dest, Preserve::default(), x, false, &HashSet::new()).await;
+        assert_eq!(res.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn round_trips() {
+        let root = tempfile::tempdir().unwrap();
+        let src = root.path().join("src");
+        std::fs::create_dir_all(src.join("a/b")).unwrap();
+        std::fs::write(src.join("a/b/c.txt"), "contents").unwrap();
+        std::os::unix::fs::symlink("b/c.txt", src.join("a/link")).unwrap();
+        let mut summary = Summary::new("test", crate::summary::Direction::Push);
+        let tar = create_tar(&src, Preserve::default(), Symlinks::Preserve, &Exclude::default(),
+                             Compression::Gzip, Some(16), &mut summary).await.unwrap();
+        assert!(tar.spilled());
+        let dest = root.path().join("dest");
+        let paths = unpack_tar(&tar, &dest, Preserve::default(), Limits::default(), true,
+                               &HashSet::new())
+            .await
+            .unwrap();
+        assert!(paths.contains(Path::new("a/b/c.txt")) && paths.contains(Path::new("a/link")));
+        assert_eq!(std::fs::read_to_string(dest.join("a/link")).unwrap(), "contents");
+    }
+
+    /// Archive `src` uncompressed and unpack it into `dest`.
+    async fn round_trip(src: &Path, dest: &Path) -> HashSet<PathBuf> {
+        let mut summary = Summary::new("test", crate::summary::Direction::Push);
+        let tar = create_tar(src, Preserve::default(), Symlinks::Preserve, &Exclude::default(),
+                             Compression::None, None, &mut summary).await.unwrap();
+        unpack_tar(&tar, dest, Preserve::default(), Limits::default(), false, &HashSet::new())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn round_trips_long_paths() {
+        let root = tempfile::tempdir().unwrap();
+        let src = root.path().join("src");
+        let long_dir = "d".repeat(60);
+        let long_name = format!("{}.txt", "n".repeat(200));
+        let deep = Path::new(&long_dir).join(&long_dir).join(&long_name);
+        assert!(deep.as_os_str().len() > 100);
+        std::fs::create_dir_all(src.join(deep.parent().unwrap())).unwrap();
+        std::fs::write(src.join(&deep), "deep").unwrap();
+        let target = Path::new(&long_dir).join(&long_dir).join(&long_dir).join("target");
+        std::os::unix::fs::symlink(&target, src.join("link")).unwrap();
+
+        let dest = root.path().join("dest");
+        let paths = round_trip(&src, &dest).await;
+        assert!(paths.contains(&deep));
+        assert_eq!(std::fs::read_to_string(dest.join(&deep)).unwrap(), "deep");
+        assert_eq!(std::fs::read_link(dest.join("link")).unwrap(), target);
+    }
+
+    #[tokio::test]
+    async fn round_trips_odd_names() {
+        let root = tempfile::tempdir().unwrap();
+        let src = root.path().join("src");
+        let names = ["ünïcødé 文件.txt", "  spaces  ", "tab\tand\nnewline", " ", "-dash", "émoji 🗂"];
+        std::fs::create_dir_all(src.join("dír with spaces ")).unwrap();
+        for x in names {
+            std::fs::write(src.join(x), x).unwrap();
+            std::fs::write(src.join("dír with spaces ").join(x), x).unwrap();
+        }
+
+        let dest = root.path().join("dest");
+        let paths = round_trip(&src, &dest).await;
+        for x in names {
+            for path in [PathBuf::from(x), Path::new("dír with spaces ").join(x)] {
+                assert!(paths.contains(&path), "{:?}", path);
+                assert_eq!(std::fs::read_to_string(dest.join(&path)).unwrap(), x);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_subsecond_mtimes() {
+        let root = tempfile::tempdir().unwrap();
+        let src = root.path().join("src");
+        std::fs::create_dir_all(src.join("dir")).unwrap();
+        std::fs::write(src.join("dir/file"), "x").unwrap();
+        let file_time = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        let dir_time = UNIX_EPOCH + Duration::new(1_600_000_000, 987_654_321);
+        File::options().write(true).open(src.join("dir/file")).unwrap()
+            .set_modified(file_time).unwrap();
+        File::open(src.join("dir")).unwrap().set_modified(dir_time).unwrap();
+
+        let dest = root.path().join("dest");
+        round_trip(&src, &dest).await;
+        let mtime = |x: &str| std::fs::metadata(dest.join(x)).unwrap().modified().unwrap();
+        assert_eq!(mtime("dir/file"), file_time);
+        assert_eq!(mtime("dir"), dir_time);
+    }
+
+    #[tokio::test]
+    async fn reads_pax_sizes() {
+        // A PAX size overrides the header's, as it must for files too big
+        // for the header to hold. The header's is faked here, to keep the
+        // file small.
+        let root = tempfile::tempdir().unwrap();
+        let data = vec![7u8; 1500];
+        let records = pax_record(b"size", data.len().to_string().as_bytes());
+        let mut tar = Vec::new();
+        // Extensions are only read from ustar or GNU headers.
+        for (name, kind, size, data) in [("PaxHeader", EntryType::XHeader, records.len(), &records),
+                                         ("big", EntryType::Regular, 0, &data)] {
+            let mut h = Header::new_ustar();
+            h.set_path(name).unwrap();
+            h.set_entry_type(kind);
+            h.set_mode(0o644);
+            h.set_size(size as u64);
+            h.set_cksum();
+            tar.extend_from_slice(h.as_bytes());
+            tar.extend_from_slice(data);
+            tar.resize(tar.len().div_ceil(512) * 512, 0);
+        }
+        raw_entry(&mut tar, b"after", EntryType::Regular, b"", b"after");
+
+        let dest = root.path().join("dest");
+        let paths = unpack(&finish(tar), &dest).await.unwrap();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(std::fs::read(dest.join("big")).unwrap(), data);
+        assert_eq!(std::fs::read_to_string(dest.join("after")).unwrap(), "after");
+    }
+
+    #[test]
+    fn prune_leaves_what_links_point_to() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = root.path().join("outside");
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::write(outside.join("kept"), "kept").unwrap();
+        std::fs::write(outside.join("unsynced"), "unsynced").unwrap();
+        let dir = root.path().join("dir");
+        std::fs::create_dir_all(dir.join("old")).unwrap();
+        std::fs::write(dir.join("old/file"), "old").unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("linked")).unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("stale")).unwrap();
+        // What a followed link puts in the archive: its target's contents.
+        let keep: HashSet<PathBuf> = ["linked", "linked/kept"].iter().map(PathBuf::from).collect();
+
+        let gone = prune(&dir, &keep, Symlinks::Follow, &Exclude::default()).unwrap();
+        assert_eq!(gone, [PathBuf::from("old"), PathBuf::from("stale")]);
+        assert!(dir.join("linked").exists() && ! dir.join("stale").exists());
+        assert!(outside.join("kept").exists() && outside.join("unsynced").exists());
+    }
+}
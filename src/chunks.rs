@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Read;
+use std::path::{ Path, PathBuf };
+
+use tar::{ Archive, EntryType };
+
+use crate::hash::{ from_hex, to_hex };
+
+/// How a folder's archive is kept on the remote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Storage {
+    /// One encrypted archive, replaced on every push.
+    Archive,
+    /// Encrypted content-defined chunks, uploaded only when new, plus an
+    /// encrypted index listing the chunks that make up the archive.
+    Chunks,
+}
+
+impl Storage {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "archive" => Some(Storage::Archive),
+            "chunks"  => Some(Storage::Chunks),
+            _ => None,
+        }
+    }
+}
+
+/// No chunk is cut before this many bytes...
+const MIN_SIZE: usize = 256 * 1024;
+/// ...or allowed to grow past this many.
+const MAX_SIZE: usize = 4 * 1024 * 1024;
+/// Chunks end where the top this-many bits of the rolling hash are zero,
+/// giving about 1 MiB between cuts on average.
+const AVG_BITS: u32 = 20;
+
+/// Pseudo-random values the rolling hash mixes in for each byte value.
+/// They're fixed so the same data is always cut in the same places.
+fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut x: u64 = 0x6d69_7374; // "mist"
+    for v in table.iter_mut() {
+        // splitmix64
+        x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = x;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        *v = z ^ (z >> 31);
+    }
+    table
+}
+
+/// Split what `data` reads into chunks whose boundaries depend on the
+/// content around them, so an insertion or deletion only changes the
+/// chunks it touches. Each chunk is passed to `f` in turn, so no more than
+/// the biggest chunk is held at once.
+pub fn split(mut data: impl Read, mut f: impl FnMut(&[u8])) -> std::io::Result<()> {
+    let gear = gear_table();
+    let mut window = Vec::with_capacity(MAX_SIZE);
+    loop {
+        data.by_ref().take((MAX_SIZE - window.len()) as u64).read_to_end(&mut window)?;
+        if window.is_empty() {
+            return Ok(())
+        }
+        let end = window.len();
+        let mut cut = end;
+        let mut h: u64 = 0;
+        let from = MIN_SIZE.min(end);
+        for (i, b) in window[from..end].iter().enumerate() {
+            h = (h << 1).wrapping_add(gear[*b as usize]);
+            if h >> (64 - AVG_BITS) == 0 {
+                cut = from + i + 1;
+                break
+            }
+        }
+        f(&window[..cut]);
+        window.drain(..cut);
+    }
+}
+
+/// One chunk of an archive: its name, and how many bytes of the archive
+/// it holds.
+pub struct Chunk {
+    pub id: String,
+    pub len: u64,
+}
+
+/// Where one entry sits in the archive: the byte range from its first
+/// header to the end of its data, so it can be fetched on its own.
+pub struct Entry {
+    pub path: PathBuf,
+    pub start: u64,
+    pub len: u64,
+    /// The size of the file, or the link's; None for a directory, and in
+    /// indexes written before sizes were recorded.
+    pub size: Option<u64>,
+}
+
+/// Find where each entry of an (uncompressed) archive is, reading it twice
+/// with what `open` returns. An entry's range starts at any PAX or
+/// long-name headers that come before its own.
+pub fn entries<R: Read>(open: impl Fn() -> R) -> std::io::Result<Vec<Entry>> {
+    let mut starts = HashMap::new();
+    let mut pending = None;
+    for e in Archive::new(open()).entries()?.raw(true) {
+        let e = e?;
+        let pos = e.raw_header_position();
+        match e.header().entry_type() {
+            EntryType::XHeader | EntryType::GNULongName | EntryType::GNULongLink => {
+                pending.get_or_insert(pos);
+            }
+            _ => {
+                starts.insert(pos, pending.take().unwrap_or(pos));
+            }
+        }
+    }
+    let mut entries = Vec::new();
+    for e in Archive::new(open()).entries()? {
+        let e = e?;
+        let pos = e.raw_header_position();
+        let start = starts.get(&pos).copied().unwrap_or(pos);
+        let end = e.raw_file_position() + e.size().div_ceil(512) * 512;
+        let size = match e.header().entry_type() {
+            EntryType::Directory => None,
+            _ => Some(e.size()),
+        };
+        entries.push(Entry { path: e.path()?.into_owned(), start, len: end - start, size });
+    }
+    Ok(entries)
+}
+
+/// How many of the archive's bytes `start..end` belong to the entries
+/// `pick` chooses, given the entries in order.
+pub fn bytes_in(entries: &[Entry], start: u64, end: u64, pick: impl Fn(&Path) -> bool) -> u64 {
+    let first = entries.partition_point(|x| x.start + x.len <= start);
+    entries[first..].iter()
+        .take_while(|x| x.start < end)
+        .filter(|x| pick(&x.path))
+        .map(|x| (x.start + x.len).min(end) - x.start.max(start))
+        .sum()
+}
+
+/// The list of chunks that make up a folder's archive, in order, and
+/// where each file is within them.
+///
+/// Chunks are named by a hash keyed with a random secret kept in the
+/// (encrypted) index, so their names reveal nothing about their contents.
+pub struct Index {
+    pub key: [u8; 32],
+    pub chunks: Vec<Chunk>,
+    /// Empty for indexes written before entries were recorded.
+    pub entries: Vec<Entry>,
+}
+
+impl Index {
+    /// An empty index with a fresh key.
+    pub fn new() -> std::io::Result<Self> {
+        let mut key = [0; 32];
+        File::open("/dev/urandom")?.read_exact(&mut key)?;
+        Ok(Index { key, chunks: Vec::new(), entries: Vec::new() })
+    }
+
+    /// The name a chunk with this content is stored under.
+    pub fn chunk_id(&self, data: &[u8]) -> String {
+        blake3::keyed_hash(&self.key, data).to_hex().to_string()
+    }
+
+    /// The byte ranges of the archive holding `path` and, if it's a
+    /// directory, everything under it. Adjacent ranges are merged.
+    pub fn ranges(&self, path: &Path) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for e in self.entries.iter().filter(|x| x.path.starts_with(path)) {
+            match ranges.last_mut() {
+                Some(x) if x.1 == e.start => x.1 += e.len,
+                _ => ranges.push((e.start, e.start + e.len)),
+            }
+        }
+        ranges
+    }
+
+    /// The chunks overlapping the byte range `start..end` of the archive,
+    /// as their positions in the chunk list and offsets in the archive.
+    pub fn chunks_in(&self, start: u64, end: u64) -> Vec<(usize, u64)> {
+        let mut found = Vec::new();
+        let mut offset = 0;
+        for (i, c) in self.chunks.iter().enumerate() {
+            if offset < end && offset + c.len > start {
+                found.push((i, offset));
+            }
+            offset += c.len;
+        }
+        found
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut s = format!("mist-chunks 3\nkey {}\n", to_hex(&self.key));
+        for c in &self.chunks {
+            s.push_str(&format!("{} {}\n", c.id, c.len));
+        }
+        for e in &self.entries {
+            // A newline would break the line format; such files can only
+            // be pulled with the rest of the folder.
+            if let Some(p) = e.path.to_str().filter(|x| ! x.contains('\n')) {
+                let size = e.size.map_or("-".to_string(), |x| x.to_string());
+                s.push_str(&format!("entry {} {} {} {}\n", e.start, e.len, size, p));
+            }
+        }
+        s.into_bytes()
+    }
+
+    /// Parse an index. Version 1 listed only chunk ids, without sizes or
+    /// entries, and version 2 entries without their files' sizes.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut lines = std::str::from_utf8(bytes).ok()?.lines();
+        let version = match lines.next()? {
+            "mist-chunks 1" => 1,
+            "mist-chunks 2" => 2,
+            "mist-chunks 3" => 3,
+            _ => return None,
+        };
+        let key = from_hex(lines.next()?.strip_prefix("key ")?)?.try_into().ok()?;
+        let mut index = Index { key, chunks: Vec::new(), entries: Vec::new() };
+        for line in lines {
+            if version == 1 {
+                index.chunks.push(Chunk { id: line.to_string(), len: 0 });
+            } else if let Some(x) = line.strip_prefix("entry ") {
+                let mut f = x.splitn(if version == 2 { 3 } else { 4 }, ' ');
+                let start = f.next()?.parse().ok()?;
+                let len = f.next()?.parse().ok()?;
+                let size = match version {
+                    2 => None,
+                    _ => match f.next()? {
+                        "-" => None,
+                        x => Some(x.parse().ok()?),
+                    },
+                };
+                index.entries.push(Entry { path: PathBuf::from(f.next()?), start, len, size });
+            } else {
+                let (id, len) = line.split_once(' ')?;
+                index.chunks.push(Chunk { id: id.to_string(), len: len.parse().ok()? });
+            }
+        }
+        Some(index)
+    }
+}
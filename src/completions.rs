@@ -0,0 +1,22 @@
+use std::io::stdout;
+
+use clap::Command;
+use clap_complete::{ Shell, generate };
+
+/// Print a completion script for `shell`, offering `names` wherever a
+/// profile is expected. The names are baked into the script, so it should
+/// be regenerated after adding or removing profiles.
+pub fn print<'a>(shell: Shell, cmd: Command<'a>, names: &'a [String]) {
+    let mut cmd = cmd;
+    if ! names.is_empty() {
+        let names: Vec<&str> = names.iter().map(|x| x.as_str()).collect();
+        cmd = cmd.mut_arg("profile", |a| a.possible_values(names.clone()));
+        for sub in cmd.get_subcommands_mut() {
+            if sub.get_arguments().any(|a| a.get_id() == "profile") {
+                *sub = sub.clone().mut_arg("profile", |a| a.possible_values(names.clone()));
+            }
+        }
+    }
+    let name = cmd.get_name().to_string();
+    generate(shell, &mut cmd, name, &mut stdout());
+}
@@ -1,129 +1,1171 @@
+use std::env::var;
+use std::fmt;
+use std::time::Duration;
 use std::path::{ Path, PathBuf };
 use std::fs::{ read_to_string };
 
+use serde::Deserialize;
 use toml::Value;
+use toml::value::Table;
 
-pub struct Config {
+use crate::archive::{ Exclude, Limits, Preserve, Symlinks };
+use crate::chunks::Storage;
+use crate::crypto::Encryption;
+use crate::email::{ Email, SMTP_PORT };
+use crate::format::Compression;
+use crate::hash::{ Detect, HashAlgorithm };
+use crate::http;
+use crate::keys::Pinentry;
+use crate::logging::{ LogFile, Rotation };
+use crate::merge::MergeTool;
+use crate::network::NetworkPolicy;
+use crate::secrets::Passphrase;
+use crate::notify::NotifyPolicy;
+use crate::prompt::{ Answer, Answers };
+use crate::schedule::{ Cron, Schedule, Window, parse_interval };
+use crate::ssh::{ HostKeyPolicy, SshClient };
+use crate::webhook::Webhook;
+
+/// Which ways a profile's runs go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Both: merge local and remote changes.
+    Sync,
+    /// Only ever push, as a backup of this machine.
+    MirrorUp,
+    /// Only ever pull, as a copy of the remote.
+    MirrorDown,
+}
+
+impl Mode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "sync"        => Some(Mode::Sync),
+            "mirror-up"   => Some(Mode::MirrorUp),
+            "mirror-down" => Some(Mode::MirrorDown),
+            _ => None,
+        }
+    }
+}
+
+/// A local folder and the paths of its archive and hash file on the remote.
+#[derive(Debug, Clone)]
+pub struct Folder {
     pub dir: PathBuf,
-    pub sshaddr: String,
     pub temp: PathBuf,
-    pub gpg_id: String,
     pub tar: String,
     pub tar_hash: String,
-    pub gpg_bin: Option<Value>,
+}
+
+impl Folder {
+    /// The remote directory the folder's files are in, "." being the
+    /// remote home directory.
+    pub fn remote_dir(&self) -> &str {
+        match self.tar.rsplit_once('/') {
+            Some(("", _)) => "/",
+            Some((x, _)) => x,
+            None => ".",
+        }
+    }
+
+    /// The remote file listing how many parts a split archive has.
+    pub fn parts_index(&self) -> String {
+        format!("{}.parts", self.tar)
+    }
+
+    /// The remote file holding part `n` of a split archive.
+    pub fn part(&self, n: usize) -> String {
+        format!("{}.{:03}", self.tar, n)
+    }
+
+    /// The remote file describing how the archive was written.
+    pub fn format(&self) -> String {
+        format!("{}.format", self.tar)
+    }
+
+    /// The remote file listing the chunks the archive is made of.
+    pub fn chunk_index(&self) -> String {
+        format!("{}.index", self.tar)
+    }
+
+    /// The remote directory holding copies of the chunk index as each
+    /// push left it, named by when that was in seconds since 1970.
+    pub fn version_dir(&self) -> String {
+        format!("{}.versions", self.tar)
+    }
+
+    /// The remote copy of the chunk index pushed at `time`.
+    pub fn version(&self, time: u64) -> String {
+        format!("{}/{}", self.version_dir(), time)
+    }
+
+    /// The remote file listing the files a mirror that doesn't propagate
+    /// deletes keeps in its archive after they were deleted, and when.
+    pub fn deleted(&self) -> String {
+        format!("{}.deleted", self.tar)
+    }
+
+    /// The remote file recording the runs, from any machine, that changed
+    /// the folder (see `runlog`).
+    pub fn run_log(&self) -> String {
+        format!("{}.runs", self.tar)
+    }
+
+    /// The remote directory holding the archive's chunks.
+    pub fn chunk_dir(&self) -> String {
+        format!("{}.chunks", self.tar)
+    }
+
+    /// The remote file holding the chunk with the given id.
+    pub fn chunk(&self, id: &str) -> String {
+        format!("{}/{}", self.chunk_dir(), id)
+    }
+}
+
+pub struct Config {
+    pub folders: Vec<Folder>,
+    /// Empty if the remote is over HTTP.
+    pub sshaddr: String,
+    pub http_url: Option<String>,
+    /// Prints the bearer token for `http_url`.
+    pub http_token_command: Option<String>,
+    pub restic_url: Option<String>,
+    /// Prints the password for `restic_url`'s user.
+    pub restic_password_command: Option<String>,
+    /// None until a default key is picked (see `keys::default_key`).
+    pub gpg_id: Option<String>,
+    pub gpg_bin: Option<String>,
+    pub merge_tool: MergeTool,
+    pub unison_bin: Option<String>,
+    /// Passed to unison after mist's own arguments.
+    pub unison_args: Vec<String>,
+    pub encryption: Encryption,
     pub symmetric: bool,
+    /// Where the symmetric passphrase comes from, if not from gpg's prompt.
+    pub passphrase: Option<Passphrase>,
+    pub pinentry: Option<Pinentry>,
+    pub armor: bool,
+    pub pre_sync: Option<String>,
+    pub post_sync: Option<String>,
+    pub on_failure: Option<String>,
+    pub notify: NotifyPolicy,
+    /// Where to email when runs keep failing.
+    pub email: Option<Email>,
+    /// Where to POST each run's summary.
+    pub webhook: Option<Webhook>,
+    /// The loopback port a daemon serves Prometheus metrics on.
+    pub metrics_port: Option<u16>,
+    /// A Healthchecks.io-style URL pinged as each run starts and ends.
+    pub healthcheck_url: Option<String>,
+    pub answers: Answers,
+    pub stats: bool,
+    /// A file to append each run's summary to, as a line of JSON.
+    pub results_file: Option<PathBuf>,
+    pub log_file: Option<LogFile>,
+    /// Send run records to the journal when running under systemd.
+    pub journald: bool,
+    pub schedule: Option<Schedule>,
+    /// Which networks `--daemon` syncs over.
+    pub network: NetworkPolicy,
+    /// The hours `--daemon` syncs in.
+    pub sync_hours: Option<Window>,
+    /// Restrict only uploads bigger than this to `sync_hours`.
+    pub sync_hours_above: Option<u64>,
+    pub hash: HashAlgorithm,
+    pub detect: Detect,
+    pub ssh_client: SshClient,
+    pub ssh_port: Option<u16>,
+    pub ssh_user: Option<String>,
+    pub ssh_identity_file: Option<PathBuf>,
+    pub ssh_connect_timeout: Option<Duration>,
+    pub ssh_keep_alive: Option<Duration>,
+    pub known_hosts: HostKeyPolicy,
+    pub proxy_jump: Option<String>,
+    pub preserve: Preserve,
+    /// Caps on what unpacking the remote copy may write.
+    pub unpack_limits: Limits,
+    /// Bytes of an archive held in memory while packing or unpacking it;
+    /// past that, it goes to an encrypted temporary file.
+    pub spill_above: Option<u64>,
+    pub symlinks: Symlinks,
+    pub exclude: Exclude,
+    pub split_size: Option<u64>,
+    pub storage: Storage,
+    /// How many versions of the folder chunk storage keeps, the current
+    /// one included...
+    pub keep_versions: Option<usize>,
+    /// ...and for how long. Neither means no versions are kept.
+    pub keep_versions_for: Option<Duration>,
+    pub compression: Compression,
+    pub mode: Mode,
+    /// In a mirror mode, whether files deleted on the source side are
+    /// deleted on the other too.
+    pub propagate_deletes: bool,
 }
 
-/// Load the configuration file and unpack its values.
-/// 
-/// The following locations are checked:
-/// 1. $HOME/.config/mist/mist.toml
-/// 2. $HOME/.config/mist.toml
-/// 3. $HOME/.mist.toml 
-///
-/// The configuration file has the following parameters. 
-/// [<profile-name>]            
-/// folder = "/path/to/sync/folder"  (folder to sync)
-/// ssh_address = "user@host" (remote ssh address to sync with)
-/// gpg_id = "youremail@yourprovider.com" (gpg id to encrypt with)
-/// temp_folder    = "/tmp/sync-folder" (temp folder location)
-///
-/// Note that multiple profiles are allowed and the profile to use at runtime 
-/// is specified as a required argument.
-pub async fn load_configuration(home: &Path, profile: &str) 
--> Result<Config, Box<dyn std::error::Error>> {
-    let toml = std::fs::read_to_string(home.join(".config/mist/mist.toml"))
-        .or_else(|_| read_to_string(home.join(".config/mist.toml")))
-        .or_else(|_| read_to_string(home.join("mist.toml")))
-        .expect("No configuration file found.");
+impl Config {
+    /// Where the remote is, for messages: its ssh address or URL.
+    pub fn address(&self) -> &str {
+        self.http_url.as_deref().or(self.restic_url.as_deref()).unwrap_or(&self.sshaddr)
+    }
+}
 
-    let values: Value = toml::from_str(&toml)?;  
+/// The section whose entries apply to every profile that doesn't set them.
+pub const DEFAULTS: &str = "defaults";
 
-    // Check the configuration file is populated correctly 
-    let cfg = match values.get(profile) {
-        Some(x) => x,
-        None => {
-            println!("Configuration error: profile [{}] not found", &profile);
-            panic!();
+/// Every entry a profile may contain: the fields of `Entries`.
+const KNOWN_KEYS: &[&str] = &[
+    "folder", "ssh_address", "http_url", "http_token_command", "restic_url",
+    "restic_password_command", "gpg_id", "temp_folder", "gpg_program", "encryption", "symmetric",
+    "pre_sync", "post_sync", "on_failure", "notify", "email_to", "email_from",
+    "email_after", "smtp_host", "smtp_port", "smtp_user", "smtp_password_command", "webhook_url", "webhook_when",
+    "webhook_token_command", "metrics_port", "healthcheck_url", "stats", "interval", "cron",
+    "hash", "detect", "ssh_client", "ssh_port", "ssh_user", "ssh_identity_file", "ssh_connect_timeout",
+    "ssh_keep_alive", "known_hosts", "proxy_jump", "remote_path",
+    "preserve_permissions", "preserve_ownership", "preserve_xattrs", "symlinks",
+    "split_size", "storage", "keep_versions", "keep_versions_for", "compression", "mode", "propagate_deletes", "pinentry", "armor", "passphrase",
+    "passphrase_command", "max_file_size", "exclude_types", "max_unpack_size",
+    "max_unpack_entries", "max_unpack_file_size", "spill_above", "default", "aliases",
+    "answer_overwrite_remote", "answer_overwrite_local", "answer_push_after_error",
+    "answer_gpg_key", "answer_create_remote", "results_file", "log_file", "log_rotate", "log_keep",
+    "journald", "merge_tool", "unison_path", "unison_args", "network", "sync_hours",
+    "sync_hours_above",
+];
+
+/// Entries that describe a profile itself, so they can't be shared from
+/// the [defaults] section.
+pub const PROFILE_ONLY: &[&str] = &["default", "aliases"];
+
+/// Everything wrong with a profile, reported together.
+pub struct ConfigError {
+    pub profile: String,
+    pub problems: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Configuration error in profile [{}]:", self.profile)?;
+        for p in &self.problems {
+            write!(f, "\n  - {}", p)?;
         }
+        Ok(())
+    }
+}
+
+// Debug is what `main` prints for a returned error, so keep it readable.
+impl fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// An entry that may be a single string or an array of strings.
+#[derive(Deserialize)]
+#[serde(untagged, expecting = "a string or an array of strings")]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            OneOrMany::One(x)  => vec![x],
+            OneOrMany::Many(x) => x,
+        }
+    }
+}
+
+fn yes() -> bool {
+    true
+}
+
+/// A profile's entries as written, with [defaults] filled in. Serde checks
+/// their types; values that need parsing (durations, sizes, choices) are
+/// left as strings for `load_configuration`, so that all the bad ones are
+/// reported together.
+#[derive(Deserialize)]
+struct Entries {
+    folder: Option<OneOrMany>,
+    ssh_address: Option<String>,
+    http_url: Option<String>,
+    http_token_command: Option<String>,
+    restic_url: Option<String>,
+    restic_password_command: Option<String>,
+    gpg_id: Option<String>,
+    temp_folder: Option<String>,
+    gpg_program: Option<String>,
+    merge_tool: Option<String>,
+    unison_path: Option<String>,
+    #[serde(default)]
+    unison_args: Vec<String>,
+    encryption: Option<String>,
+    #[serde(default)]
+    symmetric: bool,
+    passphrase: Option<String>,
+    passphrase_command: Option<String>,
+    #[serde(default)]
+    armor: bool,
+    pinentry: Option<String>,
+    // Used by `resolve_profile`; only its type is checked here.
+    #[serde(default)]
+    #[allow(dead_code)]
+    default: bool,
+    aliases: Option<OneOrMany>,
+    pre_sync: Option<String>,
+    post_sync: Option<String>,
+    on_failure: Option<String>,
+    notify: Option<String>,
+    email_to: Option<String>,
+    email_from: Option<String>,
+    email_after: Option<u64>,
+    smtp_host: Option<String>,
+    smtp_port: Option<u16>,
+    smtp_user: Option<String>,
+    smtp_password_command: Option<String>,
+    webhook_url: Option<String>,
+    webhook_when: Option<String>,
+    webhook_token_command: Option<String>,
+    metrics_port: Option<u16>,
+    healthcheck_url: Option<String>,
+    answer_overwrite_remote: Option<String>,
+    answer_overwrite_local: Option<String>,
+    answer_push_after_error: Option<String>,
+    answer_gpg_key: Option<String>,
+    answer_create_remote: Option<String>,
+    #[serde(default)]
+    stats: bool,
+    results_file: Option<PathBuf>,
+    log_file: Option<PathBuf>,
+    log_rotate: Option<String>,
+    log_keep: Option<u32>,
+    #[serde(default = "yes")]
+    journald: bool,
+    interval: Option<String>,
+    cron: Option<String>,
+    network: Option<String>,
+    sync_hours: Option<String>,
+    sync_hours_above: Option<String>,
+    hash: Option<String>,
+    detect: Option<String>,
+    ssh_client: Option<String>,
+    ssh_port: Option<u16>,
+    ssh_user: Option<String>,
+    ssh_identity_file: Option<PathBuf>,
+    ssh_connect_timeout: Option<String>,
+    ssh_keep_alive: Option<String>,
+    known_hosts: Option<String>,
+    proxy_jump: Option<String>,
+    remote_path: Option<String>,
+    #[serde(default)]
+    preserve_permissions: bool,
+    #[serde(default)]
+    preserve_ownership: bool,
+    #[serde(default)]
+    preserve_xattrs: bool,
+    symlinks: Option<String>,
+    max_file_size: Option<String>,
+    exclude_types: Option<OneOrMany>,
+    max_unpack_size: Option<String>,
+    max_unpack_entries: Option<u64>,
+    max_unpack_file_size: Option<String>,
+    spill_above: Option<String>,
+    split_size: Option<String>,
+    storage: Option<String>,
+    keep_versions: Option<usize>,
+    keep_versions_for: Option<String>,
+    compression: Option<String>,
+    mode: Option<String>,
+    propagate_deletes: Option<bool>,
+}
+
+/// Number of single-character edits to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == *cb { prev } else { 1 + prev.min(row[j]).min(cur) };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+/// Collects problems while checking a profile's entries, so they can all
+/// be reported at once instead of stopping at the first.
+#[derive(Default)]
+struct Checker {
+    problems: Vec<String>,
+}
+
+impl Checker {
+    fn problem(&mut self, msg: String) {
+        self.problems.push(msg);
+    }
+
+    /// Note a required entry that is missing.
+    fn required<T>(&mut self, key: &str, value: Option<T>) -> Option<T> {
+        if value.is_none() {
+            self.problem(format!("missing required entry '{}'", key));
+        }
+        value
+    }
+
+    /// Note a list entry that is present but empty.
+    fn non_empty(&mut self, key: &str, value: Option<OneOrMany>) -> Option<Vec<String>> {
+        match value.map(OneOrMany::into_vec) {
+            Some(x) if x.is_empty() => {
+                self.problem(format!("'{}' must not be an empty array", key));
+                None
+            }
+            x => x,
+        }
+    }
+
+    /// Convert a string entry with `parse`, describing the accepted values
+    /// in `expected` if that fails.
+    fn parse<T>(&mut self, key: &str, value: Option<String>, expected: &str,
+                parse: impl Fn(&str) -> Option<T>)
+    -> Option<T> {
+        let x = value?;
+        let parsed = parse(&x);
+        if parsed.is_none() {
+            self.problem(format!("'{}' value \"{}\" is invalid; expected {}", key, x, expected));
+        }
+        parsed
+    }
+
+    /// Note a setting this build can't act on, as it was built without the
+    /// cargo feature that needs.
+    fn feature(&mut self, setting: &str, feature: &str, enabled: bool) {
+        if ! enabled {
+            self.problem(format!("{} needs mist built with the '{}' feature", setting, feature));
+        }
+    }
+
+    /// Note any entries that mist doesn't know about.
+    fn unknown_keys(&mut self, table: &Table) {
+        for k in table.keys() {
+            if let Err(e) = check_key(k) {
+                self.problem(e);
+            }
+        }
+    }
+}
+
+/// Check that `key` is an entry mist knows, suggesting the closest one if
+/// it isn't.
+pub fn check_key(key: &str) -> Result<(), String> {
+    if KNOWN_KEYS.contains(&key) {
+        return Ok(())
+    }
+    let close = KNOWN_KEYS
+        .iter()
+        .map(|x| (edit_distance(key, x), x))
+        .filter(|(d, _)| *d <= 2)
+        .min();
+    match close {
+        Some((_, x)) => Err(format!("unknown entry '{}' (did you mean '{}'?)", key, x)),
+        None => Err(format!("unknown entry '{}'", key)),
+    }
+}
+
+/// Parse a size such as "4096", "512K", "512M" or "2G" (binary multiples,
+/// optionally followed by "B" or "iB").
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let s = s.strip_suffix("iB").or_else(|| s.strip_suffix('B')).unwrap_or(s);
+    let (num, shift) = match s.char_indices().last()? {
+        (i, 'K') | (i, 'k') => (&s[..i], 10),
+        (i, 'M') | (i, 'm') => (&s[..i], 20),
+        (i, 'G') | (i, 'g') => (&s[..i], 30),
+        (i, 'T') | (i, 't') => (&s[..i], 40),
+        _ => (s, 0),
     };
+    let n: u64 = num.trim().parse().ok()?;
+    n.checked_mul(1u64 << shift).filter(|x| *x > 0)
+}
 
-    for x in ["folder", "ssh_address", "gpg_id", "temp_folder"] {
-        match cfg.get(x) {
-            Some(_) => (),
-            None => { 
-                println!("Configuration error: profile [{}] missing '{}' entry", 
-                         &profile, x);
+/// Work out the temporary folder and remote file paths for each folder.
+///
+/// The remote archive and hash file are named after the temp folder and
+/// placed in `remote` (the remote home directory if unset). With several
+/// folders, each gets its own temp folder suffixed with the folder's name,
+/// e.g. /tmp/sync-notes and sync-notes.tar.gz.gpg.
+fn folders(dirs: &[String], tmp: &str, remote: Option<&str>, c: &mut Checker)
+-> Option<Vec<Folder>> {
+    let mut folders: Vec<Folder> = Vec::new();
+    for dir in dirs {
+        let dir = PathBuf::from(dir);
+        let temp = if dirs.len() == 1 {
+            PathBuf::from(tmp)
+        } else {
+            let name = match dir.file_name().and_then(|x| x.to_str()) {
+                Some(x) => x,
+                None => {
+                    c.problem(format!("folder '{}' must end in a directory name", dir.display()));
+                    continue
+                }
+            };
+            PathBuf::from(format!("{}-{}", tmp.trim_end_matches('/'), name))
+        };
+        let tar = temp.with_extension("tar.gz.gpg");
+        let tar_hash = tar.with_extension("gpg.xxhash");
+        let names = tar.file_name().and_then(|x| x.to_str()).zip(
+            tar_hash.file_name().and_then(|x| x.to_str()));
+        let (tar, tar_hash) = match names {
+            Some((a, b)) => match remote {
+                Some(r) => (format!("{}/{}", r, a), format!("{}/{}", r, b)),
+                None => (a.to_string(), b.to_string()),
+            },
+            None => {
+                c.problem("'temp_folder' must end in a directory name".to_string());
+                return None
             }
-        }  
+        };
+        if let Some(x) = folders.iter().find(|x| x.tar == tar) {
+            c.problem(format!("folders '{}' and '{}' would share the remote archive '{}'",
+                              x.dir.display(), dir.display(), tar));
+            continue
+        }
+        folders.push(Folder { dir, temp, tar, tar_hash });
+    }
+    Some(folders)
+}
+
+/// Find the configuration file. An explicitly given path is used as is;
+/// otherwise the following locations are checked in order:
+/// 1. $XDG_CONFIG_HOME/mist/mist.toml
+/// 2. $XDG_CONFIG_HOME/mist.toml
+/// 3. $HOME/.mist.toml
+///
+/// $XDG_CONFIG_HOME defaults to $HOME/.config.
+pub fn find_config(home: &Path, explicit: Option<&Path>)
+-> Result<PathBuf, Box<dyn std::error::Error>> {
+    if let Some(x) = explicit {
+        if ! x.is_file() {
+            return Err(format!("Configuration file {} not found", x.display()).into())
+        }
+        return Ok(x.to_path_buf())
+    }
+    let xdg = match var("XDG_CONFIG_HOME") {
+        Ok(x) if ! x.is_empty() => PathBuf::from(x),
+        _ => home.join(".config"),
     };
+    let candidates = [
+        xdg.join("mist/mist.toml"),
+        xdg.join("mist.toml"),
+        home.join(".mist.toml"),
+        // Read by earlier versions, despite the documentation.
+        home.join("mist.toml"),
+    ];
+    match candidates.iter().find(|x| x.is_file()) {
+        Some(x) => Ok(x.clone()),
+        None => Err(format!("No configuration file found; looked for {}, {} and {} \
+                             (see examples/mist.toml)",
+                            candidates[0].display(), candidates[1].display(),
+                            candidates[2].display()).into()),
+    }
+}
 
-    let dir = &cfg
-        .get("folder")
-        .unwrap()
-        .as_str()
-        .ok_or("Can't parse 'sync folder' value as str")?;
+/// Read and parse a configuration file. It's TOML unless its extension
+/// says it's YAML (.yaml or .yml) or JSON (.json); either way it's read
+/// into TOML values, so the rest of the loading is the same.
+fn read_config_file(path: &Path) -> Result<Value, Box<dyn std::error::Error>> {
+    let text = read_to_string(path)
+        .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let ext = path.extension().and_then(|x| x.to_str());
+    let (values, format): (Result<Value, String>, _) = match ext {
+        Some("yaml") | Some("yml") =>
+            (serde_yaml::from_str(&text).map_err(|e| e.to_string()), "YAML"),
+        Some("json") => (serde_json::from_str(&text).map_err(|e| e.to_string()), "JSON"),
+        _ => (toml::from_str(&text).map_err(|e| e.to_string()), "TOML"),
+    };
+    Ok(values.map_err(|e| format!("{} is not valid {}: {}", path.display(), format, e))?)
+}
 
-    let sshaddr = &cfg
-        .get("ssh_address")
-        .unwrap()
-        .as_str()
-        .ok_or("Can't parse 'remote_address' value as str")?;
+/// The names of the profiles defined in the configuration file.
+pub fn profile_names(path: &Path) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let values = read_config_file(path)?;
+    let names = values
+        .as_table()
+        .ok_or("Configuration file is not a table")?
+        .iter()
+        .filter(|(k, v)| v.is_table() && k.as_str() != DEFAULTS)
+        .map(|(k, _)| k.clone())
+        .collect();
+    Ok(names)
+}
 
-    let gpgid = &cfg
-        .get("gpg_id")
-        .unwrap()
-        .as_str()
-        .ok_or("Can't parse 'gpg_recipient' value as str")?;
+/// The names a profile can also be called by, from its `aliases` entry.
+fn profile_aliases(table: &Value) -> Vec<String> {
+    match table.get("aliases") {
+        Some(Value::String(x)) => vec![x.clone()],
+        Some(Value::Array(x)) => x.iter().filter_map(|x| x.as_str().map(String::from)).collect(),
+        _ => Vec::new(),
+    }
+}
 
-    let tmp = &cfg
-        .get("temp_folder")
-        .unwrap()
-        .as_str()
-        .ok_or("Can't parse 'temp_folder' value as str")?;
+/// Work out which profile is meant: the one named, the one with that
+/// alias, or, when no name is given, the one marked `default = true`.
+pub fn resolve_profile(path: &Path, name: Option<&str>)
+-> Result<String, Box<dyn std::error::Error>> {
+    let values = read_config_file(path)?;
+    let names = profile_names(path)?;
+    let profiles: Vec<(&String, &Value)> = names
+        .iter()
+        .filter_map(|x| values.get(x).map(|v| (x, v)))
+        .collect();
+    let name = match name {
+        Some(x) => x,
+        None => {
+            let defaults: Vec<&str> = profiles
+                .iter()
+                .filter(|(_, v)| v.get("default").and_then(|x| x.as_bool()) == Some(true))
+                .map(|(k, _)| k.as_str())
+                .collect();
+            return match defaults[..] {
+                [x] => Ok(x.to_string()),
+                [] => Err("No profile given, and none is marked 'default = true'".into()),
+                _ => Err(format!("Configuration error: several profiles are marked \
+                                  'default = true' ({})", defaults.join(", ")).into()),
+            }
+        }
+    };
+    if names.iter().any(|x| x == name) {
+        return Ok(name.to_string())
+    }
+    let matches: Vec<&str> = profiles
+        .iter()
+        .filter(|(_, v)| profile_aliases(v).iter().any(|x| x == name))
+        .map(|(k, _)| k.as_str())
+        .collect();
+    match matches[..] {
+        [x] => Ok(x.to_string()),
+        // Let loading the profile report that it doesn't exist.
+        [] => Ok(name.to_string()),
+        _ => Err(format!("Configuration error: '{}' is an alias of several profiles ({})",
+                         name, matches.join(", ")).into()),
+    }
+}
 
-    let is_symmetric = cfg
-        .get("symmetric");
+/// Load a profile from the configuration file (see `find_config`) and
+/// unpack its values.
+///
+/// The configuration file has the following parameters, any of which may
+/// also be set in a [defaults] section to apply to every profile that
+/// doesn't override them.
+/// [<profile-name>]            
+/// folder = "/path/to/sync/folder"  (folder to sync, or an array of folders)
+/// ssh_address = "user@host" (remote ssh address to sync with)
+/// http_url = "https://host/mist" (alternative to ssh_address, a server speaking mist's
+///          PUT/GET protocol)
+/// http_token_command = "shell command" (optional, prints the bearer token for http_url)
+/// restic_url = "https://user@host:8000/mist" (alternative to ssh_address, a restic
+///          REST server repository)
+/// restic_password_command = "shell command" (optional, prints the password for
+///          restic_url's user)
+/// gpg_id = "youremail@yourprovider.com" (optional, gpg id to encrypt with; defaults to
+///          gpg's default key, chosen once with confirmation)
+/// temp_folder    = "/tmp/sync-folder" (temp folder location; syncs unpack the remote copy
+///          here, emptied first, as unison needs the same path every run; other commands
+///          use fresh directories beside it)
+/// default = true | false (optional, the profile used when none is named)
+/// aliases = ["h"] (optional, short names the profile can also be called by)
+/// pre_sync = "shell command" (optional, run before syncing; aborts on failure)
+/// post_sync = "shell command" (optional, run after a successful run)
+/// on_failure = "shell command" (optional, run after a failed run)
+/// notify = "never" | "problems" | "always" (optional, desktop notifications)
+/// email_to = "me@example.com" (optional, email when runs keep failing)
+/// email_from = "mist@example.com" (optional, the sender; defaults to email_to)
+/// email_after = 3 (optional, how many runs in a row fail before emailing; default 3)
+/// smtp_host = "smtp.example.com" (needed with email_to, the server to send through)
+/// smtp_port = 587 (optional, 465 for TLS from the start; default 587, STARTTLS)
+/// smtp_user = "me" (optional, log in to the server as this user)
+/// smtp_password_command = "pass show smtp" (optional, prints smtp_user's password)
+/// webhook_url = "https://ntfy.sh/mist" (optional, POST each run's JSON summary here)
+/// webhook_when = "problems" | "always" (optional, which runs to send; default always)
+/// webhook_token_command = "shell command" (optional, prints a bearer token for webhook_url)
+/// metrics_port = 9464 (optional, serve Prometheus metrics on 127.0.0.1 with --daemon)
+/// healthcheck_url = "https://hc-ping.com/<uuid>" (optional, ping <url>/start as each run
+///          starts, then <url> or <url>/fail as it ends)
+/// answer_overwrite_remote = "ask" | "yes" | "no" (optional, standing answer to --push's
+///          prompt; likewise answer_overwrite_local, answer_push_after_error, answer_gpg_key,
+///          answer_create_remote)
+/// stats = true | false (optional, keep local run statistics for `mist doctor`)
+/// results_file = "/var/log/mist.jsonl" (optional, append each run's summary as JSON)
+/// log_file = "/var/log/mist.log" (optional, record each run and its warnings)
+/// log_rotate = "daily" | "weekly" | "10M" (optional, when to rotate log_file; default 10M)
+/// log_keep = 5 (optional, how many rotated log files to keep)
+/// journald = true | false (optional, log runs to the journal under systemd; default true)
+/// interval = "15m" (optional, how often `--daemon` syncs)
+/// cron = "*/15 * * * *" (optional, alternative to interval)
+/// network = "unmetered" | "online" | "any" (optional, which networks `--daemon` syncs over)
+/// sync_hours = "01:00-06:00" (optional, the hours `--daemon` syncs in)
+/// sync_hours_above = "500M" (optional, restrict only uploads bigger than this to sync_hours)
+/// hash = "xxhash64" | "xxh3" | "blake3" | "sha256" (optional, change detection hash)
+/// detect = "mtime" | "size" | "content" (optional, what change detection compares)
+/// ssh_client = "openssh" | "builtin" (optional, builtin connects without an ssh binary)
+/// ssh_port = 2222 (optional, overrides ~/.ssh/config)
+/// ssh_user = "user" (optional, overrides ~/.ssh/config)
+/// ssh_identity_file = "~/.ssh/id_mist" (optional, the only key offered)
+/// ssh_connect_timeout = "30s" (optional, give up connecting after this long)
+/// ssh_keep_alive = "60s" (optional, probe an idle connection this often)
+/// known_hosts = "strict" | "accept-new" | "off" (optional, host key checking)
+/// proxy_jump = "user@bastion" (optional, reach the remote through a jump host)
+/// remote_path = "/backups/mist" (optional, remote directory for the archives)
+/// preserve_permissions = true | false (optional, restore setuid/setgid/sticky bits)
+/// preserve_ownership = true | false (optional, restore owner and group; needs root)
+/// preserve_xattrs = true | false (optional, store and restore extended attributes)
+/// symlinks = "preserve" | "follow" | "skip" (optional, how to archive symbolic links)
+/// max_file_size = "1G" (optional, leave bigger files out of the archive and sync)
+/// exclude_types = ["iso", "mkv"] (optional, leave files with these extensions out)
+/// max_unpack_size = "20G" (optional, refuse a remote copy that unpacks to more)
+/// max_unpack_entries = 1000000 (optional, refuse a remote copy with more files)
+/// max_unpack_file_size = "4G" (optional, refuse a remote copy with a bigger file)
+/// spill_above = "1G" (optional, hold bigger archives in an encrypted temp file, and
+///          upload them in parts of at most this size)
+/// split_size = "512M" (optional, upload the archive in parts of at most this size)
+/// storage = "archive" | "chunks" (optional, chunks upload only changed data)
+/// keep_versions = 10 (optional, with chunks, how many versions of the folder to keep)
+/// keep_versions_for = "90d" (optional, with chunks, how long to keep each version)
+/// compression = "gzip" | "auto" | "none" (optional, auto skips files compressed already)
+/// mode = "sync" | "mirror-up" | "mirror-down" (optional, mirrors only ever push or pull)
+/// propagate_deletes = true | false (optional, whether a mirror deletes what its source
+///          deleted; default true)
+/// encryption = "gpg" | "builtin" (optional, builtin encrypts with a passphrase without gpg)
+/// pinentry = "default" | "ask" | "error" (optional, how gpg may prompt for a PIN)
+/// passphrase = "keychain" (optional, read the symmetric passphrase from the OS keychain)
+/// passphrase_command = "pass show mist" (optional, read it from a command's output)
+/// armor = true | false (optional, ASCII-armored rather than binary encrypted files)
+/// merge_tool = "unison" | "three-way" | "rsync" | "cmd {local} {remote}" (optional, what
+///          merges the two sides)
+/// unison_path = "/opt/unison/bin/unison" (optional, the unison binary to run)
+/// unison_args = ["-ignore", "Name *.tmp"] (optional, extra arguments for unison)
+///
+/// Note that multiple profiles are allowed and the profile to use at runtime
+/// is given as an argument (see `resolve_profile`).
+pub async fn load_configuration(path: &Path, profile: &str) 
+-> Result<Config, Box<dyn std::error::Error>> {
+    let values = read_config_file(path)?;
 
-    let symmetric = match is_symmetric {
-        Some(x) => x.as_bool(),
-        None => Some(false),
+    let cfg = match values.get(profile) {
+        Some(_) if profile == DEFAULTS => return Err(
+            format!("Configuration error: [{}] holds shared settings and isn't a profile", DEFAULTS).into()),
+        Some(x) if x.is_table() => x,
+        Some(_) => return Err(format!("Configuration error: [{}] is not a profile section", profile).into()),
+        None => {
+            let names = profile_names(path).unwrap_or_default();
+            return Err(format!("Configuration error: profile [{}] not found (available: {})",
+                               profile, names.join(", ")).into())
+        }
     };
 
-    let symmetric = match symmetric {
-        Some(x) => x,
-        None => { 
-            println!("Can't parse 'symmetric' as bool");
-            panic!()
-        },
+    // Fill in anything the profile doesn't set from the [defaults] section.
+    let mut merged = match values.get(DEFAULTS) {
+        Some(Value::Table(x)) => {
+            if let Some(k) = PROFILE_ONLY.iter().find(|k| x.contains_key(**k)) {
+                return Err(format!("Configuration error: '{}' can't be set in [{}]",
+                                   k, DEFAULTS).into())
+            }
+            x.clone()
+        }
+        Some(_) => return Err(format!("Configuration error: [{}] must be a section", DEFAULTS).into()),
+        None => Table::new(),
+    };
+    if let Some(x) = cfg.as_table() {
+        merged.extend(x.clone());
+    }
+    let mut c = Checker::default();
+    c.unknown_keys(&merged);
+    let e: Entries = match Value::Table(merged).try_into() {
+        Ok(x) => x,
+        Err(err) => {
+            c.problem(err.to_string());
+            return Err(Box::new(ConfigError { profile: profile.to_string(), problems: c.problems }))
+        }
     };
-    
-    let tar = PathBuf::from(&tmp)
-        .with_extension("tar.gz.gpg");
 
-    let tar_hash = PathBuf::from(&tar)
-        .with_extension("gpg.xxhash");
+    for alias in e.aliases.map(OneOrMany::into_vec).unwrap_or_default() {
+        if profile_names(path).unwrap_or_default().contains(&alias) {
+            c.problem(format!("alias '{}' is already the name of a profile", alias));
+        }
+    }
+    let dirs = c.required("folder", e.folder);
+    let dirs = c.non_empty("folder", dirs);
+    let http_url = c.parse("http_url", e.http_url, "a URL like \"https://host/mist\"",
+                           |x| http::valid_url(x).then(|| x.to_string()));
+    let restic_url = c.parse("restic_url", e.restic_url, "a URL like \"https://user@host:8000/mist\"",
+                             |x| http::valid_url(x).then(|| x.to_string()));
+    let remotes: Vec<&str> = [("ssh_address", e.ssh_address.is_some()),
+                              ("http_url", http_url.is_some()),
+                              ("restic_url", restic_url.is_some())]
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(key, _)| *key)
+        .collect();
+    let sshaddr = match remotes.len() {
+        0 => c.required("ssh_address", None),
+        1 => Some(e.ssh_address.unwrap_or_default()),
+        _ => {
+            c.problem(format!("set only one of '{}'", remotes.join("', '")));
+            None
+        }
+    };
+    if http_url.is_some() {
+        c.feature("'http_url'", "http", cfg!(feature = "http"));
+    }
+    if restic_url.is_some() {
+        c.feature("'restic_url'", "restic", cfg!(feature = "restic"));
+    } else if e.restic_password_command.is_some() {
+        c.problem("'restic_password_command' is only used with 'restic_url'".to_string());
+    }
+    if http_url.is_some() || restic_url.is_some() {
+        let ssh_only = [
+            ("ssh_client", e.ssh_client.is_some()),
+            ("ssh_port", e.ssh_port.is_some()),
+            ("ssh_user", e.ssh_user.is_some()),
+            ("ssh_identity_file", e.ssh_identity_file.is_some()),
+            ("ssh_keep_alive", e.ssh_keep_alive.is_some()),
+            ("known_hosts", e.known_hosts.is_some()),
+            ("proxy_jump", e.proxy_jump.is_some()),
+        ];
+        for (key, _) in ssh_only.iter().filter(|(_, set)| *set) {
+            c.problem(format!("'{}' is only used with 'ssh_address'", key));
+        }
+    }
+    if http_url.is_none() && e.http_token_command.is_some() {
+        c.problem("'http_token_command' is only used with 'http_url'".to_string());
+    }
+    let tmp = c.required("temp_folder", e.temp_folder);
+    let passphrase = match (c.parse("passphrase", e.passphrase, "\"keychain\"",
+                                    |x| Passphrase::parse(x, profile)),
+                            e.passphrase_command) {
+        (Some(_), Some(_)) => {
+            c.problem("set either 'passphrase' or 'passphrase_command', not both".to_string());
+            None
+        }
+        (x, None) => x,
+        (None, Some(x)) => Some(Passphrase::Command(x)),
+    };
+    let encryption = c.parse("encryption", e.encryption, "\"gpg\" or \"builtin\"",
+                             Encryption::parse)
+        .unwrap_or(Encryption::Gpg);
+    c.feature(&format!("encryption = \"{}\"", encryption.name()), encryption.feature(),
+              encryption.available());
+    if let Some(Passphrase::Keychain(_)) = passphrase {
+        c.feature("passphrase = \"keychain\"", "keychain", cfg!(feature = "keychain"));
+    }
+    match encryption {
+        Encryption::Gpg if passphrase.is_some() && ! e.symmetric =>
+            c.problem("'passphrase' and 'passphrase_command' are only used with \
+                       'symmetric = true' or encryption = \"builtin\"".to_string()),
+        Encryption::Builtin if passphrase.is_none() =>
+            c.problem("encryption = \"builtin\" needs 'passphrase' or \
+                       'passphrase_command'".to_string()),
+        _ => (),
+    }
+    let pinentry = c.parse("pinentry", e.pinentry, "\"default\", \"ask\" or \"error\"",
+                           Pinentry::parse);
+    let notify = c.parse("notify", e.notify, "\"never\", \"problems\" or \"always\"",
+                         NotifyPolicy::parse)
+        .unwrap_or(NotifyPolicy::Never);
+    if notify != NotifyPolicy::Never {
+        c.feature("'notify'", "notify", cfg!(feature = "notify"));
+    }
+    if e.email_after == Some(0) {
+        c.problem("'email_after' must be at least 1".to_string());
+    }
+    if e.smtp_password_command.is_some() && e.smtp_user.is_none() {
+        c.problem("'smtp_password_command' is only used with 'smtp_user'".to_string());
+    }
+    let email = match (e.email_to, e.smtp_host) {
+        (Some(to), Some(smtp_host)) => {
+            c.feature("'email_to'", "email", cfg!(feature = "email"));
+            Some(Email {
+                to,
+                from: e.email_from,
+                smtp_host,
+                smtp_port: e.smtp_port.unwrap_or(SMTP_PORT),
+                smtp_user: e.smtp_user,
+                smtp_password_command: e.smtp_password_command,
+                after: e.email_after.unwrap_or(3),
+            })
+        }
+        (Some(_), None) => {
+            c.problem("'email_to' needs 'smtp_host'".to_string());
+            None
+        }
+        (None, smtp_host) => {
+            let unused = [
+                ("email_from", e.email_from.is_some()),
+                ("email_after", e.email_after.is_some()),
+                ("smtp_host", smtp_host.is_some()),
+                ("smtp_port", e.smtp_port.is_some()),
+                ("smtp_user", e.smtp_user.is_some()),
+            ];
+            for (key, _) in unused.iter().filter(|(_, set)| *set) {
+                c.problem(format!("'{}' is only used with 'email_to'", key));
+            }
+            None
+        }
+    };
+    let webhook_when = c.parse("webhook_when", e.webhook_when,
+                               "\"never\", \"problems\" or \"always\"", NotifyPolicy::parse);
+    let webhook_url = c.parse("webhook_url", e.webhook_url, "a URL like \"https://ntfy.sh/mist\"",
+                              |x| http::valid_url(x).then(|| x.to_string()));
+    let webhook = match webhook_url {
+        Some(url) => {
+            c.feature("'webhook_url'", "webhook", cfg!(feature = "webhook"));
+            Some(Webhook {
+                url,
+                when: webhook_when.unwrap_or(NotifyPolicy::Always),
+                token_command: e.webhook_token_command,
+            })
+        }
+        None => {
+            if webhook_when.is_some() || e.webhook_token_command.is_some() {
+                c.problem("'webhook_when' and 'webhook_token_command' are only used with \
+                           'webhook_url'".to_string());
+            }
+            None
+        }
+    };
+    let healthcheck_url = c.parse("healthcheck_url", e.healthcheck_url,
+                                  "a URL like \"https://hc-ping.com/<uuid>\"",
+                                  |x| http::valid_url(x).then(|| x.trim_end_matches('/').to_string()));
+    if healthcheck_url.is_some() {
+        c.feature("'healthcheck_url'", "healthcheck", cfg!(feature = "healthcheck"));
+    }
+    let mut answer = |key, value| c.parse(key, value, "\"ask\", \"yes\" or \"no\"", Answer::parse)
+        .unwrap_or(Answer::Ask);
+    let answers = Answers {
+        overwrite_remote: answer("answer_overwrite_remote", e.answer_overwrite_remote),
+        overwrite_local: answer("answer_overwrite_local", e.answer_overwrite_local),
+        push_after_error: answer("answer_push_after_error", e.answer_push_after_error),
+        gpg_key: answer("answer_gpg_key", e.answer_gpg_key),
+        create_remote: answer("answer_create_remote", e.answer_create_remote),
+    };
+    let log_rotate = c.parse("log_rotate", e.log_rotate,
+                             "\"daily\", \"weekly\" or a size like \"10M\"", Rotation::parse);
+    let log_keep = match e.log_keep {
+        Some(x) if x > 1000 => {
+            c.problem(format!("'log_keep' value {} is out of range (0-1000)", x));
+            None
+        }
+        x => x,
+    };
+    let log_file = e.log_file.map(|path| LogFile {
+        path,
+        rotation: log_rotate.unwrap_or(Rotation::Size(10 << 20)),
+        keep: log_keep.unwrap_or(5),
+    });
+    let interval = c.parse("interval", e.interval, "a duration like \"15m\" or \"1h30m\"",
+                           parse_interval);
+    let cron = c.parse("cron", e.cron, "a five-field cron expression", Cron::parse);
+    let network = c.parse("network", e.network, "\"unmetered\", \"online\" or \"any\"",
+                          NetworkPolicy::parse)
+        .unwrap_or(NetworkPolicy::Unmetered);
+    let sync_hours = c.parse("sync_hours", e.sync_hours, "hours like \"01:00-06:00\"",
+                             Window::parse);
+    let sync_hours_above = c.parse("sync_hours_above", e.sync_hours_above,
+                                   "a size like \"512M\" or \"2G\"", parse_size);
+    if sync_hours_above.is_some() && sync_hours.is_none() {
+        c.problem("'sync_hours_above' has no effect without 'sync_hours'".to_string());
+    }
+    let hash = c.parse("hash", e.hash, "\"xxhash64\", \"xxh3\", \"blake3\" or \"sha256\"",
+                       HashAlgorithm::parse)
+        .unwrap_or(HashAlgorithm::XxHash64);
+    let detect = c.parse("detect", e.detect, "\"mtime\", \"size\" or \"content\"", Detect::parse)
+        .unwrap_or(Detect::Mtime);
+    if e.metrics_port == Some(0) {
+        c.problem("'metrics_port' value 0 is out of range (1-65535)".to_string());
+    }
+    if e.ssh_port == Some(0) {
+        c.problem("'ssh_port' value 0 is out of range (1-65535)".to_string());
+    }
+    let ssh_connect_timeout = c.parse("ssh_connect_timeout", e.ssh_connect_timeout,
+                                      "a duration like \"30s\" or \"1m\"", parse_interval);
+    let ssh_keep_alive = c.parse("ssh_keep_alive", e.ssh_keep_alive, "a duration like \"60s\"",
+                                 parse_interval);
+    let known_hosts = c.parse("known_hosts", e.known_hosts,
+                              "\"strict\", \"accept-new\" or \"off\"", HostKeyPolicy::parse)
+        .unwrap_or(HostKeyPolicy::Strict);
+    let proxy_jump = c.parse("proxy_jump", e.proxy_jump,
+                             "\"[user@]host[:port]\", or several separated by commas",
+                             |x| (! x.is_empty() && ! x.contains(char::is_whitespace))
+                                 .then(|| x.to_string()));
+    let ssh_client = c.parse("ssh_client", e.ssh_client, "\"openssh\" or \"builtin\"",
+                             SshClient::parse)
+        .unwrap_or(SshClient::Openssh);
+    if ssh_client == SshClient::Builtin {
+        c.feature("ssh_client = \"builtin\"", "builtin-ssh", cfg!(feature = "builtin-ssh"));
+        if proxy_jump.is_some() {
+            c.problem("'proxy_jump' needs ssh_client = \"openssh\"".to_string());
+        }
+    }
+    // Remote paths are relative to the remote home directory, so "~/x" is
+    // just "x"; sftp wouldn't expand the tilde. The trailing slash is added
+    // back when joining, which also keeps "/" meaning the root.
+    let remote_path = e.remote_path.map(|x| {
+        match x.strip_prefix("~/").unwrap_or(&x).trim_end_matches('/') {
+            "" if x.starts_with('/') => String::new(),
+            "" | "~" => ".".to_string(),
+            x => x.to_string(),
+        }
+    });
 
-    let tar_hash = &tar_hash
-        .file_name().unwrap()
-        .to_str().unwrap();
+    let preserve = Preserve {
+        permissions: e.preserve_permissions,
+        ownership: e.preserve_ownership,
+        xattrs: e.preserve_xattrs,
+    };
+    let symlinks = c.parse("symlinks", e.symlinks, "\"preserve\", \"follow\" or \"skip\"",
+                           Symlinks::parse)
+        .unwrap_or(Symlinks::Preserve);
+    let exclude = Exclude {
+        max_file_size: c.parse("max_file_size", e.max_file_size, "a size like \"512M\" or \"2G\"",
+                               parse_size),
+        types: e.exclude_types
+            .map(OneOrMany::into_vec)
+            .unwrap_or_default()
+            .iter()
+            .map(|x| x.trim_start_matches("*.").trim_start_matches('.').to_lowercase())
+            .filter(|x| ! x.is_empty())
+            .collect(),
+    };
+    let unpack_limits = Limits {
+        size: c.parse("max_unpack_size", e.max_unpack_size, "a size like \"20G\"", parse_size),
+        entries: e.max_unpack_entries,
+        file_size: c.parse("max_unpack_file_size", e.max_unpack_file_size,
+                           "a size like \"512M\" or \"2G\"", parse_size),
+    };
+    if unpack_limits.entries == Some(0) {
+        c.problem("'max_unpack_entries' must be at least 1".to_string());
+    }
+    let spill_above = c.parse("spill_above", e.spill_above, "a size like \"1G\"", parse_size);
+    let merge_tool = c.parse("merge_tool", e.merge_tool,
+                             "\"unison\", \"three-way\", \"rsync\" or a command using \
+                              {local} and {remote}", MergeTool::parse)
+        .unwrap_or_default();
+    if merge_tool == MergeTool::Unison {
+        c.feature("merge_tool = \"unison\"", "unison", cfg!(feature = "unison"));
+    }
+    if merge_tool != MergeTool::Unison && (e.unison_path.is_some() || ! e.unison_args.is_empty()) {
+        c.problem("'unison_path' and 'unison_args' are only used with \
+                   merge_tool = \"unison\"".to_string());
+    }
+    let split_size = c.parse("split_size", e.split_size, "a size like \"512M\" or \"2G\"",
+                             parse_size);
+    let storage = c.parse("storage", e.storage, "\"archive\" or \"chunks\"", Storage::parse)
+        .unwrap_or(Storage::Archive);
+    if storage == Storage::Chunks && split_size.is_some() {
+        c.problem("'split_size' has no effect with storage = \"chunks\"".to_string());
+    }
+    let keep_versions_for = c.parse("keep_versions_for", e.keep_versions_for,
+                                    "a duration like \"30d\"", parse_interval);
+    if storage != Storage::Chunks && (e.keep_versions.is_some() || keep_versions_for.is_some()) {
+        c.problem("'keep_versions' and 'keep_versions_for' need storage = \"chunks\"".to_string());
+    }
+    if e.keep_versions == Some(0) {
+        c.problem("'keep_versions' counts the current version, so it must be at least 1"
+                  .to_string());
+    }
+    let compression = c.parse("compression", e.compression, "\"gzip\", \"auto\" or \"none\"",
+                              Compression::parse)
+        .unwrap_or(Compression::Gzip);
+    let mode = c.parse("mode", e.mode, "\"sync\", \"mirror-up\" or \"mirror-down\"", Mode::parse)
+        .unwrap_or(Mode::Sync);
+    if mode == Mode::Sync && e.propagate_deletes.is_some() {
+        c.problem("'propagate_deletes' is only used with mode = \"mirror-up\" or \
+                   \"mirror-down\"".to_string());
+    }
+
+    let schedule = match (interval, cron) {
+        (Some(_), Some(_)) => {
+            c.problem("only one of 'interval' and 'cron' may be set".to_string());
+            None
+        }
+        (Some(x), None) => Some(Schedule::Interval(x)),
+        (None, Some(x)) => Some(Schedule::Cron(x)),
+        (None, None) => None,
+    };
 
-    let tar = &tar
-        .file_name().unwrap()
-        .to_str().unwrap();
+    let folders = match (&dirs, &tmp) {
+        (Some(dirs), Some(tmp)) => folders(dirs, tmp, remote_path.as_deref(), &mut c),
+        _ => None,
+    };
 
-    let gpgbin = cfg
-        .get("gpg_program").to_owned();
+    let (folders, sshaddr) = match (folders, sshaddr) {
+        (Some(folders), Some(sshaddr)) if c.problems.is_empty() => (folders, sshaddr),
+        _ => return Err(Box::new(ConfigError {
+            profile: profile.to_string(),
+            problems: c.problems,
+        })),
+    };
 
     let config = Config {
-        dir: PathBuf::from(dir),
-        sshaddr: sshaddr.to_string(),
-        gpg_id: gpgid.to_string(), 
-        temp: PathBuf::from(tmp),
-        tar: tar.to_string(),
-        tar_hash: tar_hash.to_string(),
-        gpg_bin: gpgbin.cloned(),
-        symmetric: symmetric,
+        folders,
+        sshaddr,
+        http_url,
+        http_token_command: e.http_token_command,
+        restic_url,
+        restic_password_command: e.restic_password_command,
+        gpg_id: e.gpg_id,
+        gpg_bin: e.gpg_program,
+        merge_tool,
+        unison_bin: e.unison_path,
+        unison_args: e.unison_args,
+        encryption,
+        symmetric: e.symmetric,
+        passphrase,
+        pinentry,
+        armor: e.armor,
+        pre_sync: e.pre_sync,
+        post_sync: e.post_sync,
+        on_failure: e.on_failure,
+        notify,
+        email,
+        webhook,
+        metrics_port: e.metrics_port,
+        healthcheck_url,
+        answers,
+        stats: e.stats,
+        results_file: e.results_file,
+        log_file,
+        journald: e.journald,
+        schedule,
+        network,
+        sync_hours,
+        sync_hours_above,
+        hash,
+        detect,
+        ssh_client,
+        ssh_port: e.ssh_port,
+        ssh_user: e.ssh_user,
+        ssh_identity_file: e.ssh_identity_file,
+        ssh_connect_timeout,
+        ssh_keep_alive,
+        known_hosts,
+        proxy_jump,
+        preserve,
+        unpack_limits,
+        spill_above,
+        symlinks,
+        exclude,
+        split_size,
+        storage,
+        keep_versions: e.keep_versions,
+        keep_versions_for,
+        compression,
+        mode,
+        propagate_deletes: e.propagate_deletes.unwrap_or(true),
     };
 
     Ok(config)
@@ -0,0 +1,503 @@
+use std::io::{ Read, Write };
+
+use crate::config::Config;
+use crate::format::Cipher;
+use crate::keys;
+use crate::spill::Buffer;
+use crate::summary::Failure;
+
+#[cfg(not(any(feature = "gpg", feature = "builtin-crypto")))]
+compile_error!("mist needs an encryption backend: the 'gpg' or 'builtin-crypto' feature");
+
+/// What encrypts a profile's archives, chunks and chunk indexes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encryption {
+    /// GnuPG, to a key or (with `symmetric`) a passphrase.
+    Gpg,
+    /// Mist's own passphrase encryption, in pure Rust, for builds without
+    /// GPGME: AES-256-CTR and HMAC-SHA256, with keys derived by PBKDF2.
+    Builtin,
+}
+
+impl Encryption {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "gpg"     => Some(Encryption::Gpg),
+            "builtin" => Some(Encryption::Builtin),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encryption::Gpg     => "gpg",
+            Encryption::Builtin => "builtin",
+        }
+    }
+
+    /// The cargo feature this build needs to have been built with to use it.
+    pub fn feature(&self) -> &'static str {
+        match self {
+            Encryption::Gpg     => "gpg",
+            Encryption::Builtin => "builtin-crypto",
+        }
+    }
+
+    pub fn available(&self) -> bool {
+        match self {
+            Encryption::Gpg     => cfg!(feature = "gpg"),
+            Encryption::Builtin => cfg!(feature = "builtin-crypto"),
+        }
+    }
+}
+
+/// How data the profile encrypts now is marked in the format file.
+pub fn cipher(cfg: &Config) -> Cipher {
+    match (cfg.encryption, cfg.symmetric) {
+        (Encryption::Builtin, _) => Cipher::Builtin,
+        (Encryption::Gpg, true)  => Cipher::GpgSymmetric,
+        (Encryption::Gpg, false) => Cipher::Gpg,
+    }
+}
+
+/// The start of everything the builtin encryption writes. gpg's output
+/// never starts this way, armored or not.
+const MAGIC: &[u8] = b"mistaes1";
+
+fn unavailable(what: Encryption) -> Box<dyn std::error::Error> {
+    Failure::crypto(format!("This mist was built without the '{}' feature", what.feature()))
+}
+
+/// The profile's symmetric passphrase, if it isn't left to gpg to ask for.
+fn passphrase(cfg: &Config) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    cfg.passphrase.as_ref().map(|x| x.get()).transpose().map_err(Failure::crypto)
+}
+
+/// Decrypt the remote archive's data, armored or binary. The data says how
+/// it was encrypted, so data from before the profile switched still reads.
+pub async fn decrypt(bytes: &[u8], cfg: &Config)
+-> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut b = Vec::new();
+    decrypt_to(bytes, cfg, &mut b).await?;
+    Ok(b)
+}
+
+/// Decrypt data as `decrypt` does, writing the plaintext to `out` as it
+/// comes, e.g. to a spill::Buffer.
+pub async fn decrypt_to(bytes: &[u8], cfg: &Config, out: &mut (impl Write + Send))
+-> Result<(), Box<dyn std::error::Error>> {
+    decrypt_with(bytes.len() as u64, || bytes, cfg, out).await
+}
+
+/// Decrypt data held in a spill::Buffer as `decrypt_to` does, reading it a
+/// piece at a time, so it needn't be in memory whole.
+pub async fn decrypt_from(data: &Buffer, cfg: &Config, out: &mut (impl Write + Send))
+-> Result<(), Box<dyn std::error::Error>> {
+    decrypt_with(data.len(), || data.reader(), cfg, out).await
+}
+
+/// Decrypt `len` bytes of data that `open` reads from the start, as many
+/// times as it's called: the builtin encryption checks the data whole
+/// before writing any of it out.
+#[cfg_attr(not(feature = "builtin-crypto"), allow(unused_variables))]
+async fn decrypt_with<R: Read + Send>(len: u64, open: impl Fn() -> R, cfg: &Config,
+                                      out: &mut (impl Write + Send))
+-> Result<(), Box<dyn std::error::Error>> {
+    // gpg may need to prompt for a passphrase or PIN, and so may the
+    // passphrase command.
+    let _prompt = keys::PROMPT.lock();
+    std::io::stdout().flush()?;
+    let secret = passphrase(cfg)?;
+    let mut start = [0; MAGIC.len()];
+    if open().read_exact(&mut start).is_ok() && start == MAGIC {
+        #[cfg(feature = "builtin-crypto")]
+        {
+            let secret = secret.ok_or_else(|| Failure::crypto(
+                "This data was encrypted with a passphrase; set 'passphrase' or \
+                 'passphrase_command'"))?;
+            return builtin::decrypt(len, open, &secret, out)
+                .map_err(|e| Failure::crypto(format!("Decryption failed: {}", e)))
+        }
+        #[cfg(not(feature = "builtin-crypto"))]
+        return Err(unavailable(Encryption::Builtin))
+    }
+    #[cfg(feature = "gpg")]
+    return gpg::decrypt(open(), cfg, secret.as_deref(), out)
+        .map_err(|e| Failure::crypto(format!("Decryption failed: {:?}", e)));
+    #[cfg(not(feature = "gpg"))]
+    Err(unavailable(Encryption::Gpg))
+}
+
+/// Encrypt data with the profile's GPG key, or its passphrase.
+pub async fn encrypt(bytes: &[u8], cfg: &Config)
+-> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut b = Vec::new();
+    encrypt_to(bytes, cfg, &mut b).await?;
+    Ok(b)
+}
+
+/// Encrypt what `plain` reads, as `encrypt` does, writing the ciphertext
+/// to `out` as it comes, e.g. to a spill::Buffer, so neither needs to be
+/// in memory whole.
+pub async fn encrypt_to(plain: impl Read + Send, cfg: &Config, out: &mut (impl Write + Send))
+-> Result<(), Box<dyn std::error::Error>> {
+    if ! cfg.encryption.available() {
+        return Err(unavailable(cfg.encryption))
+    }
+    let _prompt = keys::PROMPT.lock();
+    std::io::stdout().flush()?;
+    let secret = passphrase(cfg)?;
+    match cfg.encryption {
+        #[cfg(feature = "gpg")]
+        Encryption::Gpg => gpg::encrypt(plain, cfg, secret.as_deref(), out),
+        #[cfg(feature = "builtin-crypto")]
+        Encryption::Builtin => {
+            let secret = secret.ok_or_else(|| Failure::crypto(
+                "encryption = \"builtin\" needs 'passphrase' or 'passphrase_command'"))?;
+            Ok(builtin::encrypt(plain, &secret, out)?)
+        }
+        #[allow(unreachable_patterns)]
+        x => Err(unavailable(x)),
+    }
+}
+
+#[cfg(feature = "gpg")]
+mod gpg {
+    use std::io::{ Read, Write };
+
+    use gpgme::{ Context, Data, PassphraseRequest, PinentryMode, Protocol };
+
+    use crate::config::Config;
+    use crate::summary::Failure;
+
+    /// A GPGME context set up as the profile says.
+    fn context(cfg: &Config) -> Result<Context, Box<dyn std::error::Error>> {
+        let mut ctx = Context::from_protocol(Protocol::OpenPgp)?;
+        if let Some(x) = &cfg.gpg_bin {
+            let _ = ctx.set_engine_path(x.as_str());
+        }
+        if let Some(x) = cfg.pinentry {
+            ctx.set_pinentry_mode(x.mode())?;
+        }
+        // The passphrase is supplied by mist rather than a pinentry.
+        if cfg.passphrase.is_some() {
+            ctx.set_pinentry_mode(PinentryMode::Loopback)?;
+        }
+        Ok(ctx)
+    }
+
+    /// Run `f` with gpg's passphrase requests answered with `secret`, if
+    /// there is one. The context must be in loopback pinentry mode for gpg
+    /// to ask.
+    fn with_passphrase<R>(ctx: &mut Context, secret: Option<&str>,
+                          f: impl FnOnce(&mut Context) -> R)
+    -> R {
+        let secret = match secret {
+            Some(x) => x.to_string(),
+            None => return f(ctx),
+        };
+        let provider = move |req: PassphraseRequest<'_>, out: &mut dyn Write| {
+            // Answering again with the same passphrase would only loop.
+            if req.prev_attempt_failed {
+                return Err(gpgme::Error::BAD_PASSPHRASE)
+            }
+            out.write_all(secret.as_bytes())?;
+            out.write_all(b"\n")?;
+            Ok(())
+        };
+        ctx.with_passphrase_provider(provider, f)
+    }
+
+    pub fn decrypt(cipher: impl Read + Send, cfg: &Config, secret: Option<&str>,
+                   out: &mut (impl Write + Send))
+    -> Result<(), Box<dyn std::error::Error>> {
+        let mut ctx = context(cfg)?;
+        let mut cipher = Data::from_reader(cipher).map_err(|e| e.error())?;
+        let mut plain = Data::from_writer(out).map_err(|e| e.error())?;
+        with_passphrase(&mut ctx, secret, |ctx| ctx.decrypt(&mut cipher, &mut plain))?;
+        Ok(())
+    }
+
+    pub fn encrypt(plain: impl Read + Send, cfg: &Config, secret: Option<&str>,
+                   out: &mut (impl Write + Send))
+    -> Result<(), Box<dyn std::error::Error>> {
+        let mut ctx = context(cfg)?;
+        ctx.set_armor(cfg.armor);
+        let mut plain = Data::from_reader(plain).map_err(|e| e.error())?;
+        let mut cipher = Data::from_writer(out).map_err(|e| e.error())?;
+        if cfg.symmetric {
+            with_passphrase(&mut ctx, secret,
+                            |ctx| ctx.encrypt_symmetric(&mut plain, &mut cipher))?;
+        } else {
+            let id = cfg.gpg_id
+                .as_deref()
+                .ok_or_else(|| Failure::crypto("No GPG key to encrypt with; set 'gpg_id'"))?;
+            let key = ctx.get_key(id)?;
+            ctx.encrypt([&key], &mut plain, &mut cipher)?;
+        }
+        Ok(())
+    }
+}
+
+/// The builtin encryption. Each piece of data is
+///
+///   "mistaes1" | salt (16) | PBKDF2 rounds (4, big-endian) | IV (16)
+///   | AES-256-CTR ciphertext | HMAC-SHA256 of everything before (32)
+///
+/// with the AES and HMAC keys derived from PBKDF2-HMAC-SHA256 of the
+/// passphrase and salt.
+#[cfg(feature = "builtin-crypto")]
+mod builtin {
+    use std::convert::TryInto;
+    use std::fs::File;
+    use std::io::{ Read, Write };
+    use std::sync::{ Mutex, OnceLock };
+
+    use aes::Aes256;
+    use aes::cipher::{ KeyIvInit, StreamCipher };
+    use hmac::{ Hmac, Mac };
+    use sha2::{ Digest, Sha256 };
+
+    use super::MAGIC;
+
+    type HmacSha256 = Hmac<Sha256>;
+    /// AES-256 in CTR mode, the IV being the whole 128-bit counter.
+    type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+    const SALT_LEN: usize = 16;
+    const IV_LEN: usize = 16;
+    const TAG_LEN: usize = 32;
+    const HEADER_LEN: usize = MAGIC.len() + SALT_LEN + 4 + IV_LEN;
+    /// PBKDF2 rounds for new data. What was used is stored with the data,
+    /// so this can go up later. Tests make do with fewer.
+    const ROUNDS: u32 = if cfg!(test) { 1_000 } else { 600_000 };
+    /// How much is encrypted or decrypted at once.
+    const PIECE: usize = 1 << 20;
+    /// The rounds data may say it was encrypted with. Anything outside
+    /// this is damaged, or made to keep mist busy deriving keys.
+    const MIN_ROUNDS: u32 = 1_000;
+    const MAX_ROUNDS: u32 = 10_000_000;
+
+    #[derive(Clone)]
+    struct Keys {
+        aes: [u8; 32],
+        mac: [u8; 32],
+    }
+
+    /// Keys derived from a passphrase (known by its digest) and salt.
+    struct Derived {
+        salt: [u8; SALT_LEN],
+        rounds: u32,
+        digest: [u8; 32],
+        keys: Keys,
+    }
+
+    /// The keys derived so far. Deriving is slow on purpose, and a push
+    /// encrypts every chunk.
+    static DERIVED: Mutex<Vec<Derived>> = Mutex::new(Vec::new());
+
+    /// The salt this run encrypts with, so the keys are derived once.
+    static SALT: OnceLock<[u8; SALT_LEN]> = OnceLock::new();
+
+    fn random<const N: usize>() -> std::io::Result<[u8; N]> {
+        let mut x = [0; N];
+        File::open("/dev/urandom")?.read_exact(&mut x)?;
+        Ok(x)
+    }
+
+    fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+        let mut m = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+        for x in parts {
+            m.update(x);
+        }
+        m.finalize().into_bytes().into()
+    }
+
+    fn keys(pass: &str, salt: &[u8; SALT_LEN], rounds: u32) -> Keys {
+        let digest: [u8; 32] = Sha256::digest(pass.as_bytes()).into();
+        let mut derived = DERIVED.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(x) = derived.iter()
+            .find(|x| x.salt == *salt && x.rounds == rounds && x.digest == digest) {
+            return x.keys.clone()
+        }
+        let master = pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(pass.as_bytes(), salt, rounds);
+        let k = Keys {
+            aes: hmac(&master, &[b"mist encryption"]),
+            mac: hmac(&master, &[b"mist authentication"]),
+        };
+        derived.push(Derived { salt: *salt, rounds, digest, keys: k.clone() });
+        k
+    }
+
+    /// Call `f` with what `r` reads, a piece at a time.
+    fn pieces<E: From<std::io::Error>>(mut r: impl Read, mut f: impl FnMut(&mut [u8]) -> Result<(), E>)
+    -> Result<(), E> {
+        let mut piece = vec![0; PIECE];
+        loop {
+            match r.read(&mut piece) {
+                Ok(0) => return Ok(()),
+                Ok(n) => f(&mut piece[..n])?,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Encrypt what `plain` reads, writing the result to `out` a piece at
+    /// a time.
+    pub fn encrypt(plain: impl Read, pass: &str, out: &mut impl Write) -> std::io::Result<()> {
+        let salt = match SALT.get() {
+            Some(x) => *x,
+            None => {
+                let x = random()?;
+                *SALT.get_or_init(|| x)
+            }
+        };
+        let iv: [u8; IV_LEN] = random()?;
+        let k = keys(pass, &salt, ROUNDS);
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&salt);
+        header.extend_from_slice(&ROUNDS.to_be_bytes());
+        header.extend_from_slice(&iv);
+        let mut m = <HmacSha256 as Mac>::new_from_slice(&k.mac).expect("HMAC takes keys of any length");
+        m.update(&header);
+        out.write_all(&header)?;
+        let mut cipher = Aes256Ctr::new(&k.aes.into(), &iv.into());
+        pieces(plain, |piece| {
+            cipher.apply_keystream(piece);
+            m.update(piece);
+            out.write_all(piece)
+        })?;
+        out.write_all(&m.finalize().into_bytes())
+    }
+
+    /// Check the `len` bytes of data `open` reads are whole and were
+    /// encrypted with `pass`, then read them again to write the plaintext
+    /// to `out` a piece at a time.
+    pub fn decrypt<R: Read>(len: u64, open: impl Fn() -> R, pass: &str, out: &mut impl Write)
+    -> Result<(), String> {
+        if len < (HEADER_LEN + TAG_LEN) as u64 {
+            return Err("the data is cut short".to_string())
+        }
+        let body = len - (HEADER_LEN + TAG_LEN) as u64;
+        let mut data = open();
+        let mut header = [0; HEADER_LEN];
+        data.read_exact(&mut header).map_err(|e| e.to_string())?;
+        let fields = &header[MAGIC.len()..];
+        let salt: [u8; SALT_LEN] = fields[..SALT_LEN].try_into().expect("sized above");
+        let rounds = u32::from_be_bytes(fields[SALT_LEN..SALT_LEN + 4].try_into()
+                                            .expect("sized above"));
+        let iv: [u8; IV_LEN] = fields[SALT_LEN + 4..].try_into().expect("sized above");
+        if ! (MIN_ROUNDS..=MAX_ROUNDS).contains(&rounds) {
+            return Err(format!("the data is damaged: it gives {} key derivation rounds", rounds))
+        }
+        let k = keys(pass, &salt, rounds);
+        let mut m = <HmacSha256 as Mac>::new_from_slice(&k.mac).expect("HMAC takes keys of any length");
+        m.update(&header);
+        pieces(data.by_ref().take(body), |piece| -> Result<(), std::io::Error> {
+            m.update(piece);
+            Ok(())
+        }).map_err(|e| e.to_string())?;
+        let mut tag = [0; TAG_LEN];
+        data.read_exact(&mut tag).map_err(|e| e.to_string())?;
+        m.verify_slice(&tag).map_err(|_| "wrong passphrase, or the data is damaged".to_string())?;
+        let mut data = open();
+        data.read_exact(&mut header).map_err(|e| e.to_string())?;
+        let mut cipher = Aes256Ctr::new(&k.aes.into(), &iv.into());
+        pieces(data.take(body), |piece| {
+            cipher.apply_keystream(piece);
+            out.write_all(piece)
+        }).map_err(|e| e.to_string())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::hash::from_hex;
+        use crate::spill::Buffer;
+
+        const PASS: &str = "correct horse";
+
+        fn encrypted(plain: &[u8], pass: &str) -> Vec<u8> {
+            let mut out = Vec::new();
+            encrypt(plain, pass, &mut out).unwrap();
+            out
+        }
+
+        fn decrypted(bytes: &[u8], pass: &str) -> Result<Vec<u8>, String> {
+            let mut out = Vec::new();
+            decrypt(bytes.len() as u64, || bytes, pass, &mut out)?;
+            Ok(out)
+        }
+
+        #[test]
+        fn round_trips() {
+            let plain: Vec<u8> = (0..PIECE + 100).map(|x| x as u8).collect();
+            let bytes = encrypted(&plain, PASS);
+            assert!(bytes.starts_with(MAGIC));
+            assert!(! bytes.windows(64).any(|x| x == &plain[1000..1064]));
+            assert_eq!(decrypted(&bytes, PASS).unwrap(), plain);
+            assert_eq!(decrypted(&encrypted(b"", PASS), PASS).unwrap(), b"");
+        }
+
+        #[test]
+        fn round_trips_through_spill_buffers() {
+            let plain: Vec<u8> = (0..3 * PIECE + 7).map(|x| (x / 3) as u8).collect();
+            let mut bytes = Buffer::new(Some(1000));
+            encrypt(plain.as_slice(), PASS, &mut bytes).unwrap();
+            assert!(bytes.spilled());
+            assert_eq!(bytes.len(), (HEADER_LEN + plain.len() + TAG_LEN) as u64);
+            let mut out = Buffer::new(Some(1000));
+            decrypt(bytes.len(), || bytes.reader(), PASS, &mut out).unwrap();
+            assert_eq!(out.into_vec().unwrap(), plain);
+        }
+
+        #[test]
+        fn reads_what_earlier_versions_wrote() {
+            // Salt of 7s, 1000 rounds and an IV of all ones, so the counter
+            // wraps after the first block.
+            let bytes = from_hex("6d6973746165733107070707070707070707070707070707000003e8\
+                                  ffffffffffffffffffffffffffffffffba5deb6fccfb9880b14cc0dd\
+                                  f2384b00618aff7a7afe7dc1c2b99d6bd312406fbce2fd081f00056c\
+                                  bdf2b0c05239b62055749db0497f52ec75db1d0399dca8bce228b349\
+                                  f25f4c23a7b9a09b0de49e7eca0fc9b97a854511cc").unwrap();
+            assert_eq!(decrypted(&bytes, PASS).unwrap(),
+                       b"encrypted before the cipher crates, across a counter wrap");
+        }
+
+        #[test]
+        fn refuses_the_wrong_passphrase() {
+            let bytes = encrypted(b"secret", PASS);
+            let e = decrypted(&bytes, "battery staple").unwrap_err();
+            assert!(e.contains("wrong passphrase"), "{}", e);
+        }
+
+        #[test]
+        fn refuses_damaged_data() {
+            let bytes = encrypted(b"secret", PASS);
+            let cut = &bytes[..bytes.len() - 1];
+            assert!(decrypted(cut, PASS).unwrap_err().contains("wrong passphrase"));
+            assert!(decrypted(&bytes[..HEADER_LEN + TAG_LEN - 1], PASS)
+                .unwrap_err()
+                .contains("cut short"));
+            for i in [HEADER_LEN, bytes.len() - TAG_LEN, bytes.len() - 1] {
+                let mut tampered = bytes.clone();
+                tampered[i] ^= 1;
+                assert!(decrypted(&tampered, PASS).is_err(), "byte {} changed", i);
+            }
+        }
+
+        #[test]
+        fn refuses_unlikely_rounds() {
+            let bytes = encrypted(b"secret", PASS);
+            let at = MAGIC.len() + SALT_LEN;
+            for rounds in [0, MIN_ROUNDS - 1, MAX_ROUNDS + 1, u32::MAX] {
+                let mut changed = bytes.clone();
+                changed[at..at + 4].copy_from_slice(&rounds.to_be_bytes());
+                let e = decrypted(&changed, PASS).unwrap_err();
+                assert!(e.contains("key derivation rounds"), "{}: {}", rounds, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::path::Path;
+use std::process::Command;
+
+#[cfg(feature = "gpg")]
+use gpgme::{ Context, Protocol };
+
+use crate::config::{ Config, find_config, load_configuration, profile_names };
+use crate::hash::HashAlgorithm;
+#[cfg(feature = "gpg")]
+use crate::keys;
+use crate::merge::MergeTool;
+use crate::remote::{ self, RemoteStore };
+use crate::secrets::Passphrase;
+use crate::stats::{ Stats, stats_path };
+
+/// Tallies check results and prints each one as it completes.
+struct Report {
+    problems: usize,
+}
+
+impl Report {
+    fn ok(&self, what: &str) {
+        println!("[ ok ] {}", what);
+    }
+
+    fn warn(&self, what: &str, hint: &str) {
+        println!("[warn] {}", what);
+        println!("       {}", hint);
+    }
+
+    fn fail(&mut self, what: &str, hint: &str) {
+        self.problems += 1;
+        println!("[FAIL] {}", what);
+        println!("       {}", hint);
+    }
+}
+
+/// Check that the passphrase can be had, if mist supplies it.
+fn check_passphrase(profile: &str, cfg: &Config, r: &mut Report) {
+    match &cfg.passphrase {
+        Some(x @ Passphrase::Keychain(_)) => match x.get() {
+            Ok(_) => r.ok("The symmetric passphrase is in the keychain"),
+            Err(e) => r.fail(&e.to_string(),
+                             &format!("Run `mist keychain {}`, or remove 'passphrase'.", profile)),
+        },
+        Some(x @ Passphrase::Command(_)) => match x.get() {
+            Ok(_) => r.ok("passphrase_command gave a passphrase"),
+            Err(e) => r.fail(&e.to_string(),
+                             "Fix 'passphrase_command' so it prints the passphrase."),
+        },
+        None => (),
+    }
+}
+
+/// Check that the encryption key exists and can be used.
+#[cfg(feature = "gpg")]
+fn check_gpg(home: &Path, profile: &str, cfg: &Config, r: &mut Report) {
+    let mut ctx = match Context::from_protocol(Protocol::OpenPgp) {
+        Ok(x) => x,
+        Err(e) => return r.fail(&format!("GPGME unavailable: {}", e),
+                                "Install GPGME and GnuPG."),
+    };
+    if let Some(x) = cfg.gpg_bin.as_deref() {
+        if let Err(e) = ctx.set_engine_path(x) {
+            return r.fail(&format!("gpg_program '{}' can't be used: {}", x, e),
+                          "Point 'gpg_program' at a working gpg binary, or remove it.");
+        }
+    }
+    let gpg_id = match (&cfg.gpg_id, keys::stored_key(home, profile)) {
+        (Some(x), _) => x.clone(),
+        (None, Some(x)) => {
+            r.ok(&format!("No gpg_id set; using key {}, chosen on an earlier run", x));
+            x
+        }
+        (None, None) if cfg.symmetric => return r.ok("No gpg_id needed for symmetric encryption"),
+        (None, None) => match keys::default_key(home, &cfg.gpg_bin) {
+            Ok(x) => return r.warn(&format!("No gpg_id set; the next run will offer {}",
+                                            keys::describe(&x)),
+                                   "Confirm it then, or set 'gpg_id'."),
+            Err(e) => return r.fail(&format!("No gpg_id set, and no default key: {}", e),
+                                    "Set 'gpg_id', or create a key with `gpg --full-generate-key`."),
+        },
+    };
+    let key = match ctx.get_key(gpg_id.as_str()) {
+        Ok(x) => x,
+        Err(e) => return r.fail(&format!("GPG key '{}' not found: {}", gpg_id, e),
+                                "Import the key, or fix 'gpg_id' (see `gpg --list-keys`)."),
+    };
+    if key.is_revoked() || key.is_expired() || key.is_disabled() || key.is_invalid() {
+        return r.fail(&format!("GPG key '{}' is revoked, expired, disabled or invalid", gpg_id),
+                      "Renew the key or configure a different 'gpg_id'.");
+    }
+    if ! cfg.symmetric && ! key.can_encrypt() {
+        return r.fail(&format!("GPG key '{}' can't encrypt", gpg_id),
+                      "Add an encryption subkey or use a different key.");
+    }
+    if ! cfg.symmetric && ctx.get_secret_key(gpg_id.as_str()).is_err() {
+        return r.fail(&format!("No secret key for '{}' on this machine", gpg_id),
+                      "Import the secret key, or pulls from this machine can't be decrypted.");
+    }
+    r.ok(&format!("GPG key '{}' is usable", gpg_id));
+}
+
+/// Check that the merge tool is installed and report its version.
+fn check_merge_tool(cfg: &Config, r: &mut Report) {
+    let (name, bin, flag) = match &cfg.merge_tool {
+        MergeTool::Unison => ("unison", cfg.unison_bin.as_deref().unwrap_or("unison"), "-version"),
+        MergeTool::Rsync => ("rsync", "rsync", "--version"),
+        // Built in, or whatever the command runs is up to the user.
+        MergeTool::ThreeWay | MergeTool::Command(_) => return,
+    };
+    let out = match Command::new(bin).arg(flag).output() {
+        Ok(x) => x,
+        Err(e) if name == "unison" && cfg.unison_bin.is_some() =>
+            return r.fail(&format!("unison_path '{}' can't be run: {}", bin, e),
+                          "Point 'unison_path' at a working unison binary, or remove it."),
+        Err(_) => return r.fail(&format!("{} not found on PATH", name),
+                                &format!("Install {}; it's needed to sync (but not \
+                                          --push/--pull).", name)),
+    };
+    // "unison version 2.53.3" and "rsync  version 3.2.7  protocol ...".
+    let text = String::from_utf8_lossy(&out.stdout);
+    match text.split_whitespace().nth(2) {
+        Some(v) => r.ok(&format!("{} {} found", name, v)),
+        None => r.warn(&format!("{} found, but its version couldn't be read", name),
+                       &format!("Check that `{} {}` works.", bin, flag)),
+    }
+}
+
+/// Check the local sync and temporary folders.
+fn check_local(cfg: &Config, r: &mut Report) {
+    for f in &cfg.folders {
+        if f.dir.is_dir() {
+            r.ok(&format!("Sync folder {} exists", f.dir.display()));
+        } else {
+            r.warn(&format!("Sync folder {} doesn't exist", f.dir.display()),
+                   "Run `mist <profile> --pull` to fetch it, or create it and --push.");
+        }
+        match f.temp.parent() {
+            Some(x) if x.is_dir() => r.ok(&format!("Temporary folder parent {} exists", x.display())),
+            _ => r.fail(&format!("Can't create temporary folder {}", f.temp.display()),
+                        "Point 'temp_folder' somewhere whose parent directory exists."),
+        }
+    }
+}
+
+/// Check that the remote host is reachable and its working directory writable.
+async fn check_remote(cfg: &Config, r: &mut Report) {
+    let mut s = match remote::connect(cfg).await {
+        Ok(x) => x,
+        Err(e) => return r.fail(&format!("Can't connect to {}: {}", cfg.address(), e),
+                                "Check 'ssh_address', and that `ssh <address>` works without prompting."),
+    };
+    r.ok(&format!("Connected to {}", cfg.address()));
+    let probe = format!("{}.doctor", cfg.folders[0].tar);
+    let written = match s.write(&probe, b"").await {
+        Ok(true) => s.remove(std::slice::from_ref(&probe)).await.is_ok(),
+        _ => false,
+    };
+    match written {
+        true  => r.ok("Remote directory is writable"),
+        false => r.fail("Remote directory isn't writable",
+                        "Check that the remote directory ('remote_path', or the home \
+                         directory) exists, and its permissions and free space."),
+    }
+    let _ = s.close().await;
+}
+
+/// Suggest tuning based on the profile's local stats, if it keeps any.
+fn check_stats(home: &Path, profile: &str, cfg: &Config, r: &mut Report) {
+    if ! cfg.stats {
+        return
+    }
+    let stats = match Stats::load(&stats_path(home, profile)) {
+        Ok(x) if x.runs > 0 => x,
+        _ => return,
+    };
+    r.ok(&format!("{} runs recorded, {} failed, {:.1}s on average",
+                  stats.runs, stats.failures, stats.average_seconds()));
+    if stats.failure_streak > 0 {
+        r.warn(&format!("The last {} run(s) failed", stats.failure_streak),
+               "Run `mist <profile>` interactively to see the error.");
+    }
+    let slowest = stats.phase_seconds
+        .keys()
+        .filter_map(|k| Some((k.as_str(), stats.average_phase_seconds(k)?)))
+        .fold(None, |acc: Option<(&str, f64)>, x| match acc {
+            Some(a) if a.1 >= x.1 => Some(a),
+            _ => Some(x),
+        });
+    match slowest {
+        Some(("hash", _)) if matches!(cfg.hash, HashAlgorithm::Sha256 | HashAlgorithm::Blake3) =>
+            r.warn("Hashing dominates run time",
+                   "Set 'hash = \"xxh3\"' for faster change detection."),
+        Some(("upload", _)) | Some(("download", _)) =>
+            r.warn("Transfers dominate run time",
+                   "The whole archive moves on every sync; keep large, rarely changed files elsewhere."),
+        _ => (),
+    }
+}
+
+/// Check a profile's configuration and environment end to end.
+pub async fn run(home: &Path, explicit: Option<&Path>, profile: &str)
+-> Result<(), Box<dyn std::error::Error>> {
+    let mut r = Report { problems: 0 };
+
+    let path = match find_config(home, explicit) {
+        Ok(x) => x,
+        Err(e) => {
+            r.fail(&e.to_string(), "Create ~/.config/mist/mist.toml (see examples/mist.toml).");
+            return Err("doctor found problems".into())
+        }
+    };
+    match profile_names(&path) {
+        Ok(x) if x.iter().any(|x| x == profile) =>
+            r.ok(&format!("Configuration file {} parses", path.display())),
+        Ok(_) => r.fail(&format!("Profile [{}] not found in {}", profile, path.display()),
+                        "Add a [profile] section to mist.toml (see examples/mist.toml)."),
+        Err(e) => r.fail(&format!("Configuration file can't be read: {}", e),
+                         "Fix the file's TOML syntax."),
+    }
+    if r.problems > 0 {
+        return Err("doctor found problems".into())
+    }
+
+    let cfg = match load_configuration(&path, profile).await {
+        Ok(x) => x,
+        Err(e) => {
+            r.fail(&format!("Profile [{}] is invalid: {}", profile, e),
+                   "Fix the profile's entries (see examples/mist.toml).");
+            return Err("doctor found problems".into())
+        }
+    };
+    r.ok(&format!("Profile [{}] is valid", profile));
+
+    check_local(&cfg, &mut r);
+    check_passphrase(profile, &cfg, &mut r);
+    #[cfg(feature = "gpg")]
+    if cfg.encryption == crate::crypto::Encryption::Gpg {
+        check_gpg(home, profile, &cfg, &mut r);
+    }
+    check_merge_tool(&cfg, &mut r);
+    check_remote(&cfg, &mut r).await;
+    check_stats(home, profile, &cfg, &mut r);
+
+    match r.problems {
+        0 => {
+            println!("\nNo problems found.");
+            Ok(())
+        }
+        n => Err(format!("doctor found {} problem(s)", n).into()),
+    }
+}
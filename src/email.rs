@@ -0,0 +1,134 @@
+#[cfg(feature = "email")]
+use std::fs::{ create_dir_all, read_to_string, write };
+use std::path::Path;
+#[cfg(feature = "email")]
+use std::path::PathBuf;
+#[cfg(feature = "email")]
+use std::time::Duration;
+
+#[cfg(feature = "email")]
+use lettre::{ Message, SmtpTransport, Transport };
+#[cfg(feature = "email")]
+use lettre::message::header::ContentType;
+#[cfg(feature = "email")]
+use lettre::transport::smtp::authentication::Credentials;
+
+use crate::config::Config;
+use crate::summary::Summary;
+#[cfg(feature = "email")]
+use crate::secrets::run_command;
+#[cfg(feature = "email")]
+use crate::state::{ hostname, profile_dir };
+#[cfg(feature = "email")]
+use crate::summary::Outcome;
+
+/// Where to send a message when a profile keeps failing, and through
+/// which SMTP server.
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    /// The sender; `to` if not set.
+    pub from: Option<String>,
+    pub smtp_host: String,
+    /// 465 means TLS from the start; any other port, STARTTLS.
+    pub smtp_port: u16,
+    pub smtp_user: Option<String>,
+    /// Prints the password for `smtp_user`.
+    pub smtp_password_command: Option<String>,
+    /// How many runs in a row have to fail before a message is sent.
+    pub after: u64,
+}
+
+/// The port used when `smtp_port` isn't set.
+pub const SMTP_PORT: u16 = 587;
+
+/// How long to wait for the SMTP server.
+#[cfg(feature = "email")]
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The file counting a profile's failed runs in a row.
+#[cfg(feature = "email")]
+fn streak_path(home: &Path, profile: &str) -> PathBuf {
+    profile_dir(home, profile).join("failure_streak")
+}
+
+/// Send a message through the profile's SMTP server.
+#[cfg(feature = "email")]
+fn deliver(email: &Email, subject: &str, body: String) -> Result<(), Box<dyn std::error::Error>> {
+    let from = email.from.as_deref().unwrap_or(&email.to);
+    let message = Message::builder()
+        .from(from.parse()?)
+        .to(email.to.parse()?)
+        .subject(subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(body)?;
+    let relay = match email.smtp_port {
+        465 => SmtpTransport::relay(&email.smtp_host)?,
+        _ => SmtpTransport::starttls_relay(&email.smtp_host)?,
+    };
+    let mut relay = relay.port(email.smtp_port).timeout(Some(SMTP_TIMEOUT));
+    if let Some(user) = &email.smtp_user {
+        let password = match &email.smtp_password_command {
+            Some(x) => run_command(x)?,
+            None => String::new(),
+        };
+        relay = relay.credentials(Credentials::new(user.clone(), password));
+    }
+    relay.build().send(&message)?;
+    Ok(())
+}
+
+/// Count the run towards the profile's failures in a row, and when there
+/// are the configured number of them, email about the latest. There's one
+/// message for each run of failures, which any other outcome ends.
+/// Problems are recorded as warnings.
+#[cfg(feature = "email")]
+pub fn send(home: &Path, cfg: &Config, summary: &mut Summary) {
+    let email = match &cfg.email {
+        Some(x) => x,
+        None => return,
+    };
+    let path = streak_path(home, &summary.profile);
+    let streak = match &summary.outcome {
+        Outcome::Failed(_) => read_to_string(&path)
+            .ok()
+            .and_then(|x| x.trim().parse::<u64>().ok())
+            .unwrap_or(0) + 1,
+        _ => 0,
+    };
+    let saved = path.parent().map_or(Ok(()), create_dir_all)
+        .and_then(|_| write(&path, format!("{}\n", streak)));
+    if let Err(e) = saved {
+        summary.warn(format!("Recording failures in {}: {}", path.display(), e));
+    }
+    if streak != email.after {
+        return
+    }
+
+    let host = hostname();
+    let subject = format!("mist [{}] on {}: {} failed runs in a row", summary.profile, host,
+                          streak);
+    let mut body = format!("The last {} runs of mist profile [{}] on {} failed.\n\n\
+                            Latest run: {} ({}), {}\n",
+                           streak, summary.profile, host, summary.direction, summary.sync_id,
+                           cfg.address());
+    if let Outcome::Failed(e) = &summary.outcome {
+        body.push_str(&format!("Error: {}\n", e));
+    }
+    if ! summary.warnings.is_empty() {
+        body.push_str("\nWarnings:\n");
+        for w in &summary.warnings {
+            body.push_str(&format!("  {}\n", w));
+        }
+    }
+    body.push_str("\nNo more messages are sent until a run succeeds and the failures start \
+                   again.\n");
+    if let Err(e) = deliver(email, &subject, body) {
+        summary.warn(format!("Emailing {} about the failures: {}", email.to, e));
+    }
+}
+
+/// Built without email; the configuration refuses its settings, so there's
+/// nothing to do.
+#[cfg(not(feature = "email"))]
+pub fn send(_home: &Path, _cfg: &Config, _summary: &mut Summary) {}
@@ -0,0 +1,125 @@
+use crate::chunks::Storage;
+
+/// Whether pushes compress the archive (or each chunk).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    /// For folders of data that's compressed already, such as photos,
+    /// where gzip takes a long time for next to nothing.
+    None,
+    /// Gzip, but files whose type says they're compressed already are
+    /// stored as they are. See `archive::precompressed`.
+    Auto,
+}
+
+impl Compression {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "gzip" => Some(Compression::Gzip),
+            "none" => Some(Compression::None),
+            "auto" => Some(Compression::Auto),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::None => "none",
+            Compression::Auto => "auto",
+        }
+    }
+
+    /// Whether there's gzip to undo, whether or not every part of it is
+    /// actually compressed.
+    pub fn is_gzip(&self) -> bool {
+        *self != Compression::None
+    }
+}
+
+/// What the archive (or each chunk, and the chunk index) is encrypted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    /// gpg, to a key.
+    Gpg,
+    /// gpg, with a passphrase.
+    GpgSymmetric,
+    /// Mist's own passphrase encryption (see `crypto::Encryption`).
+    Builtin,
+}
+
+impl Cipher {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "gpg"           => Some(Cipher::Gpg),
+            "gpg-symmetric" => Some(Cipher::GpgSymmetric),
+            "builtin"       => Some(Cipher::Builtin),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Cipher::Gpg          => "gpg",
+            Cipher::GpgSymmetric => "gpg-symmetric",
+            Cipher::Builtin      => "builtin",
+        }
+    }
+}
+
+/// How a folder's remote archive was written, stored beside it as a small
+/// plaintext file so pulls decode it correctly after the profile's
+/// settings change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    pub storage: Storage,
+    /// How the archive (or each chunk) is compressed.
+    pub compression: Compression,
+    pub cipher: Cipher,
+    pub armor: bool,
+}
+
+/// The only version written so far. Files of a later version are refused
+/// rather than misread.
+const VERSION: u32 = 1;
+
+impl Format {
+    /// What archives written before the format file existed look like.
+    pub fn legacy(storage: Storage) -> Self {
+        Format { storage, compression: Compression::Gzip, cipher: Cipher::Gpg, armor: true }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let storage = match self.storage {
+            Storage::Archive => "archive",
+            Storage::Chunks  => "chunks",
+        };
+        format!("mist-format {}\nstorage {}\ncompression {}\ncipher {}\narmor {}\n",
+                VERSION, storage, self.compression.name(), self.cipher.name(), self.armor).into_bytes()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let text = std::str::from_utf8(bytes).map_err(|_| "not text".to_string())?;
+        let mut lines = text.lines();
+        match lines.next().and_then(|x| x.strip_prefix("mist-format ")) {
+            Some(x) if x.parse() == Ok(VERSION) => (),
+            Some(x) => return Err(format!("version {} is from a newer mist", x)),
+            None => return Err("missing header".to_string()),
+        }
+        let mut f = Format::legacy(Storage::Archive);
+        for line in lines {
+            let (key, value) = line.split_once(' ').ok_or(format!("bad line '{}'", line))?;
+            match (key, value) {
+                ("storage", x) => f.storage = Storage::parse(x)
+                    .ok_or(format!("unknown storage '{}'", x))?,
+                ("compression", x) => f.compression = Compression::parse(x)
+                    .ok_or(format!("unknown compression '{}'", x))?,
+                ("cipher", x) => f.cipher = Cipher::parse(x)
+                    .ok_or(format!("unknown cipher '{}'", x))?,
+                ("armor", x) => f.armor = x == "true",
+                (k, v) => return Err(format!("unknown {} '{}'", k, v)),
+            }
+        }
+        Ok(f)
+    }
+}
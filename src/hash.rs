@@ -0,0 +1,450 @@
+use std::collections::{ HashMap, HashSet };
+use std::fs::{ File, Metadata, create_dir_all, read_link, read_to_string, write };
+use std::hash::{ Hash, Hasher };
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::{ Path, PathBuf };
+use std::time::UNIX_EPOCH;
+
+use rayon::prelude::*;
+use sha2::{ Digest, Sha256 };
+use twox_hash::{ XxHash64, Xxh3Hash64 };
+use walkdir::DirEntry;
+
+use crate::spill::Buffer;
+use crate::archive::{ Exclude, Symlinks };
+
+/// Seed shared by the xxhash-based algorithms, for compatibility with
+/// hash files written by earlier versions.
+const SEED: u64 = 42;
+
+/// A hash function that can summarize a sync folder's metadata.
+pub trait DigestHasher {
+    fn update(&mut self, bytes: &[u8]);
+    fn digest(&self) -> Vec<u8>;
+}
+
+impl DigestHasher for XxHash64 {
+    fn update(&mut self, bytes: &[u8]) {
+        Hasher::write(self, bytes);
+    }
+    fn digest(&self) -> Vec<u8> {
+        Hasher::finish(self).to_be_bytes().to_vec()
+    }
+}
+
+impl DigestHasher for Xxh3Hash64 {
+    fn update(&mut self, bytes: &[u8]) {
+        Hasher::write(self, bytes);
+    }
+    fn digest(&self) -> Vec<u8> {
+        Hasher::finish(self).to_be_bytes().to_vec()
+    }
+}
+
+impl DigestHasher for blake3::Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        blake3::Hasher::update(self, bytes);
+    }
+    fn digest(&self) -> Vec<u8> {
+        self.finalize().as_bytes().to_vec()
+    }
+}
+
+impl DigestHasher for Sha256 {
+    fn update(&mut self, bytes: &[u8]) {
+        Digest::update(self, bytes);
+    }
+    fn digest(&self) -> Vec<u8> {
+        self.clone().finalize().to_vec()
+    }
+}
+
+/// Records the bytes `std::hash::Hash` values write, so they can be fed to
+/// any `DigestHasher` later and every algorithm sees exactly the bytes
+/// xxhash64 always has. (It has to be a `Hasher` for that: how a path
+/// hashes is up to std.)
+#[derive(Default)]
+struct Record(Vec<u8>);
+
+impl Hasher for Record {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+    /// The recorded bytes' xxhash64, though the digest is taken with
+    /// `DigestHasher::digest`.
+    fn finish(&self) -> u64 {
+        let mut h = XxHash64::with_seed(SEED);
+        h.write(&self.0);
+        h.finish()
+    }
+}
+
+/// The hash algorithms mist knows how to compute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    XxHash64,
+    Xxh3,
+    Blake3,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::XxHash64 => "xxhash64",
+            HashAlgorithm::Xxh3     => "xxh3",
+            HashAlgorithm::Blake3   => "blake3",
+            HashAlgorithm::Sha256   => "sha256",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "xxhash64" => Some(HashAlgorithm::XxHash64),
+            "xxh3"     => Some(HashAlgorithm::Xxh3),
+            "blake3"   => Some(HashAlgorithm::Blake3),
+            "sha256"   => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn hasher(&self) -> Box<dyn DigestHasher> {
+        match self {
+            HashAlgorithm::XxHash64 => Box::new(XxHash64::with_seed(SEED)),
+            HashAlgorithm::Xxh3     => Box::new(Xxh3Hash64::with_seed(SEED)),
+            HashAlgorithm::Blake3   => Box::new(blake3::Hasher::new()),
+            HashAlgorithm::Sha256   => Box::new(Sha256::new()),
+        }
+    }
+}
+
+/// The checksum POSIX `cksum` prints: a CRC-32 (most significant bit
+/// first) of the data followed by its length, complemented. Comparing it
+/// with `cksum` run remotely checks an upload without reading it back.
+pub fn cksum(bytes: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (i, v) in table.iter_mut().enumerate() {
+        let mut c = (i as u32) << 24;
+        for _ in 0..8 {
+            c = if c & 0x8000_0000 != 0 { (c << 1) ^ 0x04c1_1db7 } else { c << 1 };
+        }
+        *v = c;
+    }
+    let update = |crc: u32, b: u8| (crc << 8) ^ table[((crc >> 24) as u8 ^ b) as usize];
+    let mut crc = bytes.iter().fold(0, |c, b| update(c, *b));
+    let mut n = bytes.len();
+    while n > 0 {
+        crc = update(crc, n as u8);
+        n >>= 8;
+    }
+    ! crc
+}
+
+/// Lowercase hex encoding of some bytes.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode a non-empty hex string.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.is_empty() || hex.len() % 2 == 1 || ! hex.is_ascii() {
+        return None
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// What change detection compares for each file, besides its name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Detect {
+    /// Size only; what earlier versions compared.
+    Size,
+    /// Size and modification time (to the second).
+    Mtime,
+    /// Size and a hash of the contents.
+    Content,
+}
+
+impl Detect {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Detect::Size    => "size",
+            Detect::Mtime   => "mtime",
+            Detect::Content => "content",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "size"    => Some(Detect::Size),
+            "mtime"   => Some(Detect::Mtime),
+            "content" => Some(Detect::Content),
+            _ => None,
+        }
+    }
+}
+
+/// The contents of the remote hash file: how the digest was produced, and
+/// the digest itself. Stored as `<algorithm>:<detect>:<hex digest>`, or as
+/// `<algorithm>:<hex digest>` for size-only detection, optionally followed
+/// by a line `archive <hex>` with the checksum of the plaintext archive,
+/// then lines `stored <hex> <path>` with the SHA-256 of each file the
+/// encrypted archive was uploaded as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Marker {
+    pub algorithm: HashAlgorithm,
+    pub detect: Detect,
+    pub digest: Vec<u8>,
+    /// `archive_sum` of the archive as it was before encryption.
+    pub archive: Option<Vec<u8>>,
+    /// The remote files holding the encrypted archive and their SHA-256,
+    /// for checking them where they're stored; empty for chunks.
+    pub stored: Vec<(String, Vec<u8>)>,
+}
+
+impl Marker {
+    pub fn encode(&self) -> Vec<u8> {
+        let hex = to_hex(&self.digest);
+        let mut s = match self.detect {
+            Detect::Size => format!("{}:{}\n", self.algorithm.name(), hex),
+            x => format!("{}:{}:{}\n", self.algorithm.name(), x.name(), hex),
+        };
+        if let Some(x) = &self.archive {
+            s.push_str(&format!("archive {}\n", to_hex(x)));
+        }
+        for (path, sum) in &self.stored {
+            s.push_str(&format!("stored {} {}\n", to_hex(sum), path));
+        }
+        s.into_bytes()
+    }
+
+    /// Parse a hash file. Earlier versions wrote a bare 8-byte big-endian
+    /// xxhash64, which is still accepted.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if let Some(x) = Self::decode_text(bytes) {
+            return Some(x)
+        }
+        if bytes.len() == 8 {
+            return Some(Marker {
+                algorithm: HashAlgorithm::XxHash64,
+                detect: Detect::Size,
+                digest: bytes.to_vec(),
+                archive: None,
+                stored: Vec::new(),
+            })
+        }
+        None
+    }
+
+    /// Lines after the first that this version doesn't know are skipped.
+    fn decode_text(bytes: &[u8]) -> Option<Self> {
+        let mut lines = std::str::from_utf8(bytes).ok()?.trim().lines();
+        let (name, rest) = lines.next()?.split_once(':')?;
+        let algorithm = HashAlgorithm::parse(name)?;
+        let (detect, hex) = match rest.split_once(':') {
+            Some((x, hex)) => (Detect::parse(x)?, hex),
+            None => (Detect::Size, rest),
+        };
+        let digest = from_hex(hex)?;
+        let mut marker = Marker { algorithm, detect, digest, archive: None, stored: Vec::new() };
+        for line in lines {
+            if let Some(x) = line.strip_prefix("archive ") {
+                marker.archive = Some(from_hex(x)?);
+            } else if let Some((sum, path)) = line.strip_prefix("stored ").and_then(|x| x.split_once(' ')) {
+                marker.stored.push((path.to_string(), from_hex(sum)?));
+            }
+        }
+        Some(marker)
+    }
+}
+
+/// The checksum of a plaintext archive recorded in the hash file, which
+/// is checked after decryption and before anything is unpacked.
+pub fn archive_sum(tar: &Buffer) -> std::io::Result<Vec<u8>> {
+    let mut h = blake3::Hasher::new();
+    std::io::copy(&mut tar.reader(), &mut h)?;
+    Ok(h.finalize().as_bytes().to_vec())
+}
+
+/// A file's size and modification time in nanoseconds, which must both be
+/// unchanged for a cached content hash to be reused.
+type Stamp = (u64, u128);
+
+/// A file's device and inode numbers, which stay the same when it's
+/// renamed within a file system.
+type Inode = (u64, u64);
+
+/// A file's content hash, and the stamp and inode it was computed at.
+type Cached = (Stamp, Inode, Vec<u8>);
+
+fn stamp(meta: &Metadata) -> Option<Stamp> {
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((meta.len(), mtime.as_nanos()))
+}
+
+/// Content hashes of files from earlier scans, so files that haven't
+/// changed needn't be read again, nor files that were only renamed. Kept
+/// in the state directory, one file per algorithm, as lines of
+/// `<size> <mtime> <device> <inode> <hex digest> <path>`.
+#[derive(Default)]
+struct HashCache {
+    entries: HashMap<PathBuf, Cached>,
+    /// Where each inode was when it was hashed.
+    paths: HashMap<Inode, PathBuf>,
+}
+
+impl HashCache {
+    fn path(dir: &Path, algorithm: HashAlgorithm) -> PathBuf {
+        dir.join(format!("hashes-{}.cache", algorithm.name()))
+    }
+
+    /// Load a cache file; a missing or damaged one just means an empty cache.
+    fn load(path: &Path) -> Self {
+        let text = read_to_string(path).unwrap_or_default();
+        let entries: HashMap<PathBuf, Cached> = text
+            .lines()
+            .filter_map(|x| {
+                let mut f = x.splitn(6, ' ');
+                let len = f.next()?.parse().ok()?;
+                let mtime = f.next()?.parse().ok()?;
+                let dev = f.next()?.parse().ok()?;
+                let ino = f.next()?.parse().ok()?;
+                let digest = from_hex(f.next()?)?;
+                Some((PathBuf::from(f.next()?), ((len, mtime), (dev, ino), digest)))
+            })
+            .collect();
+        let paths = entries
+            .iter()
+            .map(|(p, (_, inode, _))| (*inode, p.clone()))
+            .collect();
+        HashCache { entries, paths }
+    }
+
+    /// The hash of a file that was hashed at another path, with the same
+    /// stamp and inode: one that's been renamed since.
+    fn moved(&self, stamp: Stamp, inode: Inode) -> Option<&Vec<u8>> {
+        self.paths
+            .get(&inode)
+            .and_then(|p| self.entries.get(p))
+            .filter(|(x, i, _)| *x == stamp && *i == inode)
+            .map(|(_, _, digest)| digest)
+    }
+
+    fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut text = String::new();
+        for (p, ((len, mtime), (dev, ino), digest)) in &self.entries {
+            // A newline in the path would break the line format.
+            if let Some(p) = p.to_str().filter(|x| ! x.contains('\n')) {
+                text.push_str(&format!("{} {} {} {} {} {}\n", len, mtime, dev, ino, to_hex(digest),
+                                       p));
+            }
+        }
+        if let Some(x) = path.parent() {
+            create_dir_all(x)?;
+        }
+        write(path, text)
+    }
+}
+
+/// Hash a file's contents.
+fn hash_contents(path: &Path, algorithm: HashAlgorithm) -> Option<Vec<u8>> {
+    let mut hasher = algorithm.hasher();
+    let mut f = File::open(path).ok()?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match f.read(&mut buf).ok()? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Some(hasher.digest())
+}
+
+/// What one entry contributes to a folder's digest: a file's name, size
+/// and, depending on `detect`, modification time or content hash; a
+/// preserved link's name and target; and nothing for the rest. Also
+/// returns a file's freshly computed content hash, for the cache.
+fn record(e: &DirEntry, algorithm: HashAlgorithm, symlinks: Symlinks, detect: Detect,
+          cache: &HashCache)
+-> Option<(Vec<u8>, Option<Cached>)> {
+    let mut r = Record::default();
+    let mut fresh = None;
+    if e.path_is_symlink() && symlinks == Symlinks::Preserve {
+        e.path().file_name()?.hash(&mut r);
+        read_link(e.path()).ok()?.hash(&mut r);
+    } else if e.file_type().is_file() {
+        let meta = e.metadata().ok()?;
+        e.path().file_name()?.hash(&mut r);
+        meta.len().hash(&mut r);
+        match detect {
+            Detect::Size => (),
+            Detect::Mtime => {
+                // Whole seconds, since that's all older archives keep.
+                (stamp(&meta)?.1 / 1_000_000_000).hash(&mut r);
+            }
+            Detect::Content => {
+                let stamp = stamp(&meta)?;
+                let inode = (meta.dev(), meta.ino());
+                let digest = match cache.entries.get(e.path()) {
+                    Some((x, _, digest)) if *x == stamp => digest.clone(),
+                    _ => {
+                        // A renamed file is found by its inode, and cached
+                        // again under its new path.
+                        let digest = match cache.moved(stamp, inode) {
+                            Some(x) => x.clone(),
+                            None => hash_contents(e.path(), algorithm)?,
+                        };
+                        fresh = Some((stamp, inode, digest.clone()));
+                        digest
+                    }
+                };
+                r.write(&digest);
+            }
+        }
+    }
+    Some((r.0, fresh))
+}
+
+/// Hash the metadata of the contents of a directory (see `record`). With
+/// content detection, file hashes are cached in `cache_dir`, if given.
+///
+/// Entries are looked up in parallel, then fed to the hasher in the order
+/// of the walk, so the digest doesn't depend on how the work was split.
+pub async fn hash_metadata(path: &Path, algorithm: HashAlgorithm, symlinks: Symlinks,
+                           exclude: &Exclude, detect: Detect, cache_dir: Option<&Path>)
+-> Option<Vec<u8>> {
+    let cache_path = cache_dir.map(|x| HashCache::path(x, algorithm));
+    let mut cache = match (&cache_path, detect) {
+        (Some(x), Detect::Content) => HashCache::load(x),
+        _ => HashCache::default(),
+    };
+    let entries: Vec<DirEntry> = symlinks.walk(path, exclude).filter_map(|e| e.ok()).collect();
+    let records: Vec<Option<(Vec<u8>, Option<Cached>)>> = entries
+        .par_iter()
+        .map(|e| record(e, algorithm, symlinks, detect, &cache))
+        .collect();
+    let mut hasher = algorithm.hasher();
+    let mut fresh = Vec::new();
+    for (e, r) in entries.iter().zip(records) {
+        let (r, x) = r?;
+        hasher.update(&r);
+        if let Some(x) = x {
+            fresh.push((e.path().to_path_buf(), x));
+        }
+    }
+    if let (Some(cache_path), Detect::Content) = (cache_path, detect) {
+        // Forget files under this folder that are gone.
+        let before = cache.entries.len();
+        let seen: HashSet<&Path> = entries.iter().map(|e| e.path()).collect();
+        cache.entries.retain(|p, _| ! p.starts_with(path) || seen.contains(p.as_path()));
+        if ! fresh.is_empty() || cache.entries.len() != before {
+            cache.entries.extend(fresh);
+            // Not having a cache only costs time.
+            let _ = cache.save(&cache_path);
+        }
+    }
+    Some(hasher.digest())
+}
@@ -0,0 +1,69 @@
+#[cfg(feature = "healthcheck")]
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::summary::Summary;
+#[cfg(feature = "healthcheck")]
+use crate::summary::Outcome;
+
+/// How long to wait for the healthcheck server.
+#[cfg(feature = "healthcheck")]
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ping `<healthcheck_url><suffix>`, with the run's ID so the server can
+/// pair the start with the end, and `body` for it to keep.
+#[cfg(feature = "healthcheck")]
+async fn ping(url: &str, suffix: &str, summary: &Summary, body: String)
+-> Result<(), Box<dyn std::error::Error>> {
+    let client = reqwest::Client::builder().timeout(PING_TIMEOUT).build()?;
+    let res = client.post(format!("{}{}?rid={}", url, suffix, summary.sync_id))
+        .header("Content-Type", "text/plain")
+        .body(body)
+        .send()
+        .await?;
+    match res.status().is_success() {
+        true  => Ok(()),
+        false => Err(format!("the server answered {}", res.status()).into()),
+    }
+}
+
+/// Tell the profile's healthcheck that a run has started, so it can time
+/// it and notice one that never ends. Problems are recorded as warnings.
+#[cfg(feature = "healthcheck")]
+pub async fn start(cfg: &Config, summary: &mut Summary) {
+    let url = match &cfg.healthcheck_url {
+        Some(x) => x,
+        None => return,
+    };
+    if let Err(e) = ping(url, "/start", summary, String::new()).await {
+        summary.warn(format!("Healthcheck {}: {}", url, e));
+    }
+}
+
+/// Tell the profile's healthcheck how the run ended: the URL itself if it
+/// completed or was already up to date, `/fail` otherwise. The summary goes
+/// along for the check's log. Problems are recorded as warnings.
+#[cfg(feature = "healthcheck")]
+pub async fn finish(cfg: &Config, summary: &mut Summary) {
+    let url = match &cfg.healthcheck_url {
+        Some(x) => x,
+        None => return,
+    };
+    let suffix = match summary.outcome {
+        Outcome::Completed | Outcome::UpToDate => "",
+        _ => "/fail",
+    };
+    let body = format!("mist [{}]: {}, {}\n{}", summary.profile, summary.direction,
+                       summary.outcome, summary.to_json());
+    if let Err(e) = ping(url, suffix, summary, body).await {
+        summary.warn(format!("Healthcheck {}: {}", url, e));
+    }
+}
+
+/// Built without healthchecks; the configuration refuses `healthcheck_url`,
+/// so there's nothing to do.
+#[cfg(not(feature = "healthcheck"))]
+pub async fn start(_cfg: &Config, _summary: &mut Summary) {}
+
+#[cfg(not(feature = "healthcheck"))]
+pub async fn finish(_cfg: &Config, _summary: &mut Summary) {}
@@ -0,0 +1,61 @@
+use std::process::{ Command, ExitStatus };
+
+use crate::config::Config;
+use crate::summary::{ Outcome, Summary };
+
+/// Run a hook command through `sh -c`, exposing details of the run in
+/// `MIST_*` environment variables.
+fn run_hook(cmd: &str, cfg: &Config, summary: &Summary)
+-> std::io::Result<ExitStatus> {
+    let dirs = std::env::join_paths(cfg.folders.iter().map(|x| &x.dir))
+        .unwrap_or_default();
+    let mut c = Command::new("sh");
+    c.arg("-c")
+        .arg(cmd)
+        .env("MIST_PROFILE", &summary.profile)
+        .env("MIST_DIRECTION", summary.direction.name())
+        .env("MIST_DIR", dirs)
+        .env("MIST_REMOTE", cfg.address())
+        .env("MIST_RESULT", summary.outcome.name())
+        .env("MIST_FILES_CHANGED", summary.files_changed.to_string())
+        .env("MIST_BYTES_UP", summary.bytes_up.to_string())
+        .env("MIST_BYTES_DOWN", summary.bytes_down.to_string())
+        .env("MIST_WARNINGS", summary.warnings.len().to_string());
+    if let Outcome::Failed(e) = &summary.outcome {
+        c.env("MIST_ERROR", e);
+    }
+    c.status()
+}
+
+/// Run the profile's `pre_sync` hook, if any. A hook that fails aborts the run.
+pub fn pre_sync(cfg: &Config, summary: &Summary)
+-> Result<(), Box<dyn std::error::Error>> {
+    let cmd = match &cfg.pre_sync {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let status = run_hook(cmd, cfg, summary)
+        .map_err(|e| format!("pre_sync hook: {}", e))?;
+    if ! status.success() {
+        return Err(format!("pre_sync hook failed ({})", status).into())
+    }
+    Ok(())
+}
+
+/// Run the profile's `on_failure` hook if the run failed, or its `post_sync`
+/// hook otherwise. Problems running the hook are recorded as warnings.
+pub fn finish(cfg: &Config, summary: &mut Summary) {
+    let (name, cmd) = match summary.outcome {
+        Outcome::Failed(_) => ("on_failure", &cfg.on_failure),
+        _ => ("post_sync", &cfg.post_sync),
+    };
+    let cmd = match cmd {
+        Some(x) => x,
+        None => return,
+    };
+    match run_hook(cmd, cfg, summary) {
+        Ok(x) if x.success() => (),
+        Ok(x) => summary.warn(format!("{} hook failed ({})", name, x)),
+        Err(e) => summary.warn(format!("{} hook: {}", name, e)),
+    }
+}
@@ -0,0 +1,275 @@
+/// The host and port a URL's server is at.
+pub fn host_and_port(url: &str) -> Option<(String, u16)> {
+    let (scheme, rest) = url.split_once("://")?;
+    let authority = rest.split('/').next()?;
+    let authority = authority.rsplit_once('@').map_or(authority, |(_, x)| x);
+    let default = match scheme {
+        "http"  => 80,
+        "https" => 443,
+        _ => return None,
+    };
+    let (host, port) = match authority.strip_prefix('[') {
+        // An IPv6 address, like [::1]:8080.
+        Some(x) => {
+            let (host, rest) = x.split_once(']')?;
+            (host, rest.strip_prefix(':'))
+        }
+        None => match authority.rsplit_once(':') {
+            Some((h, p)) => (h, Some(p)),
+            None => (authority, None),
+        },
+    };
+    let port = match port {
+        Some(x) => x.parse().ok()?,
+        None => default,
+    };
+    Some((host.to_string(), port))
+}
+
+/// Whether `http_url` is one this client can use.
+pub fn valid_url(url: &str) -> bool {
+    host_and_port(url).is_some_and(|(h, _)| ! h.is_empty())
+}
+
+#[cfg(feature = "http")]
+pub use client::Client;
+
+#[cfg(feature = "http")]
+mod client {
+    use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+    use chrono::{ Local, TimeZone };
+    use reqwest::{ RequestBuilder, Response, StatusCode };
+    use reqwest::header::CONTENT_LENGTH;
+
+    use crate::remote::{ Entry, RemoteStore };
+
+    /// A remote over HTTP(S): any server that stores files under a base URL
+    /// and answers, with `Authorization: Bearer <token>` if the profile has
+    /// one:
+    ///
+    ///   GET <path>       the file
+    ///   PUT <path>       store the body as the file, replacing it whole
+    ///   HEAD <path>      200 with Content-Length, or 404
+    ///   DELETE <path>    remove the file, or a directory and all it holds;
+    ///                    a missing one is no error
+    ///   GET <dir>/       one line per entry, "<size> <mtime> <name>", with
+    ///                    the time in seconds since 1970 and directories'
+    ///                    names ending in "/"; 404 if there's no such directory
+    ///
+    /// Directories are created by storing files in them. The listing is only
+    /// needed by `mist list`, `mist gc` and chunk storage; a server without it
+    /// (e.g. a presigned-URL gateway) still works with archive storage.
+    pub struct Client {
+        client: reqwest::Client,
+        base: String,
+        token: Option<String>,
+    }
+
+    /// Percent-encode a path for a URL, leaving its slashes.
+    fn encode(path: &str) -> String {
+        let mut out = String::new();
+        for b in path.bytes() {
+            match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' =>
+                    out.push(b as char),
+                _ => out.push_str(&format!("%{:02X}", b)),
+            }
+        }
+        out
+    }
+
+    impl Client {
+        /// Set up a client for the server at `url`, which is sent `token` with
+        /// each request if there is one.
+        pub fn new(url: &str, token: Option<String>, connect_timeout: Option<Duration>)
+        -> Result<Self, Box<dyn std::error::Error>> {
+            let mut b = reqwest::Client::builder();
+            if let Some(x) = connect_timeout {
+                b = b.connect_timeout(x);
+            }
+            Ok(Client { client: b.build()?, base: url.trim_end_matches('/').to_string(), token })
+        }
+
+        /// The URL of a remote path, which like the paths over SSH may start
+        /// with "./" or "/".
+        fn url(&self, path: &str) -> String {
+            let path = path.trim_start_matches("./").trim_start_matches('/');
+            match path {
+                "" | "." => format!("{}/", self.base),
+                x => format!("{}/{}", self.base, encode(x)),
+            }
+        }
+
+        async fn send(&self, req: RequestBuilder) -> Result<Response, Box<dyn std::error::Error>> {
+            let req = match &self.token {
+                Some(x) => req.bearer_auth(x),
+                None => req,
+            };
+            Ok(req.send().await?)
+        }
+
+        /// Fail with the response's status unless it's a success.
+        fn check(what: &str, path: &str, res: Response)
+        -> Result<Response, Box<dyn std::error::Error>> {
+            match res.status().is_success() {
+                true  => Ok(res),
+                false => Err(format!("Remote: {} {}: {}", what, path, res.status()).into()),
+            }
+        }
+
+        /// The size of a file, or None if there's no such file.
+        async fn size(&self, path: &str) -> Result<Option<u64>, Box<dyn std::error::Error>> {
+            let res = self.send(self.client.head(self.url(path))).await?;
+            if res.status() == StatusCode::NOT_FOUND {
+                return Ok(None)
+            }
+            let res = Client::check("HEAD", path, res)?;
+            let len = res.headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|x| x.to_str().ok())
+                .and_then(|x| x.parse().ok())
+                .ok_or(format!("Remote: HEAD {}: no Content-Length", path))?;
+            Ok(Some(len))
+        }
+
+        /// The entries of a directory with their times, in seconds since 1970.
+        async fn entries(&self, dir: &str) -> Result<Vec<(Entry, u64)>, Box<dyn std::error::Error>> {
+            let url = format!("{}/", self.url(dir).trim_end_matches('/'));
+            let res = self.send(self.client.get(url)).await?;
+            if res.status() == StatusCode::NOT_FOUND {
+                return Ok(Vec::new())
+            }
+            let text = Client::check("GET", dir, res)?.text().await?;
+            Ok(text.lines().filter_map(|line| {
+                let mut fields = line.splitn(3, ' ');
+                let size = fields.next()?.parse().ok()?;
+                let time: u64 = fields.next()?.parse().ok()?;
+                let name = fields.next()?;
+                let date = Local.timestamp_opt(time as i64, 0)
+                    .single()
+                    .map(|x| x.format("%b %e %H:%M").to_string())
+                    .unwrap_or_default();
+                let entry = Entry {
+                    name: name.trim_end_matches('/').to_string(),
+                    dir: name.ends_with('/'),
+                    size,
+                    date,
+                };
+                Some((entry, time))
+            }).collect())
+        }
+    }
+
+    impl RemoteStore for Client {
+        async fn read(&mut self, path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+            let res = self.send(self.client.get(self.url(path))).await?;
+            Ok(Client::check("GET", path, res)?.bytes().await?.to_vec())
+        }
+
+        async fn write(&mut self, path: &str, bytes: &[u8])
+        -> Result<bool, Box<dyn std::error::Error>> {
+            let res = self.send(self.client.put(self.url(path)).body(bytes.to_vec())).await?;
+            Ok(res.status().is_success())
+        }
+
+        /// Only the size can be checked without downloading the file.
+        async fn verify(&mut self, path: &str, bytes: &[u8])
+        -> Result<(), Box<dyn std::error::Error>> {
+            match self.size(path).await? {
+                Some(x) if x == bytes.len() as u64 => Ok(()),
+                Some(x) => Err(format!("Upload of {} is corrupt: sent {} bytes, remote has {}", path,
+                                       bytes.len(), x).into()),
+                None => Err(format!("Upload of {} is missing from the remote", path).into()),
+            }
+        }
+
+        /// The protocol has no way to ask for one.
+        async fn checksum(&mut self, _path: &str)
+        -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+            Ok(None)
+        }
+
+        /// A PUT replaces the file whole, or not at all.
+        fn atomic_writes(&self) -> bool {
+            true
+        }
+
+        /// There's no move, so it's a copy, then removing the original.
+        async fn rename(&mut self, from: &str, to: &str) -> Result<(), Box<dyn std::error::Error>> {
+            let bytes = self.read(from).await?;
+            if ! self.write(to, &bytes).await? {
+                return Err(format!("Remote: PUT {} failed", to).into())
+            }
+            self.remove(&[from.to_string()]).await
+        }
+
+        async fn exists(&mut self, path: &str) -> Result<bool, Box<dyn std::error::Error>> {
+            Ok(self.size(path).await?.is_some())
+        }
+
+        /// Directories are there as soon as something's stored in them.
+        async fn is_dir(&mut self, _path: &str) -> Result<bool, Box<dyn std::error::Error>> {
+            Ok(true)
+        }
+
+        async fn create_dir(&mut self, _path: &str) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(())
+        }
+
+        async fn remove(&mut self, paths: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+            for x in paths {
+                let res = self.send(self.client.delete(self.url(x))).await?;
+                if res.status() != StatusCode::NOT_FOUND {
+                    Client::check("DELETE", x, res)?;
+                }
+            }
+            Ok(())
+        }
+
+        async fn list(&mut self, dir: &str) -> Result<Vec<Entry>, Box<dyn std::error::Error>> {
+            Ok(self.entries(dir).await?.into_iter().map(|(x, _)| x).collect())
+        }
+
+        async fn older_than(&mut self, dir: &str, age: Duration)
+        -> Result<Vec<String>, Box<dyn std::error::Error>> {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            Ok(self.entries(dir)
+                .await?
+                .into_iter()
+                .filter(|(x, time)| ! x.dir && time + age.as_secs() < now)
+                .map(|(x, _)| format!("{}/{}", dir, x.name))
+                .collect())
+        }
+
+        /// Read, append and write back: two machines appending at once may
+        /// lose a line, which for the run log is no great loss.
+        async fn append(&mut self, path: &str, line: &str, keep: usize)
+        -> Result<(), Box<dyn std::error::Error>> {
+            let mut text = match self.exists(path).await? {
+                true  => String::from_utf8_lossy(&self.read(path).await?).into_owned(),
+                false => String::new(),
+            };
+            text.push_str(line);
+            let lines: Vec<&str> = text.lines().collect();
+            let mut kept = lines[lines.len().saturating_sub(keep)..].join("\n");
+            kept.push('\n');
+            match self.write(path, kept.as_bytes()).await? {
+                true  => Ok(()),
+                false => Err(format!("Remote: PUT {} failed", path).into()),
+            }
+        }
+
+        async fn free(&mut self, _dir: &str) -> Option<u64> {
+            None
+        }
+
+        async fn usage(&mut self, paths: &[String]) -> u64 {
+            let mut total = 0;
+            for x in paths {
+                total += self.size(x).await.ok().flatten().unwrap_or(0);
+            }
+            total
+        }
+    }
+}
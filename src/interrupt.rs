@@ -0,0 +1,199 @@
+use std::fs::{ remove_dir_all, remove_file };
+use std::future::Future;
+use std::path::{ Path, PathBuf };
+use std::sync::{ Mutex, OnceLock };
+use std::time::Duration;
+
+use tokio::signal::unix::{ Signal, SignalKind, signal };
+use tokio::sync::watch;
+
+use crate::keys;
+use crate::summary::{ EXIT_INTERRUPTED, Failure };
+
+/// How long a run has to clean up after being told to stop, before the
+/// process exits regardless.
+const GRACE: Duration = Duration::from_secs(10);
+
+/// Remote files being uploaded and local temporary files and directories
+/// being filled, which a run that's stopped halfway would leave behind,
+/// with the profile whose run they belong to.
+static REMOTE: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+static LOCAL: Mutex<Vec<(String, PathBuf)>> = Mutex::new(Vec::new());
+
+tokio::task_local! {
+    /// The profile being run, so runs going at the same time each clean up
+    /// their own leftovers.
+    static PROFILE: String;
+}
+
+/// The name of the signal that asked for a stop, once one has.
+static STOP: OnceLock<watch::Receiver<Option<&'static str>>> = OnceLock::new();
+
+/// Run `f` as the run of `profile`, for the functions below.
+pub async fn scoped<F: Future>(profile: &str, f: F) -> F::Output {
+    PROFILE.scope(profile.to_string(), f).await
+}
+
+fn current() -> String {
+    PROFILE.try_with(|x| x.clone()).unwrap_or_default()
+}
+
+/// Note a remote file to remove if the run is stopped before `done_remote`.
+pub fn pending_remote(file: &str) {
+    REMOTE.lock().unwrap().push((current(), file.to_string()));
+}
+
+pub fn done_remote(file: &str) {
+    let run = current();
+    REMOTE.lock().unwrap().retain(|(r, x)| ! (*r == run && x == file));
+}
+
+/// Note a local file or directory to remove if the run is stopped before
+/// `done_local`.
+pub fn pending_local(path: &Path) {
+    LOCAL.lock().unwrap().push((current(), path.to_path_buf()));
+}
+
+pub fn done_local(path: &Path) {
+    let run = current();
+    LOCAL.lock().unwrap().retain(|(r, x)| ! (*r == run && x == path));
+}
+
+/// A local directory being filled, which is removed when this is dropped,
+/// or by `remove_local` if the run is stopped before that, so no way out
+/// of the run leaves it behind.
+pub struct LocalDir(PathBuf);
+
+impl LocalDir {
+    pub fn new(path: &Path) -> Self {
+        pending_local(path);
+        LocalDir(path.to_path_buf())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Remove the directory now, returning the problem doing so.
+    pub fn close(self) -> std::io::Result<()> {
+        match remove_dir_all(&self.0) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for LocalDir {
+    fn drop(&mut self) {
+        let _ = remove_dir_all(&self.0);
+        done_local(&self.0);
+    }
+}
+
+/// Take the entries of `list` that belong to the current run, or, outside
+/// of any run, all of them.
+fn take<T>(list: &Mutex<Vec<(String, T)>>) -> Vec<T> {
+    let run = PROFILE.try_with(|x| x.clone()).ok();
+    let mut list = list.lock().unwrap();
+    let (mine, others) = std::mem::take(&mut *list)
+        .into_iter()
+        .partition(|(r, _)| run.as_ref().is_none_or(|x| x == r));
+    *list = others;
+    mine.into_iter().map(|(_, x)| x).collect::<Vec<_>>()
+}
+
+/// The remote files left over from a stopped run, forgetting them.
+pub fn take_remote() -> Vec<String> {
+    take(&REMOTE)
+}
+
+/// Remove the local files and directories left over from a stopped run,
+/// returning the problems doing so.
+pub fn remove_local() -> Vec<String> {
+    take(&LOCAL)
+        .iter()
+        .filter_map(|x| {
+            let res = match x.is_dir() {
+                true  => remove_dir_all(x),
+                false => remove_file(x),
+            };
+            match res {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound =>
+                    Some(format!("{}: {}", x.display(), e)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Whether a stop has been requested.
+pub fn is_stopped() -> bool {
+    STOP.get().is_some_and(|x| x.borrow().is_some())
+}
+
+async fn next(int: &mut Signal, term: &mut Signal) -> &'static str {
+    tokio::select! {
+        _ = int.recv()  => "SIGINT",
+        _ = term.recv() => "SIGTERM",
+    }
+}
+
+/// Handle Ctrl-C (SIGINT) and SIGTERM from now on: the run in progress is
+/// told to stop (see `stopped`) and cleans up after itself. The process
+/// exits without waiting for that at a prompt, on a second signal, or if
+/// the run takes longer than GRACE.
+pub fn watch() {
+    let (tx, rx) = watch::channel(None);
+    if STOP.set(rx).is_err() {
+        return
+    }
+    let (mut int, mut term) = match (signal(SignalKind::interrupt()),
+                                     signal(SignalKind::terminate())) {
+        (Ok(a), Ok(b)) => (a, b),
+        // The signals keep their default action, stopping at once.
+        _ => return,
+    };
+    tokio::spawn(async move {
+        let name = next(&mut int, &mut term).await;
+        let _ = tx.send(Some(name));
+        // Someone at a prompt has nothing in flight to clean up.
+        if keys::PROMPT.try_lock().is_ok() {
+            tokio::select! {
+                _ = next(&mut int, &mut term) => (),
+                _ = tokio::time::sleep(GRACE) => (),
+            }
+        }
+        println!("\nStopping ({})", name);
+        for e in remove_local() {
+            println!("Warning: removing {}", e);
+        }
+        std::process::exit(EXIT_INTERRUPTED);
+    });
+}
+
+/// Wait until a stop is requested, and return the signal's name. Never
+/// resolves unless `watch` was called.
+pub async fn stopped() -> &'static str {
+    let mut rx = match STOP.get() {
+        Some(x) => x.clone(),
+        None => return std::future::pending().await,
+    };
+    loop {
+        if let Some(x) = *rx.borrow_and_update() {
+            return x
+        }
+        if rx.changed().await.is_err() {
+            return std::future::pending().await
+        }
+    }
+}
+
+/// Run `f`, unless a stop is requested first, in which case it's dropped
+/// (ending whatever it was doing) and the result is an interrupted failure.
+pub async fn unless_stopped<T>(f: impl Future<Output = Result<T, Box<dyn std::error::Error>>>)
+-> Result<T, Box<dyn std::error::Error>> {
+    tokio::select! {
+        x = f => x,
+        x = stopped() => Err(Failure::interrupted(format!("stopped by {}", x))),
+    }
+}
@@ -0,0 +1,137 @@
+use std::env::{ set_var, var };
+use std::fs::{ create_dir_all, read_to_string, write };
+use std::path::{ Path, PathBuf };
+use std::process::{ Command, Stdio };
+use std::sync::Mutex;
+
+#[cfg(feature = "gpg")]
+use gpgme::{ Context, Key, PinentryMode, Protocol };
+
+use crate::state::profile_dir;
+
+/// How gpg may ask for a passphrase or smartcard PIN.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pinentry {
+    /// Whatever gpg-agent is configured to do.
+    Default,
+    /// Always use the agent's pinentry program.
+    Ask,
+    /// Fail instead of prompting, e.g. for unattended runs.
+    Error,
+}
+
+impl Pinentry {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "default" => Some(Pinentry::Default),
+            "ask"     => Some(Pinentry::Ask),
+            "error"   => Some(Pinentry::Error),
+            _ => None,
+        }
+    }
+
+    #[cfg(feature = "gpg")]
+    pub fn mode(&self) -> PinentryMode {
+        match self {
+            Pinentry::Default => PinentryMode::Default,
+            Pinentry::Ask     => PinentryMode::Ask,
+            Pinentry::Error   => PinentryMode::Error,
+        }
+    }
+}
+
+/// Held while asking the user anything, or while gpg might, so a pinentry
+/// and mist's own prompts never share the terminal at the same time.
+pub static PROMPT: Mutex<()> = Mutex::new(());
+
+/// Point GPG_TTY at the controlling terminal, if it isn't set and there is
+/// one, so a terminal pinentry (e.g. for a hardware key) prompts there
+/// rather than wherever gpg-agent happened to start.
+pub fn set_gpg_tty() {
+    if var("GPG_TTY").is_ok() {
+        return
+    }
+    let out = match Command::new("tty").stdin(Stdio::inherit()).output() {
+        Ok(x) if x.status.success() => x.stdout,
+        _ => return,
+    };
+    let tty = String::from_utf8_lossy(&out).trim().to_string();
+    if ! tty.is_empty() {
+        set_var("GPG_TTY", tty);
+    }
+}
+
+/// Where the key chosen for a profile without a `gpg_id` is remembered.
+fn key_path(home: &Path, profile: &str) -> PathBuf {
+    profile_dir(home, profile).join("gpg-key")
+}
+
+/// The key chosen earlier for a profile without a `gpg_id`, if any.
+pub fn stored_key(home: &Path, profile: &str) -> Option<String> {
+    let fpr = read_to_string(key_path(home, profile)).ok()?;
+    Some(fpr.trim().to_string()).filter(|x| ! x.is_empty())
+}
+
+/// Remember the key chosen for a profile without a `gpg_id`.
+pub fn store_key(home: &Path, profile: &str, fpr: &str) -> std::io::Result<()> {
+    let path = key_path(home, profile);
+    if let Some(x) = path.parent() {
+        create_dir_all(x)?;
+    }
+    write(path, format!("{}\n", fpr))
+}
+
+/// The `default-key` set in gpg.conf, if there is one.
+#[cfg(feature = "gpg")]
+fn configured_default(home: &Path) -> Option<String> {
+    let dir = match var("GNUPGHOME") {
+        Ok(x) if ! x.is_empty() => PathBuf::from(x),
+        _ => home.join(".gnupg"),
+    };
+    read_to_string(dir.join("gpg.conf"))
+        .ok()?
+        .lines()
+        .filter_map(|x| x.trim().strip_prefix("default-key"))
+        .map(|x| x.trim().to_string())
+        .find(|x| ! x.is_empty())
+}
+
+#[cfg(feature = "gpg")]
+fn usable(key: &Key) -> bool {
+    key.can_encrypt() && ! (key.is_revoked() || key.is_expired() || key.is_disabled()
+                            || key.is_invalid())
+}
+
+/// A short description of a key: its fingerprint and first user id.
+#[cfg(feature = "gpg")]
+pub fn describe(key: &Key) -> String {
+    let fpr = key.fingerprint().unwrap_or("?");
+    match key.user_ids().next().and_then(|x| x.id().ok().map(|x| x.to_string())) {
+        Some(x) => format!("{} ({})", fpr, x),
+        None => fpr.to_string(),
+    }
+}
+
+/// Pick a key for a profile that doesn't set `gpg_id`: gpg's `default-key`
+/// if it's set, or else the first secret key that can encrypt.
+#[cfg(feature = "gpg")]
+pub fn default_key(home: &Path, gpg_bin: &Option<String>)
+-> Result<Key, Box<dyn std::error::Error>> {
+    let mut ctx = Context::from_protocol(Protocol::OpenPgp)?;
+    if let Some(x) = gpg_bin {
+        let _ = ctx.set_engine_path(x.as_str());
+    }
+    if let Some(x) = configured_default(home) {
+        let key = ctx.get_secret_key(x.as_str())
+            .map_err(|e| format!("default-key '{}' from gpg.conf: {}", x, e))?;
+        if ! usable(&key) {
+            return Err(format!("default-key '{}' from gpg.conf can't be used to encrypt", x).into())
+        }
+        return Ok(key)
+    }
+    let found = ctx.secret_keys()?
+        .filter_map(|x| x.ok())
+        .find(usable);
+    Ok(found.ok_or("No secret key that can encrypt was found; create one with \
+                    `gpg --full-generate-key`, or set 'gpg_id'")?)
+}
@@ -0,0 +1,69 @@
+use std::path::Path;
+
+use crate::config::{ Folder, find_config, load_configuration };
+use crate::remote::{ self, Entry, RemoteStore };
+use crate::summary::human_bytes;
+
+/// Split a remote path into its directory and file name.
+fn split(path: &str) -> (&str, &str) {
+    match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some(x) => x,
+        None => (".", path),
+    }
+}
+
+/// Print the remote files belonging to a folder: its archive in whichever
+/// form it's stored, its kept versions, and the hash file.
+async fn list_folder(s: &mut impl RemoteStore, f: &Folder) -> Result<(), Box<dyn std::error::Error>> {
+    println!("{}", f.dir.display());
+    let (dir, base) = split(&f.tar);
+    let entries: Vec<Entry> = s.list(dir)
+        .await?
+        .into_iter()
+        .filter(|x| x.name == base || x.name.starts_with(&format!("{}.", base)))
+        .collect();
+    if entries.is_empty() {
+        println!("  nothing stored under {}", f.tar);
+        return Ok(())
+    }
+    let width = entries.iter().map(|x| x.name.len() + 1).max().unwrap_or(0);
+    for x in &entries {
+        if x.dir {
+            let (path, what) = match x.name.ends_with(".versions") {
+                true  => (f.version_dir(), "versions"),
+                false => (f.chunk_dir(), "chunks"),
+            };
+            let files: Vec<Entry> = s.list(&path)
+                .await?
+                .into_iter()
+                .filter(|x| ! x.dir)
+                .collect();
+            println!("  {:<width$}  {:>10}  {}  ({} {})", format!("{}/", x.name),
+                     human_bytes(files.iter().map(|x| x.size).sum()), x.date, files.len(), what,
+                     width = width);
+        } else {
+            println!("  {:<width$}  {:>10}  {}", x.name, human_bytes(x.size), x.date,
+                     width = width);
+        }
+    }
+    Ok(())
+}
+
+/// List what's stored on the remote for each of a profile's folders.
+pub async fn run(home: &Path, explicit: Option<&Path>, profile: &str)
+-> Result<(), Box<dyn std::error::Error>> {
+    let path = find_config(home, explicit)?;
+    let cfg = load_configuration(&path, profile).await?;
+    let mut s = remote::connect(&cfg).await?;
+    println!("[{}] {}", profile, cfg.address());
+    let mut res = Ok(());
+    for f in &cfg.folders {
+        if let Err(e) = list_folder(&mut s, f).await {
+            res = Err(e);
+            break
+        }
+    }
+    let _ = s.close().await;
+    res
+}
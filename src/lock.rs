@@ -0,0 +1,61 @@
+use std::fs::{ File, OpenOptions, TryLockError, create_dir_all, read_to_string };
+use std::io::Write;
+use std::path::{ Path, PathBuf };
+use std::time::Duration;
+
+use crate::state::profile_dir;
+
+/// A profile's lock, held for the length of a run so that two runs of the
+/// same profile (say, a timer's and a manual one) don't trample each
+/// other's temporary folder and remote files. It's an advisory lock on a
+/// file in the state directory, which the system releases when the file
+/// is closed, even if mist crashes.
+pub struct Lock {
+    _file: File,
+}
+
+/// The lock file for a profile, which also holds the locking process's id.
+fn lock_path(home: &Path, profile: &str) -> PathBuf {
+    profile_dir(home, profile).join("lock")
+}
+
+/// Take a profile's lock. If another run holds it, fail, or with `wait`,
+/// wait for that run to finish.
+pub async fn acquire(home: &Path, profile: &str, wait: bool)
+-> Result<Lock, Box<dyn std::error::Error>> {
+    let path = lock_path(home, profile);
+    if let Some(x) = path.parent() {
+        create_dir_all(x)?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)?;
+    let mut waiting = false;
+    loop {
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(TryLockError::WouldBlock) => (),
+            Err(TryLockError::Error(e)) => return Err(
+                format!("Can't lock {}: {}", path.display(), e).into()),
+        }
+        let who = match read_to_string(&path).unwrap_or_default().trim() {
+            "" => "another run".to_string(),
+            x => format!("another run (process {})", x),
+        };
+        if ! wait {
+            return Err(format!("[{}] is already being synced by {}; try again once it's \
+                                done, or use --wait", profile, who).into())
+        }
+        if ! waiting {
+            println!("Waiting for {} of [{}] to finish...", who, profile);
+            waiting = true;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    file.set_len(0)?;
+    writeln!(file, "{}", std::process::id())?;
+    Ok(Lock { _file: file })
+}
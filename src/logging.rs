@@ -0,0 +1,226 @@
+use std::env::var_os;
+use std::fs::{ OpenOptions, create_dir_all, metadata, remove_file, rename };
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+
+use chrono::{ DateTime, Datelike, Local };
+
+use crate::config::Config;
+use crate::summary::{ Outcome, Summary, human_bytes };
+
+/// How severe a log line is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn  => "WARN",
+            Level::Info  => "INFO",
+        }
+    }
+
+    /// The syslog priority journald files the level under.
+    fn priority(&self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warn  => 4,
+            Level::Info  => 6,
+        }
+    }
+}
+
+/// When a log file is moved aside for a fresh one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// Once it reaches this many bytes.
+    Size(u64),
+    /// When the first line of a new day is written.
+    Daily,
+    /// When the first line of a new (ISO) week is written.
+    Weekly,
+}
+
+impl Rotation {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "daily"  => Some(Rotation::Daily),
+            "weekly" => Some(Rotation::Weekly),
+            x => crate::config::parse_size(x).map(Rotation::Size),
+        }
+    }
+}
+
+/// A log file that runs are recorded in, next to the console output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFile {
+    pub path: PathBuf,
+    pub rotation: Rotation,
+    /// How many rotated files (mist.log.1, mist.log.2, ...) to keep.
+    pub keep: u32,
+}
+
+impl LogFile {
+    /// The path of the nth rotated file; 0 is the live one.
+    fn rotated(&self, n: u32) -> PathBuf {
+        match n {
+            0 => self.path.clone(),
+            n => {
+                let mut x = self.path.clone().into_os_string();
+                x.push(format!(".{}", n));
+                PathBuf::from(x)
+            }
+        }
+    }
+
+    /// Whether the live file should be rotated before writing at `now`.
+    fn due(&self, now: &DateTime<Local>) -> bool {
+        let meta = match metadata(&self.path) {
+            Ok(x) => x,
+            Err(_) => return false,
+        };
+        let modified: DateTime<Local> = match meta.modified() {
+            Ok(x) => x.into(),
+            Err(_) => return false,
+        };
+        match self.rotation {
+            Rotation::Size(x) => meta.len() >= x,
+            Rotation::Daily   => modified.date_naive() != now.date_naive(),
+            Rotation::Weekly  => modified.iso_week() != now.iso_week(),
+        }
+    }
+
+    /// Shift each rotated file up by one, dropping the oldest, and move the
+    /// live file to .1.
+    fn rotate(&self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            return remove_file(&self.path)
+        }
+        match remove_file(self.rotated(self.keep)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        for n in (0..self.keep).rev() {
+            if self.rotated(n).exists() {
+                rename(self.rotated(n), self.rotated(n + 1))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Append lines to the log, rotating it first if it's due.
+    pub fn write(&self, lines: &[(Level, String)]) -> std::io::Result<()> {
+        let now = Local::now();
+        if self.due(&now) {
+            self.rotate()?;
+        }
+        if let Some(dir) = self.path.parent() {
+            create_dir_all(dir)?;
+        }
+        let mut f = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let stamp = now.format("%Y-%m-%d %H:%M:%S");
+        for (level, msg) in lines {
+            writeln!(f, "{} {:<5} {}", stamp, level.name(), msg)?;
+        }
+        Ok(())
+    }
+}
+
+/// The log lines describing a finished run: one for how it ended, and one
+/// for each warning.
+pub fn run_lines(summary: &Summary) -> Vec<(Level, String)> {
+    let p = &summary.profile;
+    let level = match summary.outcome {
+        Outcome::Failed(_) => Level::Error,
+        _ if summary.has_warnings() || summary.unresolved_conflicts => Level::Warn,
+        _ => Level::Info,
+    };
+    let mut lines = vec![(level, format!(
+        "[{}] {} {}: {} files changed, {} up, {} down in {:.1}s", p,
+        summary.direction.name(), summary.outcome, summary.files_changed,
+        human_bytes(summary.bytes_up), human_bytes(summary.bytes_down),
+        summary.elapsed.as_secs_f64()))];
+    if summary.unresolved_conflicts {
+        lines.push((Level::Warn, format!("[{}] unison left conflicts unresolved", p)));
+    }
+    for w in &summary.warnings {
+        lines.push((Level::Warn, format!("[{}] {}", p, w)));
+    }
+    lines
+}
+
+/// Where journald listens for records in its native protocol.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Whether mist's output is going to the systemd journal, i.e. it's
+/// running as a systemd service.
+pub fn under_journald() -> bool {
+    var_os("JOURNAL_STREAM").is_some()
+}
+
+/// Append a field in journald's native format. Values with newlines are
+/// sent length-prefixed rather than as KEY=value.
+fn journal_field(b: &mut Vec<u8>, key: &str, value: &str) {
+    b.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        b.push(b'\n');
+        b.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        b.push(b'=');
+    }
+    b.extend_from_slice(value.as_bytes());
+    b.push(b'\n');
+}
+
+/// Send one record about a run to the journal, with its priority and the
+/// run's profile, direction and sync id as fields of their own, so e.g.
+/// `journalctl MIST_PROFILE=home -p warning` finds what went wrong.
+fn journal_send(level: Level, summary: &Summary, msg: &str) -> std::io::Result<()> {
+    let mut b = Vec::new();
+    journal_field(&mut b, "MESSAGE", msg);
+    journal_field(&mut b, "PRIORITY", &level.priority().to_string());
+    journal_field(&mut b, "SYSLOG_IDENTIFIER", "mist");
+    journal_field(&mut b, "MIST_PROFILE", &summary.profile);
+    journal_field(&mut b, "MIST_DIRECTION", summary.direction.name());
+    journal_field(&mut b, "MIST_SYNC_ID", &summary.sync_id);
+    UnixDatagram::unbound()?.send_to(&b, JOURNAL_SOCKET)?;
+    Ok(())
+}
+
+/// Write lines about a run wherever the profile logs to: its log file,
+/// and the journal when running under systemd. Problems are recorded as
+/// warnings.
+fn log_lines(cfg: &Config, summary: &mut Summary, lines: &[(Level, String)]) {
+    if let Some(log) = &cfg.log_file {
+        if let Err(e) = log.write(lines) {
+            summary.warn(format!("Writing log file {}: {}", log.path.display(), e));
+        }
+    }
+    if cfg.journald && under_journald() {
+        let res: std::io::Result<()> = lines
+            .iter()
+            .try_for_each(|(level, msg)| journal_send(*level, summary, msg));
+        if let Err(e) = res {
+            summary.warn(format!("Logging to the journal: {}", e));
+        }
+    }
+}
+
+/// Record that a run is starting.
+pub fn log_start(cfg: &Config, summary: &mut Summary) {
+    let msg = format!("[{}] {} started (sync id {})", summary.profile, summary.direction.name(),
+                      summary.sync_id);
+    log_lines(cfg, summary, &[(Level::Info, msg)]);
+}
+
+/// Record a finished run.
+pub fn log_run(cfg: &Config, summary: &mut Summary) {
+    let lines = run_lines(summary);
+    log_lines(cfg, summary, &lines);
+}
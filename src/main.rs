@@ -1,23 +1,80 @@
+use std::collections::{ BTreeMap, BTreeSet, HashMap, HashSet };
 use std::env::var;
-use std::fs::{ read_dir, remove_dir_all };
-use std::hash::{ Hash, Hasher };
-use std::io::{ stdin, Write };
+use std::fs::{ DirBuilder, read_dir, remove_dir_all };
+use std::io::Write;
+use std::os::unix::fs::{ DirBuilderExt, MetadataExt };
 use std::path::{ Path, PathBuf };
-use std::process::{ Command, Stdio };
-
-use clap::Parser;
-use flate2::{ Compression, write::GzEncoder, read::GzDecoder };
-use gpgme::{ Context, Protocol };
-use openssh::{ Session, SessionBuilder, KnownHosts };
-use tar::{ Builder, Archive };
-use tokio::io::{ AsyncReadExt, AsyncWriteExt };
-use toml::Value;
-use twox_hash::XxHash64;
+use std::sync::Arc;
+use std::time::{ Duration, Instant, SystemTime };
+
+use chrono::TimeZone;
+use clap::{ CommandFactory, Parser, Subcommand };
+use clap_complete::Shell;
+use sha2::{ Digest, Sha256 };
+use tokio::sync::Semaphore;
+use tokio::task::LocalSet;
 use walkdir::WalkDir;
 
+pub mod archive;
+pub mod chunks;
+pub mod completions;
 pub mod config;
+pub mod crypto;
+pub mod doctor;
+pub mod email;
+pub mod format;
+pub mod hash;
+pub mod healthcheck;
+pub mod hooks;
+pub mod http;
+pub mod interrupt;
+pub mod keys;
+pub mod list;
+pub mod lock;
+pub mod logging;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+pub mod memory;
+pub mod merge;
+pub mod metrics;
+#[cfg(feature = "fuse")]
+pub mod mount;
+pub mod network;
+pub mod notify;
+pub mod profiles;
+pub mod prompt;
+pub mod remote;
+pub mod restic;
+pub mod runlog;
+pub mod schedule;
+pub mod secrets;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod snapshot;
+pub mod space;
+pub mod spill;
+pub mod ssh;
+pub mod state;
+pub mod stats;
+pub mod summary;
+pub mod systemd;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod webhook;
 
-use config::{ Config, load_configuration };
+use archive::{ compress, create_tar, decompress, extract_file, prune, unpack_tar, unpacked_size };
+use chunks::{ Chunk, Index, Storage };
+use config::{ Config, ConfigError, Folder, Mode, find_config, load_configuration };
+use crypto::{ Encryption, decrypt, decrypt_from, encrypt, encrypt_to };
+use format::{ Cipher, Compression, Format };
+use interrupt::LocalDir;
+use merge::Merge;
+use prompt::Answer;
+use hash::{ Detect, HashAlgorithm, Marker, archive_sum, hash_metadata };
+use remote::{ Remote, RemoteStore };
+use spill::Buffer;
+use ssh::{ HostKeyPolicy, SshClient };
+use summary::{ Direction, Failure, FailureKind, Outcome, Summary, human_bytes };
 
 /// Test whether the local sync directory exists.
 async fn confirm_local_exists(home: &Path, dir: &Path)
@@ -35,187 +92,656 @@ async fn confirm_local_exists(home: &Path, dir: &Path)
     Ok(false)
 }
 
-/// Call Unison on the local and remote folder.
-async fn unison(local: &Path, remote: &Path, batch: bool)
--> Result<bool, std::io::Error> {
-    let mut cmd = Command::new("unison");
-    if batch {
-        cmd
-            .arg(local)
-            .arg(remote)
-            .arg("-batch");
+/// Make the folder's temporary directory, which the remote copy is unpacked
+/// into to be merged, empty and accessible only to us. Its path has to stay
+/// the same from run to run, as unison remembers a sync by the paths of its
+/// two sides, so one that already exists is only used if it's a directory
+/// of ours, and whatever an earlier run left in it is removed.
+fn private_temp(dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    match std::fs::symlink_metadata(dir) {
+        Ok(m) => {
+            // SAFETY: getuid() can't fail and touches no memory.
+            if ! m.is_dir() || m.uid() != unsafe { libc::getuid() } {
+                return Err(format!("Temporary folder {} exists but isn't a directory of yours; \
+                                    remove it or change 'temp_folder'", dir.display()).into())
+            }
+            remove_dir_all(dir)?;
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    Ok(())
+}
+
+/// A fresh private directory beside the folder's temporary one, for
+/// commands that unpack the remote copy without merging it, which have no
+/// need for the same path every run.
+fn scratch_dir(f: &Folder, what: &str) -> Result<LocalDir, Box<dyn std::error::Error>> {
+    let name = f.temp.file_name().and_then(|x| x.to_str()).unwrap_or("mist");
+    let parent = f.temp.parent().unwrap_or_else(|| Path::new("/"));
+    DirBuilder::new().recursive(true).create(parent)?;
+    let dir = tempfile::Builder::new()
+        .prefix(&format!("{}-{}-", name, what))
+        .tempdir_in(parent)?;
+    Ok(LocalDir::new(&dir.keep()))
+}
+
+/// Write bytes to a file on the remote system. A failed write is recorded
+/// as a warning, and reported by returning false.
+async fn upload(s: &mut impl RemoteStore, bytes: &[u8], dest: &str, summary: &mut Summary)
+-> Result<bool, Box<dyn std::error::Error>> {
+    if ! s.write(dest, bytes).await? {
+        summary.warn(format!("Writing {} to the remote host failed", dest));
+        return Ok(false)
+    }
+    summary.bytes_up += bytes.len() as u64;
+    Ok(true)
+}
+
+/// Write bytes to a file on the remote system. A failed write is recorded
+/// as a warning, and reported by returning false; a write that reports
+/// success but left different contents behind is an error.
+///
+/// The bytes go to a .partial file that's moved into place once checked,
+/// so a failed or interrupted upload never replaces a good file.
+async fn write_remote_file(s: &mut impl RemoteStore, bytes: &[u8], dest: &str,
+                           summary: &mut Summary)
+-> Result<bool, Box<dyn std::error::Error>> {
+    if s.atomic_writes() {
+        if ! upload(s, bytes, dest, summary).await? {
+            return Ok(false)
+        }
+        s.verify(dest, bytes).await?;
+        return Ok(true)
+    }
+    let partial = format!("{}.partial", dest);
+    interrupt::pending_remote(&partial);
+    let written: Result<bool, Box<dyn std::error::Error>> = async {
+        if ! upload(s, bytes, &partial, summary).await? {
+            return Ok(false)
+        }
+        s.verify(&partial, bytes).await?;
+        s.rename(&partial, dest).await?;
+        Ok(true)
+    }.await;
+    if ! matches!(written, Ok(true)) {
+        let _ = s.remove(std::slice::from_ref(&partial)).await;
+    }
+    interrupt::done_remote(&partial);
+    written
+}
+
+/// Alternative to write_remote_file() for writing files over SSH, which
+/// uses dd. This doesn't utilize the openssh Rust bindings but just
+/// calls scp directly, which provides some progress information.
+/// Only needs to be used if the other method isn't working or the 
+/// transfer time is long enough that it warrants progress updates.
+async fn scp_write(bytes: &[u8], dest: &str, sshaddr: &str, summary: &mut Summary)
+-> std::io::Result<()> {
+    // A private file with an unpredictable name, removed when dropped.
+    let mut local = tempfile::Builder::new().prefix("mist-").tempfile()?;
+    local.write_all(bytes)?;
+    local.flush()?;
+    let cmd = std::process::Command::new("rsync")
+        .arg("--progress")
+        .arg(local.path())
+        .arg(format!("{}:{}", sshaddr, dest))
+        .status()?;
+    local.close()?;
+    if cmd.success() {
+        summary.bytes_up += bytes.len() as u64;
     } else {
-        cmd
-            .arg(local)
-            .arg(remote);
+        summary.warn(format!("rsync: {} to remote host failed ({})", dest, cmd));
+    }
+    Ok(())
+}
+
+/// Make sure the remote directory the folder's files go in exists, creating
+/// it once the user agrees (or with --create-remote). Returns false if it's
+/// missing and wasn't created.
+async fn ensure_remote_dir(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, args: &Args)
+-> Result<bool, Box<dyn std::error::Error>> {
+    let dir = f.remote_dir();
+    if s.is_dir(dir).await? {
+        return Ok(true)
+    }
+    let q = format!("Remote directory {} doesn't exist: create it?", dir);
+    let standing = match args.create_remote {
+        true  => Answer::Yes,
+        false => cfg.answers.create_remote,
     };
-    let cmd = cmd.status()?;
-    Ok(cmd.success())
+    if ! prompt::confirm(&q, standing, args.assume()) {
+        return Ok(false)
+    }
+    s.create_dir(dir).await?;
+    println!("Created remote directory {}", dir);
+    Ok(true)
 }
 
-/// Get the contents of a remote file.
-async fn read_remote_file(s: &mut Session, file: &str)
--> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let mut sftp = s.sftp();
-    let mut f = sftp.read_from(file).await?;
-    let mut b = Vec::new();
-    f.read_to_end(&mut b).await?;
-    f.close().await?;
-    Ok(b)
+/// The number of parts the folder's remote archive is split into, if it is.
+async fn remote_parts(s: &mut impl RemoteStore, f: &Folder) -> Option<usize> {
+    let index = s.read(&f.parts_index()).await.ok()?;
+    String::from_utf8(index).ok()?.trim().parse().ok()
 }
 
-/// Decrypt the remote archive's data.
-async fn decrypt(bytes: &[u8], gpgbin: &Option<Value>)
--> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let mut ctx = Context::from_protocol(Protocol::OpenPgp)?;
-    match gpgbin {
-        Some(x) => { let _ = ctx.set_engine_path(x.as_str().unwrap().to_string()); },
-        None => (),
+/// Test whether the folder has an archive on the remote, in any form.
+async fn remote_archive_exists(s: &mut impl RemoteStore, f: &Folder)
+-> Result<bool, Box<dyn std::error::Error>> {
+    Ok(s.exists(&f.tar).await?
+       || s.exists(&f.parts_index()).await?
+       || s.exists(&f.chunk_index()).await?)
+}
+
+/// Download the folder's remote archive, joining its parts if it's split.
+/// The parts are read one at a time into a buffer that spills past
+/// `spill_above`.
+async fn read_archive(s: &mut impl RemoteStore, cfg: &Config, f: &Folder)
+-> Result<Buffer, Box<dyn std::error::Error>> {
+    let n = match remote_parts(s, f).await {
+        Some(x) => x,
+        None => return Ok(s.read(&f.tar).await?.into()),
+    };
+    let mut b = Buffer::new(cfg.spill_above);
+    for i in 0..n {
+        b.write_all(&s.read(&f.part(i))
+            .await
+            .map_err(|e| format!("Can't read part {} of {}: {}", i, f.tar, e))?)?;
     }
-    let mut b = Vec::new();
-    ctx.decrypt(bytes, &mut b)
-        .map_err(|e| format!("Decryption failed: {:?}", e))?;
     Ok(b)
 }
 
-/// Unpack tar data and write the folder to disk.
-async fn unpack_tar(bytes: &[u8], dest: &Path)
--> Result<(), std::io::Error> {
-    let dec = GzDecoder::new(bytes);
-    let mut tar = Archive::new(dec);
-    tar.unpack(dest)?;
-    Ok(())
+/// Upload the folder's archive, in parts of at most `split_size` bytes if
+/// that's set, and remove whatever the previous upload left that's no
+/// longer part of it. An archive that spilled to disk goes up in parts of
+/// at most `spill_above` bytes too, so neither it nor a pull of it is ever
+/// in memory whole. The part index is written after the parts, so a pull
+/// never sees a split archive that's only half uploaded. Returns the files
+/// written, with their SHA-256, for the hash file.
+async fn write_archive(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, args: &Args,
+                       bytes: &Buffer, summary: &mut Summary)
+-> Result<Vec<(String, Vec<u8>)>, Box<dyn std::error::Error>> {
+    if args.scpwrite && (cfg.ssh_client != SshClient::Openssh || cfg.http_url.is_some()
+                         || cfg.restic_url.is_some()) {
+        return Err("--scpwrite runs rsync over the system's ssh, so it needs \
+                    ssh_client = \"openssh\"".into())
+    }
+    let old_parts = remote_parts(s, f).await;
+    // The archive and parts being overwritten make room for the new ones.
+    let mut replaced = vec![f.tar.clone()];
+    replaced.extend((0..old_parts.unwrap_or(0)).map(|i| f.part(i)));
+    space::check_remote(s, f.remote_dir(), &replaced, bytes.len()).await?;
+    let split = match bytes.spilled() {
+        true  => cfg.split_size.into_iter().chain(cfg.spill_above).min(),
+        false => cfg.split_size,
+    };
+    let (n, size) = match split {
+        Some(x) if bytes.len() > x => (bytes.len().div_ceil(x), x),
+        _ => (1, bytes.len()),
+    };
+    let mut stale: Vec<String> = Vec::new();
+    let mut written = Vec::new();
+    for i in 0..n {
+        let (dest, piece) = match n {
+            1 => (f.tar.clone(), bytes.range(0, size)?),
+            _ => {
+                println!("Uploading part {} of {}", i + 1, n);
+                (f.part(i as usize), bytes.range(i * size, size)?)
+            }
+        };
+        if args.scpwrite {
+            scp_write(&piece, &dest, &cfg.sshaddr, summary).await?;
+            s.verify(&dest, &piece).await?;
+        } else {
+            write_remote_file(s, &piece, &dest, summary).await?;
+        }
+        written.push((dest, Sha256::digest(&piece).to_vec()));
+    }
+    if n > 1 {
+        write_remote_file(s, format!("{}\n", n).as_bytes(), &f.parts_index(), summary).await?;
+        stale.push(f.tar.clone());
+        stale.extend((n as usize..old_parts.unwrap_or(0)).map(|i| f.part(i)));
+    } else if let Some(n) = old_parts {
+        stale.push(f.parts_index());
+        stale.extend((0..n).map(|i| f.part(i)));
+    }
+    if s.exists(&f.chunk_index()).await? {
+        stale.push(f.chunk_index());
+        stale.push(f.chunk_dir());
+    }
+    if let Err(e) = s.remove(&stale).await {
+        summary.warn(format!("Leftovers of the previous upload weren't removed: {}", e));
+    }
+    Ok(written)
+}
+
+/// How the folder's remote archive was written. Archives from before the
+/// format file was added are gzip-compressed, and stored as chunks if
+/// there's a chunk index.
+async fn read_format(s: &mut impl RemoteStore, f: &Folder)
+-> Result<Format, Box<dyn std::error::Error>> {
+    // The storage goes by what's there, in case a push was interrupted
+    // between switching storage and updating the format file.
+    let storage = match s.exists(&f.chunk_index()).await? {
+        true  => Storage::Chunks,
+        false => Storage::Archive,
+    };
+    if ! s.exists(&f.format()).await? {
+        return Ok(Format::legacy(storage))
+    }
+    let fmt = Format::decode(&s.read(&f.format()).await?)
+        .map_err(|e| format!("Remote format file {}: {}", f.format(), e))?;
+    Ok(Format { storage, ..fmt })
 }
 
-/// Create a compressed and archived sync folder.
-async fn create_tar(source: &Path) -> Result<Vec<u8>, std::io::Error> {
-    let enc= GzEncoder::new(Vec::new(), Compression::default());
-    let mut tar = Builder::new(enc);
-    tar.append_dir_all("", source)?;
-    let enc_data: GzEncoder<Vec<u8>> = tar.into_inner()?;
-    let comp_vec: Vec<u8> = enc_data.finish()?;
-    Ok(comp_vec)
+/// Read and decrypt the folder's remote chunk index.
+async fn read_chunk_index(s: &mut impl RemoteStore, cfg: &Config, f: &Folder)
+-> Result<Index, Box<dyn std::error::Error>> {
+    let index = s.read(&f.chunk_index()).await?;
+    let index = decrypt(&index, cfg).await?;
+    Ok(Index::decode(&index).ok_or(format!("Remote chunk index {} is corrupt", f.chunk_index()))?)
 }
 
-/// Encrypt data with the given GPG key.
-async fn encrypt(bytes: &[u8], gpgid: &str, gpgbin: &Option<Value>, symmetric: bool)
+/// Download every chunk listed in the folder's chunk index and return the
+/// archive they make up, checking each chunk against its name.
+async fn read_chunks(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, fmt: &Format,
+                     summary: &mut Summary)
+-> Result<Buffer, Box<dyn std::error::Error>> {
+    let index = read_chunk_index(s, cfg, f).await?;
+    let mut b = Buffer::new(cfg.spill_above);
+    for c in &index.chunks {
+        b.write_all(&read_chunk(s, cfg, f, fmt, &index, &c.id, summary).await?)?;
+    }
+    Ok(b)
+}
+
+/// Download and decrypt one chunk, checking it against its name.
+async fn read_chunk(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, fmt: &Format, index: &Index,
+                    id: &str, summary: &mut Summary)
 -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let mut ctx = Context::from_protocol(Protocol::OpenPgp)?;
-    match gpgbin {
-        Some(x) => { let _ = ctx.set_engine_path(x
-                          .as_str()
-                          .unwrap()
-                          .to_string());
-                   },
-        None => (),
+    let chunk = s.read(&f.chunk(id))
+        .await
+        .map_err(|e| format!("Can't read chunk {}: {}", id, e))?;
+    summary.bytes_down += chunk.len() as u64;
+    let chunk = decrypt(&chunk, cfg).await?;
+    let chunk = match fmt.compression.is_gzip() {
+        true  => decompress(&chunk)?,
+        false => chunk,
+    };
+    if index.chunk_id(&chunk) != id {
+        return Err(format!("Chunk {} doesn't match its contents", f.chunk(id)).into())
     }
-    ctx.set_armor(true);
-    let key = ctx.get_key(gpgid)?;
-    let mut b = Vec::new();
-    if symmetric {
-        ctx.encrypt_symmetric(bytes, &mut b)?;
-    } else {
-        ctx.encrypt([&key], bytes, &mut b)?;
+    Ok(chunk)
+}
+
+/// Pull just `path` (a file or directory, relative to the folder) into the
+/// sync folder, downloading only the chunks that hold it. Returns false if
+/// the remote archive doesn't contain it.
+async fn pull_path(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, path: &Path,
+                   summary: &mut Summary)
+-> Result<bool, Box<dyn std::error::Error>> {
+    let fmt = read_format(s, f).await?;
+    if fmt.storage != Storage::Chunks {
+        return Err(format!("{} isn't stored as chunks, which pulling a single path needs \
+                            (storage = \"chunks\")", f.dir.display()).into())
     }
-    Ok(b)
+    let index = read_chunk_index(s, cfg, f).await?;
+    if index.entries.is_empty() {
+        return Err("The remote chunk index predates single path pulls; push once to update it"
+                   .into())
+    }
+    let ranges = index.ranges(path);
+    if ranges.is_empty() {
+        return Ok(false)
+    }
+    println!("Pulling {} from remote...", f.dir.join(path).display());
+    let t = Instant::now();
+    let tar = read_ranges(s, cfg, f, &fmt, &index, &ranges, summary).await?;
+    summary.phase("download", t);
+    let t = Instant::now();
+    unpack_tar(&Buffer::from(tar), &f.dir, cfg.preserve, cfg.unpack_limits, false,
+               &HashSet::new()).await?;
+    summary.phase("unpack", t);
+    Ok(true)
 }
 
+/// Download the chunks holding the given byte ranges of the archive, and
+/// return those ranges joined together.
+async fn read_ranges(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, fmt: &Format, index: &Index,
+                     ranges: &[(u64, u64)], summary: &mut Summary)
+-> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut fetched: Vec<Option<Vec<u8>>> = vec![None; index.chunks.len()];
+    let mut tar = Vec::new();
+    for &(start, end) in ranges {
+        for (i, offset) in index.chunks_in(start, end) {
+            if fetched[i].is_none() {
+                let id = &index.chunks[i].id;
+                fetched[i] = Some(read_chunk(s, cfg, f, fmt, index, id, summary).await?);
+            }
+            let chunk = fetched[i].as_ref().expect("fetched above");
+            let from = start.saturating_sub(offset) as usize;
+            let to = ((end - offset) as usize).min(chunk.len());
+            tar.extend_from_slice(&chunk[from..to]);
+        }
+    }
+    Ok(tar)
+}
 
-/// Write bytes to a file on the remote system. 
-async fn write_remote_file(s: &mut Session, bytes: &[u8], dest: &str)
+/// Upload the chunks of the (uncompressed) archive that aren't on the
+/// remote yet, then a new chunk index. Chunks no longer listed are left
+/// in place for `mist gc`.
+async fn write_chunks(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, tar: &Buffer,
+                      summary: &mut Summary)
 -> Result<(), Box<dyn std::error::Error>> {
-    let mut cmd = s.command("dd")
-            .stdin(Stdio::piped())
-            .arg("status=progress")
-            .arg(format!("of={}", dest))
-            .spawn()?;
-    let stdin = cmd
-        .stdin()
-        .as_mut()
-        .ok_or("Remote: dd: Unable to pipe to stdin")?;
-    stdin.write_all(bytes).await?;
-    let status = cmd.wait().await?;
-    match status.code() {
-        Some(0) => println!("dd: {} to remote host", &dest),
-        None => println!("Warning: dd {} on remote host: no exit code", &dest),
-        _ => println!("Warning: dd: {} to remote host failed", &dest)
+    // Keep the existing key, or identical chunks would get new names;
+    // unless they were compressed differently, when they all need replacing.
+    let reuse = s.exists(&f.chunk_index()).await? && {
+        let old = read_format(s, f).await?;
+        old.compression.is_gzip() == cfg.compression.is_gzip()
+            // Nor kept from one kind of encryption to the other.
+            && (old.cipher == Cipher::Builtin) == (cfg.encryption == Encryption::Builtin)
+    };
+    let mut index = match reuse {
+        true  => Index { chunks: Vec::new(), entries: Vec::new(),
+                         ..read_chunk_index(s, cfg, f).await? },
+        false => Index::new()?,
+    };
+    s.create_dir(&f.chunk_dir()).await?;
+    let mut present: HashSet<String> = s.list(&f.chunk_dir())
+        .await?
+        .into_iter()
+        .map(|x| x.name)
+        .collect();
+    // Named in one pass over the archive, and read again for the chunks
+    // to upload, so it needn't be in memory whole.
+    let mut pieces: Vec<(u64, String)> = Vec::new();
+    chunks::split(tar.reader(), |x| pieces.push((x.len() as u64, index.chunk_id(x))))?;
+    // Uncompressed sizes, so more than the upload will take, give or take
+    // gpg's few bytes per chunk.
+    let needed = pieces
+        .iter()
+        .filter(|(_, id)| ! present.contains(id))
+        .map(|(len, _)| len)
+        .sum();
+    space::check_remote(s, &f.chunk_dir(), &[], needed).await?;
+    let entries = chunks::entries(|| tar.reader())?;
+    let mut uploaded = 0;
+    let mut offset = 0;
+    for (len, id) in pieces {
+        let (start, end) = (offset, offset + len);
+        offset = end;
+        if ! present.contains(&id) {
+            let chunk = tar.range(start, len)?;
+            let chunk = chunk.as_ref();
+            // With "auto", a chunk that's mostly files compressed already
+            // is stored, still as gzip so it reads like any other.
+            let store = cfg.compression == Compression::Auto
+                && chunks::bytes_in(&entries, start, end, archive::precompressed) * 2
+                    > end - start;
+            let data = match cfg.compression {
+                Compression::None => encrypt(chunk, cfg).await?,
+                _ => {
+                    let x = match store {
+                        true  => archive::store(chunk)?,
+                        false => compress(chunk)?,
+                    };
+                    summary.compressed_in += chunk.len() as u64;
+                    summary.compressed_bytes += x.len() as u64;
+                    encrypt(&x, cfg).await?
+                }
+            };
+            summary.encrypted_bytes += data.len() as u64;
+            let written = write_remote_file(s, &data, &f.chunk(&id), summary).await;
+            if ! matches!(written, Ok(true)) {
+                return Err(match written {
+                    Err(e) => e,
+                    _ => format!("Uploading chunk {} failed", f.chunk(&id)).into(),
+                })
+            }
+            present.insert(id.clone());
+            uploaded += 1;
+        }
+        index.chunks.push(Chunk { id, len });
+    }
+    index.entries = entries;
+    let data = encrypt(&index.encode(), cfg).await?;
+    if ! write_remote_file(s, &data, &f.chunk_index(), summary).await? {
+        return Err(format!("Uploading chunk index {} failed", f.chunk_index()).into())
+    }
+    println!("Uploaded {} new of {} chunks", uploaded, index.chunks.len());
+    if cfg.keep_versions.is_some() || cfg.keep_versions_for.is_some() {
+        save_version(s, cfg, f, &data, ! reuse, summary).await?;
+    }
+    // Remove the single or split archive if the folder used to be stored that way.
+    let mut stale = vec![f.tar.clone()];
+    if let Some(n) = remote_parts(s, f).await {
+        stale.push(f.parts_index());
+        stale.extend((0..n).map(|i| f.part(i)));
+    }
+    if let Err(e) = s.remove(&stale).await {
+        summary.warn(format!("The previous archive wasn't removed: {}", e));
     }
     Ok(())
 }
 
-/// Test whether a file exists on the remote filesystem.
-async fn confirm_remote_exists(s: &mut Session, file: &str)
-    -> Result<bool, Box<dyn std::error::Error>> {
-    let cmd = s.command("test")
-            .arg("-f")
-            .arg(file)
-            .status()
-            .await?;
-    match cmd.code() {
-        Some(0) => Ok(true),
-        Some(1) => Ok(false),
-        Some(_) => Err(format!("{:?}", &cmd).into()),
-        None    => Err("Remote: 'test': no exit code".into()),
+/// Check a downloaded and decrypted archive against the checksum recorded
+/// in the hash file, if there is one, so a truncated or corrupt archive is
+/// caught before anything is unpacked.
+async fn check_archive(s: &mut impl RemoteStore, f: &Folder, tar: &Buffer)
+-> Result<(), Box<dyn std::error::Error>> {
+    let marker = s.read(&f.tar_hash)
+        .await
+        .ok()
+        .and_then(|x| Marker::decode(&x));
+    match marker.and_then(|x| x.archive) {
+        Some(x) if x != archive_sum(tar)? =>
+            Err(format!("The remote archive of {} doesn't match its checksum, so nothing was \
+                         unpacked. It's corrupt, or the last push was interrupted.",
+                        f.dir.display()).into()),
+        _ => Ok(()),
     }
 }
 
-/// Alternative to write_remote_file() for writing files over SSH, which
-/// uses dd. This doesn't utilize the openssh Rust bindings but just
-/// calls scp directly, which provides some progress information.
-/// Only needs to be used if the other method isn't working or the 
-/// transfer time is long enough that it warrants progress updates.
-async fn scp_write(bytes: &[u8], dest: &str, sshaddr: &str) -> std::io::Result<()> {
-    let mut f = std::fs::File::create(dest)?;
-    f.write_all(bytes)?;
-    let cmd = std::process::Command::new("rsync")
-        .arg("--progress")
-        .arg(dest)
-        .arg(format!("{}:{}", sshaddr, dest))
-        .status()?;
-    println!("{:?}", cmd);
-    std::fs::remove_file(dest)?;
-    println!("Wrote using scp.");
-    Ok(())
+/// Read the folder's remote list of files that were deleted but are kept
+/// in the archive, mapped to when they were deleted, in seconds since 1970.
+/// There's none unless a mirror doesn't propagate deletes.
+async fn read_deleted(s: &mut impl RemoteStore, cfg: &Config, f: &Folder)
+-> Result<BTreeMap<PathBuf, u64>, Box<dyn std::error::Error>> {
+    if ! s.exists(&f.deleted()).await? {
+        return Ok(BTreeMap::new())
+    }
+    let text = decrypt(&s.read(&f.deleted()).await?, cfg).await?;
+    Ok(String::from_utf8_lossy(&text)
+        .lines()
+        .filter_map(|x| {
+            let (time, path) = x.split_once('\t')?;
+            Some((PathBuf::from(path), time.parse().ok()?))
+        })
+        .collect())
 }
 
-/// Download the remote archive and unpack it to a location on disk.
-/// The temp argument determines whether the archive is being unpacked
-/// to a temporary directory or to the synced folder itself (e.g., if
-/// the folder is being pulled locally for the first time).
-async fn pull_remote(s: &mut Session, cfg: &Config, temp: bool)
+/// Write the folder's remote list of deleted files, or remove it if
+/// there are none.
+async fn write_deleted(s: &mut impl RemoteStore, cfg: &Config, f: &Folder,
+                       deleted: &BTreeMap<PathBuf, u64>, summary: &mut Summary)
 -> Result<(), Box<dyn std::error::Error>> {
-    println!("Pulling from remote...");
-    let tar = read_remote_file(s, &cfg.tar).await?;
-    let tar = decrypt(&tar, &cfg.gpg_bin).await?;
-    let dest = match temp {
-        true  => &cfg.temp,
-        false => &cfg.dir,
-    };
-    unpack_tar(&tar, dest).await?;
+    if deleted.is_empty() {
+        return s.remove(&[f.deleted()]).await
+    }
+    let text: String = deleted
+        .iter()
+        .map(|(path, time)| format!("{}\t{}\n", time, path.display()))
+        .collect();
+    let bytes = encrypt(text.as_bytes(), cfg).await?;
+    if ! write_remote_file(s, &bytes, &f.deleted(), summary).await? {
+        return Err(format!("Uploading {} failed", f.deleted()).into())
+    }
     Ok(())
 }
 
+/// Download the remote archive and unpack it to `dest`: the folder's
+/// temporary directory when syncing, the synced folder itself when
+/// pulling, or a scratch directory when verifying. Files that were deleted
+/// but are kept in the archive are left out.
+async fn pull_remote(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, dest: &Path,
+                     summary: &mut Summary)
+-> Result<HashSet<PathBuf>, Box<dyn std::error::Error>> {
+    println!("Pulling {} from remote...", f.dir.display());
+    let skip = read_deleted(s, cfg, f).await?.into_keys().collect();
+    unpack_remote(s, cfg, f, dest, &skip, summary).await
+}
+
+/// Download the remote archive and unpack it to `dest`, leaving out the
+/// paths in `skip`. Returns the paths unpacked.
+async fn unpack_remote(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, dest: &Path,
+                       skip: &HashSet<PathBuf>, summary: &mut Summary)
+-> Result<HashSet<PathBuf>, Box<dyn std::error::Error>> {
+    // Go by what's on the remote rather than the profile, so switching
+    // settings doesn't strand the data stored the old way.
+    let fmt = read_format(s, f).await?;
+    if fmt.storage == Storage::Chunks {
+        let t = Instant::now();
+        let tar = read_chunks(s, cfg, f, &fmt, summary).await?;
+        summary.phase("download", t);
+        check_archive(s, f, &tar).await?;
+        space::check_local(dest, unpacked_size(&tar, false)?)?;
+        let t = Instant::now();
+        let paths = unpack_tar(&tar, dest, cfg.preserve, cfg.unpack_limits, false, skip).await?;
+        summary.phase("unpack", t);
+        return Ok(paths)
+    }
+    let t = Instant::now();
+    let tar = read_archive(s, cfg, f).await?;
+    summary.bytes_down += tar.len();
+    summary.phase("download", t);
+    let t = Instant::now();
+    let mut plain = Buffer::new(cfg.spill_above);
+    decrypt_from(&tar, cfg, &mut plain).await?;
+    drop(tar);
+    let tar = plain;
+    summary.phase("decrypt", t);
+    check_archive(s, f, &tar).await?;
+    space::check_local(dest, unpacked_size(&tar, fmt.compression.is_gzip())?)?;
+    let t = Instant::now();
+    let paths = unpack_tar(&tar, dest, cfg.preserve, cfg.unpack_limits,
+                           fmt.compression.is_gzip(), skip).await?;
+    summary.phase("unpack", t);
+    Ok(paths)
+}
+
 /// Write archive of the sync directory and its hash to the remote file system.
-async fn push_remote(s: &mut Session, cfg: &Config, args: &Args)
+async fn push_remote(home: &Path, s: &mut impl RemoteStore, cfg: &Config, f: &Folder, args: &Args,
+                     summary: &mut Summary)
 -> Result<(), Box<dyn std::error::Error>> {
-    let hash = hash_metadata(&cfg.dir).await;
-    let tar = create_tar(&cfg.dir).await?;
-    let tar = encrypt(&tar, &cfg.gpg_id, &cfg.gpg_bin, cfg.symmetric).await?;
-    if args.scpwrite {
-        scp_write(&tar, &cfg.tar, &cfg.sshaddr).await?;
+    println!("Pushing {} to remote...", f.dir.display());
+    let t = Instant::now();
+    let hash = hash_folder(home, cfg, f, cfg.hash, cfg.detect).await;
+    summary.phase("hash", t);
+    let marker = hash.map(|digest| Marker {
+        algorithm: cfg.hash,
+        detect: cfg.detect,
+        digest,
+        archive: None,
+        stored: Vec::new(),
+    });
+    push_dir(s, cfg, f, &f.dir, marker, args, summary).await
+}
+
+/// Write an archive of `src` as the folder's, with `marker` as its hash
+/// once the archive's checksums are added; without one, the remote hash is
+/// removed.
+async fn push_dir(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, src: &Path,
+                  marker: Option<Marker>, args: &Args, summary: &mut Summary)
+-> Result<(), Box<dyn std::error::Error>> {
+    let t = Instant::now();
+    // Chunks are compressed one by one, so identical data stays identical.
+    let chunked = cfg.storage == Storage::Chunks;
+    let compression = match chunked {
+        true  => Compression::None,
+        false => cfg.compression,
+    };
+    let tar = create_tar(src, cfg.preserve, cfg.symlinks, &cfg.exclude, compression,
+                         cfg.spill_above, summary).await?;
+    let sum = archive_sum(&tar)?;
+    summary.phase("archive", t);
+    let mut t = Instant::now();
+    let stored = if chunked {
+        write_chunks(s, cfg, f, &tar, summary).await?;
+        Vec::new()
     } else {
-        write_remote_file(s, &tar, &cfg.tar).await?;
-    }
-    match hash {
+        let mut sealed = Buffer::new(cfg.spill_above);
+        encrypt_to(tar.reader(), cfg, &mut sealed).await?;
+        drop(tar);
+        summary.encrypted_bytes += sealed.len();
+        summary.phase("encrypt", t);
+        t = Instant::now();
+        write_archive(s, cfg, f, args, &sealed, summary).await?
+    };
+    let fmt = Format {
+        storage: cfg.storage,
+        compression: cfg.compression,
+        cipher: crypto::cipher(cfg),
+        armor: cfg.armor,
+    };
+    write_remote_file(s, &fmt.encode(), &f.format(), summary).await?;
+    match marker {
         Some(x) => {
-            let bytes: Vec<u8> = x.to_be_bytes().to_vec();
-            write_remote_file(s, &bytes, &cfg.tar_hash).await?;
+            let marker = Marker { archive: Some(sum), stored, ..x };
+            write_remote_file(s, &marker.encode(), &f.tar_hash, summary).await?;
+        }
+        None => {
+            // The old hash file's archive checksum would fail every pull.
+            summary.warn(format!("Error hashing {}; remote hash removed", f.dir.display()));
+            s.remove(std::slice::from_ref(&f.tar_hash)).await?;
         }
-        None => println!("Error hashing the sync folder."),
     }
+    summary.phase("upload", t);
     Ok(())
 }
 
+/// Push a folder without dropping the files deleted from it since the last
+/// push, for a mirror that doesn't propagate deletes: unpack the remote
+/// archive, put the folder over it, and push that. The files only the
+/// remote has are listed as deleted, so pulls leave them out, and the
+/// remote hash is the folder's own.
+async fn push_keeping_deleted(home: &Path, s: &mut impl RemoteStore, cfg: &Config, f: &Folder,
+                              args: &Args, summary: &mut Summary)
+-> Result<(), Box<dyn std::error::Error>> {
+    if ! remote_archive_exists(s, f).await? {
+        return push_remote(home, s, cfg, f, args, summary).await
+    }
+    let temp = scratch_dir(f, "push")?;
+    println!("Pulling {} from remote...", f.dir.display());
+    let mut deleted = read_deleted(s, cfg, f).await?;
+    let held = unpack_remote(s, cfg, f, temp.path(), &HashSet::new(), summary).await?;
+    println!("Pushing {} to remote...", f.dir.display());
+    let t = Instant::now();
+    let hash = hash_folder(home, cfg, f, cfg.hash, cfg.detect).await;
+    summary.phase("hash", t);
+    let tar = create_tar(&f.dir, cfg.preserve, cfg.symlinks, &cfg.exclude, Compression::None,
+                         cfg.spill_above, summary).await?;
+    let local = unpack_tar(&tar, temp.path(), cfg.preserve, cfg.unpack_limits, false,
+                           &HashSet::new()).await?;
+    // Files deleted before keep the time they were first found gone.
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    deleted.retain(|x, _| held.contains(x) && ! local.contains(x));
+    for x in held.difference(&local) {
+        deleted.entry(x.clone()).or_insert(now);
+    }
+    let marker = hash.map(|digest| Marker {
+        algorithm: cfg.hash,
+        detect: cfg.detect,
+        digest,
+        archive: None,
+        stored: Vec::new(),
+    });
+    push_dir(s, cfg, f, temp.path(), marker, args, summary).await?;
+    write_deleted(s, cfg, f, &deleted, summary).await?;
+    if let Err(e) = temp.close() {
+        summary.warn(format!("Error deleting temporary directory: {}", e));
+    }
+    Ok(())
+}
 
 /// Returns the path specified by the $HOME environmental variable, if set.
 async fn home_from_env() -> Option<PathBuf> {
@@ -223,117 +749,1926 @@ async fn home_from_env() -> Option<PathBuf> {
     Some(PathBuf::from(home_env))
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[clap(about, version, author)]
+#[clap(args_conflicts_with_subcommands(true), subcommand_negates_reqs(true))]
+#[clap(after_help("EXIT STATUS:\n    0  success, already up to date, or declined at a prompt\n    \
+                   1  failure\n    2  completed with warnings (listed in the summary)\n    \
+                   3  completed, but unison left conflicts unresolved\n    \
+                   4  network failure (SSH couldn't connect, or the connection failed)\n    \
+                   5  encryption failure (GPG couldn't encrypt or decrypt, or no key)\n    \
+                   6  configuration missing or invalid\n  \
+                   130  stopped by Ctrl-C or SIGTERM"))]
 struct Args {
-    #[clap(help("The configuration profile to use"))]
-    profile: String,
+    #[clap(subcommand)]
+    command: Option<Cmd>,
+    #[clap(help("The configuration profile to use, or one of its aliases; defaults to the \
+                 profile marked 'default = true'"))]
+    profile: Option<String>,
+    #[clap(short('a'), long("all"), takes_value(false), conflicts_with_all(&["profile", "daemon"]),
+           help("Sync every configured profile"))]
+    all: bool,
+    #[clap(short('j'), long("jobs"), value_name("N"), default_value("1"), requires("all"),
+           help("With --all, sync profiles on up to N hosts at a time"))]
+    jobs: usize,
     #[clap(short('p'), long("push"), takes_value(false), conflicts_with("pull"),
            help("Copy local to remote without syncing, overwriting remote if it exists"))]
     push: bool,
     #[clap(short('P'), long("pull"), takes_value(false), conflicts_with("push"),
            help("Copy remote to local without syncing, overwriting local if it exists"))]
     pull: bool,
+    #[clap(long("path"), value_name("PATH"), requires("pull"),
+           help("With --pull, fetch only this file or directory (needs storage = \"chunks\")"))]
+    path: Option<PathBuf>,
+    #[clap(long("create-remote"), takes_value(false), requires("push"),
+           help("With --push, create the remote directory without asking if it's missing"))]
+    create_remote: bool,
     #[clap(short('y'), long("assume-yes"), takes_value(false),
            help("Assume yes to all prompts and run with no interaction"))]
     assumeyes: bool,
+    #[clap(short('n'), long("assume-no"), takes_value(false), conflicts_with("assumeyes"),
+           help("Assume no to all prompts, declining anything that would overwrite data"))]
+    assumeno: bool,
+    #[clap(long("wait"), takes_value(false),
+           help("If the profile is already being synced, wait for that run instead of failing"))]
+    wait: bool,
+    #[clap(long("json"), takes_value(false),
+           help("Print the summary at the end of each run as a line of JSON"))]
+    json: bool,
     #[clap(short('s'), long("scp-write"), takes_value(false),
            help("Write remote files using scp (shows progress)"))]
     scpwrite: bool,
+    #[clap(short('d'), long("daemon"), takes_value(false), conflicts_with_all(&["push", "pull"]),
+           help("Keep running and sync on the profile's 'interval' or 'cron' schedule"))]
+    daemon: bool,
+    #[clap(short('c'), long("config"), global(true), value_name("PATH"),
+           help("Read the configuration from this file instead of the default locations \
+                 (TOML, or YAML or JSON by extension)"))]
+    config: Option<PathBuf>,
+    #[clap(long("known-hosts"), value_name("POLICY"),
+           possible_values(&["strict", "accept-new", "off"]),
+           help("How to check the remote host key, overriding the profile's 'known_hosts'"))]
+    known_hosts: Option<String>,
+    #[cfg(feature = "peer")]
+    #[clap(long("peer"), value_name("HOST[:PORT]"), conflicts_with_all(&["all", "daemon"]),
+           help("Sync with another machine running `mist peer` instead of the remote; \
+                 with mDNS, its host name will do. The token it shows is read from \
+                 MIST_PEER_TOKEN, or asked for"))]
+    peer: Option<String>,
 }
 
-/// Ask for user confirmation, return true if confirmation recieved or false if not.
-fn user_confirm(prompt: &str, assume_yes: bool) -> bool {
-    if assume_yes {
-        return true
+impl Args {
+    /// How to answer prompts the profile has no standing answer for.
+    fn assume(&self) -> Answer {
+        match (self.assumeyes, self.assumeno) {
+            (true, _) => Answer::Yes,
+            (_, true) => Answer::No,
+            _ => Answer::Ask,
+        }
     }
-    println!("{}", prompt);
-    let mut inpt = String::new();
-    stdin().read_line(&mut inpt).expect("Failed to read line");
-    matches!(inpt.trim(), "y" | "Y" | "yes")
 }
 
-/// Hash the metadata of the contents of a directory.
-async fn hash_metadata(path: &Path) -> Option<u64> {
-    let mut hash = XxHash64::with_seed(42);
-    for e in WalkDir::new(path)
-        .sort_by_file_name()
-        .into_iter().filter_map(|e| e.ok()) {
+#[derive(Subcommand, Debug, Clone)]
+enum Cmd {
+    #[clap(about("Generate systemd user units that sync a profile on a timer"))]
+    Systemd {
+        #[clap(help("The configuration profile to sync"))]
+        profile: String,
+        #[clap(short('i'), long("interval"), default_value("1h"),
+               help("How often to sync, as a systemd time span (e.g. 15min, 1h)"))]
+        interval: String,
+        #[clap(long("install"), takes_value(false),
+               help("Write the units to ~/.config/systemd/user instead of printing them"))]
+        install: bool,
+    },
+    #[clap(about("Check a profile's configuration, GPG key, SSH access and tools"))]
+    Doctor {
+        #[clap(help("The configuration profile to check"))]
+        profile: String,
+    },
+    #[clap(about("List the files stored on the remote for a profile, with sizes and dates"))]
+    List {
+        #[clap(help("The configuration profile to list"))]
+        profile: String,
+    },
+    #[clap(about("Download a profile's remote archives and check them against their hashes"))]
+    Verify {
+        #[clap(help("The configuration profile to verify"))]
+        profile: String,
+        #[clap(long("remote"), takes_value(false),
+               help("Checksum the archives on the remote instead of downloading them, against \
+                     the checksums recorded when they were pushed"))]
+        remote: bool,
+    },
+    #[clap(about("Delete remote chunks that no chunk index refers to any more"))]
+    Gc {
+        #[clap(help("The configuration profile to clean up"))]
+        profile: String,
+        #[clap(long("grace"), default_value("1h"), value_name("SPAN"),
+               help("Keep unreferenced chunks younger than this, e.g. 30m, 1h, 2d"))]
+        grace: String,
+        #[clap(long("dry-run"), takes_value(false),
+               help("List the chunks that would be removed without removing them"))]
+        dry_run: bool,
+    },
+    #[clap(about("List the files a mirror keeps on the remote after they were deleted locally"))]
+    Deleted {
+        #[clap(help("The configuration profile to list"))]
+        profile: String,
+        #[clap(long("purge"), takes_value(false),
+               help("Remove them from the remote archive for good"))]
+        purge: bool,
+        #[clap(long("older-than"), value_name("SPAN"),
+               help("Only those deleted at least this long ago, e.g. 12h, 30d"))]
+        older_than: Option<String>,
+    },
+    #[clap(about("Show a profile's recent runs, with sizes and throughput (needs stats = true)"))]
+    History {
+        #[clap(help("The configuration profile to show"))]
+        profile: String,
+        #[clap(short('n'), long("count"), default_value("20"),
+               help("How many of the most recent runs to show"))]
+        count: usize,
+    },
+    #[clap(about("Show a profile's recent runs from every machine that syncs it"))]
+    Log {
+        #[clap(help("The configuration profile to show"))]
+        profile: String,
+        #[clap(short('n'), long("count"), default_value("20"),
+               help("How many of the most recent runs to show"))]
+        count: usize,
+    },
+    #[clap(about("Print one file from a profile's remote archive"))]
+    Cat {
+        #[clap(help("The configuration profile to read from"))]
+        profile: String,
+        #[clap(help("The file, relative to the sync folder"))]
+        path: PathBuf,
+        #[clap(short('o'), long("output"), value_name("FILE"),
+               help("Write the file here instead of to standard output"))]
+        output: Option<PathBuf>,
+    },
+    #[clap(about("List the times a profile keeps an earlier version of a file from"))]
+    Versions {
+        #[clap(help("The configuration profile to read from"))]
+        profile: String,
+        #[clap(help("The file, relative to the sync folder; without one, list the versions"))]
+        path: Option<PathBuf>,
+    },
+    #[clap(about("Show the files that changed between two of a profile's kept versions"))]
+    Diff {
+        #[clap(help("The configuration profile to compare"))]
+        profile: String,
+        #[clap(help("The earlier time, e.g. 2024-05-01 or 2024-05-01T12:00"))]
+        from: String,
+        #[clap(default_value("now"), help("The later time"))]
+        to: String,
+    },
+    #[clap(about("Save a profile's encrypted remote archive to a local file"))]
+    Export {
+        #[clap(help("The configuration profile to export"))]
+        profile: String,
+        #[clap(help("The file to write, or a directory to write a file for each folder into"))]
+        dest: PathBuf,
+    },
+    #[clap(about("Unpack an encrypted archive, e.g. from `mist export`, into a profile's folder \
+                  and push it"))]
+    Import {
+        #[clap(help("The configuration profile to import into"))]
+        profile: String,
+        #[clap(help("The archive, or the directory `mist export` wrote one for each folder into"))]
+        source: PathBuf,
+    },
+    #[cfg(feature = "fuse")]
+    #[clap(about("Mount a profile's remote archives read-only, to browse and copy files from"))]
+    Mount {
+        #[clap(help("The configuration profile to mount"))]
+        profile: String,
+        #[clap(help("The empty directory to mount it on"))]
+        mountpoint: PathBuf,
+        #[clap(long("at"), value_name("TIME"),
+               help("Mount the kept versions from this time, e.g. 2024-05-01T12:00, instead of \
+                     the latest"))]
+        at: Option<String>,
+    },
+    #[clap(about("Put a file back as it was at an earlier time"))]
+    Restore {
+        #[clap(help("The configuration profile to read from"))]
+        profile: String,
+        #[clap(value_name("PATH@TIME"),
+               help("The file, relative to the sync folder, and the time, e.g. 2024-05-01T12:00"))]
+        spec: String,
+        #[clap(short('o'), long("output"), value_name("FILE"),
+               help("Write the file here instead of into the sync folder"))]
+        output: Option<PathBuf>,
+    },
+    #[cfg(feature = "keychain")]
+    #[clap(about("Store a profile's symmetric passphrase in the OS keychain"))]
+    Keychain {
+        #[clap(help("The configuration profile the passphrase is for"))]
+        profile: String,
+        #[clap(long("delete"), takes_value(false),
+               help("Remove the stored passphrase instead"))]
+        delete: bool,
+    },
+    #[clap(about("List, show and edit the profiles in the configuration file"))]
+    Config {
+        #[clap(subcommand)]
+        action: ConfigCmd,
+    },
+    #[cfg(feature = "serve")]
+    #[clap(about("Serve a directory as a remote for http_url profiles"))]
+    Serve {
+        #[clap(help("The directory to keep the files in, one directory per user"))]
+        root: PathBuf,
+        #[clap(short('l'), long("listen"), default_value("0.0.0.0:8443"), value_name("ADDR"),
+               help("The address and port to listen on"))]
+        listen: String,
+        #[clap(long("tokens"), value_name("FILE"),
+               help("The users' bearer tokens, a line of \"<user> <token>\" for each"))]
+        tokens: PathBuf,
+        #[clap(long("tls-cert"), value_name("FILE"), requires("tls-key"),
+               help("Serve HTTPS with this PEM certificate chain"))]
+        tls_cert: Option<PathBuf>,
+        #[clap(long("tls-key"), value_name("FILE"), requires("tls-cert"),
+               help("The PEM private key for --tls-cert"))]
+        tls_key: Option<PathBuf>,
+    },
+    #[cfg(feature = "peer")]
+    #[clap(about("Sync a profile with another machine on the network, without the remote"))]
+    Peer {
+        #[clap(help("The configuration profile to sync"))]
+        profile: String,
+        #[clap(short('l'), long("listen"), value_name("ADDR"),
+               help("The address and port to wait for the other machine on, over plain \
+                     HTTP; by default, this machine's address on the network, port 8444"))]
+        listen: Option<String>,
+    },
+    #[cfg(feature = "mdns")]
+    #[clap(about("List the mist servers and peers on the local network"))]
+    Pair,
+    #[cfg(feature = "tui")]
+    #[clap(about("Show a dashboard of profiles, from which they can be synced"))]
+    Tui,
+    #[clap(about("Print a shell completion script"))]
+    Completions {
+        #[clap(arg_enum, help("The shell to generate completions for"))]
+        shell: Shell,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ConfigCmd {
+    #[clap(about("List the profiles with their folders and aliases"))]
+    List,
+    #[clap(about("Print a profile's entries, including those from [defaults]"))]
+    Show {
+        #[clap(help("The configuration profile to show"))]
+        profile: String,
+    },
+    #[clap(about("Set an entry of a profile, creating the profile if needed"))]
+    Set {
+        #[clap(help("The configuration profile to change, or 'defaults'"))]
+        profile: String,
+        #[clap(help("The entry to set, e.g. ssh_address"))]
+        key: String,
+        #[clap(help("The value; read as TOML (22, true, [\"a\", \"b\"]) or else as a string"))]
+        value: String,
+    },
+    #[clap(about("Remove a profile from the configuration file"))]
+    Remove {
+        #[clap(help("The configuration profile to remove"))]
+        profile: String,
+    },
+}
+
+/// Fill in the key to encrypt with when the profile doesn't set `gpg_id`:
+/// the one chosen on an earlier run, or else gpg's default key, once the
+/// user has confirmed it. Symmetric encryption needs no key.
+#[cfg(feature = "gpg")]
+fn select_gpg_key(home: &Path, profile: &str, cfg: &mut Config, assume: Answer)
+-> Result<(), Box<dyn std::error::Error>> {
+    if cfg.gpg_id.is_some() || cfg.symmetric || cfg.encryption != Encryption::Gpg {
+        return Ok(())
+    }
+    if let Some(x) = keys::stored_key(home, profile) {
+        cfg.gpg_id = Some(x);
+        return Ok(())
+    }
+    let key = keys::default_key(home, &cfg.gpg_bin)?;
+    let q = format!("[{}] has no gpg_id: encrypt with {}?", profile, keys::describe(&key));
+    if ! prompt::confirm(&q, cfg.answers.gpg_key, assume) {
+        return Err("No GPG key to encrypt with; set 'gpg_id'".into())
+    }
+    let fpr = key.fingerprint().map_err(|_| "The key's fingerprint can't be read")?.to_string();
+    if let Err(e) = keys::store_key(home, profile, &fpr) {
+        println!("Warning: the chosen key wasn't saved, so you'll be asked again: {}", e);
+    }
+    cfg.gpg_id = Some(fpr);
+    Ok(())
+}
+
+/// Without gpg, profiles use the builtin encryption, which needs no key.
+#[cfg(not(feature = "gpg"))]
+fn select_gpg_key(_: &Path, _: &str, _: &mut Config, _: Answer)
+-> Result<(), Box<dyn std::error::Error>> {
+    Ok(())
+}
+
+/// Hash a folder's contents as `algorithm` and `detect` say, caching file
+/// hashes in the state directory.
+async fn hash_folder(home: &Path, cfg: &Config, f: &Folder, algorithm: HashAlgorithm,
+                     detect: Detect)
+-> Option<Vec<u8>> {
+    hash_metadata(&f.dir, algorithm, cfg.symlinks, &cfg.exclude, detect,
+                  Some(&state::state_dir(home))).await
+}
+
+/// Rewrite an up-to-date remote hash file using the profile's configured
+/// algorithm and detection, after the profile switched either.
+async fn migrate_hash(home: &Path, s: &mut impl RemoteStore, cfg: &Config, f: &Folder, old: &Marker,
+                      summary: &mut Summary)
+-> Result<(), Box<dyn std::error::Error>> {
+    let digest = match hash_folder(home, cfg, f, cfg.hash, cfg.detect).await {
+        Some(x) => x,
+        None => {
+            summary.warn(format!("Error hashing {}; remote hash not migrated", f.dir.display()));
+            return Ok(())
+        }
+    };
+    let marker = Marker { algorithm: cfg.hash, detect: cfg.detect, digest,
+                          archive: old.archive.clone(), stored: old.stored.clone() };
+    write_remote_file(s, &marker.encode(), &f.tar_hash, summary).await?;
+    println!("Migrated remote hash from {} ({}) to {} ({})", old.algorithm.name(),
+             old.detect.name(), cfg.hash.name(), cfg.detect.name());
+    Ok(())
+}
+
+/// Map each file under a directory to its size and modification time.
+async fn file_manifest(path: &Path) -> BTreeMap<PathBuf, (u64, Option<SystemTime>)> {
+    let mut files = BTreeMap::new();
+    for e in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
         if ! e.path().is_file() {
             continue
         }
-        let meta = e.metadata().ok()?;
-        e.path().file_name()?.hash(&mut hash);
-        meta.len().hash(&mut hash);
+        let meta = match e.metadata() {
+            Ok(x)  => x,
+            Err(_) => continue,
+        };
+        let rel = e.path().strip_prefix(path).unwrap_or_else(|_| e.path());
+        files.insert(rel.to_path_buf(), (meta.len(), meta.modified().ok()));
     }
-    Some(hash.finish())
+    files
 }
 
-async fn run_mist(home: &Path, cfg: &Config, args: &Args, s: &mut Session)
--> Result<(), Box<dyn std::error::Error>> {
+/// What a file in a manifest is matched on across a rename: its size and
+/// modification time to the second, as archives keep it.
+trait RenameKey {
+    fn rename_key(&self) -> Option<(u64, u64)>;
+}
+
+impl RenameKey for (u64, Option<SystemTime>) {
+    fn rename_key(&self) -> Option<(u64, u64)> {
+        let secs = self.1.as_ref()?.duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs();
+        Some((self.0, secs))
+    }
+}
+
+impl RenameKey for snapshot::State {
+    fn rename_key(&self) -> Option<(u64, u64)> {
+        match self {
+            snapshot::State::File { size, mtime } => (*mtime >= 0).then_some((*size, *mtime as u64)),
+            snapshot::State::Link(_) => None,
+        }
+    }
+}
+
+/// Count the files that were added, removed, or modified between two
+/// manifests into the summary. A file removed from one path and added at
+/// another with the same size and modification time (to the second, as
+/// archives keep it) was renamed, which counts as one change.
+fn count_changes<V: PartialEq + RenameKey>(before: &BTreeMap<PathBuf, V>,
+                                           after: &BTreeMap<PathBuf, V>, summary: &mut Summary) {
+    let changed = after
+        .iter()
+        .filter(|(k, v)| before.get(*k) != Some(v))
+        .count();
+    let added: Vec<_> = after
+        .iter()
+        .filter(|(k, _)| ! before.contains_key(*k))
+        .collect();
+    let removed: Vec<_> = before
+        .iter()
+        .filter(|(k, _)| ! after.contains_key(*k))
+        .collect();
+    let mut gone: HashMap<(u64, u64), usize> = HashMap::new();
+    for x in removed.iter().filter_map(|(_, v)| v.rename_key()) {
+        *gone.entry(x).or_default() += 1;
+    }
+    let renamed = added
+        .iter()
+        .filter_map(|(_, v)| v.rename_key())
+        .filter(|x| match gone.get_mut(x) {
+            Some(n) if *n > 0 => {
+                *n -= 1;
+                true
+            }
+            _ => false,
+        })
+        .count();
+    summary.files_changed += changed + removed.len() - renamed;
+    summary.files_added += added.len() - renamed;
+    summary.files_deleted += removed.len() - renamed;
+    summary.files_renamed += renamed;
+}
+
+/// Why uploading a folder now should wait for the profile's sync_hours, if
+/// it should. Only a daemon waits, and only for a folder bigger than
+/// sync_hours_above: without that, it doesn't start a sync outside them.
+async fn wait_for_hours(cfg: &Config, f: &Folder, args: &Args) -> Option<String> {
+    let hours = cfg.sync_hours.filter(|_| args.daemon)?;
+    let above = cfg.sync_hours_above?;
+    if hours.contains(&chrono::Local::now()) {
+        return None
+    }
+    let size: u64 = file_manifest(&f.dir).await.values().map(|(x, _)| x).sum();
+    match size > above {
+        true  => Some(format!("a {} upload waits for sync_hours, from {}",
+                              human_bytes(size), hours.opens())),
+        false => None,
+    }
+}
+
+/// Push, pull, or sync a single folder, returning how it ended.
+async fn run_folder(home: &Path, cfg: &Config, f: &Folder, args: &Args, s: &mut impl RemoteStore,
+                    summary: &mut Summary)
+-> Result<Outcome, Box<dyn std::error::Error>> {
     if args.push {
-        let tar_is = confirm_remote_exists(s, &cfg.tar).await.unwrap();
-        if tar_is && ! user_confirm("Remote storage exists: overwrite?",
-            args.assumeyes) {
-            return Ok(())
+        if cfg.mode == Mode::MirrorDown {
+            return Ok(Outcome::Aborted("the profile only pulls (mode = \"mirror-down\")".into()))
+        }
+        if let Some(why) = wait_for_hours(cfg, f, args).await {
+            return Ok(Outcome::Aborted(why))
+        }
+        if ! ensure_remote_dir(s, cfg, f, args).await? {
+            return Ok(Outcome::Aborted("remote directory not created".into()))
+        }
+        let tar_is = remote_archive_exists(s, f).await?;
+        // Measured against the last sync, so pushing nothing new counts
+        // nothing.
+        let before = snapshot::load(home, &summary.profile, &f.dir);
+        let q = format!("Remote storage for {} exists: overwrite?", f.dir.display());
+        // Replacing the remote is what a mirror is for.
+        if tar_is && cfg.mode != Mode::MirrorUp
+            && ! prompt::confirm(&q, cfg.answers.overwrite_remote, args.assume()) {
+            return Ok(Outcome::Aborted("remote overwrite declined".into()))
         }
-        push_remote(s, cfg, args).await?;
+        match tar_is && cfg.mode == Mode::MirrorUp && ! cfg.propagate_deletes {
+            true  => push_keeping_deleted(home, s, cfg, f, args, summary).await?,
+            false => push_remote(home, s, cfg, f, args, summary).await?,
+        }
+        count_changes(&before, &snapshot::scan(&f.dir, cfg), summary);
+    } else if let (true, Some(path)) = (args.pull, &args.path) {
+        let path = match path_in_folder(f, path) {
+            Some(x) => x,
+            None => return Ok(Outcome::Aborted("path isn't in this folder".into())),
+        };
+        let q = format!("Local {} exists: overwrite?", f.dir.join(&path).display());
+        if f.dir.join(&path).exists()
+            && ! prompt::confirm(&q, cfg.answers.overwrite_local, args.assume()) {
+            return Ok(Outcome::Aborted("local overwrite declined".into()))
+        }
+        let before = file_manifest(&f.dir).await;
+        if ! pull_path(s, cfg, f, &path, summary).await? {
+            return Ok(Outcome::Aborted(format!("{} isn't in the remote archive", path.display())))
+        }
+        count_changes(&before, &file_manifest(&f.dir).await, summary);
     } else if args.pull {
-        let dir_is = confirm_local_exists(home, &cfg.dir).await?;
-        if dir_is && ! user_confirm("Local directory exists: overwrite?",
-            args.assumeyes) {
-            return Ok(())
+        let dir_is = confirm_local_exists(home, &f.dir).await?;
+        let q = format!("Local directory {} exists: overwrite?", f.dir.display());
+        if dir_is && cfg.mode != Mode::MirrorDown
+            && ! prompt::confirm(&q, cfg.answers.overwrite_local, args.assume()) {
+            return Ok(Outcome::Aborted("local overwrite declined".into()))
+        }
+        let before = file_manifest(&f.dir).await;
+        let paths = pull_remote(s, cfg, f, &f.dir, summary).await?;
+        if cfg.mode == Mode::MirrorDown && cfg.propagate_deletes {
+            prune(&f.dir, &paths, cfg.symlinks, &cfg.exclude)?;
         }
-        pull_remote(s, cfg, false).await?;
+        count_changes(&before, &file_manifest(&f.dir).await, summary);
     } else {
-        let far_hash = read_remote_file(s, &cfg.tar_hash).await.ok();
-        let near_hash = hash_metadata(&cfg.dir).await;
-        if far_hash.is_some() && near_hash.is_some() {
-            let near_hash = near_hash
-                .unwrap()
-                .to_be_bytes();
-            let far_hash = far_hash
-                .unwrap();
-            if far_hash == near_hash {
-                println!("Already up to date");
-                return Ok(())
+        let far = s.read(&f.tar_hash).await.ok();
+        let far_hash = far.as_deref().and_then(Marker::decode);
+        if far.is_some() && far_hash.is_none() {
+            // Likely an algorithm this version doesn't know; without a
+            // warning, every run would merge for no visible reason.
+            summary.warn(format!("Remote hash file {} is damaged or from a newer mist, so {} \
+                                  can't be found up to date; update mist on this machine",
+                                 f.tar_hash, f.dir.display()));
+        }
+        if let Some(far_hash) = far_hash {
+            // Compare the way the remote hash was written, so switching
+            // algorithm or detection doesn't look like a change.
+            let t = Instant::now();
+            let near_hash = hash_folder(home, cfg, f, far_hash.algorithm, far_hash.detect).await;
+            summary.phase("hash", t);
+            if near_hash.as_ref() == Some(&far_hash.digest) {
+                if far_hash.algorithm != cfg.hash || far_hash.detect != cfg.detect {
+                    migrate_hash(home, s, cfg, f, &far_hash, summary).await?;
+                }
+                return Ok(Outcome::UpToDate)
+            }
+        }
+        if let Some(why) = wait_for_hours(cfg, f, args).await {
+            return Ok(Outcome::Aborted(why))
+        }
+        private_temp(&f.temp)?;
+        let temp = LocalDir::new(&f.temp);
+        pull_remote(s, cfg, f, temp.path(), summary).await?;
+        let before = file_manifest(&f.dir).await;
+        let t = Instant::now();
+        let merged = merge::run(home, &summary.profile, f, args.assume() != Answer::Ask, cfg)?;
+        summary.phase("merge", t);
+        if merged != Merge::Clean {
+            if merged == Merge::Conflicts {
+                summary.unresolved_conflicts = true;
+            }
+            let q = match merged {
+                Merge::Conflicts => format!("{} left conflicts. Transfer to remote anyway?",
+                                            cfg.merge_tool.name()),
+                _ => format!("{} may have produced an error. Transfer to remote anyway?",
+                             cfg.merge_tool.name()),
+            };
+            if ! prompt::confirm(&q, cfg.answers.push_after_error, args.assume()) {
+                return Ok(Outcome::Aborted("transfer to remote declined".into()))
+            }
+            if let Merge::Failed(code) = merged {
+                summary.warn(format!("{} exited with {:?}", cfg.merge_tool.name(), code));
+            }
+        }
+        count_changes(&before, &file_manifest(&f.dir).await, summary);
+        push_remote(home, s, cfg, f, args, summary).await?;
+        if let Err(e) = temp.close() {
+            summary.warn(format!("Error deleting temporary directory: {}", e));
+        }
+    }
+    Ok(Outcome::Completed)
+}
+
+/// Get one file from a folder's remote archive, downloading only the
+/// chunks that hold it if the folder is stored as chunks.
+async fn cat_file(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, path: &Path,
+                  summary: &mut Summary)
+-> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    let fmt = read_format(s, f).await?;
+    if fmt.storage == Storage::Chunks {
+        let index = read_chunk_index(s, cfg, f).await?;
+        let tar = match index.entries.is_empty() {
+            true  => read_chunks(s, cfg, f, &fmt, summary).await?,
+            false => read_ranges(s, cfg, f, &fmt, &index, &index.ranges(path), summary)
+                .await?
+                .into(),
+        };
+        return Ok(extract_file(&tar, path, false)?)
+    }
+    let mut tar = Buffer::new(cfg.spill_above);
+    decrypt_from(&read_archive(s, cfg, f).await?, cfg, &mut tar).await?;
+    Ok(extract_file(&tar, path, fmt.compression.is_gzip())?)
+}
+
+/// Write one file from the profile's remote archives to `output`, or to
+/// standard output, without unpacking anything.
+async fn cat(profile: &str, cfg: &Config, path: &Path, output: Option<&Path>)
+-> Result<(), Box<dyn std::error::Error>> {
+    let mut s = remote::connect(cfg).await?;
+    let mut summary = Summary::new(profile, Direction::Pull);
+    let mut found = None;
+    for f in &cfg.folders {
+        if let Some(rel) = path_in_folder(f, path) {
+            found = cat_file(&mut s, cfg, f, &rel, &mut summary).await?;
+            if found.is_some() {
+                break
             }
         }
-        pull_remote(s, cfg, true).await?;
-        match unison(&cfg.dir, &cfg.temp, args.assumeyes).await? {
-            true  => (),
-            false => {
-                let q = "Unison may have produced an error. Transfer to remote anyway?";
-                if ! user_confirm(q, args.assumeyes) {
-                    return Ok(())
+    }
+    close_session(Some(s)).await;
+    let data = found.ok_or(format!("{} isn't a file in the remote archive", path.display()))?;
+    match output {
+        Some(x) => std::fs::write(x, data)?,
+        None => std::io::stdout().write_all(&data)?,
+    }
+    Ok(())
+}
+
+/// Where `path` is relative to the folder: absolute paths must be inside
+/// it, and relative ones are taken to be relative to it already.
+fn path_in_folder(f: &Folder, path: &Path) -> Option<PathBuf> {
+    match path.is_absolute() {
+        true  => path.strip_prefix(&f.dir).ok().map(|x| x.to_path_buf()),
+        false => Some(path.to_path_buf()),
+    }
+}
+
+/// Download a folder's remote archive, unpack it to a scratch directory
+/// beside the temporary folder, and check what comes out against the
+/// remote hash file. The sync folder isn't touched.
+async fn verify_folder(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, summary: &mut Summary)
+-> Result<(), Box<dyn std::error::Error>> {
+    let marker = s.read(&f.tar_hash)
+        .await
+        .ok()
+        .and_then(|x| Marker::decode(&x))
+        .ok_or(format!("Remote hash file {} is missing or unreadable", f.tar_hash))?;
+    let scratch = scratch_dir(f, "verify")?;
+    let dest = scratch.path().to_path_buf();
+    let digest: Result<Option<Vec<u8>>, Box<dyn std::error::Error>> = async {
+        pull_remote(s, cfg, f, &dest, summary).await?;
+        // No cache: every file is read, and nothing is kept about the scratch copy.
+        Ok(hash_metadata(&dest, marker.algorithm, cfg.symlinks, &cfg.exclude, marker.detect,
+                         None).await)
+    }.await;
+    if let Err(e) = scratch.close() {
+        summary.warn(format!("Error deleting {}: {}", dest.display(), e));
+    }
+    match digest? {
+        Some(x) if x == marker.digest => Ok(()),
+        Some(_) => Err("Archive contents don't match the remote hash".into()),
+        None => Err("Error hashing the unpacked archive".into()),
+    }
+}
+
+/// Check a folder's remote archive where it's stored, against the
+/// checksums its hash file recorded when it was pushed, so damage on the
+/// remote shows without downloading anything.
+async fn verify_stored(s: &mut impl RemoteStore, f: &Folder)
+-> Result<(), Box<dyn std::error::Error>> {
+    if read_format(s, f).await?.storage == Storage::Chunks {
+        return Err("Folders stored as chunks can only be verified by downloading them".into())
+    }
+    let marker = s.read(&f.tar_hash)
+        .await
+        .ok()
+        .and_then(|x| Marker::decode(&x))
+        .ok_or(format!("Remote hash file {} is missing or unreadable", f.tar_hash))?;
+    if marker.stored.is_empty() {
+        return Err("The remote hash file has no checksums of the stored archive; they're \
+                    recorded from the next push".into())
+    }
+    for (path, sum) in &marker.stored {
+        match s.checksum(path).await? {
+            Some(x) if x == *sum => (),
+            Some(_) => return Err(format!("{} doesn't match the checksum recorded when it was \
+                                           pushed", path).into()),
+            None => return Err("The remote can't compute checksums; verify without --remote"
+                               .into()),
+        }
+    }
+    Ok(())
+}
+
+/// Check that each of a profile's remote archives downloads, decrypts and
+/// unpacks, and matches its hash file; or with `stored`, that the archives
+/// on the remote are as they were pushed.
+async fn verify(profile: &str, cfg: &Config, stored: bool)
+-> Result<(), Box<dyn std::error::Error>> {
+    let mut s = remote::connect(cfg).await?;
+    let mut summary = Summary::new(profile, Direction::Pull);
+    let mut failed = 0;
+    for f in &cfg.folders {
+        let res = match stored {
+            true  => verify_stored(&mut s, f).await,
+            false => verify_folder(&mut s, cfg, f, &mut summary).await,
+        };
+        match res {
+            Ok(()) => println!("{}: OK", f.dir.display()),
+            Err(e) => {
+                println!("{}: FAILED: {}", f.dir.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    close_session(Some(s)).await;
+    for w in &summary.warnings {
+        println!("Warning: {}", w);
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(format!("{} of {} folders failed verification", n, cfg.folders.len()).into()),
+    }
+}
+
+/// The times of the folder's kept versions, newest first.
+async fn version_times(s: &mut impl RemoteStore, f: &Folder)
+-> Result<Vec<u64>, Box<dyn std::error::Error>> {
+    if ! s.is_dir(&f.version_dir()).await? {
+        return Ok(Vec::new())
+    }
+    let mut times: Vec<u64> = s.list(&f.version_dir())
+        .await?
+        .into_iter()
+        .filter_map(|x| x.name.parse().ok())
+        .collect();
+    times.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(times)
+}
+
+/// Read and decrypt the chunk index of the folder's version from `time`.
+async fn read_version(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, time: u64)
+-> Result<Index, Box<dyn std::error::Error>> {
+    let index = decrypt(&s.read(&f.version(time)).await?, cfg).await?;
+    Ok(Index::decode(&index).ok_or(format!("Remote version {} is corrupt", f.version(time)))?)
+}
+
+/// Keep a copy of the chunk index just pushed (`index`, encrypted) as a
+/// version of the folder, then remove the versions the profile no longer
+/// keeps: all the older ones if the chunks were just written afresh
+/// (`replaced`), as they were stored in a way that can't be read now.
+async fn save_version(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, index: &[u8],
+                      replaced: bool, summary: &mut Summary)
+-> Result<(), Box<dyn std::error::Error>> {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+    s.create_dir(&f.version_dir()).await?;
+    if ! write_remote_file(s, index, &f.version(now), summary).await? {
+        return Err(format!("Uploading version {} failed", f.version(now)).into())
+    }
+    let expired: Vec<String> = version_times(s, f)
+        .await?
+        .into_iter()
+        .enumerate()
+        .filter(|&(i, time)| i > 0 && (replaced
+            || cfg.keep_versions.is_some_and(|n| i >= n)
+            || cfg.keep_versions_for.is_some_and(|x| time + x.as_secs() < now)))
+        .map(|(_, time)| f.version(time))
+        .collect();
+    if replaced && ! expired.is_empty() {
+        summary.warn(format!("{} earlier versions of {} were removed, as they're stored in the \
+                              old format", expired.len(), f.dir.display()));
+    }
+    if let Err(e) = s.remove(&expired).await {
+        summary.warn(format!("Expired versions weren't removed: {}", e));
+    }
+    Ok(())
+}
+
+/// Chunk ids with a byte range of each.
+type Slices = Vec<(String, u64, u64)>;
+
+/// Which chunks, and where in them, hold `path` in an index: the same for
+/// two versions where the file didn't change, as nearly always.
+fn chunks_holding(index: &Index, path: &Path) -> Slices {
+    index.ranges(path)
+        .into_iter()
+        .flat_map(|(start, end)| index.chunks_in(start, end)
+            .into_iter()
+            .map(move |(i, offset)| (i, start.saturating_sub(offset), end - offset)))
+        .map(|(i, from, to)| (index.chunks[i].id.clone(), from, to.min(index.chunks[i].len)))
+        .collect()
+}
+
+/// Format a time in seconds since 1970 the way `mist restore` takes it.
+fn version_name(time: u64) -> String {
+    chrono::Local.timestamp_opt(time as i64, 0)
+        .single()
+        .map(|x| x.format("%Y-%m-%dT%H:%M:%S").to_string())
+        .unwrap_or_else(|| time.to_string())
+}
+
+/// Parse the time of `mist restore PATH@TIME` or `mist diff`: a date, which
+/// means the end of that day, a date and time to the minute or second,
+/// seconds since 1970, or "now".
+fn parse_version_time(s: &str) -> Option<u64> {
+    use chrono::{ NaiveDate, NaiveDateTime };
+    if s == "now" {
+        return Some(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).ok()?.as_secs())
+    }
+    if let Ok(x) = s.parse() {
+        return Some(x)
+    }
+    let t = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M"))
+        .or_else(|_| NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|x| x.and_hms_opt(23, 59, 59)
+            .expect("a valid time")))
+        .ok()?;
+    Some(chrono::Local.from_local_datetime(&t).earliest()?.timestamp() as u64)
+}
+
+/// The folder `path` is in that's stored as chunks with versions, with
+/// `path` relative to it.
+async fn versioned_folder<'a>(s: &mut impl RemoteStore, cfg: &'a Config, path: &Path)
+-> Result<(&'a Folder, PathBuf), Box<dyn std::error::Error>> {
+    for f in &cfg.folders {
+        if let Some(rel) = path_in_folder(f, path) {
+            if ! version_times(s, f).await?.is_empty() {
+                return Ok((f, rel))
+            }
+        }
+    }
+    Err(format!("No versions are kept of {} (set keep_versions with storage = \"chunks\")",
+                path.display()).into())
+}
+
+/// The newest of the folder's versions pushed by `time`.
+async fn version_at(s: &mut impl RemoteStore, f: &Folder, time: u64)
+-> Result<u64, Box<dyn std::error::Error>> {
+    Ok(version_times(s, f)
+        .await?
+        .into_iter()
+        .find(|x| *x <= time)
+        .ok_or(format!("No version of {} is that old", f.dir.display()))?)
+}
+
+/// The files of a version and their sizes, with the chunks holding each
+/// to tell whether it changed.
+type VersionFiles<'a> = BTreeMap<&'a Path, (u64, Slices)>;
+
+/// The files of the version from `time`, whose chunk index is `index`.
+fn version_files(index: &Index, time: u64)
+-> Result<VersionFiles<'_>, Box<dyn std::error::Error>> {
+    if ! index.entries.is_empty() && index.entries.iter().all(|x| x.size.is_none()) {
+        return Err(format!("The version from {} was pushed by an older mist, which didn't \
+                            record file sizes", version_name(time)).into())
+    }
+    Ok(index.entries
+        .iter()
+        .filter_map(|x| Some((x.path.as_path(), (x.size?, chunks_holding(index, &x.path)))))
+        .collect())
+}
+
+/// Print each of a profile's kept versions, with how many files it has
+/// and their size.
+async fn list_versions(s: &mut impl RemoteStore, profile: &str, cfg: &Config)
+-> Result<(), Box<dyn std::error::Error>> {
+    for f in &cfg.folders {
+        let times = version_times(s, f).await?;
+        println!("[{}] {}: {} versions", profile, f.dir.display(), times.len());
+        for time in times {
+            let index = read_version(s, cfg, f, time).await?;
+            let sizes: Vec<u64> = index.entries.iter().filter_map(|x| x.size).collect();
+            println!("  {}  {:>7} files  {:>10}", version_name(time), sizes.len(),
+                     human_bytes(sizes.iter().sum()));
+        }
+    }
+    Ok(())
+}
+
+/// List the times from which a profile keeps a different version of
+/// `path`, newest first, or without one, the versions themselves.
+async fn versions(profile: &str, cfg: &Config, path: Option<&Path>)
+-> Result<(), Box<dyn std::error::Error>> {
+    let mut s = remote::connect(cfg).await?;
+    let res: Result<(), Box<dyn std::error::Error>> = async {
+        let path = match path {
+            Some(x) => x,
+            None => return list_versions(&mut s, profile, cfg).await,
+        };
+        let (f, rel) = versioned_folder(&mut s, cfg, path).await?;
+        // A run of versions with the file unchanged is shown as its oldest.
+        let mut shown: Vec<(u64, Slices)> = Vec::new();
+        for time in version_times(&mut s, f).await? {
+            let held = chunks_holding(&read_version(&mut s, cfg, f, time).await?, &rel);
+            match shown.last_mut() {
+                Some(x) if x.1 == held => x.0 = time,
+                _ => shown.push((time, held)),
+            }
+        }
+        println!("[{}] {}:", profile, f.dir.join(&rel).display());
+        for (i, (time, held)) in shown.iter().enumerate() {
+            let note = match (i, held.is_empty()) {
+                (_, true) => "  (absent)",
+                (0, false) => "  (current)",
+                _ => "",
+            };
+            println!("  {}{}", version_name(*time), note);
+        }
+        Ok(())
+    }.await;
+    close_session(Some(s)).await;
+    res
+}
+
+/// Put `path` back as it was at the time in `spec` ("PATH@TIME"), from the
+/// newest version pushed by then, into the folder or to `output`.
+async fn restore(profile: &str, cfg: &Config, args: &Args, spec: &str, output: Option<&Path>)
+-> Result<(), Box<dyn std::error::Error>> {
+    let (path, time) = spec.rsplit_once('@')
+        .ok_or(format!("Expected PATH@TIME, e.g. notes/todo.md@2024-05-01T12:00, not '{}'", spec))?;
+    let time = parse_version_time(time)
+        .ok_or(format!("Invalid time '{}': expected e.g. 2024-05-01, 2024-05-01T12:00 or \
+                        2024-05-01T12:00:30", time))?;
+    let mut s = remote::connect(cfg).await?;
+    let mut summary = Summary::new(profile, Direction::Pull);
+    let res: Result<(), Box<dyn std::error::Error>> = async {
+        let (f, rel) = versioned_folder(&mut s, cfg, Path::new(path)).await?;
+        let version = version_at(&mut s, f, time).await?;
+        let index = read_version(&mut s, cfg, f, version).await?;
+        let ranges = index.ranges(&rel);
+        if ranges.is_empty() {
+            return Err(format!("{} isn't in the version from {}", rel.display(),
+                               version_name(version)).into())
+        }
+        let fmt = read_format(&mut s, f).await?;
+        let tar = Buffer::from(read_ranges(&mut s, cfg, f, &fmt, &index, &ranges, &mut summary)
+                                   .await?);
+        if let Some(x) = output {
+            let data = extract_file(&tar, &rel, false)?
+                .ok_or(format!("{} isn't a file", rel.display()))?;
+            std::fs::write(x, data)?;
+            return Ok(())
+        }
+        let dest = f.dir.join(&rel);
+        let q = format!("Local {} exists: overwrite?", dest.display());
+        if dest.exists() && ! prompt::confirm(&q, cfg.answers.overwrite_local, args.assume()) {
+            return Err("Local overwrite declined".into())
+        }
+        unpack_tar(&tar, &f.dir, cfg.preserve, cfg.unpack_limits, false, &HashSet::new())
+            .await?;
+        println!("Restored {} from the version from {}", dest.display(), version_name(version));
+        Ok(())
+    }.await;
+    close_session(Some(s)).await;
+    res
+}
+
+/// Where `mist export` writes a folder's archive and `mist import` reads
+/// it: `path` itself, or if that's a directory, the file in it named like
+/// the remote archive, which a profile with several folders needs.
+fn export_path(cfg: &Config, f: &Folder, path: &Path)
+-> Result<PathBuf, Box<dyn std::error::Error>> {
+    if path.is_dir() {
+        let name = Path::new(&f.tar).file_name().ok_or(format!("Invalid archive name {}", f.tar))?;
+        return Ok(path.join(name))
+    }
+    if cfg.folders.len() > 1 {
+        return Err(format!("The profile has {} folders, so {} has to be a directory",
+                           cfg.folders.len(), path.display()).into())
+    }
+    Ok(path.to_path_buf())
+}
+
+/// Write the folder's remote archive to `file`, encrypted, as one file
+/// that `mist import` takes back. An archive is copied as it's stored;
+/// chunks are joined, compressed as the profile's are, and encrypted anew.
+async fn export_folder(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, file: &Path,
+                       summary: &mut Summary)
+-> Result<(), Box<dyn std::error::Error>> {
+    let fmt = read_format(s, f).await?;
+    let bytes = match fmt.storage {
+        Storage::Archive => read_archive(s, cfg, f).await?,
+        Storage::Chunks => {
+            let tar = read_chunks(s, cfg, f, &fmt, summary).await?;
+            let tar = match fmt.compression.is_gzip() {
+                true  => Buffer::from(compress(&tar.into_vec()?)?),
+                false => tar,
+            };
+            let mut b = Buffer::new(cfg.spill_above);
+            encrypt_to(tar.reader(), cfg, &mut b).await?;
+            b
+        }
+    };
+    std::io::copy(&mut bytes.reader(), &mut std::fs::File::create(file)?)?;
+    println!("Exported {} to {} ({})", f.dir.display(), file.display(),
+             human_bytes(bytes.len()));
+    Ok(())
+}
+
+/// Save each of a profile's remote archives to a local file, e.g. to keep
+/// on cold storage or carry to a machine without access to the remote.
+async fn export(profile: &str, cfg: &Config, args: &Args, dest: &Path)
+-> Result<(), Box<dyn std::error::Error>> {
+    let mut s = remote::connect(cfg).await?;
+    let mut summary = Summary::new(profile, Direction::Pull);
+    let res: Result<(), Box<dyn std::error::Error>> = async {
+        for f in &cfg.folders {
+            let file = export_path(cfg, f, dest)?;
+            let q = format!("{} exists: overwrite?", file.display());
+            if file.exists() && ! prompt::confirm(&q, cfg.answers.overwrite_local, args.assume()) {
+                return Err("Local overwrite declined".into())
+            }
+            export_folder(&mut s, cfg, f, &file, &mut summary).await?;
+        }
+        Ok(())
+    }.await;
+    close_session(Some(s)).await;
+    res
+}
+
+/// Decrypt `file`, a tar archive, gzip-compressed or not, encrypted the way
+/// the profile encrypts, and unpack it into the folder. Files the archive
+/// doesn't have are left as they are, as with a pull.
+async fn import_folder(cfg: &Config, f: &Folder, file: &Path)
+-> Result<(), Box<dyn std::error::Error>> {
+    println!("Importing {} into {}...", file.display(), f.dir.display());
+    let mut bytes = Buffer::new(cfg.spill_above);
+    std::fs::File::open(file)
+        .and_then(|mut x| std::io::copy(&mut x, &mut bytes))
+        .map_err(|e| format!("Can't read {}: {}", file.display(), e))?;
+    let mut tar = Buffer::new(cfg.spill_above);
+    decrypt_from(&bytes, cfg, &mut tar).await?;
+    drop(bytes);
+    let mut magic = [0; 2];
+    let gzip = tar.read_at(0, &mut magic)? == 2 && magic == [0x1f, 0x8b];
+    std::fs::create_dir_all(&f.dir)?;
+    space::check_local(&f.dir, unpacked_size(&tar, gzip)?)?;
+    unpack_tar(&tar, &f.dir, cfg.preserve, cfg.unpack_limits, gzip, &HashSet::new()).await?;
+    Ok(())
+}
+
+/// Unpack archives, from `mist export` or made by hand, into a profile's
+/// folders and push the result, making them what both sides hold. Returns
+/// the push's exit status.
+async fn import(home: &Path, profile: &str, cfg: &Config, args: &Args, from: &Path)
+-> Result<i32, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    for f in &cfg.folders {
+        files.push(export_path(cfg, f, from)?);
+    }
+    for (f, file) in cfg.folders.iter().zip(&files) {
+        let q = format!("Unpack {} into {} and push the result to the remote, replacing what's \
+                         there?", file.display(), f.dir.display());
+        if ! prompt::confirm(&q, cfg.answers.overwrite_remote, args.assume()) {
+            return Err("Import declined".into())
+        }
+        import_folder(cfg, f, file).await?;
+    }
+    // Answered above.
+    let mut push = args.clone();
+    push.push = true;
+    push.assumeyes = true;
+    push.assumeno = false;
+    push.create_remote = true;
+    let mut s = None;
+    let summary = run_profile(home, profile, cfg, &push, &mut s).await;
+    close_session(s).await;
+    Ok(summary.exit_code())
+}
+
+/// A size change, with its sign.
+fn size_delta(old: u64, new: u64) -> String {
+    match new >= old {
+        true  => format!("+{}", human_bytes(new - old)),
+        false => format!("-{}", human_bytes(old - new)),
+    }
+}
+
+/// Print the files added, removed and modified between the versions of
+/// each of a profile's folders pushed by the times `from` and `to`, from
+/// their chunk indexes alone.
+async fn diff(profile: &str, cfg: &Config, from: &str, to: &str)
+-> Result<(), Box<dyn std::error::Error>> {
+    let parse = |x: &str| parse_version_time(x)
+        .ok_or(format!("Invalid time '{}': expected e.g. 2024-05-01, 2024-05-01T12:00 or \
+                        2024-05-01T12:00:30", x));
+    let (from, to) = (parse(from)?, parse(to)?);
+    let mut s = remote::connect(cfg).await?;
+    let res: Result<(), Box<dyn std::error::Error>> = async {
+        let mut compared = 0;
+        for f in &cfg.folders {
+            if version_times(&mut s, f).await?.is_empty() {
+                continue
+            }
+            let (a, b) = (version_at(&mut s, f, from).await?, version_at(&mut s, f, to).await?);
+            let (old, new) = (read_version(&mut s, cfg, f, a).await?,
+                              read_version(&mut s, cfg, f, b).await?);
+            let (old, new) = (version_files(&old, a)?, version_files(&new, b)?);
+            println!("[{}] {}: {} to {}", profile, f.dir.display(), version_name(a),
+                     version_name(b));
+            let (mut added, mut removed, mut modified) = (0, 0, 0);
+            let paths: BTreeSet<&Path> = old.keys().chain(new.keys()).copied().collect();
+            for path in &paths {
+                match (old.get(path), new.get(path)) {
+                    (None, Some((size, _))) => {
+                        println!("  + {}  ({})", path.display(), size_delta(0, *size));
+                        added += 1;
+                    }
+                    (Some((size, _)), None) => {
+                        println!("  - {}  ({})", path.display(), size_delta(*size, 0));
+                        removed += 1;
+                    }
+                    (Some(x), Some(y)) if x != y => {
+                        println!("  ~ {}  ({})", path.display(), size_delta(x.0, y.0));
+                        modified += 1;
+                    }
+                    _ => (),
                 }
             }
+            let total = |x: &VersionFiles| x.values().map(|(x, _)| x).sum();
+            println!("  {} added, {} removed, {} modified ({} in all)", added, removed, modified,
+                     size_delta(total(&old), total(&new)));
+            compared += 1;
+        }
+        match compared {
+            0 => Err("No versions are kept of this profile's folders (set keep_versions with \
+                      storage = \"chunks\")".into()),
+            _ => Ok(()),
+        }
+    }.await;
+    close_session(Some(s)).await;
+    res
+}
+
+/// Remove the folder's chunks that neither its index nor a kept version lists and that are
+/// older than `grace`, so chunks a push is still uploading (whose index
+/// hasn't been written yet) are kept. Returns the chunks removed, or that
+/// would be with `dry_run`.
+async fn gc_folder(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, grace: Duration, dry_run: bool)
+-> Result<Vec<String>, Box<dyn std::error::Error>> {
+    if ! s.exists(&f.chunk_index()).await? {
+        return Ok(Vec::new())
+    }
+    let index = read_chunk_index(s, cfg, f).await?;
+    let mut listed: HashSet<String> = index.chunks.into_iter().map(|x| x.id).collect();
+    // Kept versions still need theirs.
+    for time in version_times(s, f).await? {
+        listed.extend(read_version(s, cfg, f, time).await?.chunks.into_iter().map(|x| x.id));
+    }
+    let unlisted: Vec<String> = s.older_than(&f.chunk_dir(), grace)
+        .await?
+        .into_iter()
+        .filter(|x| match x.rsplit('/').next() {
+            Some(id) => ! listed.contains(id),
+            None => false,
+        })
+        .collect();
+    if ! dry_run {
+        s.remove(&unlisted).await?;
+    }
+    Ok(unlisted)
+}
+
+/// Delete the chunks no longer referenced by any of a profile's chunk
+/// indexes.
+async fn gc(profile: &str, cfg: &Config, grace: Duration, dry_run: bool)
+-> Result<(), Box<dyn std::error::Error>> {
+    let mut s = remote::connect(cfg).await?;
+    let res: Result<(), Box<dyn std::error::Error>> = async {
+        for f in &cfg.folders {
+            let removed = gc_folder(&mut s, cfg, f, grace, dry_run).await?;
+            for x in &removed {
+                println!("{}{}", if dry_run { "Would remove " } else { "Removed " }, x);
+            }
+            println!("[{}] {}: {} unreferenced chunks {}", profile, f.dir.display(),
+                     removed.len(), if dry_run { "to remove" } else { "removed" });
+        }
+        Ok(())
+    }.await;
+    close_session(Some(s)).await;
+    res
+}
+
+/// Remove `gone` from the folder's remote archive, and from its list of
+/// deleted files. The remote hash is kept as it was: it's the hash of the
+/// folder the archive was pushed from, which still has none of them.
+async fn purge_deleted(s: &mut impl RemoteStore, cfg: &Config, f: &Folder, args: &Args,
+                       gone: &BTreeMap<PathBuf, u64>, summary: &mut Summary)
+-> Result<(), Box<dyn std::error::Error>> {
+    let marker = s.read(&f.tar_hash)
+        .await
+        .ok()
+        .and_then(|x| Marker::decode(&x))
+        .ok_or(format!("Remote hash file {} is missing or unreadable", f.tar_hash))?;
+    let temp = scratch_dir(f, "purge")?;
+    unpack_remote(s, cfg, f, temp.path(), &HashSet::new(), summary).await?;
+    for x in gone.keys() {
+        let path = temp.path().join(x);
+        let removed = match path.symlink_metadata() {
+            Ok(m) if m.is_dir() => remove_dir_all(&path),
+            Ok(_) => std::fs::remove_file(&path),
+            Err(_) => Ok(()),
+        };
+        removed.map_err(|e| format!("Removing {}: {}", path.display(), e))?;
+    }
+    push_dir(s, cfg, f, temp.path(), Some(marker), args, summary).await?;
+    let mut deleted = read_deleted(s, cfg, f).await?;
+    deleted.retain(|x, _| ! gone.contains_key(x));
+    write_deleted(s, cfg, f, &deleted, summary).await?;
+    if let Err(e) = temp.close() {
+        summary.warn(format!("Error deleting temporary directory: {}", e));
+    }
+    Ok(())
+}
+
+/// List the files each of a profile's remote archives keeps after they
+/// were deleted, those deleted at least `older_than` ago if that's given,
+/// and with `purge`, remove them from the archives for good.
+async fn deleted(home: &Path, profile: &str, cfg: &Config, args: &Args, purge: bool,
+                 older_than: Option<Duration>)
+-> Result<(), Box<dyn std::error::Error>> {
+    let _lock = lock::acquire(home, profile, args.wait).await?;
+    let mut s = remote::connect(cfg).await?;
+    let mut summary = Summary::new(profile, Direction::Push);
+    let res: Result<(), Box<dyn std::error::Error>> = async {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
+        let cutoff = now.saturating_sub(older_than.map_or(0, |x| x.as_secs()));
+        for f in &cfg.folders {
+            let mut chosen = read_deleted(&mut s, cfg, f).await?;
+            chosen.retain(|_, time| *time <= cutoff);
+            for (path, time) in &chosen {
+                let date = chrono::Local.timestamp_opt(*time as i64, 0)
+                    .single()
+                    .map(|x| x.format("%Y-%m-%d %H:%M").to_string())
+                    .unwrap_or_default();
+                println!("  {}  {}", date, path.display());
+            }
+            if purge && ! chosen.is_empty() {
+                purge_deleted(&mut s, cfg, f, args, &chosen, &mut summary).await?;
+            }
+            println!("[{}] {}: {} deleted files {}", profile, f.dir.display(), chosen.len(),
+                     if purge { "purged" } else { "kept" });
+        }
+        Ok(())
+    }.await;
+    close_session(Some(s)).await;
+    for w in &summary.warnings {
+        println!("Warning: {}", w);
+    }
+    res
+}
+
+/// The port `mist peer` waits for the other machine on by default.
+#[cfg(feature = "peer")]
+const PEER_PORT: u16 = 8444;
+
+/// Where `--peer` takes the token `mist peer` shows from, if it's set.
+#[cfg(feature = "peer")]
+const PEER_TOKEN_VAR: &str = "MIST_PEER_TOKEN";
+
+/// A token for one peer session, from /dev/urandom.
+#[cfg(feature = "peer")]
+fn peer_token() -> std::io::Result<String> {
+    use std::io::Read;
+    let mut bytes = [0u8; 12];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(hash::to_hex(&bytes))
+}
+
+/// The token for `--peer`, from MIST_PEER_TOKEN or else asked for, so it
+/// stays out of the process list and shell history.
+#[cfg(feature = "peer")]
+fn ask_peer_token() -> std::io::Result<String> {
+    let token = match var(PEER_TOKEN_VAR) {
+        Ok(x) => x,
+        Err(_) => rpassword::prompt_password("Token shown by `mist peer`: ")?,
+    };
+    Ok(token.trim().to_string())
+}
+
+/// Sync a profile straight with another machine on the network, with no
+/// remote in between: put this machine's copy in a temporary directory,
+/// serve it for the other machine to sync with (`mist PROFILE --peer`),
+/// then take back the result. Without `listen`, it waits on this machine's
+/// address on the network only, rather than on every interface. Returns
+/// the exit status.
+#[cfg(feature = "peer")]
+async fn peer(home: &Path, profile: &str, cfg: &Config, args: &Args, listen: Option<&str>)
+-> Result<i32, Box<dyn std::error::Error>> {
+    interrupt::watch();
+    let _lock = lock::acquire(home, profile, args.wait).await?;
+    let listen = match listen {
+        Some(x) => x.to_string(),
+        None => {
+            let ip = network::local_address()
+                .ok_or("Can't tell this machine's address on the network; give one with --listen")?;
+            std::net::SocketAddr::new(ip, PEER_PORT).to_string()
+        }
+    };
+    let dir = tempfile::tempdir()?;
+    let token = peer_token()?;
+    let server = serve::Peer::start(dir.path(), &listen, &token)?;
+    let local = format!("http://127.0.0.1:{}", server.addr.port());
+    let mut s = Remote::Http(http::Client::new(&local, Some(token.clone()), None)?);
+
+    let mut args = args.clone();
+    (args.push, args.create_remote, args.assumeyes) = (true, true, true);
+    let mut summary = Summary::new(profile, Direction::Push);
+    interrupt::unless_stopped(run_mist(home, cfg, &args, &mut s, &mut summary)).await?;
+    let mut before = Vec::new();
+    for f in &cfg.folders {
+        before.push((s.read(&f.tar_hash).await.ok(), file_manifest(&f.dir).await));
+    }
+
+    let ip = match server.addr.ip() {
+        x if x.is_unspecified() => network::local_address(),
+        x => Some(x),
+    };
+    // Found by `mist pair`, and by name with --peer.
+    #[cfg(feature = "mdns")]
+    let _advert = ip
+        .map(|x| mdns::advertise(mdns::Kind::Peer, Some(profile), "http", x, server.addr.port()))
+        .transpose()?;
+    let host = ip.map_or("<this machine>".to_string(), |x| x.to_string());
+    // The token goes in cleartext, like everything else over plain HTTP.
+    println!("Waiting for the other machine to sync. On it, run:\n\n    mist {} --peer {}:{}\n\n\
+              and give it this token when asked, or set {} to it first:\n\n    {}\n",
+             profile, host, server.addr.port(), PEER_TOKEN_VAR, token);
+    let done = interrupt::unless_stopped(async {
+        loop {
+            if let Some(x) = server.finished() {
+                return Ok(x)
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    }).await?;
+    if ! done {
+        return Err("The other machine's sync didn't finish; nothing was changed here".into())
+    }
+
+    let mut changed = false;
+    for (f, (hash, files)) in cfg.folders.iter().zip(&before) {
+        if file_manifest(&f.dir).await != *files {
+            return Err(format!("{} changed while the other machine was syncing; run again to \
+                                sync those changes too", f.dir.display()).into())
+        }
+        changed |= s.read(&f.tar_hash).await.ok() != *hash;
+    }
+    if ! changed {
+        println!("The other machine had no changes; {} is up to date", profile);
+        return Ok(0)
+    }
+    (args.push, args.pull) = (false, true);
+    let mut summary = Summary::new(profile, Direction::Pull);
+    let res = interrupt::unless_stopped(run_mist(home, cfg, &args, &mut s, &mut summary)).await;
+    if let Err(e) = &res {
+        summary.fail(failure_kind(e.as_ref()), e.to_string());
+    }
+    summary.print();
+    Ok(summary.exit_code())
+}
+
+#[cfg(all(feature = "peer", feature = "mdns"))]
+fn peer_address(name: &str, profile: &str) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(mdns::find_peer(name, profile)?.to_string())
+}
+
+/// Without mDNS, a name is looked up as usual, and the port is the default.
+#[cfg(all(feature = "peer", not(feature = "mdns")))]
+fn peer_address(name: &str, _profile: &str) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(format!("{}:{}", name, PEER_PORT))
+}
+
+/// Sync a profile with another machine running `mist peer`, which stands
+/// in for the remote, and let it know when that's done. Returns the exit
+/// status.
+#[cfg(feature = "peer")]
+async fn sync_with_peer(home: &Path, profile: &str, cfg: &mut Config, args: &Args, addr: &str,
+                        token: &str)
+-> Result<i32, Box<dyn std::error::Error>> {
+    // A bare name is a peer's host name, to be found on the network.
+    let url = match addr.contains(':') {
+        true  => format!("http://{}", addr),
+        false => format!("http://{}", peer_address(addr, profile)?),
+    };
+    let client = http::Client::new(&url, Some(token.to_string()), cfg.ssh_connect_timeout)?;
+    cfg.http_url = Some(url);
+    let mut s = Some(Remote::Http(client));
+    let summary = run_profile(home, profile, cfg, args, &mut s).await;
+    let done: &[u8] = match summary.outcome {
+        Outcome::Completed | Outcome::UpToDate => b"ok",
+        _ => b"failed",
+    };
+    let told = match &mut s {
+        Some(x) => x.write(serve::PEER_DONE, done).await.unwrap_or(false),
+        None => false,
+    };
+    if ! told {
+        println!("Warning: couldn't tell the other machine the sync is over; stop it with Ctrl-C");
+    }
+    close_session(s).await;
+    Ok(summary.exit_code())
+}
+
+/// Run the requested operation on each of the profile's folders. With
+/// several folders, the run only counts as up to date (or aborted) if all
+/// of them were; otherwise folders that were skipped are listed as warnings.
+async fn run_mist(home: &Path, cfg: &Config, args: &Args, s: &mut impl RemoteStore,
+                  summary: &mut Summary)
+-> Result<(), Box<dyn std::error::Error>> {
+    let mut outcomes = Vec::new();
+    for f in &cfg.folders {
+        let outcome = run_folder(home, cfg, f, args, s, summary).await?;
+        // Both sides now match, which the next three-way merge starts from
+        // and the next push counts its changes against.
+        let synced = matches!(outcome, Outcome::Completed | Outcome::UpToDate);
+        if synced && args.path.is_none() {
+            let files = snapshot::scan(&f.dir, cfg);
+            if let Err(e) = snapshot::save(home, &summary.profile, &f.dir, &files) {
+                summary.warn(format!("Saving the sync snapshot for {}: {}", f.dir.display(), e));
+            }
         }
-        push_remote(s, cfg, args).await?;
-        match remove_dir_all(&cfg.temp) {
-            Ok(()) => println!("Deleting temporary directory"),
-            Err(e) => println!("Error deleting temporary directory: {}", e),
+        outcomes.push((f, outcome));
+    }
+    let first = outcomes[0].1.clone();
+    if outcomes.iter().all(|(_, x)| *x == first) {
+        summary.outcome = first;
+        return Ok(())
+    }
+    for (f, x) in outcomes {
+        if let Outcome::Aborted(why) = x {
+            summary.warn(format!("{}: skipped ({})", f.dir.display(), why));
         }
     }
     Ok(())
 }
 
+
+/// The flags for one run of a profile: in a mirror mode, a run that names
+/// no direction goes the mirror's way.
+fn mode_args(cfg: &Config, args: &Args) -> Args {
+    let mut args = args.clone();
+    if ! args.push && ! args.pull {
+        args.push = cfg.mode == Mode::MirrorUp;
+        args.pull = cfg.mode == Mode::MirrorDown;
+    }
+    args
+}
+
+/// The direction implied by the command line flags.
+fn direction(args: &Args) -> Direction {
+    if args.push {
+        Direction::Push
+    } else if args.pull {
+        Direction::Pull
+    } else {
+        Direction::Sync
+    }
+}
+
+/// Apply command-line options that override the profile's settings.
+fn override_config(cfg: &mut Config, args: &Args) {
+    if let Some(x) = args.known_hosts.as_deref().and_then(HostKeyPolicy::parse) {
+        cfg.known_hosts = x;
+    }
+}
+
+/// Run the requested operation over the session in `s`, connecting to the
+/// profile's remote host first if there isn't one yet or it has gone away.
+/// The session is left open so later runs on the same host can reuse it.
+async fn connect_and_run(home: &Path, cfg: &Config, args: &Args, s: &mut Option<Remote>,
+                         summary: &mut Summary)
+-> Result<(), Box<dyn std::error::Error>> {
+    if let Some(x) = s {
+        if let Err(e) = x.check().await {
+            println!("SSH connection lost ({}), reconnecting", e);
+            *s = None;
+        }
+    }
+    let s = match s {
+        Some(x) => x,
+        None => s.insert(remote::connect(cfg).await.map_err(|e| Failure::network(e.to_string()))?),
+    };
+    run_mist(home, cfg, args, s, summary).await?;
+    runlog::append(home, s, cfg, summary).await;
+    Ok(())
+}
+
+/// Sync a profile once, end to end: hooks, the transfer itself,
+/// notifications, stats, logs and results. The summary is printed (as JSON with
+/// --json) before returning.
+async fn run_profile(home: &Path, profile: &str, cfg: &Config, args: &Args,
+                     s: &mut Option<Remote>) -> Summary {
+    let args = &mode_args(cfg, args);
+    let mut summary = Summary::new(profile, direction(args));
+    let start = Instant::now();
+    interrupt::watch();
+    logging::log_start(cfg, &mut summary);
+    healthcheck::start(cfg, &mut summary).await;
+
+    // Held until the run is over, results and all.
+    let (_lock, mut res) = match interrupt::unless_stopped(lock::acquire(home, profile, args.wait))
+        .await {
+        Ok(x) => (Some(x), hooks::pre_sync(cfg, &summary)),
+        Err(e) => (None, Err(e)),
+    };
+    if res.is_ok() {
+        res = interrupt::unless_stopped(connect_and_run(home, cfg, args, s, &mut summary)).await;
+    }
+    if let Err(e) = &res {
+        summary.fail(failure_kind(e.as_ref()), e.to_string());
+    }
+    let interrupted = summary.failure == FailureKind::Interrupted;
+    if interrupted {
+        clean_up(s, &mut summary).await;
+    }
+    hooks::finish(cfg, &mut summary);
+    notify::send(cfg.notify, &mut summary);
+    email::send(home, cfg, &mut summary);
+    summary.elapsed = start.elapsed();
+    webhook::send(cfg, &mut summary).await;
+    healthcheck::finish(cfg, &mut summary).await;
+    if cfg.stats {
+        stats::update(home, &mut summary);
+    }
+    logging::log_run(cfg, &mut summary);
+    if let Some(x) = &cfg.results_file {
+        if let Err(e) = summary.append_json(x) {
+            summary.warn(format!("Writing results file {}: {}", x.display(), e));
+        }
+    }
+    if args.json {
+        println!("{}", summary.to_json());
+    } else {
+        summary.print();
+    }
+    summary
+}
+
+/// Remove what a stopped run left behind: partly uploaded remote files and
+/// local temporary directories.
+async fn clean_up(s: &mut Option<Remote>, summary: &mut Summary) {
+    let remote = interrupt::take_remote();
+    if let (Some(s), false) = (s, remote.is_empty()) {
+        if let Err(e) = s.remove(&remote).await {
+            summary.warn(format!("Partly uploaded files weren't removed: {}", e));
+        }
+    }
+    for e in interrupt::remove_local() {
+        summary.warn(format!("Error deleting {}", e));
+    }
+}
+
+/// Close an SSH session, if one was opened.
+async fn close_session(s: Option<Remote>) {
+    if let Some(s) = s {
+        if let Err(e) = s.close().await {
+            println!("Warning: closing SSH session: {}", e);
+        }
+    }
+}
+
+/// How often a daemon holding back a sync checks the network again.
+const NETWORK_RECHECK: Duration = Duration::from_secs(60);
+
+/// Sync a profile repeatedly according to its schedule, without prompting.
+/// A sync that's due while the network isn't one the profile syncs over
+/// (see `network::hold_back`) waits until it is.
+async fn run_daemon(home: &Path, profile: &str, cfg: &Config, args: &Args)
+-> Result<(), Box<dyn std::error::Error>> {
+    let schedule = cfg.schedule
+        .as_ref()
+        .ok_or(format!("Profile [{}] has no 'interval' or 'cron' entry", profile))?;
+    let mut args = args.clone();
+    args.assumeyes = ! args.assumeno;
+    let metrics = cfg.metrics_port.map(|x| metrics::Metrics::serve(profile, x)).transpose()?;
+    interrupt::watch();
+    // Keep one connection for the life of the daemon rather than paying
+    // for connection and authentication on every run.
+    let mut s = None;
+    loop {
+        // With sync_hours_above, only big uploads wait; see run_folder.
+        if let (Some(hours), None) = (cfg.sync_hours, cfg.sync_hours_above) {
+            let wait = hours.until_open();
+            if ! wait.is_zero() {
+                println!("Waiting to sync until {}", hours.opens());
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => (),
+                    x = interrupt::stopped() => {
+                        println!("Stopping ({})", x);
+                        close_session(s).await;
+                        return Ok(())
+                    }
+                }
+            }
+        }
+        if let Some(why) = network::hold_back(cfg) {
+            println!("Waiting to sync: {}", why);
+            while network::hold_back(cfg).is_some() {
+                tokio::select! {
+                    _ = tokio::time::sleep(NETWORK_RECHECK) => (),
+                    x = interrupt::stopped() => {
+                        println!("Stopping ({})", x);
+                        close_session(s).await;
+                        return Ok(())
+                    }
+                }
+            }
+        }
+        if let Some(x) = &metrics {
+            x.started();
+        }
+        let summary = run_profile(home, profile, cfg, &args, &mut s).await;
+        if summary.failure == FailureKind::Interrupted {
+            close_session(s).await;
+            std::process::exit(summary.exit_code());
+        }
+        let delay = schedule.next_delay();
+        if let Some(x) = &metrics {
+            x.record(&summary);
+            x.next_run(delay);
+        }
+        println!("Next sync in {}s", delay.as_secs());
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => (),
+            x = interrupt::stopped() => {
+                println!("Stopping ({})", x);
+                close_session(s).await;
+                return Ok(())
+            }
+        }
+    }
+}
+
+/// Sync every configured profile and print an overview of the results at
+/// the end. The profiles on each host run one after the other over one SSH
+/// session, and with --jobs, up to that many hosts are synced at a time.
+async fn run_all(home: &Path, cfg_path: &Path, args: &Args)
+-> Result<Vec<Summary>, Box<dyn std::error::Error>> {
+    if args.jobs == 0 {
+        return Err("--jobs must be at least 1".into())
+    }
+    let names = config::profile_names(cfg_path)?;
+    let mut summaries: Vec<Option<Summary>> = names.iter().map(|_| None).collect();
+    // In the order the hosts first appear, so that with one job the
+    // profiles run in much the order they're configured.
+    let mut hosts: Vec<(String, Vec<_>)> = Vec::new();
+    for (i, profile) in names.into_iter().enumerate() {
+        let mut cfg = match load_configuration(cfg_path, &profile).await {
+            Ok(x) => x,
+            Err(e) => {
+                println!("{}", e);
+                let mut summary = Summary::new(&profile, direction(args));
+                summary.fail(FailureKind::Config, "invalid configuration");
+                summaries[i] = Some(summary);
+                continue
+            }
+        };
+        override_config(&mut cfg, args);
+        // Asked here, before anything runs, as prompts can't take turns.
+        if let Err(e) = select_gpg_key(home, &profile, &mut cfg, args.assume()) {
+            println!("[{}] {}", profile, e);
+            let mut summary = Summary::new(&profile, direction(args));
+            summary.fail(FailureKind::Crypto, "no GPG key");
+            summaries[i] = Some(summary);
+            continue
+        }
+        let key = ssh::connection_key(&cfg);
+        match hosts.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, runs)) => runs.push((i, profile, cfg)),
+            None => hosts.push((key, vec![(i, profile, cfg)])),
+        }
+    }
+    // The runs take turns on this thread, which is enough to overlap their
+    // waits on the network and on ssh, gpg and unison.
+    let local = LocalSet::new();
+    let slots = Arc::new(Semaphore::new(args.jobs));
+    let mut tasks = Vec::new();
+    for (_, runs) in hosts {
+        let (home, args, slots) = (home.to_path_buf(), args.clone(), slots.clone());
+        tasks.push(local.spawn_local(async move {
+            let _slot = slots.acquire_owned().await;
+            let mut s = None;
+            let mut done = Vec::new();
+            for (i, profile, cfg) in runs {
+                // The profiles not yet started are skipped.
+                if interrupt::is_stopped() {
+                    break
+                }
+                let run = run_profile(&home, &profile, &cfg, &args, &mut s);
+                done.push((i, interrupt::scoped(&profile, run).await));
+            }
+            close_session(s).await;
+            done
+        }));
+    }
+    let done = local.run_until(async {
+        let mut done = Vec::new();
+        for x in tasks {
+            done.extend(x.await?);
+        }
+        Ok::<_, tokio::task::JoinError>(done)
+    }).await?;
+    for (i, summary) in done {
+        summaries[i] = Some(summary);
+    }
+    let summaries: Vec<Summary> = summaries.into_iter().flatten().collect();
+    if ! args.json {
+        summary::print_overview(&summaries);
+    }
+    Ok(summaries)
+}
+
+/// What kind of failure an error is, for the exit status.
+fn failure_kind(e: &(dyn std::error::Error + 'static)) -> FailureKind {
+    #[cfg(feature = "gpg")]
+    if e.is::<gpgme::Error>() {
+        return FailureKind::Crypto
+    }
+    #[cfg(feature = "http")]
+    if e.is::<reqwest::Error>() {
+        return FailureKind::Network
+    }
+    if let Some(x) = e.downcast_ref::<Failure>() {
+        x.kind
+    } else if e.is::<ConfigError>() {
+        FailureKind::Config
+    } else if e.is::<openssh::Error>() {
+        FailureKind::Network
+    } else {
+        FailureKind::Other
+    }
+}
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() {
+    if let Err(e) = run().await {
+        // As returning the error from main would, but with its exit status.
+        eprintln!("Error: {:?}", e);
+        std::process::exit(failure_kind(e.as_ref()).exit_code());
+    }
+}
+
+async fn run() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
     let home = home_from_env().await.expect("$HOME variable not set.");
-    let cfg = load_configuration(&home, &args.profile)
-        .await
-        .expect("Missing configuration parameters");
+    keys::set_gpg_tty();
+    state::migrate(&home)?;
 
-    let mut s = SessionBuilder::default()
-        .known_hosts_check(KnownHosts::Strict) 
-        .control_directory("/tmp")
-        .connect(&cfg.sshaddr).await?;
+    let explicit = args.config.as_deref();
+    // Subcommands name their profile, but may do so by an alias.
+    let resolve = |name: &str| -> Result<String, Box<dyn std::error::Error>> {
+        let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+        config::resolve_profile(&cfg_path, Some(name)).map_err(Failure::config)
+    };
 
-    run_mist(&home, &cfg, &args, &mut s).await?;
+    match &args.command {
+        Some(Cmd::Systemd { profile, interval, install }) => {
+            let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+            let profile = &resolve(profile)?;
+            return systemd::generate(&home, &cfg_path, profile, interval, *install).await
+        }
+        Some(Cmd::Doctor { profile }) =>
+            return doctor::run(&home, explicit, &resolve(profile)?).await,
+        Some(Cmd::List { profile }) =>
+            return list::run(&home, explicit, &resolve(profile)?).await,
+        Some(Cmd::History { profile, count }) =>
+            return stats::show_history(&home, explicit, &resolve(profile)?, *count).await,
+        Some(Cmd::Log { profile, count }) =>
+            return runlog::show(&home, explicit, &resolve(profile)?, *count).await,
+        Some(Cmd::Verify { profile, remote }) => {
+            let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+            let profile = &resolve(profile)?;
+            let mut cfg = load_configuration(&cfg_path, profile).await.map_err(Failure::config)?;
+            override_config(&mut cfg, &args);
+            interrupt::watch();
+            return interrupt::unless_stopped(verify(profile, &cfg, *remote)).await
+        }
+        Some(Cmd::Gc { profile, grace, dry_run }) => {
+            let grace = schedule::parse_interval(grace)
+                .ok_or(format!("Invalid --grace '{}': expected e.g. 30m, 1h or 2d", grace))?;
+            let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+            let profile = &resolve(profile)?;
+            let mut cfg = load_configuration(&cfg_path, profile).await.map_err(Failure::config)?;
+            override_config(&mut cfg, &args);
+            return gc(profile, &cfg, grace, *dry_run).await
+        }
+        Some(Cmd::Deleted { profile, purge, older_than }) => {
+            let older_than = older_than.as_deref()
+                .map(|x| schedule::parse_interval(x)
+                    .ok_or(format!("Invalid --older-than '{}': expected e.g. 12h or 30d", x)))
+                .transpose()?;
+            let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+            let profile = &resolve(profile)?;
+            let mut cfg = load_configuration(&cfg_path, profile).await.map_err(Failure::config)?;
+            override_config(&mut cfg, &args);
+            select_gpg_key(&home, profile, &mut cfg, args.assume()).map_err(Failure::crypto)?;
+            return deleted(&home, profile, &cfg, &args, *purge, older_than).await
+        }
+        Some(Cmd::Cat { profile, path, output }) => {
+            let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+            let profile = &resolve(profile)?;
+            let mut cfg = load_configuration(&cfg_path, profile).await.map_err(Failure::config)?;
+            override_config(&mut cfg, &args);
+            return cat(profile, &cfg, path, output.as_deref()).await
+        }
+        Some(Cmd::Versions { profile, path }) => {
+            let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+            let profile = &resolve(profile)?;
+            let mut cfg = load_configuration(&cfg_path, profile).await.map_err(Failure::config)?;
+            override_config(&mut cfg, &args);
+            return versions(profile, &cfg, path.as_deref()).await
+        }
+        Some(Cmd::Diff { profile, from, to }) => {
+            let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+            let profile = &resolve(profile)?;
+            let mut cfg = load_configuration(&cfg_path, profile).await.map_err(Failure::config)?;
+            override_config(&mut cfg, &args);
+            return diff(profile, &cfg, from, to).await
+        }
+        Some(Cmd::Restore { profile, spec, output }) => {
+            let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+            let profile = &resolve(profile)?;
+            let mut cfg = load_configuration(&cfg_path, profile).await.map_err(Failure::config)?;
+            override_config(&mut cfg, &args);
+            return restore(profile, &cfg, &args, spec, output.as_deref()).await
+        }
+        Some(Cmd::Export { profile, dest }) => {
+            let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+            let profile = &resolve(profile)?;
+            let mut cfg = load_configuration(&cfg_path, profile).await.map_err(Failure::config)?;
+            override_config(&mut cfg, &args);
+            select_gpg_key(&home, profile, &mut cfg, args.assume()).map_err(Failure::crypto)?;
+            return export(profile, &cfg, &args, dest).await
+        }
+        Some(Cmd::Import { profile, source }) => {
+            let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+            let profile = &resolve(profile)?;
+            let mut cfg = load_configuration(&cfg_path, profile).await.map_err(Failure::config)?;
+            override_config(&mut cfg, &args);
+            select_gpg_key(&home, profile, &mut cfg, args.assume()).map_err(Failure::crypto)?;
+            return exit_with(import(&home, profile, &cfg, &args, source).await?)
+        }
+        #[cfg(feature = "fuse")]
+        Some(Cmd::Mount { profile, mountpoint, at }) => {
+            let at = at.as_deref()
+                .map(|x| parse_version_time(x)
+                    .ok_or(format!("Invalid --at '{}': expected e.g. 2024-05-01 or \
+                                    2024-05-01T12:00", x)))
+                .transpose()?;
+            let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+            let profile = &resolve(profile)?;
+            let mut cfg = load_configuration(&cfg_path, profile).await.map_err(Failure::config)?;
+            override_config(&mut cfg, &args);
+            return mount::run(profile, cfg, mountpoint, at).await
+        }
+        #[cfg(feature = "keychain")]
+        Some(Cmd::Keychain { profile, delete }) =>
+            return secrets::run(&resolve(profile)?, *delete),
+        Some(Cmd::Config { action }) => {
+            let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+            return match action {
+                ConfigCmd::List => profiles::list(&cfg_path),
+                ConfigCmd::Show { profile } => profiles::show(&cfg_path, &resolve(profile)?),
+                ConfigCmd::Set { profile, key, value } =>
+                    profiles::set(&cfg_path, &resolve(profile)?, key, value).await,
+                ConfigCmd::Remove { profile } =>
+                    profiles::remove(&cfg_path, &resolve(profile)?, args.assume()),
+            }
+        }
+        #[cfg(feature = "serve")]
+        Some(Cmd::Serve { root, listen, tokens, tls_cert, tls_key }) => {
+            let tls = tls_cert.as_deref().zip(tls_key.as_deref());
+            return serve::run(root, listen, tokens, tls)
+        }
+        #[cfg(feature = "peer")]
+        Some(Cmd::Peer { profile, listen }) => {
+            let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+            let profile = &resolve(profile)?;
+            let mut cfg = load_configuration(&cfg_path, profile).await.map_err(Failure::config)?;
+            select_gpg_key(&home, profile, &mut cfg, args.assume()).map_err(Failure::crypto)?;
+            return exit_with(peer(&home, profile, &cfg, &args, listen.as_deref()).await?)
+        }
+        #[cfg(feature = "mdns")]
+        Some(Cmd::Pair) => return mdns::pair(),
+        #[cfg(feature = "tui")]
+        Some(Cmd::Tui) => {
+            let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
+            return tui::run(&home, &cfg_path)
+        }
+        Some(Cmd::Completions { shell }) => {
+            let names = find_config(&home, explicit)
+                .and_then(|x| config::profile_names(&x))
+                .unwrap_or_default();
+            completions::print(*shell, Args::command(), &names);
+            return Ok(())
+        }
+        None => (),
+    }
 
-    s.close().await?;
+    let cfg_path = find_config(&home, explicit).map_err(Failure::config)?;
 
-    Ok(())
+    if args.all {
+        let summaries = run_all(&home, &cfg_path, &args).await?;
+        if interrupt::is_stopped() {
+            std::process::exit(summary::EXIT_INTERRUPTED);
+        }
+        return exit_with(summary::overall_exit_code(&summaries))
+    }
+
+    let profile = &config::resolve_profile(&cfg_path, args.profile.as_deref())
+        .map_err(Failure::config)?;
+    let mut cfg = load_configuration(&cfg_path, profile).await.map_err(Failure::config)?;
+    override_config(&mut cfg, &args);
+    select_gpg_key(&home, profile, &mut cfg, args.assume()).map_err(Failure::crypto)?;
+
+    if args.daemon {
+        return run_daemon(&home, profile, &cfg, &args).await
+    }
+    #[cfg(feature = "peer")]
+    if let Some(addr) = &args.peer {
+        let token = ask_peer_token()?;
+        return exit_with(sync_with_peer(&home, profile, &mut cfg, &args, addr, &token).await?)
+    }
+
+    let mut s = None;
+    let summary = run_profile(&home, profile, &cfg, &args, &mut s).await;
+    close_session(s).await;
+    exit_with(summary.exit_code())
+}
+
+/// End with an exit status from a command's summary. Only called once
+/// the command has returned, so its lock, temporary files and the like
+/// have been dropped.
+fn exit_with(code: i32) -> Result<(), Box<dyn std::error::Error>> {
+    match code {
+        0 => Ok(()),
+        code => std::process::exit(code),
+    }
 }
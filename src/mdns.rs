@@ -0,0 +1,155 @@
+use std::net::{ IpAddr, SocketAddr };
+use std::time::{ Duration, Instant };
+
+use mdns_sd::{ ServiceDaemon, ServiceEvent, ServiceInfo };
+
+use crate::state::hostname;
+
+/// The service type mist's servers and peers are advertised as.
+const SERVICE: &str = "_mist._tcp.local.";
+
+/// What an advertised mist is there for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// `mist serve`, a remote for http_url profiles.
+    Serve,
+    /// `mist peer`, waiting for another machine to sync a profile with.
+    Peer,
+}
+
+impl Kind {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "serve" => Some(Kind::Serve),
+            "peer"  => Some(Kind::Peer),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Serve => "serve",
+            Kind::Peer  => "peer",
+        }
+    }
+}
+
+/// A mist found on the network.
+pub struct Found {
+    /// The host name of the machine it runs on.
+    pub name: String,
+    pub kind: Kind,
+    /// The profile a peer is waiting to sync.
+    pub profile: Option<String>,
+    /// "http" or "https".
+    pub scheme: String,
+    pub addr: SocketAddr,
+}
+
+/// An advertisement on the network, withdrawn when it's dropped.
+pub struct Advert {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Drop for Advert {
+    fn drop(&mut self) {
+        let _ = self.daemon.unregister(&self.fullname);
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// Advertise this machine's mist at `ip` and `port`.
+pub fn advertise(kind: Kind, profile: Option<&str>, scheme: &str, ip: IpAddr, port: u16)
+-> Result<Advert, Box<dyn std::error::Error>> {
+    let name = hostname();
+    // Instance names must be unique on the network, so a peer's includes
+    // its profile.
+    let instance = match profile {
+        Some(x) => format!("{} {}", name, x),
+        None => name.clone(),
+    };
+    let mut props = vec![("kind", kind.name()), ("name", name.as_str()), ("scheme", scheme)];
+    if let Some(x) = profile {
+        props.push(("profile", x));
+    }
+    let host = format!("{}.local.", name);
+    let info = ServiceInfo::new(SERVICE, &instance, &host, ip, port, &props[..])?;
+    let fullname = info.get_fullname().to_string();
+    let daemon = ServiceDaemon::new()?;
+    daemon.register(info)?;
+    Ok(Advert { daemon, fullname })
+}
+
+/// The mists that answer within `wait`.
+pub fn browse(wait: Duration) -> Result<Vec<Found>, Box<dyn std::error::Error>> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE)?;
+    let end = Instant::now() + wait;
+    let mut found: Vec<Found> = Vec::new();
+    while let Some(left) = end.checked_duration_since(Instant::now()) {
+        let info = match events.recv_timeout(left) {
+            Ok(ServiceEvent::ServiceResolved(x)) => x,
+            Ok(_) => continue,
+            Err(_) => break,
+        };
+        let kind = match info.get_property_val_str("kind").and_then(Kind::parse) {
+            Some(x) => x,
+            None => continue,
+        };
+        // Prefer an IPv4 address, which is likelier to be reachable.
+        let mut addrs: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+        addrs.sort_by_key(|x| ! x.is_ipv4());
+        let addr = match addrs.first() {
+            Some(x) => SocketAddr::new(*x, info.get_port()),
+            None => continue,
+        };
+        let x = Found {
+            name: info.get_property_val_str("name").unwrap_or(info.get_hostname()).to_string(),
+            kind,
+            profile: info.get_property_val_str("profile").map(str::to_string),
+            scheme: info.get_property_val_str("scheme").unwrap_or("http").to_string(),
+            addr,
+        };
+        // A service can be resolved again as its records are refreshed.
+        if ! found.iter().any(|y| y.addr == x.addr) {
+            found.push(x);
+        }
+    }
+    let _ = daemon.shutdown();
+    found.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(found)
+}
+
+/// Print the mists on the network, with how to use each.
+pub fn pair() -> Result<(), Box<dyn std::error::Error>> {
+    println!("Looking for mist servers and peers on the network...");
+    let found = browse(Duration::from_secs(3))?;
+    if found.is_empty() {
+        println!("None found. Start one with `mist serve` or `mist peer`; the network \
+                  must let multicast DNS through.");
+        return Ok(())
+    }
+    for x in found {
+        match x.kind {
+            Kind::Serve => println!("{:<20} serve  http_url = \"{}://{}\"", x.name, x.scheme, x.addr),
+            Kind::Peer => {
+                let profile = x.profile.as_deref().unwrap_or("PROFILE");
+                println!("{:<20} peer   mist {} --peer {}", x.name, profile, x.name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The address of the peer called `name`; if it's waiting with several
+/// profiles, the one named `profile`.
+pub fn find_peer(name: &str, profile: &str) -> Result<SocketAddr, Box<dyn std::error::Error>> {
+    browse(Duration::from_secs(3))?
+        .into_iter()
+        .filter(|x| x.kind == Kind::Peer && x.name == name)
+        .max_by_key(|x| x.profile.as_deref() == Some(profile))
+        .map(|x| x.addr)
+        .ok_or(format!("No peer called '{}' is waiting to sync {} on the network \
+                        (see `mist pair`)", name, profile).into())
+}
@@ -0,0 +1,679 @@
+// Nothing in mist itself keeps its remote in memory: this is for code
+// driving the sync engine, such as the tests below.
+#![allow(dead_code)]
+
+use std::collections::{ BTreeMap, BTreeSet };
+use std::time::{ Duration, SystemTime };
+
+use chrono::{ DateTime, Local };
+use sha2::{ Digest, Sha256 };
+
+use crate::remote::{ Entry, RemoteStore };
+
+/// A remote kept in memory, so the sync engine can be driven without a
+/// network, with every change to the remote in view. It behaves as a
+/// Unix remote does: a write into a directory that doesn't exist fails,
+/// `remove` is recursive, and `list` shows what `ls` would.
+#[derive(Default)]
+pub struct MemoryStore {
+    /// Files by path, relative to the remote home directory, with when
+    /// each was last written.
+    pub files: BTreeMap<String, (Vec<u8>, SystemTime)>,
+    pub dirs: BTreeSet<String>,
+    /// What `free` reports; None for a remote that can't say.
+    pub free: Option<u64>,
+    /// Make writes fail, as a broken transfer would.
+    pub fail_writes: bool,
+    /// How many writes succeeded.
+    pub writes: usize,
+}
+
+/// A path as the store keeps it: "~/x", "./x" and "x/" are all "x".
+fn key(path: &str) -> String {
+    let path = path.strip_prefix("~/").unwrap_or(path);
+    let path = path.strip_prefix("./").unwrap_or(path);
+    path.trim_end_matches('/').to_string()
+}
+
+/// Whether `path` is `dir` or somewhere under it.
+fn within(path: &str, dir: &str) -> bool {
+    path == dir || path.strip_prefix(dir).is_some_and(|x| x.starts_with('/'))
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the directory holding `path` exists. The home directory and
+    /// the root always do.
+    fn has_parent(&self, path: &str) -> bool {
+        match path.rsplit_once('/') {
+            None | Some(("", _)) => true,
+            Some((dir, _)) => self.dirs.contains(dir),
+        }
+    }
+
+    /// Make every file look `by` older, as if that much time had passed.
+    pub fn age(&mut self, by: Duration) {
+        for (_, time) in self.files.values_mut() {
+            *time -= by;
+        }
+    }
+
+    /// Whether any file holds `needle`, e.g. to check nothing is stored
+    /// unencrypted.
+    pub fn contains(&self, needle: &[u8]) -> bool {
+        self.files.values().any(|(x, _)| x.windows(needle.len()).any(|w| w == needle))
+    }
+}
+
+impl RemoteStore for MemoryStore {
+    async fn read(&mut self, path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match self.files.get(&key(path)) {
+            Some((x, _)) => Ok(x.clone()),
+            None => Err(format!("Remote: {}: no such file", path).into()),
+        }
+    }
+
+    async fn write(&mut self, path: &str, bytes: &[u8])
+    -> Result<bool, Box<dyn std::error::Error>> {
+        let path = key(path);
+        if self.fail_writes || ! self.has_parent(&path) || self.dirs.contains(&path) {
+            return Ok(false)
+        }
+        self.files.insert(path, (bytes.to_vec(), SystemTime::now()));
+        self.writes += 1;
+        Ok(true)
+    }
+
+    async fn verify(&mut self, path: &str, bytes: &[u8])
+    -> Result<(), Box<dyn std::error::Error>> {
+        match self.files.get(&key(path)) {
+            Some((x, _)) if x == bytes => Ok(()),
+            _ => Err(format!("Upload of {} is corrupt", path).into()),
+        }
+    }
+
+    async fn checksum(&mut self, path: &str) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        match self.files.get(&key(path)) {
+            Some((x, _)) => Ok(Some(Sha256::digest(x).to_vec())),
+            None => Err(format!("Remote: sha256sum {}: no such file", path).into()),
+        }
+    }
+
+    async fn rename(&mut self, from: &str, to: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let (from, to) = (key(from), key(to));
+        if ! self.has_parent(&to) {
+            return Err(format!("Remote: mv {} {}: no such directory", from, to).into())
+        }
+        if let Some(x) = self.files.remove(&from) {
+            self.files.insert(to, x);
+            return Ok(())
+        }
+        if ! self.dirs.contains(&from) {
+            return Err(format!("Remote: mv {} {}: no such file", from, to).into())
+        }
+        let moved = |x: &String| format!("{}{}", to, &x[from.len()..]);
+        let files: Vec<String> = self.files.keys().filter(|x| within(x, &from)).cloned().collect();
+        for x in files {
+            let file = self.files.remove(&x).unwrap();
+            self.files.insert(moved(&x), file);
+        }
+        let dirs: Vec<String> = self.dirs.iter().filter(|x| within(x, &from)).cloned().collect();
+        for x in dirs {
+            self.dirs.remove(&x);
+            self.dirs.insert(moved(&x));
+        }
+        Ok(())
+    }
+
+    async fn exists(&mut self, path: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.files.contains_key(&key(path)))
+    }
+
+    async fn is_dir(&mut self, path: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.dirs.contains(&key(path)))
+    }
+
+    async fn create_dir(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let path = key(path);
+        if self.files.contains_key(&path) {
+            return Err(format!("Remote: mkdir {}: a file is in the way", path).into())
+        }
+        let mut at = 0;
+        while let Some(i) = path[at..].find('/') {
+            if at + i > 0 {
+                self.dirs.insert(path[..at + i].to_string());
+            }
+            at += i + 1;
+        }
+        self.dirs.insert(path);
+        Ok(())
+    }
+
+    async fn remove(&mut self, paths: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        for path in paths.iter().map(|x| key(x)) {
+            self.files.retain(|x, _| ! within(x, &path));
+            self.dirs.retain(|x| ! within(x, &path));
+        }
+        Ok(())
+    }
+
+    async fn list(&mut self, dir: &str) -> Result<Vec<Entry>, Box<dyn std::error::Error>> {
+        let dir = key(dir);
+        let child = |x: &str| x.strip_prefix(&dir)?
+            .strip_prefix('/')
+            .filter(|x| ! x.contains('/'))
+            .map(str::to_string);
+        let date = |x: SystemTime| DateTime::<Local>::from(x).format("%b %e %H:%M").to_string();
+        let mut entries: Vec<Entry> = self.dirs
+            .iter()
+            .filter_map(|x| child(x))
+            .map(|name| Entry { name, dir: true, size: 4096, date: date(SystemTime::now()) })
+            .collect();
+        entries.extend(self.files.iter().filter_map(|(x, (bytes, time))| Some(Entry {
+            name: child(x)?,
+            dir: false,
+            size: bytes.len() as u64,
+            date: date(*time),
+        })));
+        Ok(entries)
+    }
+
+    async fn older_than(&mut self, dir: &str, age: Duration)
+    -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let (shown, dir) = (dir.trim_end_matches('/'), key(dir));
+        if ! self.dirs.contains(&dir) {
+            return Err(format!("Remote: find {}: no such directory", shown).into())
+        }
+        let now = SystemTime::now();
+        Ok(self.files
+            .iter()
+            .filter(|(x, (_, time))| {
+                within(x, &dir) && now.duration_since(*time).is_ok_and(|x| x > age)
+            })
+            .map(|(x, _)| format!("{}{}", shown, &x[dir.len()..]))
+            .collect())
+    }
+
+    async fn append(&mut self, path: &str, line: &str, keep: usize)
+    -> Result<(), Box<dyn std::error::Error>> {
+        let path = key(path);
+        if ! self.has_parent(&path) {
+            return Err(format!("Remote: {}: no such directory", path).into())
+        }
+        let mut text = self.files
+            .get(&path)
+            .map(|(x, _)| String::from_utf8_lossy(x).into_owned())
+            .unwrap_or_default();
+        text.push_str(line);
+        let lines: Vec<&str> = text.lines().collect();
+        let mut kept = lines[lines.len().saturating_sub(keep)..].join("\n");
+        kept.push('\n');
+        self.files.insert(path, (kept.into_bytes(), SystemTime::now()));
+        Ok(())
+    }
+
+    async fn free(&mut self, _dir: &str) -> Option<u64> {
+        self.free
+    }
+
+    async fn usage(&mut self, paths: &[String]) -> u64 {
+        let paths: Vec<String> = paths.iter().map(|x| key(x)).collect();
+        self.files
+            .iter()
+            .filter(|(x, _)| paths.iter().any(|p| within(x, p)))
+            .map(|(_, (bytes, _))| bytes.len() as u64)
+            .sum()
+    }
+}
+
+/// Machines syncing through a `MemoryStore`, for the engine's tests. Their
+/// profiles use builtin encryption, so no gpg key is needed.
+#[cfg(all(test, feature = "builtin-crypto"))]
+pub mod fixture {
+    use std::collections::BTreeMap;
+    use std::fs::{ File, create_dir_all, read_to_string, remove_file, rename, write };
+    use std::path::{ Path, PathBuf };
+    use std::sync::atomic::{ AtomicU64, Ordering };
+    use std::time::{ Duration, UNIX_EPOCH };
+
+    use clap::Parser;
+    use tempfile::TempDir;
+
+    use super::MemoryStore;
+    use crate::config::{ Config, load_configuration };
+    use crate::summary::Summary;
+    use crate::{ Args, direction, failure_kind, run_mist };
+
+    /// The modification time the next file a `Machine` writes gets. Each
+    /// write is a second later than the last, so the change shows even to a
+    /// merge that compares times to the second.
+    static CLOCK: AtomicU64 = AtomicU64::new(1_600_000_000);
+
+    /// The profile every `Machine` syncs.
+    pub const PROFILE: &str = "test";
+
+    /// A machine syncing one folder through a `MemoryStore`: a home of its own
+    /// in a temporary directory, holding its state, its folder and a profile
+    /// with builtin encryption, so no gpg key is needed. Machines share a
+    /// remote by passing them the same store.
+    pub struct Machine {
+        pub home: PathBuf,
+        /// The synced folder.
+        pub dir: PathBuf,
+        pub cfg: Config,
+        _root: TempDir,
+    }
+
+    impl Machine {
+        /// A machine with an empty folder. `settings` are added to the
+        /// profile, one per line.
+        pub async fn new(settings: &[&str]) -> Self {
+            let root = tempfile::Builder::new().prefix("mist-test-").tempdir().unwrap();
+            let home = root.path().to_path_buf();
+            let dir = home.join("sync");
+            create_dir_all(&dir).unwrap();
+            let mut config = format!(
+                "[{}]\n\
+                 folder = {:?}\n\
+                 ssh_address = \"nobody@localhost\"\n\
+                 temp_folder = {:?}\n\
+                 encryption = \"builtin\"\n\
+                 passphrase_command = \"echo correct horse battery staple\"\n\
+                 merge_tool = \"three-way\"\n",
+                PROFILE, dir, home.join("tmp/sync"));
+            for x in settings {
+                config.push_str(x);
+                config.push('\n');
+            }
+            let path = home.join("mist.toml");
+            write(&path, config).unwrap();
+            let cfg = load_configuration(&path, PROFILE).await.unwrap();
+            Machine { home, dir, cfg, _root: root }
+        }
+
+        /// Run mist with these flags (say, `--push`) against `s`, answering
+        /// yes to every question.
+        pub async fn run(&self, flags: &[&str], s: &mut MemoryStore) -> Summary {
+            let mut argv = vec!["mist", PROFILE, "-y"];
+            argv.extend(flags);
+            let args = Args::parse_from(argv);
+            let mut summary = Summary::new(PROFILE, direction(&args));
+            if let Err(e) = run_mist(&self.home, &self.cfg, &args, s, &mut summary).await {
+                summary.fail(failure_kind(e.as_ref()), e.to_string());
+            }
+            summary
+        }
+
+        /// Write a file in the folder, creating directories as needed.
+        pub fn write(&self, path: &str, contents: &str) {
+            let path = self.dir.join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(&path, contents).unwrap();
+            let time = UNIX_EPOCH + Duration::from_secs(CLOCK.fetch_add(1, Ordering::Relaxed));
+            File::options().write(true).open(&path).unwrap().set_modified(time).unwrap();
+        }
+
+        /// A file in the folder, or None if it isn't there.
+        pub fn read(&self, path: &str) -> Option<String> {
+            read_to_string(self.dir.join(path)).ok()
+        }
+
+        pub fn remove(&self, path: &str) {
+            remove_file(self.dir.join(path)).unwrap();
+        }
+
+        /// Move a file within the folder, keeping its modification time.
+        pub fn rename(&self, from: &str, to: &str) {
+            rename(self.dir.join(from), self.dir.join(to)).unwrap();
+        }
+
+        /// Every file in the folder, with its contents.
+        pub fn files(&self) -> BTreeMap<PathBuf, String> {
+            walkdir::WalkDir::new(&self.dir)
+                .into_iter()
+                .map(Result::unwrap)
+                .filter(|x| x.file_type().is_file())
+                .map(|x| (x.path().strip_prefix(&self.dir).unwrap().to_path_buf(),
+                          read_to_string(x.path()).unwrap()))
+                .collect()
+        }
+    }
+
+    /// Whether `dir` holds any of `names`.
+    pub fn holds(dir: &Path, names: &[&str]) -> bool {
+        names.iter().any(|x| dir.join(x).exists())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "builtin-crypto")]
+    use super::fixture::*;
+    #[cfg(feature = "builtin-crypto")]
+    use crate::summary::{ Direction, EXIT_WARNINGS, Outcome, Summary };
+    #[cfg(feature = "builtin-crypto")]
+    use crate::{ Args, export_folder, import_folder, purge_deleted, read_deleted, verify_stored };
+    #[cfg(feature = "builtin-crypto")]
+    use clap::Parser;
+    #[cfg(feature = "builtin-crypto")]
+    use std::path::Path;
+
+    /// Shows in a plain `cargo test` that the engine's tests didn't run.
+    #[cfg(not(feature = "builtin-crypto"))]
+    #[test]
+    #[ignore = "the sync engine's tests need --features builtin-crypto"]
+    fn sync_engine() {}
+
+    #[tokio::test]
+    async fn writes_need_the_directory() {
+        let mut s = MemoryStore::new();
+        assert!(! s.write("a/b", b"x").await.unwrap());
+        s.create_dir("a").await.unwrap();
+        assert!(s.write("a/b", b"x").await.unwrap());
+        assert_eq!(s.read("~/a/b").await.unwrap(), b"x");
+        s.rename("a", "c").await.unwrap();
+        assert!(s.exists("c/b").await.unwrap());
+        assert!(! s.is_dir("a").await.unwrap());
+        let names: Vec<String> = s.list("c").await.unwrap().into_iter().map(|x| x.name).collect();
+        assert_eq!(names, ["b"]);
+        s.remove(&["c".to_string()]).await.unwrap();
+        assert!(s.files.is_empty() && s.dirs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn older_than_and_append() {
+        let mut s = MemoryStore::new();
+        s.create_dir("d/e").await.unwrap();
+        s.write("d/old", b"").await.unwrap();
+        s.age(Duration::from_secs(7200));
+        s.write("d/e/new", b"").await.unwrap();
+        assert_eq!(s.older_than("d/", Duration::from_secs(3600)).await.unwrap(), ["d/old"]);
+        for x in ["1\n", "2\n", "3\n"] {
+            s.append("d/log", x, 2).await.unwrap();
+        }
+        assert_eq!(s.read("d/log").await.unwrap(), b"2\n3\n");
+        assert_eq!(s.usage(&["d".to_string()]).await, 4);
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    #[tokio::test]
+    async fn push_then_pull() {
+        let mut s = MemoryStore::new();
+        let (a, b) = (Machine::new(&[]).await, Machine::new(&[]).await);
+        a.write("notes.txt", "shopping: milk, eggs\n");
+        a.write("deep/down/file.txt", "below\n");
+        assert_eq!(a.run(&["--push"], &mut s).await.outcome, Outcome::Completed);
+        assert!(! s.contains(b"shopping"), "the remote holds plain text");
+        assert_eq!(b.run(&["--pull"], &mut s).await.outcome, Outcome::Completed);
+        assert_eq!(a.files(), b.files());
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    #[tokio::test]
+    async fn sync_both_ways() {
+        let mut s = MemoryStore::new();
+        let (a, b) = (Machine::new(&[]).await, Machine::new(&[]).await);
+        a.write("notes.txt", "v1\n");
+        a.write("gone.txt", "soon\n");
+        a.run(&["--push"], &mut s).await;
+        b.run(&["--pull"], &mut s).await;
+
+        b.write("notes.txt", "v2\n");
+        b.remove("gone.txt");
+        b.write("new.txt", "from b\n");
+        assert_eq!(b.run(&[], &mut s).await.outcome, Outcome::Completed);
+        assert_eq!(a.run(&[], &mut s).await.outcome, Outcome::Completed);
+        assert_eq!(a.read("notes.txt").as_deref(), Some("v2\n"));
+        assert!(! holds(&a.dir, &["gone.txt"]));
+        assert_eq!(a.files(), b.files());
+
+        // Nothing changed, so nothing is written.
+        let writes = s.writes;
+        assert_eq!(b.run(&[], &mut s).await.outcome, Outcome::UpToDate);
+        assert_eq!(s.writes, writes);
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    #[tokio::test]
+    async fn conflicts_keep_both_versions() {
+        let mut s = MemoryStore::new();
+        let (a, b) = (Machine::new(&[]).await, Machine::new(&[]).await);
+        a.write("notes.txt", "base\n");
+        a.run(&["--push"], &mut s).await;
+        b.run(&["--pull"], &mut s).await;
+
+        a.write("notes.txt", "from a\n");
+        a.run(&[], &mut s).await;
+        b.write("notes.txt", "from b\n");
+        let summary = b.run(&[], &mut s).await;
+        assert_eq!(summary.outcome, Outcome::Completed);
+        assert!(summary.unresolved_conflicts);
+        assert_eq!(b.read("notes.txt").as_deref(), Some("from b\n"));
+        assert_eq!(b.read("notes.txt.conflict").as_deref(), Some("from a\n"));
+
+        a.run(&[], &mut s).await;
+        assert_eq!(a.files(), b.files());
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    #[tokio::test]
+    async fn declined_syncs_leave_no_copy() {
+        let mut s = MemoryStore::new();
+        let a = Machine::new(&[]).await;
+        let b = Machine::new(&["answer_push_after_error = \"no\""]).await;
+        a.write("notes.txt", "base\n");
+        a.run(&["--push"], &mut s).await;
+        b.run(&["--pull"], &mut s).await;
+
+        a.write("notes.txt", "from a\n");
+        a.run(&[], &mut s).await;
+        b.write("notes.txt", "from b\n");
+        let summary = b.run(&[], &mut s).await;
+        assert_eq!(summary.outcome, Outcome::Aborted("transfer to remote declined".into()));
+        assert!(! b.home.join("tmp/sync").exists());
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    #[tokio::test]
+    async fn mirrors_keep_deleted_files_only() {
+        let mut s = MemoryStore::new();
+        let a = Machine::new(&["mode = \"mirror-up\"", "propagate_deletes = false"]).await;
+        let b = Machine::new(&[]).await;
+        a.write("kept.txt", "1\n");
+        a.write("gone.txt", "2\n");
+        a.run(&["--push"], &mut s).await;
+        a.remove("gone.txt");
+        let summary = a.run(&["--push"], &mut s).await;
+        assert_eq!(summary.outcome, Outcome::Completed);
+        assert!(read_deleted(&mut s, &a.cfg, &a.cfg.folders[0]).await.unwrap()
+            .contains_key(Path::new("gone.txt")));
+
+        b.run(&["--pull"], &mut s).await;
+        assert_eq!(b.files(), a.files());
+        let leftovers = std::fs::read_dir(a.home.join("tmp")).unwrap()
+            .map(|x| x.unwrap().file_name())
+            .filter(|x| x.to_string_lossy().starts_with("sync-push-"))
+            .count();
+        assert_eq!(leftovers, 0);
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    #[tokio::test]
+    async fn purges_drop_deleted_files() {
+        let mut s = MemoryStore::new();
+        let a = Machine::new(&["mode = \"mirror-up\"", "propagate_deletes = false"]).await;
+        let b = Machine::new(&[]).await;
+        a.write("kept.txt", "1\n");
+        a.write("gone.txt", "2\n");
+        a.run(&["--push"], &mut s).await;
+        a.remove("gone.txt");
+        a.run(&["--push"], &mut s).await;
+
+        let f = &a.cfg.folders[0];
+        let gone = read_deleted(&mut s, &a.cfg, f).await.unwrap();
+        assert_eq!(gone.keys().collect::<Vec<_>>(), [Path::new("gone.txt")]);
+        let args = Args::parse_from(["mist", PROFILE, "-y"]);
+        let mut summary = Summary::new(PROFILE, Direction::Push);
+        purge_deleted(&mut s, &a.cfg, f, &args, &gone, &mut summary).await.unwrap();
+        assert!(read_deleted(&mut s, &a.cfg, f).await.unwrap().is_empty());
+        assert_eq!(std::fs::read_dir(a.home.join("tmp")).unwrap().count(), 0);
+
+        // No longer listed as deleted, so it would be pulled if it were still there.
+        b.run(&["--pull"], &mut s).await;
+        assert_eq!(b.files(), a.files());
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    #[tokio::test]
+    async fn failed_upload_keeps_the_remote() {
+        let mut s = MemoryStore::new();
+        let (a, b) = (Machine::new(&[]).await, Machine::new(&[]).await);
+        a.write("notes.txt", "v1\n");
+        a.run(&["--push"], &mut s).await;
+
+        a.write("notes.txt", "v2\n");
+        s.fail_writes = true;
+        let before = s.files.clone();
+        let summary = a.run(&["--push"], &mut s).await;
+        // A write that fails is a warning, not a failure of the run.
+        assert_eq!(summary.outcome, Outcome::Completed);
+        assert_eq!(summary.exit_code(), EXIT_WARNINGS);
+        assert_eq!(summary.warnings, [
+            "Writing sync.tar.gz.gpg.partial to the remote host failed",
+            "Writing sync.tar.gz.gpg.format.partial to the remote host failed",
+            "Writing sync.tar.gz.gpg.xxhash.partial to the remote host failed",
+        ]);
+        assert_eq!(s.files, before);
+        s.fail_writes = false;
+        b.run(&["--pull"], &mut s).await;
+        assert_eq!(b.read("notes.txt").as_deref(), Some("v1\n"));
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    #[tokio::test]
+    async fn chunked_folders_sync() {
+        let mut s = MemoryStore::new();
+        let a = Machine::new(&["storage = \"chunks\""]).await;
+        let b = Machine::new(&["storage = \"chunks\""]).await;
+        a.write("one.txt", "1\n");
+        a.write("two/three.txt", "3\n");
+        a.run(&["--push"], &mut s).await;
+        b.run(&["--pull"], &mut s).await;
+        assert_eq!(a.files(), b.files());
+        b.write("one.txt", "one\n");
+        b.run(&[], &mut s).await;
+        a.run(&[], &mut s).await;
+        assert_eq!(a.read("one.txt").as_deref(), Some("one\n"));
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    #[tokio::test]
+    async fn archives_past_spill_above_sync() {
+        let mut s = MemoryStore::new();
+        let a = Machine::new(&["spill_above = \"1K\""]).await;
+        let b = Machine::new(&["spill_above = \"1K\""]).await;
+        let big: String = (0..20_000u64).map(|x| format!("{:x}\n", x * 2_654_435_761)).collect();
+        a.write("big.txt", &big);
+        a.write("small.txt", "1\n");
+        assert_eq!(a.run(&["--push"], &mut s).await.outcome, Outcome::Completed);
+        // Uploaded a part at a time, none bigger than what's held in memory.
+        let parts: Vec<usize> = s.files.iter()
+            .filter(|(k, _)| k.starts_with("sync.tar.gz.gpg.0"))
+            .map(|(_, (x, _))| x.len())
+            .collect();
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|x| *x <= 1024), "{:?}", parts);
+        assert_eq!(b.run(&["--pull"], &mut s).await.outcome, Outcome::Completed);
+        assert_eq!(a.files(), b.files());
+        b.write("small.txt", "2\n");
+        assert_eq!(b.run(&[], &mut s).await.outcome, Outcome::Completed);
+        assert_eq!(a.run(&[], &mut s).await.outcome, Outcome::Completed);
+        assert_eq!(a.read("small.txt").as_deref(), Some("2\n"));
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    #[tokio::test]
+    async fn chunked_archives_past_spill_above_sync() {
+        let mut s = MemoryStore::new();
+        let a = Machine::new(&["spill_above = \"1K\"", "storage = \"chunks\""]).await;
+        let b = Machine::new(&["spill_above = \"1K\"", "storage = \"chunks\""]).await;
+        let big: String = (0..600_000u64).map(|x| format!("{:x}\n", x * 2_654_435_761)).collect();
+        a.write("big.txt", &big);
+        a.write("small.txt", "1\n");
+        assert_eq!(a.run(&["--push"], &mut s).await.outcome, Outcome::Completed);
+        assert!(s.files.keys().filter(|x| x.contains("chunks/")).count() > 1);
+        assert_eq!(b.run(&["--pull"], &mut s).await.outcome, Outcome::Completed);
+        assert_eq!(a.files(), b.files());
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    #[tokio::test]
+    async fn renames_count_once() {
+        let mut s = MemoryStore::new();
+        let (a, b) = (Machine::new(&[]).await, Machine::new(&[]).await);
+        a.write("old.txt", "moving\n");
+        a.write("stays.txt", "here\n");
+        a.run(&["--push"], &mut s).await;
+        b.run(&["--pull"], &mut s).await;
+        a.rename("old.txt", "new.txt");
+        a.run(&["--push"], &mut s).await;
+        let summary = b.run(&[], &mut s).await;
+        assert_eq!(a.files(), b.files());
+        assert_eq!((summary.files_changed, summary.files_added, summary.files_deleted,
+                    summary.files_renamed), (1, 0, 0, 1));
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    #[tokio::test]
+    async fn pushes_count_only_changes() {
+        let mut s = MemoryStore::new();
+        let a = Machine::new(&[]).await;
+        a.write("one.txt", "one\n");
+        a.write("two.txt", "two\n");
+        assert_eq!(a.run(&["--push"], &mut s).await.files_added, 2);
+        let summary = a.run(&["--push"], &mut s).await;
+        assert_eq!((summary.outcome, summary.files_changed), (Outcome::Completed, 0));
+        a.write("two.txt", "changed\n");
+        let summary = a.run(&["--push"], &mut s).await;
+        assert_eq!((summary.files_changed, summary.files_added), (1, 0));
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    #[tokio::test]
+    async fn stored_archives_verify_in_place() {
+        let mut s = MemoryStore::new();
+        let a = Machine::new(&["split_size = \"1K\"", "compression = \"none\""]).await;
+        let big: String = (0..1_000u64).map(|x| format!("{:x}\n", x * 2_654_435_761)).collect();
+        a.write("big.txt", &big);
+        a.run(&["--push"], &mut s).await;
+        let f = &a.cfg.folders[0];
+        assert!(verify_stored(&mut s, f).await.is_ok());
+        let part = f.part(1);
+        let (bytes, _) = s.files.get_mut(&key(&part)).unwrap();
+        bytes[100] ^= 1;
+        let e = verify_stored(&mut s, f).await.unwrap_err().to_string();
+        assert!(e.contains(&part), "{}", e);
+    }
+
+    #[cfg(feature = "builtin-crypto")]
+    #[tokio::test]
+    async fn exports_import_elsewhere() {
+        for storage in ["archive", "chunks"] {
+            let mut s = MemoryStore::new();
+            let a = Machine::new(&[&format!("storage = \"{}\"", storage)]).await;
+            let b = Machine::new(&[]).await;
+            a.write("notes.txt", "milk\n");
+            a.write("sub/deep.txt", "eggs\n");
+            a.run(&["--push"], &mut s).await;
+            let file = a.home.join("export");
+            let mut summary = Summary::new(PROFILE, Direction::Pull);
+            export_folder(&mut s, &a.cfg, &a.cfg.folders[0], &file, &mut summary).await.unwrap();
+            import_folder(&b.cfg, &b.cfg.folders[0], &file).await.unwrap();
+            assert_eq!(a.files(), b.files(), "{}", storage);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use std::collections::BTreeSet;
+use std::fs::{ File, copy, create_dir_all, read, read_link, remove_dir, remove_file };
+use std::os::unix::fs::symlink;
+use std::path::{ Path, PathBuf };
+use std::process::Command;
+use std::time::{ Duration, UNIX_EPOCH };
+
+use crate::archive::Symlinks;
+use crate::config::{ Config, Folder };
+use crate::hash::Detect;
+use crate::snapshot::{ self, Files, State };
+
+/// What merges the local folder with the unpacked remote copy when syncing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeTool {
+    Unison,
+    /// Mist's own merge, which compares both sides with the snapshot taken
+    /// at the last sync to tell which side changed each file.
+    ThreeWay,
+    /// Copy whatever is newer each way with rsync. Files deleted on one
+    /// side come back from the other, and a file changed on both sides
+    /// keeps the newer version.
+    Rsync,
+    /// A shell command, with `{local}` and `{remote}` standing for the two
+    /// folders. It must leave both folders as they should end up.
+    Command(String),
+}
+
+impl MergeTool {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "unison"    => Some(MergeTool::Unison),
+            "three-way" => Some(MergeTool::ThreeWay),
+            "rsync"     => Some(MergeTool::Rsync),
+            x if x.contains("{local}") && x.contains("{remote}") =>
+                Some(MergeTool::Command(x.to_string())),
+            _ => None,
+        }
+    }
+
+    /// The program, for messages.
+    pub fn name(&self) -> &str {
+        match self {
+            MergeTool::Unison     => "unison",
+            MergeTool::ThreeWay   => "three-way merge",
+            MergeTool::Rsync      => "rsync",
+            MergeTool::Command(_) => "merge_tool",
+        }
+    }
+}
+
+/// Unison where it's built in, and otherwise the three-way merge.
+impl Default for MergeTool {
+    fn default() -> Self {
+        match cfg!(feature = "unison") {
+            true  => MergeTool::Unison,
+            false => MergeTool::ThreeWay,
+        }
+    }
+}
+
+/// How a merge went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Merge {
+    Clean,
+    /// Done, but some files changed on both sides: unison skips them in
+    /// batch mode, and the three-way merge keeps both versions.
+    Conflicts,
+    /// The tool failed, with this exit code.
+    Failed(Option<i32>),
+}
+
+/// Merge the folder with its remote copy, unpacked in its temporary
+/// directory, with the profile's tool. `batch` means nobody is there to
+/// answer the tool's questions.
+pub fn run(home: &Path, profile: &str, f: &Folder, batch: bool, cfg: &Config)
+-> Result<Merge, std::io::Error> {
+    let (local, remote) = (f.dir.as_path(), f.temp.as_path());
+    match &cfg.merge_tool {
+        MergeTool::Unison => unison(local, remote, batch, cfg),
+        MergeTool::ThreeWay =>
+            three_way(local, remote, &snapshot::load(home, profile, local), cfg),
+        MergeTool::Rsync => {
+            // Each side's newer files over the other's, in two passes.
+            for (from, to) in [(local, remote), (remote, local)] {
+                match rsync(from, to, cfg)? {
+                    Merge::Clean => (),
+                    x => return Ok(x),
+                }
+            }
+            Ok(Merge::Clean)
+        }
+        MergeTool::Command(x) => {
+            // The paths are passed as arguments rather than pasted into the
+            // command, so they need no quoting.
+            let cmd = x.replace("{local}", "\"$1\"").replace("{remote}", "\"$2\"");
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(cmd)
+                .arg("sh")
+                .arg(local)
+                .arg(remote)
+                .status()?;
+            match status.success() {
+                true  => Ok(Merge::Clean),
+                false => Ok(Merge::Failed(status.code())),
+            }
+        }
+    }
+}
+
+/// Call Unison on the local and remote folder. Unison exits with 1 when
+/// some files were skipped (e.g. conflicts left unresolved in batch mode),
+/// and 2 or 3 on errors.
+fn unison(local: &Path, remote: &Path, batch: bool, cfg: &Config)
+-> Result<Merge, std::io::Error> {
+    let mut cmd = Command::new(cfg.unison_bin.as_deref().unwrap_or("unison"));
+    if cfg.symlinks == Symlinks::Follow {
+        cmd.arg("-follow").arg("Regex .*");
+    }
+    if cfg.preserve.ownership {
+        cmd.arg("-owner").arg("-group");
+    }
+    if cfg.preserve.xattrs {
+        cmd.arg("-xattrs");
+    }
+    // Otherwise files unison copies get new times, and every machine
+    // would see the others' pushes as changes.
+    if cfg.detect == Detect::Mtime {
+        cmd.arg("-times");
+    }
+    cmd.args(cfg.exclude.unison_args(local, cfg.symlinks));
+    cmd.args(&cfg.unison_args);
+    if batch {
+        cmd
+            .arg(local)
+            .arg(remote)
+            .arg("-batch");
+    } else {
+        cmd
+            .arg(local)
+            .arg(remote);
+    };
+    Ok(match cmd.status()?.code() {
+        Some(0) => Merge::Clean,
+        Some(1) => Merge::Conflicts,
+        x => Merge::Failed(x),
+    })
+}
+
+/// Copy the files in `from` that are newer than (or missing from) `to`.
+fn rsync(from: &Path, to: &Path, cfg: &Config) -> Result<Merge, std::io::Error> {
+    let mut cmd = Command::new("rsync");
+    // Archive mode keeps times, which the next pass compares.
+    cmd.arg("-a").arg("--update");
+    if cfg.symlinks == Symlinks::Follow {
+        cmd.arg("--copy-links");
+    }
+    if cfg.preserve.xattrs {
+        cmd.arg("--xattrs");
+    }
+    // The trailing slashes copy the folders' contents rather than the
+    // folders themselves.
+    let mut from = from.as_os_str().to_owned();
+    from.push("/");
+    let mut to = to.as_os_str().to_owned();
+    to.push("/");
+    let status = cmd.arg(from).arg(to).status()?;
+    match status.success() {
+        true  => Ok(Merge::Clean),
+        false => Ok(Merge::Failed(status.code())),
+    }
+}
+
+/// Merge the remote copy's changes since the last sync (`base`) into the
+/// local folder. A file changed on one side only takes that side's
+/// version, including deletions; a file changed on both sides keeps the
+/// local version, with the remote one saved beside it as a conflict copy.
+/// Only the local folder is changed, as that's what's pushed afterwards.
+fn three_way(local: &Path, remote: &Path, base: &Files, cfg: &Config)
+-> Result<Merge, std::io::Error> {
+    let ours = snapshot::scan(local, cfg);
+    let theirs = snapshot::scan(remote, cfg);
+    let paths: BTreeSet<&PathBuf> = ours.keys().chain(theirs.keys()).collect();
+    let mut conflicts = 0;
+    for path in paths {
+        let (l, r, b) = (ours.get(path), theirs.get(path), base.get(path));
+        let (dest, src) = (local.join(path), remote.join(path));
+        let res = if l == r || r == b {
+            // The same on both sides, or only changed here.
+            Ok(())
+        } else if l == b {
+            match r {
+                Some(x) => take(&src, &dest, x, local),
+                None => delete(&dest, local),
+            }
+        } else {
+            match (l, r) {
+                // A change wins over a deletion.
+                (None, Some(x)) => take(&src, &dest, x, local),
+                (_, None) => Ok(()),
+                (Some(_), Some(_)) if same_contents(&dest, &src) => Ok(()),
+                (Some(_), Some(x)) => {
+                    let copy = conflict_path(&dest);
+                    println!("Conflict: {} changed on both sides; the remote version is in {}",
+                             dest.display(), copy.display());
+                    conflicts += 1;
+                    take(&src, &copy, x, local)
+                }
+            }
+        };
+        res.map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", dest.display(), e)))?;
+    }
+    match conflicts {
+        0 => Ok(Merge::Clean),
+        _ => Ok(Merge::Conflicts),
+    }
+}
+
+/// Copy the remote file `src` (in state `state`) to `dest`, keeping its
+/// modification time so the next scan sees the two as the same.
+fn take(src: &Path, dest: &Path, state: &State, root: &Path) -> Result<(), std::io::Error> {
+    if let Some(x) = dest.parent() {
+        create_dir_all(x)?;
+    }
+    if dest.symlink_metadata().is_ok() {
+        delete(dest, root)?;
+        if let Some(x) = dest.parent() {
+            create_dir_all(x)?;
+        }
+    }
+    match state {
+        State::Link(x) => symlink(x, dest),
+        State::File { mtime, .. } => {
+            copy(src, dest)?;
+            let time = UNIX_EPOCH + Duration::from_secs((*mtime).max(0) as u64);
+            File::options().write(true).open(dest)?.set_modified(time)
+        }
+    }
+}
+
+/// Remove a file, and the directories it leaves empty up to `root`.
+fn delete(path: &Path, root: &Path) -> Result<(), std::io::Error> {
+    match remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    for dir in path.ancestors().skip(1).take_while(|x| *x != root) {
+        if remove_dir(dir).is_err() {
+            break
+        }
+    }
+    Ok(())
+}
+
+fn same_contents(a: &Path, b: &Path) -> bool {
+    match (read_link(a), read_link(b)) {
+        (Ok(x), Ok(y)) => x == y,
+        (Err(_), Err(_)) => matches!((read(a), read(b)), (Ok(x), Ok(y)) if x == y),
+        _ => false,
+    }
+}
+
+/// A free name beside `path` for the remote version of a conflicting file,
+/// e.g. notes.txt.conflict, or notes.txt.conflict-2 if that's taken.
+fn conflict_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".conflict");
+    let mut candidate = PathBuf::from(&name);
+    let mut n = 2;
+    while candidate.symlink_metadata().is_ok() {
+        let mut x = name.clone();
+        x.push(format!("-{}", n));
+        candidate = PathBuf::from(x);
+        n += 1;
+    }
+    candidate
+}
@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{ self, BufRead, BufReader, Read, Write };
+use std::net::{ Ipv4Addr, TcpListener, TcpStream };
+use std::sync::{ Arc, Mutex };
+use std::thread;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use crate::summary::{ Outcome, Summary };
+
+/// How long a scrape may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What a daemon has done so far, as Prometheus reports it.
+#[derive(Default)]
+struct State {
+    /// Runs by result name.
+    runs: BTreeMap<&'static str, u64>,
+    last_result: Option<&'static str>,
+    last_run: u64,
+    last_success: u64,
+    last_duration: f64,
+    /// When the run in progress started, if there is one.
+    running_since: Option<u64>,
+    next_run: u64,
+    bytes_up: u64,
+    bytes_down: u64,
+    files_changed: u64,
+    warnings: u64,
+    /// Runs that left conflicts, and whether the last one did.
+    conflict_runs: u64,
+    unresolved_conflicts: bool,
+}
+
+/// A daemon's metrics, served on `/metrics` for Prometheus to scrape.
+#[derive(Clone)]
+pub struct Metrics {
+    profile: String,
+    state: Arc<Mutex<State>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs())
+}
+
+/// Quote a label value.
+fn label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Metrics {
+    /// Serve the profile's metrics on `port` of the loopback interface, on
+    /// a thread of their own.
+    pub fn serve(profile: &str, port: u16) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .map_err(|e| io::Error::new(e.kind(), format!("metrics on port {}: {}", port, e)))?;
+        let metrics = Metrics { profile: profile.to_string(), state: Default::default() };
+        let served = metrics.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // A scraper that misbehaves only loses its own request.
+                let _ = served.answer(stream);
+            }
+        });
+        println!("Serving metrics on http://127.0.0.1:{}/metrics", port);
+        Ok(metrics)
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        // The state stays consistent even if a holder panicked.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Note that a run has started.
+    pub fn started(&self) {
+        self.state().running_since = Some(now());
+    }
+
+    /// Fold a finished run into the metrics.
+    pub fn record(&self, summary: &Summary) {
+        let mut s = self.state();
+        let result = summary.outcome.name();
+        *s.runs.entry(result).or_default() += 1;
+        s.last_result = Some(result);
+        s.last_run = now();
+        if matches!(summary.outcome, Outcome::Completed | Outcome::UpToDate) {
+            s.last_success = s.last_run;
+        }
+        s.last_duration = summary.elapsed.as_secs_f64();
+        s.running_since = None;
+        s.bytes_up += summary.bytes_up;
+        s.bytes_down += summary.bytes_down;
+        s.files_changed += summary.files_changed as u64;
+        s.warnings += summary.warnings.len() as u64;
+        s.conflict_runs += summary.unresolved_conflicts as u64;
+        s.unresolved_conflicts = summary.unresolved_conflicts;
+    }
+
+    /// Note when the next run is due.
+    pub fn next_run(&self, delay: Duration) {
+        self.state().next_run = now() + delay.as_secs();
+    }
+
+    /// The metrics in Prometheus' text format.
+    fn render(&self) -> String {
+        let s = self.state();
+        let p = format!("profile=\"{}\"", label(&self.profile));
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(String, String)]| {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+            for (labels, value) in values {
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+            }
+        };
+        let one = |x: String| [(p.clone(), x)];
+        let runs: Vec<(String, String)> = s.runs
+            .iter()
+            .map(|(result, n)| (format!("{},result=\"{}\"", p, result), n.to_string()))
+            .collect();
+        metric("mist_runs_total", "counter", "Runs, by result.", &runs);
+        let last: Vec<(String, String)> = s.last_result
+            .iter()
+            .map(|x| (format!("{},result=\"{}\"", p, x), "1".to_string()))
+            .collect();
+        metric("mist_last_result", "gauge", "The result of the last run.", &last);
+        metric("mist_last_run_timestamp_seconds", "gauge", "When the last run ended.",
+               &one(s.last_run.to_string()));
+        metric("mist_last_success_timestamp_seconds", "gauge",
+               "When the last run that didn't fail or abort ended.",
+               &one(s.last_success.to_string()));
+        metric("mist_last_run_duration_seconds", "gauge", "How long the last run took.",
+               &one(format!("{:.3}", s.last_duration)));
+        metric("mist_running", "gauge", "Whether a run is in progress.",
+               &one((s.running_since.is_some() as u8).to_string()));
+        metric("mist_run_started_timestamp_seconds", "gauge",
+               "When the run in progress started, or 0.",
+               &one(s.running_since.unwrap_or(0).to_string()));
+        metric("mist_next_run_timestamp_seconds", "gauge", "When the next run is due.",
+               &one(s.next_run.to_string()));
+        metric("mist_bytes_up_total", "counter", "Bytes uploaded.", &one(s.bytes_up.to_string()));
+        metric("mist_bytes_down_total", "counter", "Bytes downloaded.",
+               &one(s.bytes_down.to_string()));
+        metric("mist_files_changed_total", "counter", "Files added, removed or modified.",
+               &one(s.files_changed.to_string()));
+        metric("mist_warnings_total", "counter", "Warnings.", &one(s.warnings.to_string()));
+        metric("mist_conflict_runs_total", "counter", "Runs that left unresolved conflicts.",
+               &one(s.conflict_runs.to_string()));
+        metric("mist_unresolved_conflicts", "gauge", "Whether the last run left conflicts.",
+               &one((s.unresolved_conflicts as u8).to_string()));
+        out
+    }
+
+    /// Answer one request: the metrics for GET /metrics, 404 for anything
+    /// else.
+    fn answer(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut r = BufReader::new(stream);
+        let mut line = String::new();
+        r.by_ref().take(8192).read_line(&mut line)?;
+        let target = line.split(' ').nth(1).unwrap_or("");
+        // Skip the headers; there's no body to a GET.
+        let mut header = String::new();
+        while r.by_ref().take(8192).read_line(&mut header)? > 2 {
+            header.clear();
+        }
+        let (status, body) = match (line.starts_with("GET "), target) {
+            (true, "/metrics") => ("200 OK", self.render()),
+            _ => ("404 Not Found", String::new()),
+        };
+        write!(r.get_mut(), "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                            Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+               status, body.len(), body)?;
+        r.get_mut().flush()
+    }
+}
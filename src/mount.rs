@@ -0,0 +1,419 @@
+use std::collections::{ BTreeMap, HashMap };
+use std::ffi::{ OsStr, OsString };
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{ Component, Path, PathBuf };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use flate2::read::MultiGzDecoder;
+use fuser::{ FUSE_ROOT_ID, FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData,
+             ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, Request };
+use tar::{ Archive, EntryType };
+use tokio::runtime::Handle;
+
+use crate::archive::extract_file;
+use crate::chunks::{ Index, Storage };
+use crate::config::{ Config, Folder };
+use crate::crypto::decrypt_from;
+use crate::format::Format;
+use crate::interrupt;
+use crate::remote::{ self, Remote };
+use crate::spill::Buffer;
+use crate::summary::{ Direction, Summary };
+use crate::{ read_archive, read_chunk_index, read_format, read_ranges, read_version, version_at,
+             version_name };
+
+/// How long the kernel may keep what it's told: nothing in the mount
+/// changes.
+const TTL: Duration = Duration::from_secs(3600);
+
+/// How often to check whether the mount was unmounted from outside.
+const POLL: Duration = Duration::from_secs(1);
+
+/// Where a folder's files are read from.
+enum Source {
+    /// The whole archive, downloaded, decrypted and decompressed when
+    /// mounting, as a single encrypted file can't be read in pieces.
+    Archive(Buffer),
+    /// An archive stored as chunks, of which only those holding a file are
+    /// downloaded, when it's opened.
+    Chunks(Format, Index),
+}
+
+/// Where in its folder's source a file's contents are.
+struct Contents {
+    source: usize,
+    /// The file's path in the archive.
+    path: PathBuf,
+    /// For an archive, where the file's data starts; for chunks, the byte
+    /// range of its entry.
+    start: u64,
+    len: u64,
+}
+
+/// A file, link or directory in the mount.
+struct Node {
+    attr: FileAttr,
+    parent: u64,
+    /// For a directory, what's in it by name.
+    children: BTreeMap<OsString, u64>,
+    contents: Option<Contents>,
+    /// For a link, where it points.
+    target: Option<PathBuf>,
+}
+
+impl Node {
+    fn new(kind: FileType, size: u64, perm: u16, mtime: SystemTime) -> Self {
+        // Only the user who mounted it can look inside anyway.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let attr = FileAttr {
+            ino: 0, size, blocks: size.div_ceil(512), atime: mtime, mtime, ctime: mtime,
+            crtime: mtime, kind, perm, nlink: 1, uid, gid, rdev: 0, blksize: 4096, flags: 0,
+        };
+        Node { attr, parent: 0, children: BTreeMap::new(), contents: None, target: None }
+    }
+}
+
+/// A read-only view of a profile's remote archives: one folder's at the
+/// root, or with several, each under its own name.
+pub struct Mount {
+    cfg: Config,
+    s: Remote,
+    runtime: Handle,
+    summary: Summary,
+    sources: Vec<(Folder, Source)>,
+    /// Nodes by inode number, less one.
+    nodes: Vec<Node>,
+    /// The contents of files from chunks, while they're open.
+    open: HashMap<u64, Vec<u8>>,
+    next_handle: u64,
+}
+
+/// Read into `buf` from `offset` of `data` until it's full or the data
+/// ends, returning how much was read.
+fn read_full(data: &Buffer, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match data.read_at(offset + n as u64, &mut buf[n..])? {
+            0 => break,
+            x => n += x,
+        }
+    }
+    Ok(n)
+}
+
+impl Mount {
+    /// Read what's needed to list the profile's folders as they are, or
+    /// with `at`, as the kept versions from that time.
+    async fn new(profile: &str, cfg: Config, s: Remote, at: Option<u64>)
+    -> Result<Self, Box<dyn std::error::Error>> {
+        let mut root = Node::new(FileType::Directory, 0, 0o555, SystemTime::now());
+        root.attr.ino = FUSE_ROOT_ID;
+        root.parent = FUSE_ROOT_ID;
+        let mut m = Mount {
+            cfg,
+            s,
+            runtime: Handle::current(),
+            summary: Summary::new(profile, Direction::Pull),
+            sources: Vec::new(),
+            nodes: vec![root],
+            open: HashMap::new(),
+            next_handle: 1,
+        };
+        let folders = m.cfg.folders.clone();
+        for f in &folders {
+            let dir = match folders.len() {
+                1 => FUSE_ROOT_ID,
+                _ => {
+                    let name = f.dir.file_name().unwrap_or(f.dir.as_os_str());
+                    if m.nodes[0].children.contains_key(name) {
+                        return Err(format!("Two folders are named {:?}, so they can't both be \
+                                            mounted", name).into())
+                    }
+                    let node = Node::new(FileType::Directory, 0, 0o555, SystemTime::now());
+                    m.insert(FUSE_ROOT_ID, name, node)
+                }
+            };
+            m.load(f, dir, at).await?;
+        }
+        Ok(m)
+    }
+
+    /// List the folder's files under the directory `dir`.
+    async fn load(&mut self, f: &Folder, dir: u64, at: Option<u64>)
+    -> Result<(), Box<dyn std::error::Error>> {
+        let fmt = read_format(&mut self.s, f).await?;
+        let source = self.sources.len();
+        if fmt.storage == Storage::Archive {
+            if at.is_some() {
+                return Err(format!("No versions are kept of {} (set keep_versions with \
+                                    storage = \"chunks\")", f.dir.display()).into())
+            }
+            println!("Downloading {}...", f.dir.display());
+            let data = read_archive(&mut self.s, &self.cfg, f).await?;
+            let mut tar = Buffer::new(self.cfg.spill_above);
+            decrypt_from(&data, &self.cfg, &mut tar).await?;
+            drop(data);
+            if fmt.compression.is_gzip() {
+                let mut plain = Buffer::new(self.cfg.spill_above);
+                io::copy(&mut MultiGzDecoder::new(tar.reader()), &mut plain)?;
+                tar = plain;
+            }
+            for e in Archive::new(tar.reader()).entries()? {
+                let e = e?;
+                let kind = match e.header().entry_type() {
+                    EntryType::Directory => FileType::Directory,
+                    EntryType::Symlink => FileType::Symlink,
+                    x if x.is_file() => FileType::RegularFile,
+                    _ => continue,
+                };
+                let mtime = UNIX_EPOCH + Duration::from_secs(e.header().mtime()?);
+                let perm = (e.header().mode()? & 0o555) as u16;
+                let mut node = Node::new(kind, e.size(), perm, mtime);
+                let path = e.path()?.into_owned();
+                match kind {
+                    FileType::Symlink => {
+                        let target = e.link_name()?.unwrap_or_default().into_owned();
+                        node.attr.size = target.as_os_str().len() as u64;
+                        node.target = Some(target);
+                    }
+                    FileType::RegularFile => {
+                        let start = e.raw_file_position();
+                        node.contents = Some(Contents { source, path: path.clone(), start,
+                                                        len: e.size() });
+                    }
+                    _ => (),
+                }
+                self.add(dir, &path, node);
+            }
+            self.sources.push((f.clone(), Source::Archive(tar)));
+            return Ok(())
+        }
+
+        let (index, mtime) = match at {
+            Some(time) => {
+                let version = version_at(&mut self.s, f, time).await?;
+                println!("{}: the version from {}", f.dir.display(), version_name(version));
+                (read_version(&mut self.s, &self.cfg, f, version).await?,
+                 UNIX_EPOCH + Duration::from_secs(version))
+            }
+            None => (read_chunk_index(&mut self.s, &self.cfg, f).await?, SystemTime::now()),
+        };
+        if index.entries.iter().all(|x| x.size.is_none()) {
+            return Err(format!("The remote chunk index of {} was written by an older mist, \
+                                which didn't record its files; push once to update it",
+                               f.dir.display()).into())
+        }
+        // The index doesn't tell links from files, so links show as empty files.
+        for e in &index.entries {
+            let node = match e.size {
+                None => Node::new(FileType::Directory, 0, 0o555, mtime),
+                Some(size) => Node {
+                    contents: Some(Contents { source, path: e.path.clone(), start: e.start,
+                                              len: e.len }),
+                    ..Node::new(FileType::RegularFile, size, 0o444, mtime)
+                },
+            };
+            self.add(dir, &e.path, node);
+        }
+        self.sources.push((f.clone(), Source::Chunks(fmt, index)));
+        Ok(())
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get(ino.checked_sub(1)? as usize)
+    }
+
+    /// Put `node` in the directory `parent` as `name`, returning its inode
+    /// number.
+    fn insert(&mut self, parent: u64, name: &OsStr, mut node: Node) -> u64 {
+        let ino = self.nodes.len() as u64 + 1;
+        node.attr.ino = ino;
+        node.parent = parent;
+        self.nodes.push(node);
+        self.nodes[parent as usize - 1].children.insert(name.to_os_string(), ino);
+        ino
+    }
+
+    /// Put `node` at `path` under the directory `dir`, making the
+    /// directories on the way that aren't there yet. A node that's there
+    /// already takes the new one's place, keeping what's in it. Paths
+    /// leading out of the archive are left out.
+    fn add(&mut self, mut dir: u64, path: &Path, node: Node) {
+        let mut parts = Vec::new();
+        for c in path.components() {
+            match c {
+                Component::Normal(x) => parts.push(x),
+                Component::CurDir => (),
+                _ => return,
+            }
+        }
+        let (name, dirs) = match parts.split_last() {
+            Some(x) => x,
+            None => return,
+        };
+        for d in dirs {
+            dir = match self.nodes[dir as usize - 1].children.get(*d) {
+                Some(x) if self.nodes[*x as usize - 1].attr.kind == FileType::Directory => *x,
+                Some(_) => return,
+                None => {
+                    let mtime = self.nodes[dir as usize - 1].attr.mtime;
+                    self.insert(dir, d, Node::new(FileType::Directory, 0, 0o555, mtime))
+                }
+            };
+        }
+        match self.nodes[dir as usize - 1].children.get(*name).copied() {
+            Some(ino) => {
+                let old = &mut self.nodes[ino as usize - 1];
+                old.attr = FileAttr { ino, ..node.attr };
+                old.contents = node.contents;
+                old.target = node.target;
+            }
+            None => {
+                self.insert(dir, name, node);
+            }
+        }
+    }
+}
+
+impl Filesystem for Mount {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let found = self.node(parent)
+            .and_then(|x| x.children.get(name))
+            .and_then(|x| self.node(*x));
+        match found {
+            Some(x) => reply.entry(&TTL, &x.attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        match self.node(ino) {
+            Some(x) => reply.attr(&TTL, &x.attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.node(ino).and_then(|x| x.target.as_ref()) {
+            Some(x) => reply.data(x.as_os_str().as_bytes()),
+            None => reply.error(libc::EINVAL),
+        }
+    }
+
+    fn readdir(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64,
+               mut reply: ReplyDirectory) {
+        let node = match self.node(ino) {
+            Some(x) if x.attr.kind == FileType::Directory => x,
+            Some(_) => return reply.error(libc::ENOTDIR),
+            None => return reply.error(libc::ENOENT),
+        };
+        let mut entries = vec![(ino, FileType::Directory, OsStr::new(".")),
+                               (node.parent, FileType::Directory, OsStr::new(".."))];
+        entries.extend(node.children
+            .iter()
+            .map(|(name, x)| (*x, self.nodes[*x as usize - 1].attr.kind, name.as_os_str())));
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, i as i64 + 1, kind, name) {
+                break
+            }
+        }
+        reply.ok();
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return reply.error(libc::EROFS)
+        }
+        let Mount { cfg, s, runtime, summary, sources, nodes, open, next_handle } = self;
+        let c = match nodes.get(ino as usize - 1).and_then(|x| x.contents.as_ref()) {
+            Some(x) => x,
+            None => return reply.error(libc::EISDIR),
+        };
+        let (f, source) = &sources[c.source];
+        let (fmt, index) = match source {
+            Source::Archive(_) => return reply.opened(0, 0),
+            Source::Chunks(fmt, index) => (fmt, index),
+        };
+        let ranges = [(c.start, c.start + c.len)];
+        let data = runtime.block_on(read_ranges(s, cfg, f, fmt, index, &ranges, summary))
+            .and_then(|x| Ok(extract_file(&Buffer::from(x), &c.path, false)?))
+            .and_then(|x| Ok(x.ok_or("it isn't a file in the archive")?));
+        match data {
+            Ok(x) => {
+                let fh = *next_handle;
+                *next_handle += 1;
+                open.insert(fh, x);
+                reply.opened(fh, 0);
+            }
+            Err(e) => {
+                eprintln!("Reading {}: {}", f.dir.join(&c.path).display(), e);
+                reply.error(libc::EIO);
+            }
+        }
+    }
+
+    fn read(&mut self, _req: &Request<'_>, ino: u64, fh: u64, offset: i64, size: u32, _flags: i32,
+            _lock_owner: Option<u64>, reply: ReplyData) {
+        let c = match self.node(ino).and_then(|x| x.contents.as_ref()) {
+            Some(x) => x,
+            None => return reply.error(libc::EISDIR),
+        };
+        let offset = offset.max(0) as u64;
+        match &self.sources[c.source].1 {
+            Source::Archive(tar) => {
+                let mut buf = vec![0; (size as u64).min(c.len.saturating_sub(offset)) as usize];
+                match read_full(tar, c.start + offset, &mut buf) {
+                    Ok(n) => reply.data(&buf[..n]),
+                    Err(e) => {
+                        eprintln!("Reading {}: {}", c.path.display(), e);
+                        reply.error(libc::EIO);
+                    }
+                }
+            }
+            Source::Chunks(..) => match self.open.get(&fh) {
+                Some(data) => {
+                    let from = (offset as usize).min(data.len());
+                    let to = (from + size as usize).min(data.len());
+                    reply.data(&data[from..to]);
+                }
+                None => reply.error(libc::EBADF),
+            },
+        }
+    }
+
+    fn release(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, _flags: i32,
+               _lock_owner: Option<u64>, _flush: bool, reply: ReplyEmpty) {
+        self.open.remove(&fh);
+        reply.ok();
+    }
+}
+
+/// Mount a profile's remote archives read-only on `mountpoint`, as they
+/// are or, with `at`, as the kept versions from that time, until Ctrl-C
+/// or until it's unmounted.
+pub async fn run(profile: &str, cfg: Config, mountpoint: &Path, at: Option<u64>)
+-> Result<(), Box<dyn std::error::Error>> {
+    let s = remote::connect(&cfg).await?;
+    let mount = Mount::new(profile, cfg, s, at).await?;
+    let options = [
+        MountOption::RO,
+        MountOption::FSName(format!("mist:{}", profile)),
+        MountOption::Subtype("mist".to_string()),
+        MountOption::DefaultPermissions,
+    ];
+    let session = fuser::spawn_mount2(mount, mountpoint, &options)
+        .map_err(|e| format!("Can't mount on {}: {}", mountpoint.display(), e))?;
+    println!("Mounted [{}] read-only on {}; press Ctrl-C to unmount it", profile,
+             mountpoint.display());
+    interrupt::watch();
+    while ! session.guard.is_finished() {
+        tokio::select! {
+            _ = interrupt::stopped() => break,
+            _ = tokio::time::sleep(POLL) => (),
+        }
+    }
+    drop(session);
+    println!("Unmounted {}", mountpoint.display());
+    Ok(())
+}
@@ -0,0 +1,142 @@
+use std::net::{ IpAddr, TcpStream, ToSocketAddrs, UdpSocket };
+use std::process::Command;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::http;
+use crate::ssh::{ self, SshClient };
+
+/// Which networks `--daemon` syncs over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkPolicy {
+    /// Don't check; try to sync whatever the network.
+    Any,
+    /// Wait while the remote host can't be reached.
+    Online,
+    /// Also wait while the connection is metered (e.g. a phone's hotspot).
+    Unmetered,
+}
+
+impl NetworkPolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "any"       => Some(NetworkPolicy::Any),
+            "online"    => Some(NetworkPolicy::Online),
+            "unmetered" => Some(NetworkPolicy::Unmetered),
+            _ => None,
+        }
+    }
+}
+
+/// How long the reachability probe waits for the remote host.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Read a property of NetworkManager over D-Bus, e.g. "u 4" for a number.
+fn networkmanager(property: &str) -> Option<u32> {
+    let out = Command::new("busctl")
+        .args(["--system", "get-property", "org.freedesktop.NetworkManager",
+               "/org/freedesktop/NetworkManager", "org.freedesktop.NetworkManager", property])
+        .output()
+        .ok()?;
+    if ! out.status.success() {
+        return None
+    }
+    String::from_utf8_lossy(&out.stdout).trim().strip_prefix("u ")?.parse().ok()
+}
+
+/// Whether the connection is metered, according to NetworkManager. None if
+/// it isn't running, or doesn't know.
+fn metered() -> Option<bool> {
+    // NM_METERED_YES, NO, GUESS_YES and GUESS_NO.
+    match networkmanager("Metered")? {
+        1 | 3 => Some(true),
+        2 | 4 => Some(false),
+        _ => None,
+    }
+}
+
+/// Whether NetworkManager says there's no network at all. None if it isn't
+/// running, or hasn't checked.
+fn offline() -> Option<bool> {
+    // NM_CONNECTIVITY_NONE; PORTAL, LIMITED and FULL may all reach the host.
+    match networkmanager("Connectivity")? {
+        0 => None,
+        x => Some(x == 1),
+    }
+}
+
+/// This machine's address on the network, as other machines would reach it.
+pub fn local_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    // Connecting a UDP socket sends nothing; it only picks the route out.
+    socket.connect("8.8.8.8:53").ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// The host and port the first hop of the connection goes to, as ssh
+/// itself works them out from ~/.ssh/config.
+fn first_hop(cfg: &Config) -> Option<(String, u16)> {
+    if let Some(x) = cfg.http_url.as_ref().or(cfg.restic_url.as_ref()) {
+        return http::host_and_port(x)
+    }
+    // The builtin client goes straight to the host, with no ssh_config.
+    if cfg.ssh_client == SshClient::Builtin {
+        let (_, host, port) = ssh::address(cfg);
+        return Some((host, port))
+    }
+    let mut cmd = Command::new("ssh");
+    cmd.arg("-G");
+    if let Some(x) = cfg.ssh_port {
+        cmd.arg("-p").arg(x.to_string());
+    }
+    let out = cmd.arg(&cfg.sshaddr).output().ok()?;
+    if ! out.status.success() {
+        return None
+    }
+    let text = String::from_utf8_lossy(&out.stdout);
+    let get = |key: &str| text.lines()
+        .find_map(|x| x.strip_prefix(key)?.strip_prefix(' '))
+        .map(str::trim);
+    // A jump host, whether from the profile or ~/.ssh/config, is what's
+    // connected to first: "[user@]host[:port]", maybe the first of several.
+    let jump = cfg.proxy_jump.as_deref().or_else(|| get("proxyjump").filter(|x| *x != "none"));
+    if let Some(x) = jump {
+        let x = x.split(',').next()?;
+        let x = x.rsplit_once('@').map_or(x, |(_, h)| h);
+        return match x.rsplit_once(':') {
+            Some((h, p)) => Some((h.to_string(), p.parse().ok()?)),
+            None => Some((x.to_string(), 22)),
+        }
+    }
+    Some((get("hostname")?.to_string(), get("port")?.parse().ok()?))
+}
+
+/// Whether the first hop of the connection accepts connections.
+fn reachable(cfg: &Config) -> bool {
+    let (host, port) = match first_hop(cfg) {
+        Some(x) => x,
+        // Let the sync itself find out.
+        None => return true,
+    };
+    let addrs = match (host.as_str(), port).to_socket_addrs() {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    addrs.into_iter().any(|x| TcpStream::connect_timeout(&x, PROBE_TIMEOUT).is_ok())
+}
+
+/// Why a daemon shouldn't sync over the network as it is now, or None if
+/// it should. NetworkManager is asked first; without it, whether the
+/// remote host can be reached is all that can be checked.
+pub fn hold_back(cfg: &Config) -> Option<&'static str> {
+    if cfg.network == NetworkPolicy::Any {
+        return None
+    }
+    if offline() == Some(true) || ! reachable(cfg) {
+        return Some("the network is down or the remote host can't be reached")
+    }
+    if cfg.network == NetworkPolicy::Unmetered && metered() == Some(true) {
+        return Some("the connection is metered")
+    }
+    None
+}
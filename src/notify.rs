@@ -0,0 +1,78 @@
+#[cfg(feature = "notify")]
+use notify_rust::{ Notification, Urgency };
+
+use crate::summary::{ Outcome, Summary };
+
+/// When to show a desktop notification, or call the webhook, at the end of
+/// a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotifyPolicy {
+    Never,
+    Problems,
+    Always,
+}
+
+impl NotifyPolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "never"    => Some(NotifyPolicy::Never),
+            "problems" => Some(NotifyPolicy::Problems),
+            "always"   => Some(NotifyPolicy::Always),
+            _ => None,
+        }
+    }
+
+    /// Whether the policy asks to be told about this run.
+    pub fn wants(self, summary: &Summary) -> bool {
+        match self {
+            NotifyPolicy::Never    => false,
+            NotifyPolicy::Problems => has_problems(summary),
+            NotifyPolicy::Always   => true,
+        }
+    }
+}
+
+/// Whether a run went badly enough to notify under the "problems" policy.
+fn has_problems(summary: &Summary) -> bool {
+    matches!(summary.outcome, Outcome::Failed(_))
+        || summary.unresolved_conflicts
+        || ! summary.warnings.is_empty()
+}
+
+/// Show a desktop notification describing the run, if the policy asks for one.
+/// Failing to reach the notification daemon is recorded as a warning.
+#[cfg(feature = "notify")]
+pub fn send(policy: NotifyPolicy, summary: &mut Summary) {
+    if ! policy.wants(summary) {
+        return
+    }
+
+    let (title, urgency) = match &summary.outcome {
+        Outcome::Failed(_) => (format!("mist [{}]: sync failed", summary.profile),
+                               Urgency::Critical),
+        _ if summary.unresolved_conflicts =>
+            (format!("mist [{}]: conflicts need attention", summary.profile),
+             Urgency::Normal),
+        _ => (format!("mist [{}]: {}", summary.profile, summary.outcome.name()),
+              Urgency::Low),
+    };
+    let mut body = format!("{}, {}", summary.direction, summary.outcome);
+    if ! summary.warnings.is_empty() {
+        body.push_str(&format!("\n{} warning(s)", summary.warnings.len()));
+    }
+
+    let res = Notification::new()
+        .appname("mist")
+        .summary(&title)
+        .body(&body)
+        .urgency(urgency)
+        .show();
+    if let Err(e) = res {
+        summary.warn(format!("Desktop notification failed: {}", e));
+    }
+}
+
+/// Built without notifications; the configuration refuses any policy but
+/// "never", so there's nothing to do.
+#[cfg(not(feature = "notify"))]
+pub fn send(_policy: NotifyPolicy, _summary: &mut Summary) {}
@@ -0,0 +1,180 @@
+use std::fs::{ metadata, read_to_string, remove_file, rename, set_permissions, write };
+use std::path::{ Path, PathBuf };
+
+use toml_edit::{ Document, Item, Table, Value };
+
+use crate::config::{ ConfigError, DEFAULTS, PROFILE_ONLY, check_key, load_configuration,
+                     profile_names };
+use crate::prompt::{ self, Answer };
+
+/// Read the configuration file for editing, keeping its comments and
+/// layout. Only TOML files can be edited.
+fn read_document(path: &Path) -> Result<Document, Box<dyn std::error::Error>> {
+    match path.extension().and_then(|x| x.to_str()) {
+        Some("yaml") | Some("yml") | Some("json") => return Err(format!(
+            "{} isn't TOML; edit it with the tool that generates it", path.display()).into()),
+        _ => (),
+    }
+    let text = read_to_string(path)
+        .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    Ok(text.parse().map_err(|e| format!("{} is not valid TOML: {}", path.display(), e))?)
+}
+
+/// Where an edited configuration is written before it replaces the
+/// original, so a failed write never leaves a half-written file.
+fn scratch_path(path: &Path) -> PathBuf {
+    let mut x = path.as_os_str().to_owned();
+    x.push(".new");
+    PathBuf::from(x)
+}
+
+/// The problems loading `profile` from `doc` reports, or, for the
+/// [defaults] section, every profile's problems.
+async fn problems(path: &Path, doc: &Document, section: &str)
+-> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let scratch = scratch_path(path);
+    write(&scratch, doc.to_string())?;
+    let profiles = match section {
+        DEFAULTS => profile_names(&scratch)?,
+        x => vec![x.to_string()],
+    };
+    let mut found = Vec::new();
+    for p in profiles {
+        match load_configuration(&scratch, &p).await {
+            Ok(_) => (),
+            Err(e) => match e.downcast_ref::<ConfigError>() {
+                Some(x) => found.extend(x.problems.iter().map(|x| format!("[{}] {}", p, x))),
+                None => found.push(e.to_string()),
+            },
+        }
+    }
+    Ok(found)
+}
+
+/// Replace the configuration file with the edited document, keeping the
+/// original's permissions.
+fn save(path: &Path, doc: &Document) -> Result<(), Box<dyn std::error::Error>> {
+    let scratch = scratch_path(path);
+    write(&scratch, doc.to_string())?;
+    set_permissions(&scratch, metadata(path)?.permissions())?;
+    rename(&scratch, path)?;
+    Ok(())
+}
+
+/// An entry's value as written in the file, without its comment.
+fn show_value(item: &Item) -> String {
+    let mut item = item.clone();
+    if let Some(x) = item.as_value_mut() {
+        x.decor_mut().clear();
+    }
+    item.to_string().trim().to_string()
+}
+
+/// List the profiles in the configuration file, with their aliases and
+/// folders.
+pub fn list(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let doc = read_document(path)?;
+    for (name, item) in doc.iter() {
+        let table = match item.as_table() {
+            Some(x) if name != DEFAULTS => x,
+            _ => continue,
+        };
+        let mut notes = Vec::new();
+        if table.get("default").and_then(|x| x.as_bool()) == Some(true) {
+            notes.push("default".to_string());
+        }
+        if let Some(x) = table.get("aliases") {
+            notes.push(format!("aliases: {}", show_value(x)));
+        }
+        let folder = table.get("folder")
+            .or_else(|| doc.get(DEFAULTS).and_then(|x| x.get("folder")))
+            .map(show_value)
+            .unwrap_or_default();
+        match notes.is_empty() {
+            true  => println!("{:<16} {}", name, folder),
+            false => println!("{:<16} {} ({})", name, folder, notes.join("; ")),
+        }
+    }
+    Ok(())
+}
+
+/// Print a profile's entries, followed by those it takes from [defaults].
+pub fn show(path: &Path, profile: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let doc = read_document(path)?;
+    let table = doc.get(profile)
+        .and_then(|x| x.as_table())
+        .ok_or(format!("Profile [{}] not found in {}", profile, path.display()))?;
+    println!("[{}]", profile);
+    for (k, v) in table.iter() {
+        println!("{} = {}", k, show_value(v));
+    }
+    if profile == DEFAULTS {
+        return Ok(())
+    }
+    if let Some(defaults) = doc.get(DEFAULTS).and_then(|x| x.as_table()) {
+        for (k, v) in defaults.iter().filter(|(k, _)| ! table.contains_key(k)) {
+            println!("{} = {}  # from [{}]", k, show_value(v), DEFAULTS);
+        }
+    }
+    Ok(())
+}
+
+/// Set an entry of a profile, creating the profile if it doesn't exist.
+/// The value is read as TOML (a number, `true`, an array, a quoted
+/// string) if it is valid TOML and taken as a string otherwise. The change
+/// is refused if it would make the configuration invalid in a new way.
+pub async fn set(path: &Path, profile: &str, key: &str, value: &str)
+-> Result<(), Box<dyn std::error::Error>> {
+    check_key(key)?;
+    if profile == DEFAULTS && PROFILE_ONLY.contains(&key) {
+        return Err(format!("'{}' can't be set in [{}]", key, DEFAULTS).into())
+    }
+    let mut doc = read_document(path)?;
+    if doc.get(profile).is_none() {
+        doc[profile] = Item::Table(Table::new());
+    }
+    if ! doc[profile].is_table() {
+        return Err(format!("[{}] is not a profile section", profile).into())
+    }
+    let value = value.parse::<Value>().unwrap_or_else(|_| Value::from(value));
+    let res: Result<(), Box<dyn std::error::Error>> = async {
+        let before = problems(path, &doc, profile).await?;
+        doc[profile][key] = Item::Value(value);
+        let after = problems(path, &doc, profile).await?;
+        let new: Vec<&String> = after.iter().filter(|x| ! before.contains(x)).collect();
+        if ! new.is_empty() {
+            let list: Vec<String> = new.iter().map(|x| format!("\n  - {}", x)).collect();
+            return Err(format!("Not changed; setting {} in [{}] would cause:{}", key, profile,
+                               list.concat()).into())
+        }
+        save(path, &doc)?;
+        if ! after.is_empty() {
+            println!("The configuration still has problems:");
+            for x in &after {
+                println!("  - {}", x);
+            }
+        }
+        Ok(())
+    }.await;
+    let _ = remove_file(scratch_path(path));
+    res?;
+    println!("Set {} in [{}] of {}", key, profile, path.display());
+    Ok(())
+}
+
+/// Remove a profile from the configuration file, after confirmation.
+pub fn remove(path: &Path, profile: &str, assume: Answer)
+-> Result<(), Box<dyn std::error::Error>> {
+    let mut doc = read_document(path)?;
+    if doc.get(profile).and_then(|x| x.as_table()).is_none() {
+        return Err(format!("Profile [{}] not found in {}", profile, path.display()).into())
+    }
+    let q = format!("Remove [{}] from {}?", profile, path.display());
+    if ! prompt::confirm(&q, Answer::Ask, assume) {
+        return Ok(())
+    }
+    doc.as_table_mut().remove(profile);
+    save(path, &doc)?;
+    println!("Removed [{}]", profile);
+    Ok(())
+}
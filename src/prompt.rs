@@ -0,0 +1,61 @@
+use std::io::stdin;
+
+use crate::keys;
+
+/// How a confirmation prompt is answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Answer {
+    /// Ask, unless `--assume-yes` or `--assume-no` says otherwise.
+    Ask,
+    Yes,
+    No,
+}
+
+impl Answer {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "ask" => Some(Answer::Ask),
+            "yes" => Some(Answer::Yes),
+            "no"  => Some(Answer::No),
+            _ => None,
+        }
+    }
+}
+
+/// A profile's standing answers to each of mist's prompts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Answers {
+    /// "Remote storage exists: overwrite?" on --push.
+    pub overwrite_remote: Answer,
+    /// "Local directory exists: overwrite?" on --pull.
+    pub overwrite_local: Answer,
+    /// "Transfer to remote anyway?" after unison reports a problem.
+    pub push_after_error: Answer,
+    /// "Encrypt with <key>?" for a profile without a gpg_id.
+    pub gpg_key: Answer,
+    /// "Remote directory <dir> doesn't exist: create it?" on --push.
+    pub create_remote: Answer,
+}
+
+/// Ask for user confirmation, return true if confirmation recieved or false
+/// if not. A standing answer from the profile wins over `assume`, which is
+/// what the command line says to answer everything else with.
+pub fn confirm(prompt: &str, standing: Answer, assume: Answer) -> bool {
+    let answer = match standing {
+        Answer::Ask => assume,
+        x => x,
+    };
+    match answer {
+        Answer::Yes => return true,
+        Answer::No  => {
+            println!("{} no", prompt);
+            return false
+        }
+        Answer::Ask => (),
+    }
+    let _prompt = keys::PROMPT.lock();
+    println!("{}", prompt);
+    let mut inpt = String::new();
+    stdin().read_line(&mut inpt).expect("Failed to read line");
+    matches!(inpt.trim(), "y" | "Y" | "yes")
+}
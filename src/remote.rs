@@ -0,0 +1,499 @@
+// The runs share one thread (see the LocalSet in main), so nothing needs
+// the traits' futures to be Send.
+#![allow(async_fn_in_trait)]
+
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::hash::{ cksum, from_hex };
+#[cfg(feature = "http")]
+use crate::http;
+#[cfg(feature = "restic")]
+use crate::restic;
+#[cfg(any(feature = "http", feature = "restic"))]
+use crate::secrets::run_command;
+use crate::ssh::{ self, Connection };
+
+/// What a command run on the remote host left: its exit code, if it had one,
+/// and what it printed. Its stderr goes to the terminal.
+pub struct Output {
+    pub code: Option<i32>,
+    pub stdout: Vec<u8>,
+}
+
+impl Output {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    /// How the command ended, for messages.
+    pub fn status(&self) -> String {
+        match self.code {
+            Some(x) => format!("exit status: {}", x),
+            None => "no exit code".to_string(),
+        }
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.stdout).into_owned()
+    }
+}
+
+/// A connection that runs commands on the remote host, such as an SSH
+/// session. Any of them can store folders, with the standard Unix tools.
+pub trait Shell {
+    /// Run a command, with `args` passed as they are (no quoting needed),
+    /// and `stdin` as its input.
+    async fn run(&mut self, args: &[&str], stdin: Option<&[u8]>)
+    -> Result<Output, Box<dyn std::error::Error>>;
+
+    /// Get the contents of a remote file.
+    async fn read_file(&mut self, path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let out = self.run(&["cat", path], None).await?;
+        if ! out.success() {
+            return Err(format!("Remote: cat {}: {}", path, out.status()).into())
+        }
+        Ok(out.stdout)
+    }
+}
+
+/// One entry of a remote directory.
+pub struct Entry {
+    pub name: String,
+    pub dir: bool,
+    pub size: u64,
+    /// When it was last modified, as the remote shows it.
+    pub date: String,
+}
+
+/// Parse a line of `ls -ln` output: mode, links, owner, group, size, three
+/// date fields, then the name.
+fn parse_entry(line: &str) -> Option<Entry> {
+    let mut fields = line.split_whitespace();
+    let mode = fields.next()?;
+    let size = fields.nth(3)?.parse().ok()?;
+    let date: Vec<&str> = fields.by_ref().take(3).collect();
+    let name = fields.collect::<Vec<_>>().join(" ");
+    if date.len() != 3 || name.is_empty() {
+        return None
+    }
+    Some(Entry { name, dir: mode.starts_with('d'), size, date: date.join(" ") })
+}
+
+/// The bytes available to unprivileged users, from the output of `df -Pk`:
+/// a header, then the filesystem, its size, used and available KiB, ....
+pub fn parse_df(out: &str) -> Option<u64> {
+    let kib: u64 = out.lines().nth(1)?.split_whitespace().nth(3)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Where a profile's folders are kept: files at paths relative to the
+/// remote home directory, like "Documents.tar.gz.gpg", and directories of
+/// chunks. Everything mist does on the remote goes through this.
+pub trait RemoteStore {
+    /// Get the contents of a file.
+    async fn read(&mut self, path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
+
+    /// Write a file, replacing it if it's there. Returns false if the
+    /// transfer itself failed, rather than the connection.
+    async fn write(&mut self, path: &str, bytes: &[u8])
+    -> Result<bool, Box<dyn std::error::Error>>;
+
+    /// Check that a file holds exactly `bytes`, without downloading it.
+    async fn verify(&mut self, path: &str, bytes: &[u8])
+    -> Result<(), Box<dyn std::error::Error>>;
+
+    /// The SHA-256 of a file, computed where it's stored, or None if the
+    /// remote can't compute one.
+    async fn checksum(&mut self, path: &str) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>>;
+
+    /// Whether a write that fails part way leaves the file as it was, so
+    /// uploads needn't go to a temporary file first.
+    fn atomic_writes(&self) -> bool {
+        false
+    }
+
+    /// Move a file over another.
+    async fn rename(&mut self, from: &str, to: &str) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Whether a file exists.
+    async fn exists(&mut self, path: &str) -> Result<bool, Box<dyn std::error::Error>>;
+
+    /// Whether a directory exists.
+    async fn is_dir(&mut self, path: &str) -> Result<bool, Box<dyn std::error::Error>>;
+
+    /// Create a directory and any missing parents.
+    async fn create_dir(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Remove files and directories, ignoring any that don't exist.
+    async fn remove(&mut self, paths: &[String]) -> Result<(), Box<dyn std::error::Error>>;
+
+    /// The entries of a directory, or nothing if it can't be read.
+    async fn list(&mut self, dir: &str) -> Result<Vec<Entry>, Box<dyn std::error::Error>>;
+
+    /// The paths of the files under `dir` last modified more than `age` ago.
+    async fn older_than(&mut self, dir: &str, age: Duration)
+    -> Result<Vec<String>, Box<dyn std::error::Error>>;
+
+    /// Add a line to the end of a file, keeping its last `keep` lines.
+    async fn append(&mut self, path: &str, line: &str, keep: usize)
+    -> Result<(), Box<dyn std::error::Error>>;
+
+    /// Free space in a directory, or None if the remote can't say.
+    async fn free(&mut self, dir: &str) -> Option<u64>;
+
+    /// Space taken by files (or directories); missing ones count as nothing.
+    async fn usage(&mut self, paths: &[String]) -> u64;
+}
+
+/// Fail with the command's status unless it succeeded.
+fn check(what: &str, out: Output) -> Result<Output, Box<dyn std::error::Error>> {
+    match out.success() {
+        true  => Ok(out),
+        false => Err(format!("Remote: {}: {}", what, out.status()).into()),
+    }
+}
+
+impl<T: Shell> RemoteStore for T {
+    async fn read(&mut self, path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.read_file(path).await
+    }
+
+    async fn write(&mut self, path: &str, bytes: &[u8])
+    -> Result<bool, Box<dyn std::error::Error>> {
+        let dest = format!("of={}", path);
+        let out = self.run(&["dd", "status=progress", &dest], Some(bytes)).await?;
+        Ok(out.success())
+    }
+
+    /// Compares the size and checksum `cksum` reports on the remote host,
+    /// rather than trusting that a transfer which exited cleanly wrote
+    /// everything.
+    async fn verify(&mut self, path: &str, bytes: &[u8])
+    -> Result<(), Box<dyn std::error::Error>> {
+        let text = check(&format!("cksum {}", path), self.run(&["cksum", path], None).await?)?
+            .text();
+        let mut fields = text.split_whitespace();
+        let crc: Option<u32> = fields.next().and_then(|x| x.parse().ok());
+        let len: Option<u64> = fields.next().and_then(|x| x.parse().ok());
+        if crc != Some(cksum(bytes)) || len != Some(bytes.len() as u64) {
+            return Err(format!("Upload of {} is corrupt: sent {} bytes with cksum {}, remote \
+                                has '{}'", path, bytes.len(), cksum(bytes), text.trim()).into())
+        }
+        Ok(())
+    }
+
+    /// With `sha256sum`, or `shasum` where there's no GNU or BusyBox one.
+    async fn checksum(&mut self, path: &str) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let script = "command -v sha256sum >/dev/null && exec sha256sum -- \"$1\"; \
+                      command -v shasum >/dev/null && exec shasum -a 256 -- \"$1\"; exit 127";
+        let out = self.run(&["sh", "-c", script, "sh", path], None).await?;
+        if out.code == Some(127) {
+            return Ok(None)
+        }
+        let text = check(&format!("sha256sum {}", path), out)?.text();
+        let digest = text.split_whitespace()
+            .next()
+            .and_then(from_hex)
+            .filter(|x| x.len() == 32)
+            .ok_or(format!("Remote: sha256sum {}: unexpected output '{}'", path, text.trim()))?;
+        Ok(Some(digest))
+    }
+
+    async fn rename(&mut self, from: &str, to: &str) -> Result<(), Box<dyn std::error::Error>> {
+        check(&format!("mv {} {}", from, to), self.run(&["mv", "-f", from, to], None).await?)?;
+        Ok(())
+    }
+
+    async fn exists(&mut self, path: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        let out = self.run(&["test", "-f", path], None).await?;
+        match out.code {
+            Some(0) => Ok(true),
+            Some(1) => Ok(false),
+            _ => Err(format!("Remote: test -f {}: {}", path, out.status()).into()),
+        }
+    }
+
+    async fn is_dir(&mut self, path: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(self.run(&["test", "-d", path], None).await?.success())
+    }
+
+    async fn create_dir(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        check(&format!("mkdir {}", path), self.run(&["mkdir", "-p", path], None).await?)?;
+        Ok(())
+    }
+
+    async fn remove(&mut self, paths: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        if paths.is_empty() {
+            return Ok(())
+        }
+        let mut args = vec!["rm", "-rf"];
+        args.extend(paths.iter().map(String::as_str));
+        check(&format!("rm {}", paths.join(" ")), self.run(&args, None).await?)?;
+        Ok(())
+    }
+
+    async fn list(&mut self, dir: &str) -> Result<Vec<Entry>, Box<dyn std::error::Error>> {
+        let out = self.run(&["env", "LC_ALL=C", "ls", "-lna", "--", dir], None).await?;
+        Ok(out.text()
+            .lines()
+            .filter_map(parse_entry)
+            .filter(|x| x.name != "." && x.name != "..")
+            .collect())
+    }
+
+    async fn older_than(&mut self, dir: &str, age: Duration)
+    -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let minutes = format!("+{}", age.as_secs().div_ceil(60));
+        let out = self.run(&["find", dir, "-type", "f", "-mmin", &minutes], None).await?;
+        Ok(check(&format!("find {}", dir), out)?.text().lines().map(str::to_string).collect())
+    }
+
+    async fn append(&mut self, path: &str, line: &str, keep: usize)
+    -> Result<(), Box<dyn std::error::Error>> {
+        let script = format!("cat >> \"$1\" && tail -n {} \"$1\" > \"$1.new\" && \
+                              mv -f \"$1.new\" \"$1\"", keep);
+        let out = self.run(&["sh", "-c", &script, "sh", path], Some(line.as_bytes())).await?;
+        match out.success() {
+            true  => Ok(()),
+            false => Err(out.status().into()),
+        }
+    }
+
+    async fn free(&mut self, dir: &str) -> Option<u64> {
+        let out = self.run(&["df", "-Pk", dir], None).await.ok()?;
+        if ! out.success() {
+            return None
+        }
+        parse_df(&out.text())
+    }
+
+    async fn usage(&mut self, paths: &[String]) -> u64 {
+        if paths.is_empty() {
+            return 0
+        }
+        // du complains about missing files, but still lists the others.
+        let mut args = vec!["sh", "-c", "du -sk \"$@\" 2>/dev/null", "sh"];
+        args.extend(paths.iter().map(String::as_str));
+        let out = match self.run(&args, None).await {
+            Ok(x) => x,
+            Err(_) => return 0,
+        };
+        let kib: u64 = out.text()
+            .lines()
+            .filter_map(|x| x.split_whitespace().next()?.parse::<u64>().ok())
+            .sum();
+        kib * 1024
+    }
+}
+
+/// An open connection to a profile's remote.
+pub enum Remote {
+    Ssh(Connection),
+    #[cfg(feature = "http")]
+    Http(http::Client),
+    #[cfg(feature = "restic")]
+    Restic(restic::Client),
+}
+
+/// Connect to the profile's remote: over HTTP if it has an `http_url`, to a
+/// restic REST server if it has a `restic_url`, and otherwise over SSH.
+pub async fn connect(cfg: &Config) -> Result<Remote, Box<dyn std::error::Error>> {
+    #[cfg(feature = "http")]
+    if let Some(x) = &cfg.http_url {
+        let token = match &cfg.http_token_command {
+            Some(x) => Some(run_command(x)?),
+            None => None,
+        };
+        return Ok(Remote::Http(http::Client::new(x, token, cfg.ssh_connect_timeout)?))
+    }
+    #[cfg(feature = "restic")]
+    if let Some(x) = &cfg.restic_url {
+        let password = match &cfg.restic_password_command {
+            Some(x) => Some(run_command(x)?),
+            None => None,
+        };
+        return Ok(Remote::Restic(restic::Client::new(x, password, cfg.ssh_connect_timeout)?))
+    }
+    Ok(Remote::Ssh(ssh::connect(cfg).await.map_err(|e| format!("ssh: {}", e))?))
+}
+
+impl Remote {
+    /// Fail if the connection has gone away.
+    pub async fn check(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Remote::Ssh(x) => x.check().await,
+            // Each request makes its own connection, as needed.
+            #[cfg(feature = "http")]
+            Remote::Http(_) => Ok(()),
+            #[cfg(feature = "restic")]
+            Remote::Restic(_) => Ok(()),
+        }
+    }
+
+    pub async fn close(self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Remote::Ssh(x) => x.close().await,
+            #[cfg(feature = "http")]
+            Remote::Http(_) => Ok(()),
+            #[cfg(feature = "restic")]
+            Remote::Restic(_) => Ok(()),
+        }
+    }
+}
+
+impl RemoteStore for Remote {
+    async fn read(&mut self, path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match self {
+            Remote::Ssh(x) => x.read(path).await,
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.read(path).await,
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.read(path).await,
+        }
+    }
+
+    async fn write(&mut self, path: &str, bytes: &[u8])
+    -> Result<bool, Box<dyn std::error::Error>> {
+        match self {
+            Remote::Ssh(x) => x.write(path, bytes).await,
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.write(path, bytes).await,
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.write(path, bytes).await,
+        }
+    }
+
+    async fn verify(&mut self, path: &str, bytes: &[u8])
+    -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Remote::Ssh(x) => x.verify(path, bytes).await,
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.verify(path, bytes).await,
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.verify(path, bytes).await,
+        }
+    }
+
+    async fn checksum(&mut self, path: &str) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        match self {
+            Remote::Ssh(x) => x.checksum(path).await,
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.checksum(path).await,
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.checksum(path).await,
+        }
+    }
+
+    fn atomic_writes(&self) -> bool {
+        match self {
+            Remote::Ssh(x) => x.atomic_writes(),
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.atomic_writes(),
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.atomic_writes(),
+        }
+    }
+
+    async fn rename(&mut self, from: &str, to: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Remote::Ssh(x) => x.rename(from, to).await,
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.rename(from, to).await,
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.rename(from, to).await,
+        }
+    }
+
+    async fn exists(&mut self, path: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        match self {
+            Remote::Ssh(x) => x.exists(path).await,
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.exists(path).await,
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.exists(path).await,
+        }
+    }
+
+    async fn is_dir(&mut self, path: &str) -> Result<bool, Box<dyn std::error::Error>> {
+        match self {
+            Remote::Ssh(x) => x.is_dir(path).await,
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.is_dir(path).await,
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.is_dir(path).await,
+        }
+    }
+
+    async fn create_dir(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Remote::Ssh(x) => x.create_dir(path).await,
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.create_dir(path).await,
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.create_dir(path).await,
+        }
+    }
+
+    async fn remove(&mut self, paths: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Remote::Ssh(x) => x.remove(paths).await,
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.remove(paths).await,
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.remove(paths).await,
+        }
+    }
+
+    async fn list(&mut self, dir: &str) -> Result<Vec<Entry>, Box<dyn std::error::Error>> {
+        match self {
+            Remote::Ssh(x) => x.list(dir).await,
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.list(dir).await,
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.list(dir).await,
+        }
+    }
+
+    async fn older_than(&mut self, dir: &str, age: Duration)
+    -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        match self {
+            Remote::Ssh(x) => x.older_than(dir, age).await,
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.older_than(dir, age).await,
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.older_than(dir, age).await,
+        }
+    }
+
+    async fn append(&mut self, path: &str, line: &str, keep: usize)
+    -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Remote::Ssh(x) => x.append(path, line, keep).await,
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.append(path, line, keep).await,
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.append(path, line, keep).await,
+        }
+    }
+
+    async fn free(&mut self, dir: &str) -> Option<u64> {
+        match self {
+            Remote::Ssh(x) => x.free(dir).await,
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.free(dir).await,
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.free(dir).await,
+        }
+    }
+
+    async fn usage(&mut self, paths: &[String]) -> u64 {
+        match self {
+            Remote::Ssh(x) => x.usage(paths).await,
+            #[cfg(feature = "http")]
+            Remote::Http(x) => x.usage(paths).await,
+            #[cfg(feature = "restic")]
+            Remote::Restic(x) => x.usage(paths).await,
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use chrono::{ Local, TimeZone };
+
+use crate::config::{ Config, find_config, load_configuration };
+use crate::remote::{ self, RemoteStore };
+use crate::state::{ self, Device };
+use crate::stats::{ history_path, load_history };
+use crate::summary::{ Outcome, Summary, human_bytes };
+
+/// How many runs each folder's remote run log keeps.
+const LOG_RUNS: usize = 1000;
+
+/// One run, as recorded in a folder's remote run log (by whichever
+/// machine made it) or in the local history.
+struct Record {
+    started: u64,
+    sync_id: String,
+    device: String,
+    device_name: String,
+    direction: String,
+    result: String,
+    files: u64,
+    up: u64,
+    down: u64,
+}
+
+impl Record {
+    fn from_summary(summary: &Summary, device: &Device) -> Self {
+        Record {
+            started: summary.started,
+            sync_id: summary.sync_id.clone(),
+            device: device.id.clone(),
+            device_name: device.name.clone(),
+            direction: summary.direction.name().to_string(),
+            result: summary.outcome.name().to_string(),
+            files: summary.files_changed as u64,
+            up: summary.bytes_up,
+            down: summary.bytes_down,
+        }
+    }
+
+    /// A line of the run log: the fields, separated by tabs.
+    fn to_line(&self) -> String {
+        let clean = |x: &str| x.replace(['\t', '\n'], " ");
+        format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n", self.started, clean(&self.sync_id),
+                clean(&self.device), clean(&self.device_name), self.direction, self.result,
+                self.files, self.up, self.down)
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let f: Vec<&str> = line.split('\t').collect();
+        if f.len() < 9 {
+            return None
+        }
+        Some(Record {
+            started: f[0].parse().ok()?,
+            sync_id: f[1].to_string(),
+            device: f[2].to_string(),
+            device_name: f[3].to_string(),
+            direction: f[4].to_string(),
+            result: f[5].to_string(),
+            files: f[6].parse().ok()?,
+            up: f[7].parse().ok()?,
+            down: f[8].parse().ok()?,
+        })
+    }
+}
+
+/// Record a run that changed something in each of the profile's folders'
+/// remote run logs, so every machine syncing them can see who did what.
+/// Problems are recorded as warnings.
+pub async fn append(home: &Path, s: &mut impl RemoteStore, cfg: &Config, summary: &mut Summary) {
+    if summary.outcome != Outcome::Completed {
+        return
+    }
+    let device = match state::device(home) {
+        Ok(x) => x,
+        Err(e) => return summary.warn(format!("Reading the device id: {}", e)),
+    };
+    let line = Record::from_summary(summary, &device).to_line();
+    for f in &cfg.folders {
+        let path = f.run_log();
+        if let Err(e) = s.append(&path, &line, LOG_RUNS).await {
+            summary.warn(format!("Updating the remote run log {}: {}", path, e));
+        }
+    }
+}
+
+/// The runs in a remote run log; a missing log has none.
+async fn read_log(s: &mut impl RemoteStore, path: &str) -> Vec<Record> {
+    match s.read(path).await {
+        Ok(x) => String::from_utf8_lossy(&x).lines().filter_map(Record::parse).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// This machine's runs from the local history, which also has the ones
+/// that failed before getting as far as the remote log.
+fn local_runs(home: &Path, profile: &str, device: &Device) -> Vec<Record> {
+    let runs = load_history(&history_path(home, profile)).unwrap_or_default();
+    runs.iter()
+        .filter_map(|t| {
+            let text = |k: &str| t.get(k).and_then(|x| x.as_str()).unwrap_or("").to_string();
+            let int = |k: &str| t.get(k).and_then(|x| x.as_integer()).unwrap_or(0) as u64;
+            Some(Record {
+                started: t.get("started")?.as_integer()? as u64,
+                sync_id: text("sync_id"),
+                // Runs recorded before there were device ids are this machine's.
+                device: Some(text("device")).filter(|x| ! x.is_empty())
+                    .unwrap_or_else(|| device.id.clone()),
+                device_name: Some(text("device_name")).filter(|x| ! x.is_empty())
+                    .unwrap_or_else(|| device.name.clone()),
+                direction: text("direction"),
+                result: text("result"),
+                files: int("files_changed"),
+                up: int("bytes_up"),
+                down: int("bytes_down"),
+            })
+        })
+        .collect()
+}
+
+/// Print a profile's most recent runs from every machine, oldest first:
+/// the remote run logs of its folders, and this machine's local history.
+pub async fn show(home: &Path, explicit: Option<&Path>, profile: &str, count: usize)
+-> Result<(), Box<dyn std::error::Error>> {
+    let cfg = load_configuration(&find_config(home, explicit)?, profile).await?;
+    let device = state::device(home)?;
+    let mut s = remote::connect(&cfg).await?;
+    let mut runs = local_runs(home, profile, &device);
+    for f in &cfg.folders {
+        runs.extend(read_log(&mut s, &f.run_log()).await);
+    }
+    let _ = s.close().await;
+    // A run is in the log of each folder, and maybe the history too.
+    let mut seen = HashSet::new();
+    runs.retain(|x| x.sync_id.is_empty() || seen.insert(x.sync_id.clone()));
+    runs.sort_by_key(|x| x.started);
+    if runs.is_empty() {
+        println!("No runs of [{}] recorded yet", profile);
+        return Ok(())
+    }
+    println!("{:<16} {:<20} {:<5} {:<10} {:>6} {:>10} {:>10}",
+             "STARTED", "MACHINE", "DIR", "RESULT", "FILES", "UP", "DOWN");
+    for x in &runs[runs.len().saturating_sub(count)..] {
+        let started = Local.timestamp_opt(x.started as i64, 0)
+            .single()
+            .map(|x| x.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        let machine = match x.device == device.id {
+            true  => format!("{} (this)", x.device_name),
+            false => x.device_name.clone(),
+        };
+        println!("{:<16} {:<20} {:<5} {:<10} {:>6} {:>10} {:>10}",
+                 started, machine, x.direction, x.result, x.files, human_bytes(x.up),
+                 human_bytes(x.down));
+    }
+    Ok(())
+}
@@ -0,0 +1,209 @@
+use std::time::Duration;
+
+use chrono::{ Datelike, DateTime, Local, Timelike };
+
+/// When a daemon should run the next sync.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    /// Wait this long after each sync finishes.
+    Interval(Duration),
+    /// Run whenever the local time matches a cron expression.
+    Cron(Cron),
+}
+
+impl Schedule {
+    /// How long to wait from now until the next sync is due.
+    pub fn next_delay(&self) -> Duration {
+        match self {
+            Schedule::Interval(x) => *x,
+            Schedule::Cron(x) => {
+                let now = Local::now();
+                match x.next_after(now) {
+                    Some(t) => (t - now).to_std().unwrap_or_default(),
+                    // No match within a year, e.g. "0 0 31 2 *"; check again tomorrow.
+                    None => Duration::from_secs(24 * 60 * 60),
+                }
+            }
+        }
+    }
+}
+
+/// Parse an interval such as "90s", "15m", "1h30m" or "1d".
+pub fn parse_interval(s: &str) -> Option<Duration> {
+    let mut total = 0u64;
+    let mut num = String::new();
+    for c in s.trim().chars() {
+        if c.is_ascii_digit() {
+            num.push(c);
+            continue
+        }
+        let n: u64 = num.parse().ok()?;
+        num.clear();
+        let unit = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return None,
+        };
+        total += n * unit;
+    }
+    if ! num.is_empty() || total == 0 {
+        return None
+    }
+    Some(Duration::from_secs(total))
+}
+
+/// A standard five-field cron expression (minute, hour, day of month,
+/// month, day of week), evaluated in local time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    /// Whether the day-of-month and day-of-week fields were "*". When both
+    /// are restricted, a day matches if either does, as in Vixie cron.
+    days_star: bool,
+    weekdays_star: bool,
+}
+
+/// Parse one cron field into a table of allowed values in `min..=max`.
+fn parse_field(field: &str, min: u32, max: u32) -> Option<Vec<bool>> {
+    let mut allowed = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((r, s)) => (r, s.parse::<u32>().ok().filter(|x| *x > 0)?),
+            None => (part, 1),
+        };
+        let (lo, hi) = if range == "*" {
+            (min, max)
+        } else if let Some((a, b)) = range.split_once('-') {
+            (a.parse().ok()?, b.parse().ok()?)
+        } else {
+            let x = range.parse().ok()?;
+            // "5/15" means starting at 5, every 15.
+            if part.contains('/') { (x, max) } else { (x, x) }
+        };
+        if lo < min || hi > max || lo > hi {
+            return None
+        }
+        for x in (lo..=hi).step_by(step as usize) {
+            allowed[x as usize] = true;
+        }
+    }
+    Some(allowed)
+}
+
+impl Cron {
+    pub fn parse(expr: &str) -> Option<Self> {
+        let expr = match expr.trim() {
+            "@hourly"  => "0 * * * *",
+            "@daily"   => "0 0 * * *",
+            "@weekly"  => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            x => x,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return None
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // Both 0 and 7 mean Sunday.
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        Some(Cron {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            days_star: fields[2] == "*",
+            weekdays_star: fields[4] == "*",
+        })
+    }
+
+    fn matches(&self, t: &DateTime<Local>) -> bool {
+        let day = self.days[t.day() as usize];
+        let weekday = self.weekdays[t.weekday().num_days_from_sunday() as usize];
+        let day_ok = match (self.days_star, self.weekdays_star) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        self.minutes[t.minute() as usize]
+            && self.hours[t.hour() as usize]
+            && self.months[t.month() as usize]
+            && day_ok
+    }
+
+    /// The first matching minute strictly after `t`, searching up to a year ahead.
+    pub fn next_after(&self, t: DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut t = t.with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        for _ in 0..366 * 24 * 60 {
+            if self.matches(&t) {
+                return Some(t)
+            }
+            t += chrono::Duration::minutes(1);
+        }
+        None
+    }
+}
+
+/// Hours of the day, in local time, such as 01:00-06:00. A window whose end
+/// is before its start runs past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    /// Minutes after midnight.
+    start: u32,
+    end: u32,
+}
+
+fn parse_time(s: &str) -> Option<u32> {
+    let (h, m) = s.trim().split_once(':')?;
+    let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+    if h > 24 || m > 59 || (h == 24 && m > 0) {
+        return None
+    }
+    Some(h * 60 + m)
+}
+
+impl Window {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (start, end) = s.split_once('-')?;
+        let (start, end) = (parse_time(start)? % (24 * 60), parse_time(end)? % (24 * 60));
+        if start == end {
+            return None
+        }
+        Some(Window { start, end })
+    }
+
+    fn minute_of_day(t: &DateTime<Local>) -> u32 {
+        t.hour() * 60 + t.minute()
+    }
+
+    pub fn contains(&self, t: &DateTime<Local>) -> bool {
+        let x = Window::minute_of_day(t);
+        match self.start < self.end {
+            true  => self.start <= x && x < self.end,
+            false => self.start <= x || x < self.end,
+        }
+    }
+
+    /// How long from now until the window next opens; nothing if it's open.
+    pub fn until_open(&self) -> Duration {
+        let now = Local::now();
+        if self.contains(&now) {
+            return Duration::ZERO
+        }
+        let minutes = (self.start + 24 * 60 - Window::minute_of_day(&now)) % (24 * 60);
+        // Counted from the start of the current minute.
+        Duration::from_secs(minutes as u64 * 60 - now.second() as u64)
+    }
+
+    /// When the window opens, e.g. "01:00".
+    pub fn opens(&self) -> String {
+        format!("{:02}:{:02}", self.start / 60, self.start % 60)
+    }
+}
@@ -0,0 +1,100 @@
+use std::process::{ Command, Stdio };
+
+#[cfg(feature = "keychain")]
+use keyring::Entry;
+
+/// The keychain service mist's secrets are stored under; the account is
+/// the profile name.
+#[cfg(feature = "keychain")]
+const SERVICE: &str = "mist";
+
+/// Where a symmetric passphrase comes from when gpg shouldn't prompt for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Passphrase {
+    /// The OS keychain (Secret Service, macOS Keychain or Windows Credential
+    /// Manager), under this account.
+    Keychain(String),
+    /// The output of a shell command, e.g. `pass show mist/home`.
+    Command(String),
+}
+
+impl Passphrase {
+    /// Parse the `passphrase` setting of the named profile.
+    pub fn parse(s: &str, profile: &str) -> Option<Self> {
+        match s {
+            "keychain" => Some(Passphrase::Keychain(profile.to_string())),
+            _ => None,
+        }
+    }
+
+    /// Look the passphrase up.
+    pub fn get(&self) -> Result<String, Box<dyn std::error::Error>> {
+        match self {
+            #[cfg(feature = "keychain")]
+            Passphrase::Keychain(account) => Entry::new(SERVICE, account)?
+                .get_password()
+                .map_err(|e| format!("No passphrase for '{}' in the keychain ({}); store one \
+                                      with `mist keychain {}`", account, e, account).into()),
+            #[cfg(not(feature = "keychain"))]
+            Passphrase::Keychain(_) =>
+                Err("This mist was built without the 'keychain' feature".into()),
+            Passphrase::Command(cmd) => run_command(cmd),
+        }
+    }
+}
+
+/// Run a `*_command` setting and take its first line of output as the
+/// secret. Its stderr is left on the terminal, so tools like `pass` can
+/// prompt or explain why they failed.
+pub fn run_command(cmd: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let out = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| format!("Couldn't run '{}': {}", cmd, e))?;
+    if ! out.status.success() {
+        return Err(format!("'{}' failed ({})", cmd, out.status).into())
+    }
+    let text = String::from_utf8(out.stdout)
+        .map_err(|_| format!("'{}' printed something that isn't text", cmd))?;
+    match text.lines().next() {
+        Some(x) if ! x.is_empty() => Ok(x.to_string()),
+        _ => Err(format!("'{}' printed nothing", cmd).into()),
+    }
+}
+
+/// Store a profile's passphrase in the OS keychain.
+#[cfg(feature = "keychain")]
+pub fn store(profile: &str, secret: &str) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(Entry::new(SERVICE, profile)?.set_password(secret)?)
+}
+
+/// Remove a profile's passphrase from the OS keychain.
+#[cfg(feature = "keychain")]
+pub fn forget(profile: &str) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(Entry::new(SERVICE, profile)?.delete_password()?)
+}
+
+/// Ask for a profile's passphrase twice and store it in the OS keychain, or
+/// remove it.
+#[cfg(feature = "keychain")]
+pub fn run(profile: &str, delete: bool) -> Result<(), Box<dyn std::error::Error>> {
+    if delete {
+        forget(profile)?;
+        println!("Removed the passphrase for '{}' from the keychain", profile);
+        return Ok(())
+    }
+    let secret = rpassword::prompt_password(format!("Passphrase for '{}': ", profile))?;
+    if secret.is_empty() {
+        return Err("The passphrase is empty".into())
+    }
+    if rpassword::prompt_password("Again: ")? != secret {
+        return Err("The passphrases don't match".into())
+    }
+    store(profile, &secret)?;
+    println!("Stored the passphrase for '{}' in the keychain; set passphrase = \"keychain\" \
+              in the profile to use it", profile);
+    Ok(())
+}
@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::fs::{ self, File };
+use std::io::{ self, BufRead, BufReader, ErrorKind, Read, Write };
+use std::net::{ SocketAddr, TcpListener };
+use std::path::{ Component, Path, PathBuf };
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::thread;
+use std::time::{ Duration, UNIX_EPOCH };
+
+use rustls::{ ServerConfig, ServerConnection, StreamOwned };
+
+#[cfg(feature = "mdns")]
+use crate::mdns::{ self, Kind };
+#[cfg(feature = "mdns")]
+use crate::network;
+
+/// How long a connection may sit idle before it's closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The longest request line or header accepted.
+const MAX_LINE: u64 = 8192;
+
+/// Where uploads are written before they're renamed into place, so a file
+/// is never seen half-written. It's under the root, so the rename doesn't
+/// cross filesystems, but outside every user's directory.
+const UPLOADS: &str = ".uploads";
+
+/// Numbers the uploads in progress.
+static UPLOAD: AtomicU64 = AtomicU64::new(0);
+
+/// Serves the protocol of `http::Client` from a local directory, giving
+/// each user their own directory under it.
+struct Server {
+    root: PathBuf,
+    /// Token to user name.
+    users: HashMap<String, String>,
+}
+
+struct Request {
+    method: String,
+    target: String,
+    token: Option<String>,
+    length: Option<u64>,
+    /// Whether the client asked to close the connection after this request.
+    close: bool,
+}
+
+/// Read a line of at most MAX_LINE bytes, without its line ending. None at
+/// the end of the stream.
+fn read_line(r: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    if r.take(MAX_LINE).read_line(&mut line)? == 0 {
+        return Ok(None)
+    }
+    if ! line.ends_with('\n') {
+        return Err(io::Error::new(ErrorKind::InvalidData, "request line too long"))
+    }
+    Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+/// Read a request's line and headers, leaving its body. None if the client
+/// closed the connection.
+fn read_request(r: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let line = match read_line(r)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "malformed request");
+    let mut fields = line.split(' ');
+    let (method, target, version) = match (fields.next(), fields.next(), fields.next()) {
+        (Some(m), Some(t), Some(v)) => (m.to_string(), t.to_string(), v),
+        _ => return Err(invalid()),
+    };
+    let mut req = Request { method, target, token: None, length: None,
+                            close: version == "HTTP/1.0" };
+    loop {
+        let line = read_line(r)?.ok_or_else(invalid)?;
+        if line.is_empty() {
+            return Ok(Some(req))
+        }
+        let (name, value) = line.split_once(':').ok_or_else(invalid)?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "authorization" => req.token = value.strip_prefix("Bearer ").map(str::to_string),
+            "content-length" => req.length = Some(value.parse().map_err(|_| invalid())?),
+            "connection" => req.close = value.eq_ignore_ascii_case("close"),
+            // Only Content-Length bodies are read.
+            "transfer-encoding" => return Err(invalid()),
+            _ => (),
+        }
+    }
+}
+
+/// Undo percent-encoding. None if it's malformed or not UTF-8.
+fn decode(s: &str) -> Option<String> {
+    let mut out = Vec::new();
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            x => out.push(x),
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// Compare tokens in time that doesn't depend on where they differ.
+fn same_token(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        411 => "Length Required",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Send a response's status line and headers.
+fn head(w: &mut impl Write, status: u16, length: u64, close: bool) -> io::Result<()> {
+    write!(w, "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n", status, reason(status), length)?;
+    if status == 401 {
+        write!(w, "WWW-Authenticate: Bearer\r\n")?;
+    }
+    if close {
+        write!(w, "Connection: close\r\n")?;
+    }
+    write!(w, "\r\n")
+}
+
+/// Send a whole response, leaving out the body for a HEAD request.
+fn reply(w: &mut impl Write, req: &Request, status: u16, body: &[u8], close: bool)
+-> io::Result<u16> {
+    head(w, status, body.len() as u64, close)?;
+    if req.method != "HEAD" {
+        w.write_all(body)?;
+    }
+    w.flush()?;
+    Ok(status)
+}
+
+/// A directory's listing: "<size> <mtime> <name>" for each entry, with a
+/// "/" after directories' names.
+fn listing(dir: &Path) -> io::Result<String> {
+    let mut out = String::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        let time = meta.modified()?.duration_since(UNIX_EPOCH).map_or(0, |x| x.as_secs());
+        let name = entry.file_name().to_string_lossy().into_owned();
+        match meta.is_dir() {
+            true  => out.push_str(&format!("0 {} {}/\n", time, name)),
+            false => out.push_str(&format!("{} {} {}\n", meta.len(), time, name)),
+        }
+    }
+    Ok(out)
+}
+
+impl Server {
+    /// The file a request names in the user's directory, or None if it
+    /// would be outside it.
+    fn resolve(&self, user: &str, target: &str) -> Option<PathBuf> {
+        let path = decode(target.split('?').next()?)?;
+        let mut out = self.root.join(user);
+        for c in Path::new(&path).components() {
+            match c {
+                Component::Normal(x) => out.push(x),
+                Component::RootDir | Component::CurDir => (),
+                _ => return None,
+            }
+        }
+        Some(out)
+    }
+
+    /// Store a PUT's body as `path`, replacing it whole.
+    fn store(&self, r: &mut impl Read, path: &Path, length: u64) -> io::Result<()> {
+        if let Some(x) = path.parent() {
+            fs::create_dir_all(x)?;
+        }
+        let tmp = self.root.join(UPLOADS).join(UPLOAD.fetch_add(1, Ordering::Relaxed).to_string());
+        let res = (|| {
+            let mut f = File::create(&tmp)?;
+            if io::copy(&mut r.take(length), &mut f)? < length {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "upload cut short"))
+            }
+            f.sync_all()?;
+            fs::rename(&tmp, path)
+        })();
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        res
+    }
+
+    /// Answer one request, returning its status and whether the connection
+    /// can take another: not if a body was left unread.
+    fn respond<S: Read + Write>(&self, r: &mut BufReader<S>, req: &Request, user: Option<&str>)
+    -> io::Result<(u16, bool)> {
+        let unread = req.length.unwrap_or(0) > 0 && req.method != "PUT";
+        let close = req.close || unread;
+        let user = match user {
+            Some(x) => x,
+            None => return Ok((reply(r.get_mut(), req, 401, b"", true)?, false)),
+        };
+        let path = match self.resolve(user, &req.target) {
+            Some(x) => x,
+            None => return Ok((reply(r.get_mut(), req, 400, b"", true)?, false)),
+        };
+        let status = match req.method.as_str() {
+            "GET" | "HEAD" if req.target.ends_with('/') => match listing(&path) {
+                Ok(x) => reply(r.get_mut(), req, 200, x.as_bytes(), close)?,
+                Err(e) if e.kind() == ErrorKind::NotFound => reply(r.get_mut(), req, 404, b"", close)?,
+                Err(e) => return Err(e),
+            },
+            "GET" | "HEAD" => match File::open(&path) {
+                Ok(mut f) => {
+                    let len = match f.metadata()? {
+                        x if x.is_dir() => 0,
+                        x => x.len(),
+                    };
+                    head(r.get_mut(), 200, len, close)?;
+                    if req.method == "GET" && len > 0 {
+                        io::copy(&mut f, r.get_mut())?;
+                    }
+                    r.get_mut().flush()?;
+                    200
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => reply(r.get_mut(), req, 404, b"", close)?,
+                Err(e) => return Err(e),
+            },
+            "PUT" => {
+                let length = match req.length {
+                    Some(x) => x,
+                    None => return Ok((reply(r.get_mut(), req, 411, b"", true)?, false)),
+                };
+                if path.is_dir() || path == self.root.join(user) {
+                    return Ok((reply(r.get_mut(), req, 409, b"", true)?, false))
+                }
+                match self.store(r, &path, length) {
+                    Ok(()) => reply(r.get_mut(), req, 201, b"", close)?,
+                    // The body may be partly unread, so the connection can't be reused.
+                    Err(e) => {
+                        reply(r.get_mut(), req, 500, e.to_string().as_bytes(), true)?;
+                        return Ok((500, false))
+                    }
+                }
+            }
+            "DELETE" if path == self.root.join(user) => reply(r.get_mut(), req, 409, b"", close)?,
+            "DELETE" => {
+                let res = match path.is_dir() {
+                    true  => fs::remove_dir_all(&path),
+                    false => fs::remove_file(&path),
+                };
+                match res {
+                    Ok(()) => reply(r.get_mut(), req, 204, b"", close)?,
+                    Err(e) if e.kind() == ErrorKind::NotFound => reply(r.get_mut(), req, 404, b"", close)?,
+                    Err(e) => reply(r.get_mut(), req, 500, e.to_string().as_bytes(), close)?,
+                }
+            }
+            _ => reply(r.get_mut(), req, 405, b"", close)?,
+        };
+        Ok((status, ! close))
+    }
+
+    /// Answer a connection's requests until it's closed.
+    fn handle<S: Read + Write>(&self, stream: S) -> io::Result<()> {
+        let mut r = BufReader::new(stream);
+        while let Some(req) = read_request(&mut r)? {
+            let user = req.token.as_deref().and_then(|token| {
+                self.users.iter().find(|(x, _)| same_token(x, token)).map(|(_, name)| name.as_str())
+            });
+            let (status, keep) = self.respond(&mut r, &req, user)?;
+            println!("{} {} {} {}", user.unwrap_or("-"), req.method, req.target, status);
+            if ! keep {
+                break
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Read the tokens file: a line for each token, "<user> <token>", where a
+/// user may have several tokens, e.g. one per machine. Blank lines and
+/// lines starting with '#' are skipped.
+fn read_tokens(path: &Path) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Can't read tokens file {}: {}", path.display(), e))?;
+    let mut users = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue
+        }
+        let err = |msg: &str| format!("{} line {}: {}", path.display(), i + 1, msg);
+        let (name, token) = line.split_once(char::is_whitespace)
+            .ok_or_else(|| err("expected \"<user> <token>\""))?;
+        let token = token.trim();
+        if ! name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(err("user names may only have letters, digits, '-' and '_'").into())
+        }
+        if token.len() < 16 {
+            return Err(err("the token is too short; use at least 16 characters").into())
+        }
+        if users.insert(token.to_string(), name.to_string()).is_some() {
+            return Err(err("the token is used twice").into())
+        }
+    }
+    if users.is_empty() {
+        return Err(format!("{} has no tokens", path.display()).into())
+    }
+    Ok(users)
+}
+
+/// Load a PEM certificate chain and private key.
+fn tls_config(cert: &Path, key: &Path) -> Result<ServerConfig, Box<dyn std::error::Error>> {
+    let open = |path: &Path| File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("Can't read {}: {}", path.display(), e));
+    let certs = rustls_pemfile::certs(&mut open(cert)?).collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut open(key)?)?
+        .ok_or(format!("{} has no private key", key.display()))?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    Ok(ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?)
+}
+
+/// Serve `root` on `listen` to the users in the tokens file, over HTTPS if
+/// given a certificate and key. Runs until killed.
+pub fn run(root: &Path, listen: &str, tokens: &Path, tls: Option<(&Path, &Path)>)
+-> Result<(), Box<dyn std::error::Error>> {
+    let users = read_tokens(tokens)?;
+    fs::create_dir_all(root.join(UPLOADS))?;
+    for x in users.values() {
+        fs::create_dir_all(root.join(x))?;
+    }
+    let tls = match tls {
+        Some((cert, key)) => Some(Arc::new(tls_config(cert, key)?)),
+        None => None,
+    };
+    let listener = TcpListener::bind(listen)
+        .map_err(|e| format!("Can't listen on {}: {}", listen, e))?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    println!("Serving {} on {}://{}", root.display(), scheme, listener.local_addr()?);
+    if tls.is_none() {
+        println!("Warning: without --tls-cert, tokens and files cross the network unencrypted");
+    }
+    // Found by `mist pair`.
+    #[cfg(feature = "mdns")]
+    let _advert = {
+        let addr = listener.local_addr()?;
+        let ip = match addr.ip() {
+            x if x.is_unspecified() => network::local_address(),
+            x => Some(x),
+        };
+        ip.map(|x| mdns::advertise(Kind::Serve, None, scheme, x, addr.port())).transpose()?
+    };
+    accept(listener, Arc::new(Server { root: root.to_path_buf(), users }), tls);
+    Ok(())
+}
+
+/// Answer each connection in a thread of its own, forever.
+fn accept(listener: TcpListener, server: Arc<Server>, tls: Option<Arc<ServerConfig>>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(x) => x,
+            Err(e) => {
+                eprintln!("Can't accept a connection: {}", e);
+                continue
+            }
+        };
+        let (server, tls) = (server.clone(), tls.clone());
+        thread::spawn(move || {
+            let _ = stream.set_read_timeout(Some(IDLE_TIMEOUT));
+            let peer = stream.peer_addr().map(|x| x.to_string()).unwrap_or_default();
+            let res = match tls {
+                Some(x) => ServerConnection::new(x)
+                    .map_err(io::Error::other)
+                    .and_then(|conn| server.handle(StreamOwned::new(conn, stream))),
+                None => server.handle(stream),
+            };
+            // Clients hanging up or going quiet are routine.
+            match res {
+                Err(e) if ! matches!(e.kind(), ErrorKind::UnexpectedEof
+                                     | ErrorKind::ConnectionReset
+                                     | ErrorKind::BrokenPipe
+                                     | ErrorKind::WouldBlock
+                                     | ErrorKind::TimedOut) =>
+                    eprintln!("{}: {}", peer, e),
+                _ => (),
+            }
+        });
+    }
+}
+
+/// The file a peer stores when it's done syncing with a `Peer`: "ok", or
+/// "failed" if the sync didn't finish.
+pub const PEER_DONE: &str = ".mist-peer-done";
+
+/// A transient server for one other machine to sync with, which knows
+/// `token`; see `mist peer`.
+pub struct Peer {
+    /// Where it's listening.
+    pub addr: SocketAddr,
+    done: PathBuf,
+}
+
+impl Peer {
+    /// Serve `root` on `listen`, over plain HTTP, until the process ends.
+    pub fn start(root: &Path, listen: &str, token: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let users = HashMap::from([(token.to_string(), "peer".to_string())]);
+        fs::create_dir_all(root.join(UPLOADS))?;
+        fs::create_dir_all(root.join("peer"))?;
+        let listener = TcpListener::bind(listen)
+            .map_err(|e| format!("Can't listen on {}: {}", listen, e))?;
+        let addr = listener.local_addr()?;
+        let server = Arc::new(Server { root: root.to_path_buf(), users });
+        thread::spawn(move || accept(listener, server, None));
+        Ok(Peer { addr, done: root.join("peer").join(PEER_DONE) })
+    }
+
+    /// Whether the peer is done, and if so whether its sync finished.
+    pub fn finished(&self) -> Option<bool> {
+        fs::read(&self.done).ok().map(|x| x == b"ok")
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use std::fs::{ create_dir_all, read_link, read_to_string, rename, write };
+use std::os::unix::ffi::OsStrExt;
+use std::path::{ Path, PathBuf };
+use std::time::UNIX_EPOCH;
+
+use crate::config::Config;
+use crate::hash::{ from_hex, to_hex };
+use crate::state::profile_dir;
+
+/// A file as the three-way merge compares it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum State {
+    /// A file's size and modification time to the second, which is as
+    /// much of it as the archive keeps.
+    File { size: u64, mtime: i64 },
+    /// A symbolic link and its target.
+    Link(PathBuf),
+}
+
+/// The files of a folder, by their paths in it.
+pub type Files = BTreeMap<PathBuf, State>;
+
+/// The snapshot file for a profile: the state of each of its folders when
+/// they were last synced, as lines of `f <size> <mtime> <path>` and
+/// `l <hex target> <path>`, with absolute paths.
+fn snapshot_path(home: &Path, profile: &str) -> PathBuf {
+    profile_dir(home, profile).join("snapshot")
+}
+
+/// The files in a folder, as it would be archived.
+pub fn scan(dir: &Path, cfg: &Config) -> Files {
+    let mut files = Files::new();
+    for e in cfg.symlinks.walk(dir, &cfg.exclude).filter_map(|x| x.ok()) {
+        let state = if e.path_is_symlink() && ! e.file_type().is_file() {
+            match read_link(e.path()) {
+                Ok(x) => State::Link(x),
+                Err(_) => continue,
+            }
+        } else if e.file_type().is_file() {
+            let meta = match e.metadata() {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            let mtime = meta.modified()
+                .ok()
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |x| x.as_secs() as i64);
+            State::File { size: meta.len(), mtime }
+        } else {
+            continue
+        };
+        let rel = e.path().strip_prefix(dir).expect("walkdir stays under dir");
+        files.insert(rel.to_path_buf(), state);
+    }
+    files
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, State)> {
+    let (kind, rest) = line.split_once(' ')?;
+    match kind {
+        "f" => {
+            let mut f = rest.splitn(3, ' ');
+            let size = f.next()?.parse().ok()?;
+            let mtime = f.next()?.parse().ok()?;
+            Some((PathBuf::from(f.next()?), State::File { size, mtime }))
+        }
+        "l" => {
+            let (target, path) = rest.split_once(' ')?;
+            let target = std::ffi::OsStr::from_bytes(&from_hex(target)?).to_owned();
+            Some((PathBuf::from(path), State::Link(PathBuf::from(target))))
+        }
+        _ => None,
+    }
+}
+
+/// Every entry of a profile's snapshot, by absolute path. A missing or
+/// damaged snapshot just means nothing is known about the last sync.
+fn load_all(home: &Path, profile: &str) -> Files {
+    read_to_string(snapshot_path(home, profile))
+        .unwrap_or_default()
+        .lines()
+        .filter_map(parse_line)
+        .collect()
+}
+
+/// The state of `dir` when it was last synced.
+pub fn load(home: &Path, profile: &str, dir: &Path) -> Files {
+    load_all(home, profile)
+        .into_iter()
+        .filter_map(|(p, x)| Some((p.strip_prefix(dir).ok()?.to_path_buf(), x)))
+        .collect()
+}
+
+/// Record the state of `dir` as just synced, keeping the profile's other
+/// folders' entries.
+pub fn save(home: &Path, profile: &str, dir: &Path, files: &Files) -> std::io::Result<()> {
+    let mut all = load_all(home, profile);
+    all.retain(|p, _| ! p.starts_with(dir));
+    all.extend(files.iter().map(|(p, x)| (dir.join(p), x.clone())));
+    let mut text = String::new();
+    for (p, x) in &all {
+        // A newline in the path would break the line format.
+        let p = match p.to_str().filter(|x| ! x.contains('\n')) {
+            Some(x) => x,
+            None => continue,
+        };
+        match x {
+            State::File { size, mtime } =>
+                text.push_str(&format!("f {} {} {}\n", size, mtime, p)),
+            State::Link(t) =>
+                text.push_str(&format!("l {} {}\n", to_hex(t.as_os_str().as_bytes()), p)),
+        }
+    }
+    let path = snapshot_path(home, profile);
+    if let Some(x) = path.parent() {
+        create_dir_all(x)?;
+    }
+    // Half a snapshot would look like a lot of deletions.
+    let scratch = path.with_extension("new");
+    write(&scratch, text)?;
+    rename(&scratch, &path)
+}
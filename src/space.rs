@@ -0,0 +1,57 @@
+use std::path::Path;
+use std::process::Command;
+
+use walkdir::WalkDir;
+
+use crate::remote::{ RemoteStore, parse_df };
+use crate::summary::human_bytes;
+
+/// Free space where `path` is, or would be created.
+fn local_free(path: &Path) -> Option<u64> {
+    let dir = path.ancestors().find(|x| x.exists())?;
+    let out = Command::new("df").arg("-Pk").arg(dir).output().ok()?;
+    if ! out.status.success() {
+        return None
+    }
+    parse_df(&String::from_utf8_lossy(&out.stdout))
+}
+
+/// The total size of the files under `dir`.
+fn local_usage(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|x| x.ok())
+        .filter(|x| x.file_type().is_file())
+        .filter_map(|x| x.metadata().ok())
+        .map(|x| x.len())
+        .sum()
+}
+
+fn check(place: &str, needed: u64, free: u64) -> Result<(), Box<dyn std::error::Error>> {
+    if needed > free {
+        return Err(format!("Not enough space {}: {} needed, {} available", place,
+                           human_bytes(needed), human_bytes(free)).into())
+    }
+    Ok(())
+}
+
+/// Check that `needed` bytes fit in a remote directory, counting the space
+/// of the `replaced` files the upload overwrites as free. If the remote
+/// can't say how much space it has, there's nothing to check.
+pub async fn check_remote(s: &mut impl RemoteStore, dir: &str, replaced: &[String], needed: u64)
+-> Result<(), Box<dyn std::error::Error>> {
+    match s.free(dir).await {
+        Some(x) => check(&format!("on the remote in {}", dir), needed,
+                         x + s.usage(replaced).await),
+        None => Ok(()),
+    }
+}
+
+/// Check that `needed` bytes can be unpacked into `dest`, counting what's
+/// there already as free, since unpacking overwrites it.
+pub fn check_local(dest: &Path, needed: u64) -> Result<(), Box<dyn std::error::Error>> {
+    match local_free(dest) {
+        Some(x) => check(&format!("in {}", dest.display()), needed, x + local_usage(dest)),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,258 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{ self, Read, Write };
+use std::os::unix::fs::FileExt;
+
+/// How much is encrypted and written to a spill file at once.
+const PIECE: usize = 1 << 20;
+
+/// Data that may be too big to hold in memory, such as a whole archive:
+/// kept in memory up to a limit, and past it in a temporary file. The file
+/// is encrypted with a key only this process has, and is deleted as soon
+/// as it's made, so nothing of it outlives the run.
+pub struct Buffer {
+    mem: Vec<u8>,
+    /// Bytes held in memory before spilling; None means no limit.
+    limit: Option<u64>,
+    spill: Option<Spill>,
+}
+
+/// The part of a buffer on disk.
+struct Spill {
+    file: File,
+    key: [u8; 32],
+    /// Bytes in the file.
+    len: u64,
+    /// Bytes written to the buffer but not yet to the file, which is
+    /// written a piece at a time.
+    pending: Vec<u8>,
+}
+
+impl Spill {
+    fn new(pending: Vec<u8>) -> io::Result<Self> {
+        let mut key = [0; 32];
+        File::open("/dev/urandom")?.read_exact(&mut key)?;
+        Ok(Spill { file: tempfile::tempfile()?, key, len: 0, pending })
+    }
+
+    /// XOR data at `offset` in the file with the keystream, which both
+    /// encrypts and decrypts.
+    fn xor(&self, offset: u64, data: &mut [u8]) {
+        let mut stream = blake3::Hasher::new_keyed(&self.key).finalize_xof();
+        stream.set_position(offset);
+        let mut pad = vec![0; data.len()];
+        stream.fill(&mut pad);
+        for (x, p) in data.iter_mut().zip(pad) {
+            *x ^= p;
+        }
+    }
+
+    /// Write what's pending to the file.
+    fn write_pending(&mut self) -> io::Result<()> {
+        let mut piece = std::mem::take(&mut self.pending);
+        self.xor(self.len, &mut piece);
+        self.file.write_all_at(&piece, self.len)?;
+        self.len += piece.len() as u64;
+        piece.clear();
+        self.pending = piece;
+        Ok(())
+    }
+}
+
+impl Buffer {
+    /// An empty buffer that spills to disk past `limit` bytes.
+    pub fn new(limit: Option<u64>) -> Self {
+        Buffer { mem: Vec::new(), limit, spill: None }
+    }
+
+    pub fn len(&self) -> u64 {
+        match &self.spill {
+            Some(x) => x.len + x.pending.len() as u64,
+            None => self.mem.len() as u64,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the buffer went over its limit, to disk.
+    pub fn spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    /// Read into `buf` from `offset`, returning how many bytes were read:
+    /// none at the end.
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let (mem, start) = match &self.spill {
+            None => (&self.mem, 0),
+            Some(x) if offset >= x.len => (&x.pending, x.len),
+            Some(x) => {
+                let want = buf.len().min((x.len - offset) as usize);
+                let n = x.file.read_at(&mut buf[..want], offset)?;
+                x.xor(offset, &mut buf[..n]);
+                return Ok(n)
+            }
+        };
+        let rest = mem.get((offset - start) as usize..).unwrap_or_default();
+        let n = buf.len().min(rest.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        Ok(n)
+    }
+
+    /// A reader over the data from the start. There can be any number at
+    /// once.
+    pub fn reader(&self) -> Reader<'_> {
+        Reader { buffer: self, pos: 0 }
+    }
+
+    /// `len` bytes from `offset`, or as many as there are: borrowed from
+    /// the buffer if it stayed in memory, so reading it a piece at a time
+    /// costs no copies until it spills.
+    pub fn range(&self, offset: u64, len: u64) -> io::Result<Cow<'_, [u8]>> {
+        if self.spill.is_none() {
+            let start = (offset as usize).min(self.mem.len());
+            let end = start.saturating_add(len as usize).min(self.mem.len());
+            return Ok(Cow::Borrowed(&self.mem[start..end]))
+        }
+        let mut b = Vec::new();
+        let mut r = self.reader();
+        r.pos = offset;
+        r.take(len).read_to_end(&mut b)?;
+        Ok(Cow::Owned(b))
+    }
+
+    /// The last `n` bytes, or all of them if there are fewer.
+    pub fn tail(&self, n: u64) -> io::Result<Vec<u8>> {
+        let mut b = Vec::new();
+        let mut r = self.reader();
+        r.pos = self.len().saturating_sub(n);
+        r.read_to_end(&mut b)?;
+        Ok(b)
+    }
+
+    /// The data as one Vec, which for a buffer that stayed in memory costs
+    /// nothing.
+    pub fn into_vec(self) -> io::Result<Vec<u8>> {
+        if self.spill.is_none() {
+            return Ok(self.mem)
+        }
+        let mut b = Vec::with_capacity(self.len() as usize);
+        self.reader().read_to_end(&mut b)?;
+        Ok(b)
+    }
+}
+
+impl From<Vec<u8>> for Buffer {
+    /// Data that's in memory already, and stays there.
+    fn from(mem: Vec<u8>) -> Self {
+        Buffer { mem, limit: None, spill: None }
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.spill.is_none() {
+            let over = self.limit.is_some_and(|x| (self.mem.len() + buf.len()) as u64 > x);
+            if ! over {
+                self.mem.extend_from_slice(buf);
+                return Ok(buf.len())
+            }
+            self.spill = Some(Spill::new(std::mem::take(&mut self.mem))?);
+        }
+        let spill = self.spill.as_mut().expect("made above");
+        spill.pending.extend_from_slice(buf);
+        if spill.pending.len() >= PIECE {
+            spill.write_pending()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads a buffer from the start.
+pub struct Reader<'a> {
+    buffer: &'a Buffer,
+    pos: u64,
+}
+
+impl Read for Reader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.buffer.read_at(self.pos, buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ Seek, SeekFrom };
+
+    use super::*;
+
+    /// Bytes that don't repeat for a while.
+    fn data(n: usize) -> Vec<u8> {
+        (0..n).map(|x| (x * 7 + x / 251) as u8).collect()
+    }
+
+    fn read_all(b: &Buffer) -> Vec<u8> {
+        let mut out = Vec::new();
+        b.reader().read_to_end(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn stays_in_memory_under_the_limit() {
+        let mut b = Buffer::new(Some(100));
+        b.write_all(&data(100)).unwrap();
+        assert!(! b.spilled());
+        assert_eq!(read_all(&b), data(100));
+        assert_eq!(b.into_vec().unwrap(), data(100));
+    }
+
+    #[test]
+    fn spills_past_the_limit() {
+        let all = data(3 * PIECE + 12_345);
+        let mut b = Buffer::new(Some(1000));
+        for piece in all.chunks(4093) {
+            b.write_all(piece).unwrap();
+        }
+        assert!(b.spilled());
+        assert_eq!(b.len(), all.len() as u64);
+        assert_eq!(read_all(&b), all);
+        assert_eq!(b.tail(4).unwrap(), all[all.len() - 4..]);
+        assert_eq!(b.tail(1 << 40).unwrap(), all);
+        assert_eq!(*b.range(PIECE as u64 - 3, 10).unwrap(), all[PIECE - 3..PIECE + 7]);
+        assert_eq!(*b.range(all.len() as u64 - 3, 10).unwrap(), all[all.len() - 3..]);
+        assert!(b.range(1 << 40, 10).unwrap().is_empty());
+        assert_eq!(b.into_vec().unwrap(), all);
+    }
+
+    #[test]
+    fn ranges_in_memory_are_borrowed() {
+        let mut b = Buffer::new(None);
+        b.write_all(&data(100)).unwrap();
+        assert!(matches!(b.range(90, 20).unwrap(), Cow::Borrowed(x) if x == &data(100)[90..]));
+        assert!(b.range(200, 20).unwrap().is_empty());
+    }
+
+    #[test]
+    fn spill_files_are_encrypted() {
+        let all = data(2 * PIECE);
+        let mut b = Buffer::new(Some(0));
+        b.write_all(&all).unwrap();
+        let spill = b.spill.as_ref().unwrap();
+        let mut file = spill.file.try_clone().unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut on_disk = Vec::new();
+        file.read_to_end(&mut on_disk).unwrap();
+        assert_eq!(on_disk.len() as u64, spill.len);
+        assert!(on_disk.len() >= PIECE);
+        let same = on_disk.iter().zip(&all).filter(|(x, y)| x == y).count();
+        assert!(same < on_disk.len() / 100, "{} of {} bytes are as written", same, on_disk.len());
+        assert_eq!(read_all(&b), all);
+    }
+}
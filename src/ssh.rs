@@ -0,0 +1,379 @@
+use std::io::Write;
+use std::process::Stdio;
+
+use openssh::{ KnownHosts, Session, SessionBuilder };
+use tempfile::NamedTempFile;
+use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+
+use crate::config::Config;
+use crate::remote::{ Output, Shell };
+
+/// How to treat the remote host's key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostKeyPolicy {
+    /// Only connect to hosts already in known_hosts.
+    Strict,
+    /// Add unknown hosts to known_hosts, but refuse changed keys.
+    AcceptNew,
+    /// Don't check host keys at all.
+    Off,
+}
+
+impl HostKeyPolicy {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "strict"     => Some(HostKeyPolicy::Strict),
+            "accept-new" => Some(HostKeyPolicy::AcceptNew),
+            "off"        => Some(HostKeyPolicy::Off),
+            _ => None,
+        }
+    }
+
+    fn known_hosts(&self) -> KnownHosts {
+        match self {
+            HostKeyPolicy::Strict    => KnownHosts::Strict,
+            HostKeyPolicy::AcceptNew => KnownHosts::Add,
+            HostKeyPolicy::Off       => KnownHosts::Accept,
+        }
+    }
+}
+
+/// What makes the SSH connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SshClient {
+    /// The system's ssh, through openssh, so ~/.ssh/config and everything
+    /// else ssh can do applies.
+    Openssh,
+    /// Mist's own client, in pure Rust, for systems without ssh. It uses
+    /// ~/.ssh/known_hosts, ssh-agent and the usual key files, but doesn't
+    /// read ~/.ssh/config.
+    Builtin,
+}
+
+impl SshClient {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "openssh" => Some(SshClient::Openssh),
+            "builtin" => Some(SshClient::Builtin),
+            _ => None,
+        }
+    }
+}
+
+/// Write an ssh_config that reaches every host through `jump` and otherwise
+/// defers to the usual user and system configuration, which `-F` skips.
+/// It's a private temporary file, removed when dropped.
+fn jump_config(jump: &str) -> std::io::Result<NamedTempFile> {
+    let mut f = tempfile::Builder::new().prefix("mist-ssh-").suffix(".conf").tempfile()?;
+    write!(f, "Host *\n    ProxyJump {}\n    Include ~/.ssh/config\n    \
+               Include /etc/ssh/ssh_config\n", jump)?;
+    f.flush()?;
+    Ok(f)
+}
+
+/// Open an SSH session to the profile's remote host with the system's ssh.
+async fn connect_openssh(cfg: &Config) -> Result<Session, openssh::Error> {
+    let mut b = SessionBuilder::default();
+    b.known_hosts_check(cfg.known_hosts.known_hosts())
+        .control_directory("/tmp");
+    if let Some(x) = cfg.ssh_port {
+        b.port(x);
+    }
+    if let Some(x) = &cfg.ssh_user {
+        b.user(x.clone());
+    }
+    if let Some(x) = &cfg.ssh_identity_file {
+        b.keyfile(x);
+    }
+    if let Some(x) = cfg.ssh_connect_timeout {
+        b.connect_timeout(x);
+    }
+    if let Some(x) = cfg.ssh_keep_alive {
+        b.server_alive_interval(x);
+    }
+    let jump = match &cfg.proxy_jump {
+        Some(x) => Some(jump_config(x).map_err(openssh::Error::Connect)?),
+        None => None,
+    };
+    if let Some(x) = &jump {
+        b.config_file(x.path());
+    }
+    // Only the master reads the configuration, so it can go once connected.
+    b.connect(&cfg.sshaddr).await
+}
+
+/// Open a connection to the profile's remote host, with the client it's
+/// configured to use.
+pub async fn connect(cfg: &Config) -> Result<Connection, Box<dyn std::error::Error>> {
+    match cfg.known_hosts {
+        HostKeyPolicy::Strict => (),
+        HostKeyPolicy::AcceptNew => println!(
+            "Warning: the host key of {} is trusted on first use if it isn't known yet",
+            cfg.sshaddr),
+        HostKeyPolicy::Off => println!(
+            "Warning: host key checking is off; the connection to {} can be intercepted",
+            cfg.sshaddr),
+    }
+    match cfg.ssh_client {
+        SshClient::Openssh => Ok(Connection::Openssh(connect_openssh(cfg).await?)),
+        #[cfg(feature = "builtin-ssh")]
+        SshClient::Builtin => Ok(Connection::Builtin(builtin::connect(cfg).await?)),
+        // The configuration refuses it in builds without the client.
+        #[cfg(not(feature = "builtin-ssh"))]
+        SshClient::Builtin => Err("This mist was built without the 'builtin-ssh' feature".into()),
+    }
+}
+
+/// Identifies the connection a profile needs, so profiles that would open
+/// the same one can share a session.
+pub fn connection_key(cfg: &Config) -> String {
+    format!("{}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}|{:?}", cfg.sshaddr, cfg.http_url,
+            cfg.restic_url, cfg.ssh_client, cfg.ssh_port, cfg.ssh_user, cfg.ssh_identity_file,
+            cfg.ssh_connect_timeout, cfg.ssh_keep_alive, cfg.known_hosts, cfg.proxy_jump)
+}
+
+/// The user (if the profile says), host and port the builtin client
+/// connects to, from an address like "user@host" and the ssh_* settings.
+pub fn address(cfg: &Config) -> (Option<String>, String, u16) {
+    let (user, host) = match cfg.sshaddr.rsplit_once('@') {
+        Some((u, h)) => (Some(u.to_string()), h.to_string()),
+        None => (None, cfg.sshaddr.clone()),
+    };
+    (user.or_else(|| cfg.ssh_user.clone()), host, cfg.ssh_port.unwrap_or(22))
+}
+
+/// An open connection to a profile's remote host.
+pub enum Connection {
+    Openssh(Session),
+    #[cfg(feature = "builtin-ssh")]
+    Builtin(builtin::Client),
+}
+
+impl Connection {
+    /// Fail if the connection has gone away.
+    pub async fn check(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Connection::Openssh(x) => Ok(x.check().await?),
+            #[cfg(feature = "builtin-ssh")]
+            Connection::Builtin(x) => x.check(),
+        }
+    }
+
+    pub async fn close(self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Connection::Openssh(x) => Ok(x.close().await?),
+            #[cfg(feature = "builtin-ssh")]
+            Connection::Builtin(x) => x.close().await,
+        }
+    }
+}
+
+impl Shell for Connection {
+    async fn run(&mut self, args: &[&str], stdin: Option<&[u8]>)
+    -> Result<Output, Box<dyn std::error::Error>> {
+        match self {
+            Connection::Openssh(x) => x.run(args, stdin).await,
+            #[cfg(feature = "builtin-ssh")]
+            Connection::Builtin(x) => x.run(args, stdin).await,
+        }
+    }
+
+    async fn read_file(&mut self, path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match self {
+            Connection::Openssh(x) => x.read_file(path).await,
+            #[cfg(feature = "builtin-ssh")]
+            Connection::Builtin(x) => x.read_file(path).await,
+        }
+    }
+}
+
+impl Shell for Session {
+    async fn run(&mut self, args: &[&str], stdin: Option<&[u8]>)
+    -> Result<Output, Box<dyn std::error::Error>> {
+        let mut cmd = self.command(args[0]);
+        cmd.args(&args[1..])
+            .stdin(match stdin {
+                Some(_) => Stdio::piped(),
+                None => Stdio::null(),
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit());
+        let mut child = cmd.spawn()?;
+        if let Some(bytes) = stdin {
+            // Closed when dropped, so the command sees the end of its input.
+            let mut pipe = child.stdin().take().ok_or("Unable to pipe to stdin")?;
+            pipe.write_all(bytes).await?;
+        }
+        let out = child.wait_with_output().await?;
+        Ok(Output { code: out.status.code(), stdout: out.stdout })
+    }
+
+    /// Over sftp, rather than with cat.
+    async fn read_file(&mut self, path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut sftp = self.sftp();
+        let mut f = sftp.read_from(path).await?;
+        let mut b = Vec::new();
+        f.read_to_end(&mut b).await?;
+        f.close().await?;
+        Ok(b)
+    }
+}
+
+#[cfg(feature = "builtin-ssh")]
+mod builtin {
+    use std::env::var;
+    use std::io::Write;
+    use std::path::{ Path, PathBuf };
+    use std::sync::Arc;
+
+    use russh::{ ChannelMsg, Disconnect };
+    use russh::client::{ self, Handle };
+    use russh::keys::{ HashAlg, PrivateKeyWithHashAlg, PublicKey, check_known_hosts_path,
+                       load_secret_key };
+    use russh::keys::agent::client::AgentClient;
+    use russh::keys::known_hosts::learn_known_hosts_path;
+
+    use crate::config::Config;
+    use crate::remote::{ Output, Shell };
+    use super::{ HostKeyPolicy, address };
+
+    /// The key files tried, after the agent's keys, when the profile
+    /// doesn't set `ssh_identity_file`.
+    const DEFAULT_KEYS: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+    fn ssh_dir() -> PathBuf {
+        PathBuf::from(var("HOME").unwrap_or_default()).join(".ssh")
+    }
+
+    /// Checks the server's key against ~/.ssh/known_hosts, as the profile's
+    /// `known_hosts` says.
+    struct Checker {
+        host: String,
+        port: u16,
+        policy: HostKeyPolicy,
+    }
+
+    impl client::Handler for Checker {
+        type Error = russh::Error;
+
+        async fn check_server_key(&mut self, key: &PublicKey) -> Result<bool, Self::Error> {
+            if self.policy == HostKeyPolicy::Off {
+                return Ok(true)
+            }
+            // A key that has changed is an error, whatever the policy.
+            let path = ssh_dir().join("known_hosts");
+            let known = check_known_hosts_path(&self.host, self.port, key, &path)?;
+            if ! known && self.policy == HostKeyPolicy::AcceptNew {
+                learn_known_hosts_path(&self.host, self.port, key, &path)?;
+                return Ok(true)
+            }
+            Ok(known)
+        }
+    }
+
+    pub struct Client {
+        handle: Handle<Checker>,
+    }
+
+    pub async fn connect(cfg: &Config) -> Result<Client, Box<dyn std::error::Error>> {
+        let (user, host, port) = address(cfg);
+        let user = user.or_else(|| var("USER").ok()).ok_or("No user to log in as; set 'ssh_user'")?;
+        let config = Arc::new(client::Config {
+            keepalive_interval: cfg.ssh_keep_alive,
+            ..Default::default()
+        });
+        let checker = Checker { host: host.clone(), port, policy: cfg.known_hosts };
+        let connecting = client::connect(config, (host.as_str(), port), checker);
+        let mut handle = match cfg.ssh_connect_timeout {
+            Some(x) => tokio::time::timeout(x, connecting)
+                .await
+                .map_err(|_| format!("Connecting to {} timed out", host))??,
+            None => connecting.await?,
+        };
+        if ! authenticate(&mut handle, &user, cfg).await? {
+            return Err(format!("{}@{} accepted none of the keys offered", user, host).into())
+        }
+        Ok(Client { handle })
+    }
+
+    /// Log in with the profile's identity file, if it has one, or else with
+    /// the agent's keys and then the usual key files. Keys protected by a
+    /// passphrase can only be used through the agent.
+    async fn authenticate(handle: &mut Handle<Checker>, user: &str, cfg: &Config)
+    -> Result<bool, Box<dyn std::error::Error>> {
+        let hash = handle.best_supported_rsa_hash().await?.flatten();
+        if let Some(x) = &cfg.ssh_identity_file {
+            return offer_file(handle, user, x, hash).await
+        }
+        if let Ok(mut agent) = AgentClient::connect_env().await {
+            for key in agent.request_identities().await? {
+                if handle.authenticate_publickey_with(user, key, hash, &mut agent).await?.success() {
+                    return Ok(true)
+                }
+            }
+        }
+        for name in DEFAULT_KEYS {
+            let path = ssh_dir().join(name);
+            if path.exists() && offer_file(handle, user, &path, hash).await? {
+                return Ok(true)
+            }
+        }
+        Ok(false)
+    }
+
+    async fn offer_file(handle: &mut Handle<Checker>, user: &str, path: &Path,
+                        hash: Option<HashAlg>)
+    -> Result<bool, Box<dyn std::error::Error>> {
+        let key = load_secret_key(path, None)
+            .map_err(|e| format!("Can't load {} ({}); add it to ssh-agent if it has a \
+                                  passphrase", path.display(), e))?;
+        let key = PrivateKeyWithHashAlg::new(Arc::new(key), hash);
+        Ok(handle.authenticate_publickey(user, key).await?.success())
+    }
+
+    /// Quote each argument for the remote user's shell, which runs the
+    /// command line.
+    fn command_line(args: &[&str]) -> String {
+        args.iter()
+            .map(|x| format!("'{}'", x.replace('\'', "'\\''")))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    impl Client {
+        pub fn check(&self) -> Result<(), Box<dyn std::error::Error>> {
+            match self.handle.is_closed() {
+                true  => Err("the connection was closed".into()),
+                false => Ok(()),
+            }
+        }
+
+        pub async fn close(self) -> Result<(), Box<dyn std::error::Error>> {
+            Ok(self.handle.disconnect(Disconnect::ByApplication, "", "en").await?)
+        }
+    }
+
+    impl Shell for Client {
+        async fn run(&mut self, args: &[&str], stdin: Option<&[u8]>)
+        -> Result<Output, Box<dyn std::error::Error>> {
+            let mut channel = self.handle.channel_open_session().await?;
+            channel.exec(true, command_line(args)).await?;
+            if let Some(x) = stdin {
+                channel.data(x).await?;
+            }
+            channel.eof().await?;
+            let mut out = Output { code: None, stdout: Vec::new() };
+            while let Some(msg) = channel.wait().await {
+                match msg {
+                    ChannelMsg::Data { data } => out.stdout.extend_from_slice(&data),
+                    // As with ssh, the command's stderr goes to ours.
+                    ChannelMsg::ExtendedData { data, ext: 1 } =>
+                        std::io::stderr().write_all(&data)?,
+                    ChannelMsg::ExitStatus { exit_status } => out.code = Some(exit_status as i32),
+                    _ => (),
+                }
+            }
+            Ok(out)
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::env::var;
+use std::fs::{ File, create_dir_all, read_dir, read_to_string, remove_file, rename, write };
+use std::io::Read;
+use std::path::{ Path, PathBuf };
+
+use toml::Value;
+use toml::value::Table;
+
+use crate::hash::to_hex;
+
+/// The directory mist keeps local state in: `$XDG_STATE_HOME/mist`, or
+/// `$HOME/.local/state/mist` if that isn't set. It holds:
+///
+///   version                    the layout's version (see `migrate`)
+///   device.toml                this machine's id
+///   hashes-<algorithm>.cache   file hashes, shared by every profile
+///   profiles/<profile>/        each profile's stats.toml, history.toml,
+///                              snapshot, gpg-key and lock
+pub fn state_dir(home: &Path) -> PathBuf {
+    match var("XDG_STATE_HOME") {
+        // Tests keep the state in the home they pass, whatever the
+        // environment says.
+        Ok(x) if ! x.is_empty() && ! cfg!(test) => PathBuf::from(x).join("mist"),
+        _ => home.join(".local/state/mist"),
+    }
+}
+
+/// The directory holding a profile's state.
+pub fn profile_dir(home: &Path, profile: &str) -> PathBuf {
+    state_dir(home).join("profiles").join(profile)
+}
+
+/// The files each profile has in its directory, which earlier versions
+/// kept in the state directory itself as `<profile>.<name>`.
+const PROFILE_FILES: &[&str] = &["stats.toml", "history.toml", "snapshot", "gpg-key"];
+
+/// Changes to the state directory's layout, in order; the version is how
+/// many have been applied.
+const MIGRATIONS: &[fn(&Path) -> std::io::Result<()>] = &[
+    profile_dirs,
+    hash_inodes,
+];
+
+/// Version 1: move each profile's files into its own directory. Old lock
+/// files go, unless a run of an earlier version still holds them.
+fn profile_dirs(dir: &Path) -> std::io::Result<()> {
+    for e in read_dir(dir)? {
+        let e = e?;
+        let name = e.file_name();
+        let name = match name.to_str() {
+            Some(x) => x,
+            None => continue,
+        };
+        if name.ends_with(".lock") && File::open(e.path())?.try_lock().is_ok() {
+            remove_file(e.path())?;
+            continue
+        }
+        for file in PROFILE_FILES {
+            let profile = match name.strip_suffix(file).and_then(|x| x.strip_suffix('.')) {
+                Some(x) if ! x.is_empty() => x,
+                _ => continue,
+            };
+            let dest = dir.join("profiles").join(profile);
+            create_dir_all(&dest)?;
+            rename(e.path(), dest.join(file))?;
+        }
+    }
+    Ok(())
+}
+
+/// Version 2: hash caches record each file's inode, to follow renames.
+/// The old ones are removed, and rebuilt by the next scan.
+fn hash_inodes(dir: &Path) -> std::io::Result<()> {
+    for e in read_dir(dir)? {
+        let e = e?;
+        let name = e.file_name();
+        if name.to_str().is_some_and(|x| x.starts_with("hashes-") && x.ends_with(".cache")) {
+            remove_file(e.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Bring the state directory up to the current layout. Runs from before
+/// the directory had a version start at 0.
+pub fn migrate(home: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let dir = state_dir(home);
+    if ! dir.is_dir() {
+        create_dir_all(&dir)?;
+        write(dir.join("version"), MIGRATIONS.len().to_string())?;
+        return Ok(())
+    }
+    let version: usize = match read_to_string(dir.join("version")) {
+        Ok(x) => x.trim().parse()
+            .map_err(|_| format!("{} is damaged", dir.join("version").display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    };
+    if version > MIGRATIONS.len() {
+        return Err(format!("{} was written by a newer version of mist", dir.display()).into())
+    }
+    for (i, m) in MIGRATIONS.iter().enumerate().skip(version) {
+        m(&dir).map_err(|e| format!("Updating {} to version {}: {}", dir.display(), i + 1, e))?;
+        write(dir.join("version"), (i + 1).to_string())?;
+    }
+    Ok(())
+}
+
+/// This machine, as recorded in the runs it makes.
+#[derive(Debug, Clone)]
+pub struct Device {
+    /// Random, made up the first time it's needed; it stays the same when
+    /// the machine is renamed.
+    pub id: String,
+    pub name: String,
+}
+
+/// This machine's host name, or "unknown" if it can't be had.
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    // SAFETY: the buffer is valid for its length, and gethostname writes
+    // no more than that.
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    let len = buf.iter().position(|x| *x == 0).unwrap_or(buf.len());
+    match res {
+        0 => String::from_utf8_lossy(&buf[..len]).into_owned(),
+        _ => "unknown".to_string(),
+    }
+}
+
+/// This machine's device record, creating it on first use. The name is
+/// the current host name, so a renamed machine shows its new name.
+pub fn device(home: &Path) -> Result<Device, Box<dyn std::error::Error>> {
+    let path = state_dir(home).join("device.toml");
+    let id = match read_to_string(&path) {
+        Ok(x) => {
+            let values: Value = toml::from_str(&x)?;
+            values.get("id")
+                .and_then(|x| x.as_str())
+                .ok_or(format!("{} has no 'id'", path.display()))?
+                .to_string()
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut bytes = [0u8; 8];
+            File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+            let id = to_hex(&bytes);
+            let mut t = Table::new();
+            t.insert("id".into(), Value::String(id.clone()));
+            create_dir_all(state_dir(home))?;
+            write(&path, toml::to_string(&Value::Table(t))?)?;
+            id
+        }
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Device { id, name: hostname() })
+}
@@ -0,0 +1,284 @@
+use std::collections::BTreeMap;
+use std::fs::{ create_dir_all, read_to_string, write };
+use std::path::{ Path, PathBuf };
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use chrono::{ Local, TimeZone };
+use toml::Value;
+use toml::value::Table;
+
+use crate::config::{ find_config, load_configuration };
+use crate::state::{ self, Device, profile_dir };
+use crate::summary::{ Outcome, Summary, human_bytes };
+
+/// Aggregate, purely local statistics about a profile's runs. Nothing in
+/// here ever leaves the machine; it exists so `mist doctor` can make
+/// tuning suggestions.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub runs: u64,
+    pub failures: u64,
+    pub up_to_date: u64,
+    pub failure_streak: u64,
+    pub longest_failure_streak: u64,
+    pub total_seconds: f64,
+    pub last_run: u64,
+    pub last_result: String,
+    /// Total seconds spent in each phase, and how many runs included it.
+    pub phase_seconds: BTreeMap<String, f64>,
+    pub phase_runs: BTreeMap<String, u64>,
+}
+
+/// The stats file for a profile.
+pub fn stats_path(home: &Path, profile: &str) -> PathBuf {
+    profile_dir(home, profile).join("stats.toml")
+}
+
+/// The history file for a profile: a `[[run]]` table for each recent run.
+pub fn history_path(home: &Path, profile: &str) -> PathBuf {
+    profile_dir(home, profile).join("history.toml")
+}
+
+/// How many runs the history file keeps.
+const HISTORY_RUNS: usize = 500;
+
+fn get_u64(t: &Table, key: &str) -> u64 {
+    t.get(key).and_then(|x| x.as_integer()).unwrap_or(0) as u64
+}
+
+fn get_f64(t: &Table, key: &str) -> f64 {
+    t.get(key).and_then(|x| x.as_float()).unwrap_or(0.0)
+}
+
+impl Stats {
+    /// Load a stats file. A missing file yields empty stats.
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let toml = match read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Stats::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let values: Value = toml::from_str(&toml)?;
+        let t = values.as_table().ok_or("stats file is not a table")?;
+        let mut stats = Stats {
+            runs: get_u64(t, "runs"),
+            failures: get_u64(t, "failures"),
+            up_to_date: get_u64(t, "up_to_date"),
+            failure_streak: get_u64(t, "failure_streak"),
+            longest_failure_streak: get_u64(t, "longest_failure_streak"),
+            total_seconds: get_f64(t, "total_seconds"),
+            last_run: get_u64(t, "last_run"),
+            last_result: t.get("last_result")
+                .and_then(|x| x.as_str())
+                .unwrap_or("")
+                .to_string(),
+            ..Stats::default()
+        };
+        if let Some(x) = t.get("phase_seconds").and_then(|x| x.as_table()) {
+            for (k, v) in x {
+                stats.phase_seconds.insert(k.clone(), v.as_float().unwrap_or(0.0));
+            }
+        }
+        if let Some(x) = t.get("phase_runs").and_then(|x| x.as_table()) {
+            for (k, v) in x {
+                stats.phase_runs.insert(k.clone(), v.as_integer().unwrap_or(0) as u64);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Write the stats file, creating the state directory if needed.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut t = Table::new();
+        t.insert("runs".into(), Value::Integer(self.runs as i64));
+        t.insert("failures".into(), Value::Integer(self.failures as i64));
+        t.insert("up_to_date".into(), Value::Integer(self.up_to_date as i64));
+        t.insert("failure_streak".into(), Value::Integer(self.failure_streak as i64));
+        t.insert("longest_failure_streak".into(),
+                 Value::Integer(self.longest_failure_streak as i64));
+        t.insert("total_seconds".into(), Value::Float(self.total_seconds));
+        t.insert("last_run".into(), Value::Integer(self.last_run as i64));
+        t.insert("last_result".into(), Value::String(self.last_result.clone()));
+        let secs = self.phase_seconds
+            .iter()
+            .map(|(k, v)| (k.clone(), Value::Float(*v)))
+            .collect();
+        t.insert("phase_seconds".into(), Value::Table(secs));
+        let runs = self.phase_runs
+            .iter()
+            .map(|(k, v)| (k.clone(), Value::Integer(*v as i64)))
+            .collect();
+        t.insert("phase_runs".into(), Value::Table(runs));
+
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        write(path, toml::to_string(&Value::Table(t))?)?;
+        Ok(())
+    }
+
+    /// Fold the outcome of one run into the totals.
+    pub fn record(&mut self, summary: &Summary) {
+        self.runs += 1;
+        self.total_seconds += summary.elapsed.as_secs_f64();
+        self.last_run = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+        self.last_result = summary.outcome.name().to_string();
+        match summary.outcome {
+            Outcome::Failed(_) => {
+                self.failures += 1;
+                self.failure_streak += 1;
+                self.longest_failure_streak = self.longest_failure_streak
+                    .max(self.failure_streak);
+            }
+            Outcome::UpToDate => {
+                self.up_to_date += 1;
+                self.failure_streak = 0;
+            }
+            _ => self.failure_streak = 0,
+        }
+        for (k, v) in &summary.phases {
+            *self.phase_seconds.entry(k.to_string()).or_default() += v.as_secs_f64();
+            *self.phase_runs.entry(k.to_string()).or_default() += 1;
+        }
+    }
+
+    /// Average wall time of a run, in seconds.
+    pub fn average_seconds(&self) -> f64 {
+        if self.runs == 0 {
+            return 0.0
+        }
+        self.total_seconds / self.runs as f64
+    }
+
+    /// Average wall time of a phase across the runs that included it.
+    pub fn average_phase_seconds(&self, phase: &str) -> Option<f64> {
+        let secs = self.phase_seconds.get(phase)?;
+        match self.phase_runs.get(phase) {
+            Some(n) if *n > 0 => Some(secs / *n as f64),
+            _ => None,
+        }
+    }
+}
+
+/// Load the runs recorded in a history file, oldest first. A missing file
+/// has none.
+pub fn load_history(path: &Path) -> Result<Vec<Table>, Box<dyn std::error::Error>> {
+    let toml = match read_to_string(path) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let values: Value = toml::from_str(&toml)?;
+    let runs = values
+        .get("run")
+        .and_then(|x| x.as_array())
+        .map(|x| x.iter().filter_map(|x| x.as_table().cloned()).collect())
+        .unwrap_or_default();
+    Ok(runs)
+}
+
+/// Add a run to a history file, dropping the oldest runs beyond
+/// HISTORY_RUNS.
+fn append_history(path: &Path, summary: &Summary, device: &Device)
+-> Result<(), Box<dyn std::error::Error>> {
+    let int = |x: u64| Value::Integer(x as i64);
+    let mut t = Table::new();
+    t.insert("started".into(), int(summary.started));
+    t.insert("sync_id".into(), Value::String(summary.sync_id.clone()));
+    t.insert("device".into(), Value::String(device.id.clone()));
+    t.insert("device_name".into(), Value::String(device.name.clone()));
+    t.insert("direction".into(), Value::String(summary.direction.name().into()));
+    t.insert("result".into(), Value::String(summary.outcome.name().into()));
+    t.insert("seconds".into(), Value::Float(summary.elapsed.as_secs_f64()));
+    t.insert("files_changed".into(), int(summary.files_changed as u64));
+    t.insert("bytes_up".into(), int(summary.bytes_up));
+    t.insert("bytes_down".into(), int(summary.bytes_down));
+    t.insert("archive_bytes".into(), int(summary.archive_bytes));
+    t.insert("compressed_bytes".into(), int(summary.compressed_bytes));
+    t.insert("encrypted_bytes".into(), int(summary.encrypted_bytes));
+    if let Some(x) = summary.compression_ratio() {
+        t.insert("compression_ratio".into(), Value::Float(x));
+    }
+    if let Some(x) = summary.throughput(summary.bytes_up, "upload") {
+        t.insert("upload_rate".into(), Value::Float(x));
+    }
+    if let Some(x) = summary.throughput(summary.bytes_down, "download") {
+        t.insert("download_rate".into(), Value::Float(x));
+    }
+    let phases = summary.phases
+        .iter()
+        .map(|(k, v)| (k.to_string(), Value::Float(v.as_secs_f64())))
+        .collect();
+    t.insert("phase_seconds".into(), Value::Table(phases));
+
+    let mut runs = load_history(path)?;
+    runs.push(t);
+    let skip = runs.len().saturating_sub(HISTORY_RUNS);
+    let mut doc = Table::new();
+    doc.insert("run".into(), Value::Array(runs.drain(skip..).map(Value::Table).collect()));
+    if let Some(dir) = path.parent() {
+        create_dir_all(dir)?;
+    }
+    write(path, toml::to_string(&Value::Table(doc))?)?;
+    Ok(())
+}
+
+/// Add this run to the profile's stats and history files. Problems are
+/// recorded as warnings.
+pub fn update(home: &Path, summary: &mut Summary) {
+    let path = stats_path(home, &summary.profile);
+    let res = Stats::load(&path).and_then(|mut stats| {
+        stats.record(summary);
+        stats.save(&path)
+    });
+    if let Err(e) = res {
+        summary.warn(format!("Updating stats file {}: {}", path.display(), e));
+    }
+    let path = history_path(home, &summary.profile);
+    let res = state::device(home).and_then(|x| append_history(&path, summary, &x));
+    if let Err(e) = res {
+        summary.warn(format!("Updating history file {}: {}", path.display(), e));
+    }
+}
+
+/// Print a profile's most recent runs, oldest first.
+pub async fn show_history(home: &Path, explicit: Option<&Path>, profile: &str, count: usize)
+-> Result<(), Box<dyn std::error::Error>> {
+    let cfg = load_configuration(&find_config(home, explicit)?, profile).await?;
+    let runs = load_history(&history_path(home, profile))?;
+    if runs.is_empty() {
+        match cfg.stats {
+            true  => println!("No runs of [{}] recorded yet", profile),
+            false => println!("[{}] doesn't keep a history; set 'stats = true'", profile),
+        }
+        return Ok(())
+    }
+    let int = |t: &Table, k: &str| get_u64(t, k);
+    let rate = |t: &Table, k: &str| match t.get(k).and_then(|x| x.as_float()) {
+        Some(x) => format!("{}/s", human_bytes(x as u64)),
+        None => "-".to_string(),
+    };
+    println!("{:<16} {:<5} {:<10} {:>6} {:>10} {:>12} {:>10} {:>12} {:>6} {:>7}",
+             "STARTED", "DIR", "RESULT", "FILES", "UP", "UP RATE", "DOWN", "DOWN RATE",
+             "RATIO", "TIME");
+    for t in &runs[runs.len().saturating_sub(count)..] {
+        let started = Local.timestamp_opt(int(t, "started") as i64, 0)
+            .single()
+            .map(|x| x.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default();
+        let text = |k: &str| t.get(k).and_then(|x| x.as_str()).unwrap_or("").to_string();
+        let ratio = match t.get("compression_ratio").and_then(|x| x.as_float()) {
+            Some(x) => format!("{:.0}%", x * 100.0),
+            None => "-".to_string(),
+        };
+        println!("{:<16} {:<5} {:<10} {:>6} {:>10} {:>12} {:>10} {:>12} {:>6} {:>6.1}s",
+                 started, text("direction"), text("result"), int(t, "files_changed"),
+                 human_bytes(int(t, "bytes_up")), rate(t, "upload_rate"),
+                 human_bytes(int(t, "bytes_down")), rate(t, "download_rate"), ratio,
+                 get_f64(t, "seconds"));
+    }
+    Ok(())
+}
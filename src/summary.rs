@@ -0,0 +1,451 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{ OpenOptions, create_dir_all };
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{ AtomicU32, Ordering };
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
+
+/// Which way data moved during a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Push,
+    Pull,
+    Sync,
+}
+
+impl Direction {
+    /// Short, machine-friendly name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Direction::Push => "push",
+            Direction::Pull => "pull",
+            Direction::Sync => "sync",
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Push => write!(f, "push (local -> remote)"),
+            Direction::Pull => write!(f, "pull (remote -> local)"),
+            Direction::Sync => write!(f, "sync (local <-> remote)"),
+        }
+    }
+}
+
+/// How a run ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Completed,
+    UpToDate,
+    Aborted(String),
+    Failed(String),
+}
+
+impl Outcome {
+    /// Short, machine-friendly name.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Completed  => "completed",
+            Outcome::UpToDate   => "up-to-date",
+            Outcome::Aborted(_) => "aborted",
+            Outcome::Failed(_)  => "failed",
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Completed  => write!(f, "completed"),
+            Outcome::UpToDate   => write!(f, "already up to date"),
+            Outcome::Aborted(x) => write!(f, "aborted ({})", x),
+            Outcome::Failed(x)  => write!(f, "failed: {}", x),
+        }
+    }
+}
+
+/// Exit statuses, so scripts can tell how a run ended; see `Summary::exit_code`.
+pub const EXIT_FAILURE: i32 = 1;
+pub const EXIT_WARNINGS: i32 = 2;
+pub const EXIT_CONFLICTS: i32 = 3;
+pub const EXIT_NETWORK: i32 = 4;
+pub const EXIT_CRYPTO: i32 = 5;
+pub const EXIT_CONFIG: i32 = 6;
+/// As shells report a process stopped by SIGINT.
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+/// What kind of problem made a run fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureKind {
+    Other,
+    /// SSH couldn't connect, or the connection failed mid-run.
+    Network,
+    /// GPG couldn't encrypt or decrypt, or there's no key to do it with.
+    Crypto,
+    /// The configuration file or profile is missing or invalid.
+    Config,
+    /// Stopped by Ctrl-C or SIGTERM.
+    Interrupted,
+}
+
+impl FailureKind {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            FailureKind::Other   => EXIT_FAILURE,
+            FailureKind::Network => EXIT_NETWORK,
+            FailureKind::Crypto  => EXIT_CRYPTO,
+            FailureKind::Config  => EXIT_CONFIG,
+            FailureKind::Interrupted => EXIT_INTERRUPTED,
+        }
+    }
+}
+
+/// An error that knows what kind of failure it is.
+pub struct Failure {
+    pub kind: FailureKind,
+    pub msg: String,
+}
+
+impl Failure {
+    fn boxed(kind: FailureKind, e: impl fmt::Display) -> Box<dyn std::error::Error> {
+        Box::new(Failure { kind, msg: e.to_string() })
+    }
+
+    pub fn network(e: impl fmt::Display) -> Box<dyn std::error::Error> {
+        Failure::boxed(FailureKind::Network, e)
+    }
+
+    pub fn crypto(e: impl fmt::Display) -> Box<dyn std::error::Error> {
+        Failure::boxed(FailureKind::Crypto, e)
+    }
+
+    pub fn config(e: impl fmt::Display) -> Box<dyn std::error::Error> {
+        Failure::boxed(FailureKind::Config, e)
+    }
+
+    pub fn interrupted(e: impl fmt::Display) -> Box<dyn std::error::Error> {
+        Failure::boxed(FailureKind::Interrupted, e)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+// Debug is what `main` prints for a returned error, so keep it readable.
+impl fmt::Debug for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Collects what happened during a run so it can be reported in one
+/// block at the end instead of piecemeal as it happens.
+pub struct Summary {
+    pub profile: String,
+    /// Tells this run apart from others in logs, e.g. a daemon's.
+    pub sync_id: String,
+    pub direction: Direction,
+    pub outcome: Outcome,
+    /// What kind of failure it was, if the outcome is `Failed`.
+    pub failure: FailureKind,
+    pub files_changed: usize,
+    /// Of the files changed, how many were new, how many were deleted, and
+    /// how many only moved, which counts as neither.
+    pub files_added: usize,
+    pub files_deleted: usize,
+    pub files_renamed: usize,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    /// Size of the uncompressed archive pushed.
+    pub archive_bytes: u64,
+    /// Bytes that went into compression, and what came out.
+    pub compressed_in: u64,
+    pub compressed_bytes: u64,
+    /// Size of the ciphertext written, before upload.
+    pub encrypted_bytes: u64,
+    pub warnings: Vec<String>,
+    pub unresolved_conflicts: bool,
+    pub phases: BTreeMap<&'static str, Duration>,
+    /// When the run started, in seconds since the Unix epoch.
+    pub started: u64,
+    /// How long the whole run took, once it's over.
+    pub elapsed: Duration,
+}
+
+/// Runs started by this process so far, for sync ids.
+static RUNS: AtomicU32 = AtomicU32::new(0);
+
+impl Summary {
+    pub fn new(profile: &str, direction: Direction) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+        Summary {
+            profile: profile.to_string(),
+            sync_id: format!("{:x}-{:x}-{}", started, std::process::id(),
+                             RUNS.fetch_add(1, Ordering::Relaxed)),
+            direction,
+            outcome: Outcome::Completed,
+            failure: FailureKind::Other,
+            files_changed: 0,
+            files_added: 0,
+            files_deleted: 0,
+            files_renamed: 0,
+            bytes_up: 0,
+            bytes_down: 0,
+            archive_bytes: 0,
+            compressed_in: 0,
+            compressed_bytes: 0,
+            encrypted_bytes: 0,
+            warnings: Vec::new(),
+            unresolved_conflicts: false,
+            phases: BTreeMap::new(),
+            started,
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// Add the time elapsed since `start` to the named phase (e.g. "hash").
+    pub fn phase(&mut self, name: &'static str, start: Instant) {
+        *self.phases.entry(name).or_default() += start.elapsed();
+    }
+
+    /// Compressed size as a fraction of the original, if anything was
+    /// compressed.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        match self.compressed_in {
+            0 => None,
+            n => Some(self.compressed_bytes as f64 / n as f64),
+        }
+    }
+
+    /// Bytes per second moved during a phase, if it took any time.
+    pub fn throughput(&self, bytes: u64, phase: &str) -> Option<f64> {
+        let secs = self.phases.get(phase)?.as_secs_f64();
+        match bytes > 0 && secs > 0.0 {
+            true  => Some(bytes as f64 / secs),
+            false => None,
+        }
+    }
+
+    /// Record a non-fatal problem to be listed at the end of the run.
+    pub fn warn(&mut self, msg: impl Into<String>) {
+        self.warnings.push(msg.into());
+    }
+
+    /// Record that the run failed, and how.
+    pub fn fail(&mut self, kind: FailureKind, msg: impl Into<String>) {
+        self.outcome = Outcome::Failed(msg.into());
+        self.failure = kind;
+    }
+
+    /// The exit status for this run: the kind of failure, unresolved
+    /// conflicts, warnings, or 0 for anything else (including aborting at
+    /// a prompt, and having nothing to do).
+    pub fn exit_code(&self) -> i32 {
+        match self.outcome {
+            Outcome::Failed(_) => self.failure.exit_code(),
+            _ if self.unresolved_conflicts => EXIT_CONFLICTS,
+            _ if self.has_warnings() => EXIT_WARNINGS,
+            _ => 0,
+        }
+    }
+
+    /// Whether the run finished its work but hit non-fatal problems.
+    pub fn has_warnings(&self) -> bool {
+        ! self.warnings.is_empty()
+    }
+
+    /// Suggest the command the user most likely wants to run next.
+    pub fn next_command(&self) -> Option<String> {
+        let p = &self.profile;
+        match &self.outcome {
+            Outcome::UpToDate | Outcome::Aborted(_) => return None,
+            Outcome::Failed(_) => return Some(format!("mist {}", p)),
+            Outcome::Completed => (),
+        }
+        if self.unresolved_conflicts {
+            return Some(format!("mist {}  (without --assume-yes, to resolve conflicts)", p))
+        }
+        if self.warnings.iter().any(|w| w.starts_with("dd:")) {
+            return Some(format!("mist {} --push --scp-write", p))
+        }
+        match self.direction {
+            Direction::Push => Some(format!("mist {} --pull  (on your other machines)", p)),
+            Direction::Pull => Some(format!("mist {}", p)),
+            Direction::Sync => None,
+        }
+    }
+
+    /// The summary as a single line of JSON, for monitoring.
+    pub fn to_json(&self) -> String {
+        let error = match &self.outcome {
+            Outcome::Failed(x) | Outcome::Aborted(x) => json_string(x),
+            _ => "null".to_string(),
+        };
+        let warnings: Vec<String> = self.warnings.iter().map(|x| json_string(x)).collect();
+        let phases: Vec<String> = self.phases
+            .iter()
+            .map(|(k, v)| format!("{}:{:.3}", json_string(k), v.as_secs_f64()))
+            .collect();
+        format!("{{\"profile\":{},\"sync_id\":\"{}\",\"direction\":\"{}\",\"result\":\"{}\",\"message\":{},\
+                 \"exit_code\":{},\"started\":{},\"duration_seconds\":{:.3},\
+                 \"files_changed\":{},\"files_added\":{},\"files_deleted\":{},\"files_renamed\":{},\
+                 \"bytes_up\":{},\"bytes_down\":{},\"archive_bytes\":{},\
+                 \"compressed_bytes\":{},\"compression_ratio\":{},\"encrypted_bytes\":{},\
+                 \"unresolved_conflicts\":{},\
+                 \"warnings\":[{}],\"phase_seconds\":{{{}}}}}",
+                json_string(&self.profile), self.sync_id, self.direction.name(), self.outcome.name(), error,
+                self.exit_code(), self.started, self.elapsed.as_secs_f64(),
+                self.files_changed, self.files_added, self.files_deleted, self.files_renamed,
+                self.bytes_up, self.bytes_down, self.archive_bytes, self.compressed_bytes,
+                self.compression_ratio().map_or("null".to_string(), |x| format!("{:.3}", x)),
+                self.encrypted_bytes, self.unresolved_conflicts,
+                warnings.join(","), phases.join(","))
+    }
+
+    /// Append the JSON summary to a results file, one line per run.
+    pub fn append_json(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            create_dir_all(dir)?;
+        }
+        let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(f, "{}", self.to_json())
+    }
+
+    /// Print the summary block, all at once so that runs going at the same
+    /// time don't mix their lines into it.
+    pub fn print(&self) {
+        let _ = self.write_block(&mut std::io::stdout().lock());
+    }
+
+    fn write_block(&self, out: &mut impl Write) -> std::io::Result<()> {
+        writeln!(out)?;
+        writeln!(out, "Summary [{}]", self.profile)?;
+        writeln!(out, "  direction: {}", self.direction)?;
+        if self.outcome == Outcome::Completed && self.has_warnings() {
+            writeln!(out, "  result:    completed with warnings")?;
+        } else {
+            writeln!(out, "  result:    {}", self.outcome)?;
+        }
+        writeln!(out, "  files:     {} changed", self.files_changed)?;
+        writeln!(out, "  transfer:  {}{} up, {}{} down",
+                 human_bytes(self.bytes_up), rate(self.throughput(self.bytes_up, "upload")),
+                 human_bytes(self.bytes_down),
+                 rate(self.throughput(self.bytes_down, "download")))?;
+        if self.archive_bytes > 0 {
+            let compressed = match self.compression_ratio() {
+                Some(x) => format!(", compressed to {} ({:.0}%)",
+                                   human_bytes(self.compressed_bytes), x * 100.0),
+                None => String::new(),
+            };
+            writeln!(out, "  archive:   {}{}, encrypted {}", human_bytes(self.archive_bytes),
+                     compressed, human_bytes(self.encrypted_bytes))?;
+        }
+        if ! self.phases.is_empty() {
+            let phases: Vec<String> = self.phases
+                .iter()
+                .map(|(k, v)| format!("{} {:.1}s", k, v.as_secs_f64()))
+                .collect();
+            writeln!(out, "  time:      {:.1}s ({})", self.elapsed.as_secs_f64(),
+                     phases.join(", "))?;
+        }
+        if self.unresolved_conflicts {
+            writeln!(out, "  conflicts: unresolved, see the merge tool's output")?;
+        } else {
+            writeln!(out, "  conflicts: none")?;
+        }
+        if self.warnings.is_empty() {
+            writeln!(out, "  warnings:  none")?;
+        } else {
+            writeln!(out, "  warnings:  {}", self.warnings.len())?;
+            for w in &self.warnings {
+                writeln!(out, "    - {}", w)?;
+            }
+        }
+        if let Some(x) = self.next_command() {
+            writeln!(out, "  next:      {}", x)?;
+        }
+        Ok(())
+    }
+}
+
+/// Format a throughput for the summary, e.g. ` (1.4 MiB/s)`.
+fn rate(bytes_per_sec: Option<f64>) -> String {
+    match bytes_per_sec {
+        Some(x) => format!(" ({}/s)", human_bytes(x as u64)),
+        None => String::new(),
+    }
+}
+
+/// Quote a string for JSON.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"'  => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Format a byte count for humans, e.g. `1.4 MiB`.
+pub fn human_bytes(n: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = n as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", n, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// The exit status for several runs: that of their failures if they all
+/// failed the same way, a plain failure if they failed in different ways,
+/// and otherwise the most notable of the rest.
+pub fn overall_exit_code(summaries: &[Summary]) -> i32 {
+    let mut failures = summaries
+        .iter()
+        .filter(|x| matches!(x.outcome, Outcome::Failed(_)))
+        .map(|x| x.exit_code());
+    if let Some(first) = failures.next() {
+        return match failures.all(|x| x == first) {
+            true  => first,
+            false => EXIT_FAILURE,
+        }
+    }
+    summaries.iter().map(|x| x.exit_code()).max().unwrap_or(0)
+}
+
+/// Print one line per run, e.g. after syncing every profile.
+pub fn print_overview(summaries: &[Summary]) {
+    println!();
+    println!("{:<16} {:<12} {:>7} {:>10} {:>10} {:>8}",
+             "PROFILE", "RESULT", "FILES", "UP", "DOWN", "WARNINGS");
+    for x in summaries {
+        println!("{:<16} {:<12} {:>7} {:>10} {:>10} {:>8}",
+                 x.profile, x.outcome.name(), x.files_changed,
+                 human_bytes(x.bytes_up), human_bytes(x.bytes_down), x.warnings.len());
+    }
+}
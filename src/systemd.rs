@@ -0,0 +1,103 @@
+use std::env::{ current_exe, var };
+use std::fs::{ create_dir_all, write };
+use std::path::{ Path, PathBuf };
+
+use crate::config::load_configuration;
+
+/// Turn a profile name into something usable in a unit file name.
+fn unit_name(profile: &str) -> String {
+    let name: String = profile
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "_.-".contains(c) { c } else { '_' })
+        .collect();
+    format!("mist-{}", name)
+}
+
+/// Quote a word for an `ExecStart=` line if it needs it.
+fn quote(word: &str) -> String {
+    if word.chars().any(|c| c.is_whitespace() || c == '"' || c == '\\') {
+        format!("\"{}\"", word.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        word.to_string()
+    }
+}
+
+/// The service unit, which runs a single non-interactive sync.
+fn service_unit(profile: &str, exe: &Path, config: &Path) -> String {
+    format!("\
+[Unit]
+Description=mist sync of profile {profile}
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+SyslogIdentifier=mist
+ExecStart={exe} {arg} --assume-yes --config {config}
+",
+        profile = profile,
+        exe = quote(&exe.to_string_lossy()),
+        arg = quote(profile),
+        config = quote(&config.to_string_lossy()))
+}
+
+/// The timer unit, which starts the service every `interval` (any systemd
+/// time span, e.g. "15min" or "1h").
+fn timer_unit(profile: &str, interval: &str) -> String {
+    format!("\
+[Unit]
+Description=Periodic mist sync of profile {profile}
+
+[Timer]
+OnBootSec=5min
+OnUnitActiveSec={interval}
+Persistent=true
+
+[Install]
+WantedBy=timers.target
+",
+        profile = profile,
+        interval = interval)
+}
+
+/// Where systemd looks for user units.
+fn user_unit_dir(home: &Path) -> PathBuf {
+    match var("XDG_CONFIG_HOME") {
+        Ok(x) if ! x.is_empty() => PathBuf::from(x).join("systemd/user"),
+        _ => home.join(".config/systemd/user"),
+    }
+}
+
+/// Print, or install into the user unit directory, a service and timer that
+/// sync the given profile periodically. The service names the configuration
+/// file explicitly, since the unit's environment may differ from the shell's.
+pub async fn generate(home: &Path, config: &Path, profile: &str, interval: &str, install: bool)
+-> Result<(), Box<dyn std::error::Error>> {
+    // Make sure the profile exists before writing units for it.
+    load_configuration(config, profile).await?;
+
+    let exe = current_exe()?;
+    let config = config.canonicalize()?;
+    let name = unit_name(profile);
+    let service = service_unit(profile, &exe, &config);
+    let timer = timer_unit(profile, interval);
+
+    if ! install {
+        println!("# {}.service\n{}", name, service);
+        println!("# {}.timer\n{}", name, timer);
+        return Ok(())
+    }
+
+    let dir = user_unit_dir(home);
+    create_dir_all(&dir)?;
+    let service_path = dir.join(format!("{}.service", name));
+    let timer_path = dir.join(format!("{}.timer", name));
+    write(&service_path, service)?;
+    write(&timer_path, timer)?;
+    println!("Wrote {}", service_path.display());
+    println!("Wrote {}", timer_path.display());
+    println!("Enable with:");
+    println!("  systemctl --user daemon-reload");
+    println!("  systemctl --user enable --now {}.timer", name);
+    Ok(())
+}
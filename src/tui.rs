@@ -0,0 +1,250 @@
+use std::collections::VecDeque;
+use std::io::{ BufRead, BufReader, Read, stdout };
+use std::path::{ Path, PathBuf };
+use std::process::{ Child, Command, Stdio };
+use std::sync::mpsc::{ Receiver, Sender, channel };
+use std::thread;
+use std::time::Duration;
+
+use chrono::{ Local, TimeZone };
+use crossterm::event::{ self, Event, KeyCode, KeyEventKind };
+use crossterm::execute;
+use crossterm::terminal::{
+    EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode };
+use ratatui::Terminal;
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{ Constraint, Direction, Layout };
+use ratatui::style::{ Modifier, Style };
+use ratatui::text::Line;
+use ratatui::widgets::{ Block, Borders, Paragraph, Row, Table, TableState };
+
+use crate::config::profile_names;
+use crate::stats::{ Stats, stats_path };
+
+/// How many lines of output the log pane keeps.
+const LOG_LINES: usize = 1000;
+
+const HELP: &str = "up/down select   s sync   p push   P pull   r refresh   q quit";
+
+/// A sync started from the dashboard. It runs as a separate `mist`
+/// process with `--assume-yes`, whose output is shown in the log pane.
+struct Job {
+    profile: String,
+    child: Child,
+}
+
+struct App {
+    home: PathBuf,
+    config: PathBuf,
+    profiles: Vec<String>,
+    stats: Vec<Option<Stats>>,
+    table: TableState,
+    log: VecDeque<String>,
+    job: Option<Job>,
+    lines: (Sender<String>, Receiver<String>),
+}
+
+impl App {
+    fn refresh(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.profiles = profile_names(&self.config)?;
+        self.stats = self.profiles
+            .iter()
+            .map(|x| Stats::load(&stats_path(&self.home, x)).ok().filter(|x| x.runs > 0))
+            .collect();
+        if ! matches!(self.table.selected(), Some(x) if x < self.profiles.len()) {
+            self.table.select(if self.profiles.is_empty() { None } else { Some(0) });
+        }
+        Ok(())
+    }
+
+    fn log(&mut self, line: String) {
+        if self.log.len() == LOG_LINES {
+            self.log.pop_front();
+        }
+        self.log.push_back(line);
+    }
+
+    fn select(&mut self, delta: isize) {
+        if let Some(x) = self.table.selected() {
+            let n = self.profiles.len() as isize;
+            self.table.select(Some((x as isize + delta).rem_euclid(n) as usize));
+        }
+    }
+
+    /// Start syncing the selected profile, with `flag` (e.g. "--push").
+    fn start(&mut self, flag: Option<&str>) {
+        if let Some(x) = &self.job {
+            let msg = format!("[{}] is still running", x.profile);
+            return self.log(msg)
+        }
+        let profile = match self.table.selected().and_then(|x| self.profiles.get(x)) {
+            Some(x) => x.clone(),
+            None => return,
+        };
+        let exe = match std::env::current_exe() {
+            Ok(x) => x,
+            Err(e) => return self.log(format!("Can't find the mist executable: {}", e)),
+        };
+        let mut cmd = Command::new(exe);
+        cmd.arg(&profile)
+            .arg("--assume-yes")
+            .arg("--config")
+            .arg(&self.config)
+            .args(flag)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = match cmd.spawn() {
+            Ok(x) => x,
+            Err(e) => return self.log(format!("[{}] can't start: {}", profile, e)),
+        };
+        forward(child.stdout.take(), &self.lines.0);
+        forward(child.stderr.take(), &self.lines.0);
+        self.log(format!("[{}] started{}", profile,
+                         flag.map(|x| format!(" ({})", x)).unwrap_or_default()));
+        self.job = Some(Job { profile, child });
+    }
+
+    /// Collect new output, and notice when the running sync has finished.
+    fn poll(&mut self) {
+        while let Ok(x) = self.lines.1.try_recv() {
+            self.log(x);
+        }
+        let done = match &mut self.job {
+            Some(x) => x.child.try_wait().ok().flatten(),
+            None => None,
+        };
+        if let Some(status) = done {
+            let job = self.job.take().expect("a finished job was running");
+            self.log(format!("[{}] finished: {}", job.profile, status));
+            let _ = self.refresh();
+        }
+    }
+}
+
+/// Send each line read from `r` down the channel, from a background thread.
+fn forward<R: Read + Send + 'static>(r: Option<R>, tx: &Sender<String>) {
+    if let Some(r) = r {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(r).lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break
+                }
+            }
+        });
+    }
+}
+
+/// One row of the profile table.
+fn row<'a>(profile: &'a str, stats: &'a Option<Stats>, running: bool) -> Row<'a> {
+    let (last, result, runs, failures) = match stats {
+        Some(x) => (
+            Local.timestamp_opt(x.last_run as i64, 0)
+                .single()
+                .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default(),
+            x.last_result.clone(),
+            x.runs.to_string(),
+            x.failures.to_string(),
+        ),
+        None => ("-".into(), "-".into(), "-".into(), "-".into()),
+    };
+    let result = if running { "running".to_string() } else { result };
+    Row::new(vec![profile.to_string(), last, result, runs, failures])
+}
+
+fn draw(f: &mut ratatui::Frame, app: &mut App) {
+    let rows = (app.profiles.len() as u16).max(1) + 3;
+    let area = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(rows), Constraint::Min(3), Constraint::Length(1)])
+        .split(f.size());
+
+    let running = app.job.as_ref().map(|x| x.profile.as_str());
+    let table = Table::new(
+        app.profiles
+            .iter()
+            .zip(&app.stats)
+            .map(|(p, s)| row(p, s, Some(p.as_str()) == running)),
+        [Constraint::Min(16), Constraint::Length(17), Constraint::Length(12),
+         Constraint::Length(6), Constraint::Length(9)])
+        .header(Row::new(vec!["PROFILE", "LAST RUN", "RESULT", "RUNS", "FAILURES"])
+                .style(Style::default().add_modifier(Modifier::BOLD)))
+        .block(Block::default().borders(Borders::ALL).title("Profiles"))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    f.render_stateful_widget(table, area[0], &mut app.table);
+
+    // Show as much of the end of the log as fits.
+    let height = area[1].height.saturating_sub(2) as usize;
+    let log: Vec<Line> = app.log
+        .iter()
+        .skip(app.log.len().saturating_sub(height))
+        .map(|x| Line::from(x.as_str()))
+        .collect();
+    f.render_widget(Paragraph::new(log).block(Block::default().borders(Borders::ALL).title("Log")),
+                    area[1]);
+    f.render_widget(Paragraph::new(HELP), area[2]);
+}
+
+fn event_loop(terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>, app: &mut App)
+-> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        app.poll();
+        terminal.draw(|f| draw(f, app))?;
+        if ! event::poll(Duration::from_millis(200))? {
+            continue
+        }
+        let key = match event::read()? {
+            Event::Key(x) if x.kind == KeyEventKind::Press => x,
+            _ => continue,
+        };
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Up | KeyCode::Char('k') => app.select(-1),
+            KeyCode::Down | KeyCode::Char('j') => app.select(1),
+            KeyCode::Char('s') => app.start(None),
+            KeyCode::Char('p') => app.start(Some("--push")),
+            KeyCode::Char('P') => app.start(Some("--pull")),
+            KeyCode::Char('r') => {
+                if let Err(e) = app.refresh() {
+                    app.log(format!("Can't reload the configuration: {}", e));
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Show a dashboard of the configured profiles, from which they can be
+/// synced, pushed or pulled. Last-run details come from each profile's
+/// stats, so they're only shown for profiles with `stats = true`.
+pub fn run(home: &Path, config: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let mut app = App {
+        home: home.to_path_buf(),
+        config: config.to_path_buf(),
+        profiles: Vec::new(),
+        stats: Vec::new(),
+        table: TableState::default(),
+        log: VecDeque::new(),
+        job: None,
+        lines: channel(),
+    };
+    app.refresh()?;
+
+    enable_raw_mode()?;
+    execute!(stdout(), EnterAlternateScreen)?;
+    let res = Terminal::new(CrosstermBackend::new(stdout()))
+        .map_err(|e| e.into())
+        .and_then(|mut t| event_loop(&mut t, &mut app));
+    // Put the terminal back even if the dashboard failed.
+    disable_raw_mode()?;
+    execute!(stdout(), LeaveAlternateScreen, crossterm::cursor::Show)?;
+
+    // Stopping a sync halfway could leave a partly written archive behind.
+    if let Some(mut x) = app.job.take() {
+        println!("Waiting for the sync of [{}] to finish...", x.profile);
+        let _ = x.child.wait();
+    }
+    res
+}
@@ -0,0 +1,66 @@
+#[cfg(feature = "webhook")]
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::notify::NotifyPolicy;
+use crate::summary::Summary;
+#[cfg(feature = "webhook")]
+use crate::secrets::run_command;
+#[cfg(feature = "webhook")]
+use crate::summary::json_string;
+
+/// A URL to POST each run's summary to, e.g. for ntfy, Slack or home
+/// automation.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub url: String,
+    /// Which runs to send.
+    pub when: NotifyPolicy,
+    /// Prints a bearer token to send with the request.
+    pub token_command: Option<String>,
+}
+
+/// How long to wait for the webhook's server.
+#[cfg(feature = "webhook")]
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The request body: the run's JSON summary (as in `results_file`), with a
+/// line of text first for services that show only that.
+#[cfg(feature = "webhook")]
+fn payload(summary: &Summary) -> String {
+    let text = format!("mist [{}]: {}, {}", summary.profile, summary.direction, summary.outcome);
+    let json = summary.to_json();
+    format!("{{\"text\":{},{}", json_string(&text), &json[1..])
+}
+
+/// POST the run's summary to the profile's webhook, if the policy asks for
+/// it. Problems are recorded as warnings.
+#[cfg(feature = "webhook")]
+pub async fn send(cfg: &Config, summary: &mut Summary) {
+    let hook = match &cfg.webhook {
+        Some(x) if x.when.wants(summary) => x,
+        _ => return,
+    };
+    let res: Result<(), Box<dyn std::error::Error>> = async {
+        let client = reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build()?;
+        let mut req = client.post(&hook.url)
+            .header("Content-Type", "application/json")
+            .body(payload(summary));
+        if let Some(x) = &hook.token_command {
+            req = req.bearer_auth(run_command(x)?);
+        }
+        let res = req.send().await?;
+        match res.status().is_success() {
+            true  => Ok(()),
+            false => Err(format!("the server answered {}", res.status()).into()),
+        }
+    }.await;
+    if let Err(e) = res {
+        summary.warn(format!("Webhook {}: {}", hook.url, e));
+    }
+}
+
+/// Built without webhooks; the configuration refuses `webhook_url`, so
+/// there's nothing to do.
+#[cfg(not(feature = "webhook"))]
+pub async fn send(_cfg: &Config, _summary: &mut Summary) {}
@@ -0,0 +1,288 @@
+// End-to-end runs of the mist binary against a real SSH server, with a
+// throwaway GPG key. They start a container from tests/sshd, so they're
+// ignored unless asked for, and then fail if docker isn't there:
+//
+//     cargo test --test remote -- --ignored
+//
+// MIST_DOCKER names another docker-compatible program, e.g. podman.
+#![cfg(feature = "gpg")]
+
+use std::collections::BTreeMap;
+use std::env::var_os;
+use std::fs;
+use std::io::Read;
+use std::net::TcpStream;
+use std::os::unix::fs::{ symlink, MetadataExt, PermissionsExt };
+use std::path::{ Path, PathBuf };
+use std::process::{ Command, Output };
+use std::thread::sleep;
+use std::time::{ Duration, Instant };
+
+use tempfile::TempDir;
+
+/// The image built from tests/sshd.
+const IMAGE: &str = "mist-test-sshd";
+
+/// How long the server gets to start accepting connections.
+const STARTUP: Duration = Duration::from_secs(30);
+
+/// The profile each machine syncs.
+const PROFILE: &str = "test";
+
+fn docker() -> Command {
+    Command::new(var_os("MIST_DOCKER").unwrap_or_else(|| "docker".into()))
+}
+
+/// Run a command, failing the test with its output unless it succeeds.
+fn run(cmd: &mut Command) -> Output {
+    let out = cmd.output().unwrap_or_else(|e| panic!("{:?}: {}", cmd, e));
+    assert!(out.status.success(), "{:?} exited with {}:\n{}{}", cmd, out.status,
+            String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+    out
+}
+
+fn stdout(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stdout).trim().to_string()
+}
+
+/// An SSH server in a container, removed when dropped.
+struct Server {
+    id: String,
+    port: u16,
+}
+
+impl Server {
+    /// Build the image and start a server that lets the key at `key` in.
+    fn start(key: &Path) -> Self {
+        let context = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/sshd");
+        run(docker().args(["build", "-q", "-t", IMAGE]).arg(context));
+        let public = fs::read_to_string(key.with_extension("pub")).unwrap();
+        let out = run(docker()
+            .args(["run", "-d", "--rm", "-p", "127.0.0.1::22", "-e"])
+            .arg(format!("PUBLIC_KEY={}", public.trim()))
+            .arg(IMAGE));
+        let mut server = Server { id: stdout(&out), port: 0 };
+        let out = run(docker().args(["port", &server.id, "22/tcp"]));
+        server.port = stdout(&out)
+            .lines()
+            .find_map(|x| x.rsplit_once(':')?.1.parse().ok())
+            .expect("the container's SSH port");
+        server.wait();
+        server
+    }
+
+    /// Wait until sshd itself answers, not just the port forward.
+    fn wait(&self) {
+        let start = Instant::now();
+        loop {
+            let mut banner = [0; 4];
+            let answered = TcpStream::connect(("127.0.0.1", self.port))
+                .and_then(|mut x| x.read_exact(&mut banner))
+                .is_ok();
+            if answered && &banner == b"SSH-" {
+                return
+            }
+            assert!(start.elapsed() < STARTUP, "sshd didn't start in {:?}", STARTUP);
+            sleep(Duration::from_millis(250));
+        }
+    }
+
+    /// Run a shell command in the container.
+    fn exec(&self, script: &str) -> Output {
+        run(docker().args(["exec", &self.id, "sh", "-c", script]))
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = docker().args(["rm", "-f", &self.id]).output();
+    }
+}
+
+/// A machine syncing the profile: a home of its own, with its own
+/// configuration, state and folder.
+struct Machine {
+    home: PathBuf,
+    gnupg: PathBuf,
+    folder: PathBuf,
+}
+
+impl Machine {
+    fn new(root: &Path, name: &str, server: &Server, key: &Path, gpg_id: &str) -> Self {
+        let home = root.join(name);
+        let folder = home.join("sync");
+        fs::create_dir_all(&folder).unwrap();
+        fs::create_dir_all(home.join(".config/mist")).unwrap();
+        let config = format!(
+            "[{}]\n\
+             folder = {:?}\n\
+             ssh_address = \"mist@127.0.0.1\"\n\
+             ssh_port = {}\n\
+             ssh_identity_file = {:?}\n\
+             known_hosts = \"off\"\n\
+             gpg_id = {:?}\n\
+             temp_folder = {:?}\n\
+             remote_path = \"mist-test\"\n",
+            PROFILE, folder, server.port, key, gpg_id, home.join("tmp/sync"));
+        fs::write(home.join(".config/mist/mist.toml"), config).unwrap();
+        Machine { home, gnupg: root.join("gnupg"), folder }
+    }
+
+    /// Run mist on the profile, failing the test unless it succeeds
+    /// without warnings.
+    fn mist(&self, args: &[&str]) -> Output {
+        run(Command::new(env!("CARGO_BIN_EXE_mist"))
+            .arg(PROFILE)
+            .args(args)
+            .env("HOME", &self.home)
+            .env("GNUPGHOME", &self.gnupg)
+            .env_remove("XDG_CONFIG_HOME")
+            .env_remove("XDG_STATE_HOME")
+            .env_remove("XDG_CACHE_HOME"))
+    }
+}
+
+/// Everything a sync should carry across, by path: for files the
+/// permission bits, modification time and contents; for links the target.
+fn tree(dir: &Path) -> BTreeMap<PathBuf, (String, Vec<u8>)> {
+    let mut found = BTreeMap::new();
+    let mut todo = vec![dir.to_path_buf()];
+    while let Some(d) = todo.pop() {
+        for e in fs::read_dir(&d).unwrap() {
+            let path = e.unwrap().path();
+            let meta = fs::symlink_metadata(&path).unwrap();
+            let rel = path.strip_prefix(dir).unwrap().to_path_buf();
+            let entry = if meta.file_type().is_symlink() {
+                let target = fs::read_link(&path).unwrap();
+                (format!("link {}", target.display()), Vec::new())
+            } else if meta.is_dir() {
+                todo.push(path);
+                ("dir".to_string(), Vec::new())
+            } else {
+                (format!("file {:o} {}", meta.mode() & 0o7777, meta.mtime()), fs::read(&path).unwrap())
+            };
+            found.insert(rel, entry);
+        }
+    }
+    found
+}
+
+/// Fill a folder with the kinds of things people sync.
+fn populate(dir: &Path) {
+    fs::write(dir.join("notes.txt"), "shopping: milk, eggs\n").unwrap();
+    fs::write(dir.join("empty"), "").unwrap();
+    fs::create_dir_all(dir.join("bin")).unwrap();
+    // A megabyte that doesn't compress away.
+    let mut x: u32 = 1;
+    let blob: Vec<u8> = (0..1 << 20)
+        .map(|_| {
+            x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (x >> 16) as u8
+        })
+        .collect();
+    fs::write(dir.join("bin/blob"), blob).unwrap();
+    fs::set_permissions(dir.join("bin/blob"), fs::Permissions::from_mode(0o600)).unwrap();
+    fs::create_dir_all(dir.join("scripts")).unwrap();
+    fs::write(dir.join("scripts/run.sh"), "#!/bin/sh\necho hi\n").unwrap();
+    fs::set_permissions(dir.join("scripts/run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+    fs::create_dir_all(dir.join("nested/deep/down")).unwrap();
+    fs::write(dir.join("nested/deep/down/file with spaces é.txt"), "ünïcödé\n").unwrap();
+    fs::create_dir_all(dir.join("empty dir")).unwrap();
+    symlink("notes.txt", dir.join("link")).unwrap();
+}
+
+/// A server, a GPG key, and two machines syncing through them: `a` with a
+/// populated folder, `b` with an empty one.
+struct World {
+    server: Server,
+    a: Machine,
+    b: Machine,
+    gnupg: PathBuf,
+    _root: TempDir,
+}
+
+impl World {
+    fn new() -> Self {
+        let root = tempfile::Builder::new().prefix("mist-it-").tempdir().unwrap();
+        let key = root.path().join("id_ed25519");
+        run(Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", "mist-test", "-f"])
+            .arg(&key));
+        let gnupg = root.path().join("gnupg");
+        fs::create_dir(&gnupg).unwrap();
+        fs::set_permissions(&gnupg, fs::Permissions::from_mode(0o700)).unwrap();
+        run(Command::new("gpg")
+            .env("GNUPGHOME", &gnupg)
+            .args(["--batch", "--passphrase", "", "--quick-gen-key",
+                   "mist test <mist-test@example.invalid>", "default", "default", "never"]));
+        let out = run(Command::new("gpg")
+            .env("GNUPGHOME", &gnupg)
+            .args(["--batch", "--with-colons", "--list-secret-keys"]));
+        let fingerprint = stdout(&out)
+            .lines()
+            .find_map(|x| Some(x.strip_prefix("fpr:")?.split(':').nth(8)?.to_string()))
+            .expect("the test key's fingerprint");
+
+        let server = Server::start(&key);
+        let a = Machine::new(root.path(), "a", &server, &key, &fingerprint);
+        let b = Machine::new(root.path(), "b", &server, &key, &fingerprint);
+        populate(&a.folder);
+        World { server, a, b, gnupg, _root: root }
+    }
+}
+
+impl Drop for World {
+    fn drop(&mut self) {
+        let _ = Command::new("gpgconf")
+            .env("GNUPGHOME", &self.gnupg)
+            .args(["--kill", "gpg-agent"])
+            .output();
+    }
+}
+
+#[test]
+#[ignore = "needs docker, gpg and ssh-keygen; run with --ignored"]
+fn push_then_pull_round_trips() {
+    let w = World::new();
+    w.a.mist(&["--push", "--create-remote", "-y"]);
+    w.b.mist(&["--pull", "-y"]);
+    assert_eq!(tree(&w.a.folder), tree(&w.b.folder));
+
+    // What reached the server is encrypted.
+    let listing = w.server.exec("ls -A /home/mist/mist-test");
+    assert!(! stdout(&listing).is_empty(), "nothing was pushed");
+    let plain = w.server.exec("cat /home/mist/mist-test/* | grep -c 'shopping: milk' || true");
+    assert_eq!(stdout(&plain), "0", "the remote copy holds plain text");
+}
+
+#[test]
+#[ignore = "needs docker, gpg and ssh-keygen; run with --ignored"]
+fn sync_carries_changes_both_ways() {
+    let w = World::new();
+    w.a.mist(&["--push", "--create-remote", "-y"]);
+    w.b.mist(&["--pull", "-y"]);
+
+    // From b to a: an edit, a new file and a deletion.
+    fs::write(w.b.folder.join("notes.txt"), "shopping: milk, eggs, bread\n").unwrap();
+    fs::write(w.b.folder.join("nested/new.txt"), "from b\n").unwrap();
+    fs::remove_file(w.b.folder.join("empty")).unwrap();
+    w.b.mist(&["-y"]);
+    w.a.mist(&["-y"]);
+    assert_eq!(tree(&w.a.folder), tree(&w.b.folder));
+    assert_eq!(fs::read_to_string(w.a.folder.join("notes.txt")).unwrap(),
+               "shopping: milk, eggs, bread\n");
+    assert!(! w.a.folder.join("empty").exists());
+
+    // And back again.
+    fs::remove_dir_all(w.a.folder.join("scripts")).unwrap();
+    fs::write(w.a.folder.join("empty dir/now not"), "from a\n").unwrap();
+    w.a.mist(&["-y"]);
+    w.b.mist(&["-y"]);
+    assert_eq!(tree(&w.a.folder), tree(&w.b.folder));
+    assert!(! w.b.folder.join("scripts").exists());
+
+    // A run with nothing to do changes nothing.
+    let before = tree(&w.b.folder);
+    w.b.mist(&["-y"]);
+    assert_eq!(tree(&w.b.folder), before);
+}